@@ -1,4 +1,3 @@
-use arrayref::array_ref;
 use failure::{err_msg, Error};
 use serde::Deserialize;
 use std::fs::{File, OpenOptions};
@@ -16,6 +15,7 @@ Usage: bao hash [<inputs>...]
        bao decode <hash> [<input>] [<output>] [--outboard=<file>] [--start=<offset>] [--count=<count>]
        bao slice <start> <count> [<input>] [<output>] [--outboard=<file>]
        bao decode-slice <hash> <start> <count> [<input>] [<output>]
+       bao encode-all <dir> [--manifest=<file>] [--outboard-dir=<dir>]
        bao (--help | --version)
 ";
 
@@ -26,6 +26,8 @@ struct Args {
     cmd_hash: bool,
     cmd_slice: bool,
     cmd_decode_slice: bool,
+    cmd_encode_all: bool,
+    arg_dir: Option<PathBuf>,
     arg_input: Option<PathBuf>,
     arg_inputs: Vec<PathBuf>,
     arg_output: Option<PathBuf>,
@@ -34,7 +36,9 @@ struct Args {
     arg_count: u64,
     flag_count: Option<u64>,
     flag_help: bool,
+    flag_manifest: Option<PathBuf>,
     flag_outboard: Option<PathBuf>,
+    flag_outboard_dir: Option<PathBuf>,
     flag_start: Option<u64>,
     flag_version: bool,
 }
@@ -58,6 +62,8 @@ fn main() -> Result<(), Error> {
         slice(&args)?;
     } else if args.cmd_decode_slice {
         decode_slice(&args)?;
+    } else if args.cmd_encode_all {
+        encode_all(&args)?;
     } else {
         unreachable!();
     }
@@ -228,6 +234,14 @@ fn decode_slice(args: &Args) -> Result<(), Error> {
     Ok(())
 }
 
+fn encode_all(args: &Args) -> Result<(), Error> {
+    let dir = args.arg_dir.as_ref().expect("docopt requires <dir>");
+    let entries = bao::batch::encode_dir(dir, args.flag_outboard_dir.as_deref())?;
+    let mut output = open_output(&args.flag_manifest)?;
+    bao::batch::write_json(&entries, &mut output)?;
+    Ok(())
+}
+
 fn open_input(maybe_path: &Option<PathBuf>) -> Result<Input, Error> {
     Ok(
         if let Some(ref path) = path_if_some_and_not_dash(maybe_path) {
@@ -349,11 +363,7 @@ fn maybe_memmap_input(input: &Input) -> Result<Option<memmap::Mmap>, Error> {
 }
 
 fn parse_hash(args: &Args) -> Result<bao::Hash, Error> {
-    let hash_vec = hex::decode(&args.arg_hash).map_err(|_| err_msg("invalid hex"))?;
-    if hash_vec.len() != bao::HASH_SIZE {
-        return Err(err_msg("wrong length hash"));
-    };
-    Ok((*array_ref!(hash_vec, 0, bao::HASH_SIZE)).into())
+    bao::Hash::from_hex(&args.arg_hash).map_err(|e| err_msg(format!("invalid hash: {}", e)))
 }
 
 // When streaming out decoded content, it's acceptable for the caller to pipe us
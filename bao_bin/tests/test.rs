@@ -277,3 +277,46 @@ fn test_slice() {
     .unwrap();
     assert_hash_mismatch(&output);
 }
+
+#[test]
+fn test_encode_all() {
+    let dir = tempdir().unwrap();
+    let input_dir = dir.path().join("input");
+    fs::create_dir_all(input_dir.join("sub")).unwrap();
+    fs::write(input_dir.join("foo"), b"foo").unwrap();
+    let mut bar = vec![0; 3000];
+    rand::thread_rng().fill_bytes(&mut bar);
+    fs::write(input_dir.join("sub").join("bar"), &bar).unwrap();
+    let outboard_dir = dir.path().join("outboards");
+    let manifest = dir.path().join("manifest.json");
+    cmd!(
+        bao_exe(),
+        "encode-all",
+        &input_dir,
+        "--outboard-dir",
+        &outboard_dir,
+        "--manifest",
+        &manifest
+    )
+    .run()
+    .unwrap();
+
+    let manifest = fs::read_to_string(&manifest).unwrap();
+    let (bar_outboard, bar_hash) = bao::encode::outboard(&bar);
+    assert_eq!(
+        bar_outboard,
+        fs::read(outboard_dir.join("sub").join("bar.obao")).unwrap()
+    );
+    let expected_bar = format!(
+        "{{\"path\": \"sub/bar\", \"hash\": \"{}\", \"content_len\": 3000, \"outboard_len\": {}}}",
+        bar_hash.to_hex(),
+        bar_outboard.len(),
+    );
+    assert!(manifest.contains(&expected_bar), "{}", manifest);
+    let foo_hash = blake3::hash(b"foo");
+    assert!(manifest.contains(foo_hash.to_hex().as_str()));
+
+    // Without a --manifest, the manifest goes to stdout.
+    let output = cmd!(bao_exe(), "encode-all", &input_dir).read().unwrap();
+    assert_eq!(manifest.trim_end(), output);
+}
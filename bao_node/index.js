@@ -0,0 +1,87 @@
+// Stream transforms around the native classes. The native module is built by `npm run build`.
+
+const { Duplex, Transform } = require('stream')
+const native = require('./bao_node.node')
+
+// Encode everything written to the stream. The encoding is only complete once the input ends,
+// so nothing comes out before that. Then the root hash comes with a 'hash' event, and the
+// encoding streams out from a temporary file, as fast as the reader takes it. The native calls
+// run off the event loop, and each write's callback waits for its call, which is what applies
+// backpressure to the writer.
+function createEncodeStream ({ outboard = false } = {}) {
+  const encoder = new native.Encoder(outboard)
+  let finalized = false
+  let wanted = false
+  let reading = false
+  const pump = () => {
+    if (reading) {
+      return
+    }
+    reading = true
+    encoder.read().then((piece) => {
+      reading = false
+      if (piece === null) {
+        stream.push(null)
+      } else if (stream.push(piece)) {
+        pump()
+      }
+    }, (err) => stream.destroy(err))
+  }
+  const stream = new Duplex({
+    write (chunk, encoding, callback) {
+      encoder.write(chunk).then(() => callback(), callback)
+    },
+    final (callback) {
+      encoder.finalize().then((hash) => {
+        stream.emit('hash', hash)
+        finalized = true
+        callback()
+        if (wanted) {
+          pump()
+        }
+      }, callback)
+    },
+    read () {
+      if (finalized) {
+        pump()
+      } else {
+        wanted = true
+      }
+    }
+  })
+  return stream
+}
+
+// The Transform doesn't take the next chunk until the callback runs, so a decoder that's behind
+// holds up the writer without blocking the event loop.
+function decodeTransform (decoder) {
+  const done = (callback) => (output) => callback(null, output.length > 0 ? output : undefined)
+  return new Transform({
+    transform (chunk, encoding, callback) {
+      decoder.write(chunk).then(done(callback), callback)
+    },
+    flush (callback) {
+      decoder.end().then(done(callback), callback)
+    }
+  })
+}
+
+// Decode a combined encoding, emitting only verified content. A hash mismatch or a truncated
+// encoding is an 'error' event.
+function createDecodeStream (hash) {
+  return decodeTransform(new native.Decoder(hash))
+}
+
+// Decode a slice, given the start and length that extracted it.
+function createSliceDecodeStream (hash, start, length) {
+  return decodeTransform(native.Decoder.slice(hash, start, length))
+}
+
+module.exports = {
+  Hasher: native.Hasher,
+  Encoder: native.Encoder,
+  Decoder: native.Decoder,
+  createEncodeStream,
+  createDecodeStream,
+  createSliceDecodeStream
+}
@@ -0,0 +1,533 @@
+//! Node.js bindings for bao, built with [napi-rs](https://napi.rs).
+//!
+//! This exposes a `Hasher`, an `Encoder`, and verifying `Decoder`s to JavaScript. The classes
+//! are push-based, taking `Buffer`s as they arrive, so that `index.js` can wrap them in streams.
+//! Hashes cross the boundary as hex strings.
+//!
+//! Hashing and IO never happen on the JS event loop. The `Encoder` and `Decoder` methods return
+//! Promises, and their work runs on the libuv thread pool. Each call resolves once its work is
+//! done, so a stream that waits for one call before making the next gets backpressure from that.
+//!
+//! The encoding isn't complete until all the input is in, so the `Encoder` writes it to a
+//! temporary file rather than holding it in memory, and after `finalize` the JS side reads it
+//! back out a piece at a time.
+//!
+//! The Rust decoders are pull-based, reading from an `io::Read`. To turn that around, each JS
+//! `Decoder` runs its Rust decoder on a background thread, feeding it input through a `Pipe` and
+//! collecting the verified output from the same pipe. Both directions are bounded, so a `write`
+//! whose input doesn't fit waits, off the event loop, for the decoder to catch up. Output comes
+//! back from `write` whenever it's ready, so a `write` might return nothing, and the rest comes
+//! back from `end`.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+// The largest piece of output that `Encoder.read` and the decoder threads hand back at once.
+const READ_SIZE: usize = 65536;
+
+// The number of pieces that each direction of a decoder's pipe holds before the sender has to
+// wait.
+const PIPE_BOUND: usize = 4;
+
+fn parse_hash(hex: &str) -> Result<bao::Hash> {
+    hex.parse()
+        .map_err(|_| Error::new(Status::InvalidArg, "invalid hash".to_owned()))
+}
+
+fn parse_offset(value: i64, name: &str) -> Result<u64> {
+    if value < 0 {
+        return Err(Error::new(Status::InvalidArg, format!("negative {}", name)));
+    }
+    Ok(value as u64)
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::new(Status::GenericFailure, e.to_string())
+}
+
+fn failure(message: &str) -> Error {
+    Error::new(Status::GenericFailure, message.to_owned())
+}
+
+// A panic on another thread can't leave any of our state half-updated in a way that matters, so
+// a poisoned lock is as good as any other.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// An incremental BLAKE3 hasher.
+#[napi]
+pub struct Hasher {
+    inner: blake3::Hasher,
+}
+
+#[napi]
+impl Hasher {
+    #[napi(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            inner: blake3::Hasher::new(),
+        }
+    }
+
+    #[napi]
+    pub fn update(&mut self, data: Buffer) {
+        self.inner.update(&data);
+    }
+
+    /// The hash of everything so far, as hex. The hasher can keep going afterwards.
+    #[napi]
+    pub fn digest(&self) -> String {
+        self.inner.finalize().to_hex().to_string()
+    }
+}
+
+// The state of an Encoder, shared with the tasks that run its calls.
+struct EncoderState {
+    inner: Option<bao::encode::Encoder<File>>,
+    output: Option<File>,
+}
+
+impl EncoderState {
+    fn encoder(&mut self) -> Result<&mut bao::encode::Encoder<File>> {
+        self.inner
+            .as_mut()
+            .ok_or_else(|| failure("already finalized"))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.encoder()?.write_all(data).map_err(io_error)
+    }
+
+    fn finalize(&mut self) -> Result<bao::Hash> {
+        let hash = self.encoder()?.finalize().map_err(io_error)?;
+        let mut file = self.inner.take().unwrap().into_inner();
+        file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+        self.output = Some(file);
+        Ok(hash)
+    }
+
+    fn read(&mut self) -> Result<Vec<u8>> {
+        let file = self
+            .output
+            .as_mut()
+            .ok_or_else(|| failure("not finalized"))?;
+        let mut buf = Vec::with_capacity(READ_SIZE);
+        file.take(READ_SIZE as u64)
+            .read_to_end(&mut buf)
+            .map_err(io_error)?;
+        Ok(buf)
+    }
+}
+
+/// An encoder for the combined or outboard encoding. The encoding isn't available until
+/// `finalize`, because the tree is only complete once all the input is in. After that, `read`
+/// returns it a piece at a time. Every method returns a Promise. Wait for each one before
+/// calling the next.
+#[napi]
+pub struct Encoder {
+    state: Arc<Mutex<EncoderState>>,
+}
+
+#[napi]
+impl Encoder {
+    #[napi(constructor)]
+    pub fn new(outboard: Option<bool>) -> Result<Self> {
+        let file = tempfile::tempfile().map_err(io_error)?;
+        let inner = if outboard.unwrap_or(false) {
+            bao::encode::Encoder::new_outboard(file)
+        } else {
+            bao::encode::Encoder::new(file)
+        };
+        Ok(Self {
+            state: Arc::new(Mutex::new(EncoderState {
+                inner: Some(inner),
+                output: None,
+            })),
+        })
+    }
+
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn write(&self, data: Buffer) -> AsyncTask<EncoderWrite> {
+        AsyncTask::new(EncoderWrite {
+            state: self.state.clone(),
+            data: data.to_vec(),
+        })
+    }
+
+    /// Finish the encoding and resolve to the root hash. After this, the encoding comes from
+    /// `read`.
+    #[napi(ts_return_type = "Promise<string>")]
+    pub fn finalize(&self) -> AsyncTask<EncoderFinalize> {
+        AsyncTask::new(EncoderFinalize {
+            state: self.state.clone(),
+        })
+    }
+
+    /// Resolve to the next piece of the encoding, after `finalize`, or `null` at the end.
+    #[napi(ts_return_type = "Promise<Buffer | null>")]
+    pub fn read(&self) -> AsyncTask<EncoderRead> {
+        AsyncTask::new(EncoderRead {
+            state: self.state.clone(),
+        })
+    }
+}
+
+pub struct EncoderWrite {
+    state: Arc<Mutex<EncoderState>>,
+    data: Vec<u8>,
+}
+
+impl Task for EncoderWrite {
+    type Output = ();
+    type JsValue = Undefined;
+
+    fn compute(&mut self) -> Result<()> {
+        lock(&self.state).write(&self.data)
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> Result<Undefined> {
+        Ok(())
+    }
+}
+
+pub struct EncoderFinalize {
+    state: Arc<Mutex<EncoderState>>,
+}
+
+impl Task for EncoderFinalize {
+    type Output = bao::Hash;
+    type JsValue = String;
+
+    fn compute(&mut self) -> Result<bao::Hash> {
+        lock(&self.state).finalize()
+    }
+
+    fn resolve(&mut self, _env: Env, hash: bao::Hash) -> Result<String> {
+        Ok(hash.to_hex().to_string())
+    }
+}
+
+pub struct EncoderRead {
+    state: Arc<Mutex<EncoderState>>,
+}
+
+impl Task for EncoderRead {
+    type Output = Vec<u8>;
+    type JsValue = Option<Buffer>;
+
+    fn compute(&mut self) -> Result<Vec<u8>> {
+        lock(&self.state).read()
+    }
+
+    fn resolve(&mut self, _env: Env, piece: Vec<u8>) -> Result<Option<Buffer>> {
+        Ok(if piece.is_empty() {
+            None
+        } else {
+            Some(piece.into())
+        })
+    }
+}
+
+// What passes between a JS Decoder and its background thread. Everyone waits on the same
+// condition variable, and every change to the state wakes them all, so a write that's waiting
+// for room in the input also collects output as it comes, and the decoder thread is never stuck
+// on a full output while the writer is stuck on a full input.
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    input: VecDeque<Vec<u8>>,
+    // Set by `end`, or when the JS Decoder is dropped.
+    input_closed: bool,
+    output: VecDeque<io::Result<Vec<u8>>>,
+    // Set when the JS Decoder is dropped, so that no one is left to take the output.
+    abandoned: bool,
+    // Set by the decoder thread just before it exits.
+    finished: bool,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        lock(&self.state)
+    }
+
+    fn wait<'a>(&self, guard: MutexGuard<'a, PipeState>) -> MutexGuard<'a, PipeState> {
+        self.changed.wait(guard).unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Send a piece of output from the decoder thread, waiting for room. This returns false if
+    // the JS Decoder is gone.
+    fn send_output(&self, result: io::Result<Vec<u8>>) -> bool {
+        let mut state = self.lock();
+        while state.output.len() >= PIPE_BOUND && !state.abandoned {
+            state = self.wait(state);
+        }
+        if state.abandoned {
+            return false;
+        }
+        state.output.push_back(result);
+        self.changed.notify_all();
+        true
+    }
+
+    // Move whatever output is ready into `output`, stopping at the first error, and wake the
+    // decoder thread if that made room.
+    fn drain_output(&self, state: &mut PipeState, output: &mut Vec<u8>) -> Result<()> {
+        if !state.output.is_empty() {
+            self.changed.notify_all();
+        }
+        while let Some(result) = state.output.pop_front() {
+            output.extend_from_slice(&result.map_err(io_error)?);
+        }
+        Ok(())
+    }
+
+    fn finish(&self) {
+        self.lock().finished = true;
+        self.changed.notify_all();
+    }
+}
+
+// The input side of a background decoder. It reads until the JS side calls `end`.
+struct PipeReader {
+    pipe: Arc<Pipe>,
+    buf: Vec<u8>,
+    position: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buf.len() {
+            let mut state = self.pipe.lock();
+            loop {
+                if let Some(next) = state.input.pop_front() {
+                    self.buf = next;
+                    self.position = 0;
+                    self.pipe.changed.notify_all();
+                    break;
+                }
+                if state.input_closed {
+                    return Ok(0);
+                }
+                state = self.pipe.wait(state);
+            }
+        }
+        let n = (&self.buf[self.position..]).read(buf)?;
+        self.position += n;
+        Ok(n)
+    }
+}
+
+// Once the decoder has returned all the content, anything else that comes in is an error. This
+// waits for the end of the input to find out.
+fn check_trailing(reader: &mut PipeReader) -> io::Result<()> {
+    if reader.read(&mut [0])? == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "trailing data after the end of the encoding",
+        ))
+    }
+}
+
+/// A verifying decoder for a combined encoding or a slice. Write the encoding in, in pieces of
+/// any size, and get the verified content back out. Any error, like a hash mismatch or trailing
+/// data after the end of the encoding, rejects the `write` or `end` call that finds it, and the
+/// content before the error is all verified. Every method returns a Promise. Wait for each one
+/// before calling the next.
+#[napi]
+pub struct Decoder {
+    pipe: Arc<Pipe>,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+#[napi]
+impl Decoder {
+    /// A decoder for a whole combined encoding.
+    #[napi(constructor)]
+    pub fn new(hash: String) -> Result<Self> {
+        let hash = parse_hash(&hash)?;
+        Ok(Self::spawn(move |reader| {
+            Box::new(bao::decode::Decoder::new(reader, &hash))
+        }))
+    }
+
+    /// A decoder for a slice from `bao slice` or the Rust `SliceExtractor`, with the same start
+    /// and length that extracted it.
+    #[napi(factory)]
+    pub fn slice(hash: String, start: i64, len: i64) -> Result<Self> {
+        let hash = parse_hash(&hash)?;
+        let start = parse_offset(start, "start")?;
+        let len = parse_offset(len, "length")?;
+        Ok(Self::spawn(move |reader| {
+            Box::new(bao::decode::SliceDecoder::new(reader, &hash, start, len))
+        }))
+    }
+
+    fn spawn(
+        make_decoder: impl FnOnce(&mut PipeReader) -> Box<dyn Read + Send + '_> + Send + 'static,
+    ) -> Self {
+        let pipe = Arc::new(Pipe {
+            state: Mutex::new(PipeState::default()),
+            changed: Condvar::new(),
+        });
+        let mut reader = PipeReader {
+            pipe: pipe.clone(),
+            buf: Vec::new(),
+            position: 0,
+        };
+        let thread_pipe = pipe.clone();
+        let thread = thread::spawn(move || {
+            let mut decoder = make_decoder(&mut reader);
+            loop {
+                let mut buf = vec![0; READ_SIZE];
+                let result = match decoder.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Ok(buf)
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = result.is_err();
+                if !thread_pipe.send_output(result) || failed {
+                    thread_pipe.finish();
+                    return;
+                }
+            }
+            drop(decoder);
+            if let Err(e) = check_trailing(&mut reader) {
+                thread_pipe.send_output(Err(e));
+            }
+            thread_pipe.finish();
+        });
+        Self {
+            pipe,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        }
+    }
+
+    /// Feed in more of the encoding, and resolve to whatever verified content is ready.
+    #[napi(ts_return_type = "Promise<Buffer>")]
+    pub fn write(&self, data: Buffer) -> AsyncTask<DecoderWrite> {
+        AsyncTask::new(DecoderWrite {
+            pipe: self.pipe.clone(),
+            data: Some(data.to_vec()),
+        })
+    }
+
+    /// Signal the end of the encoding, wait for the decoder to finish, and resolve to the rest
+    /// of the content. This rejects if the encoding was truncated.
+    #[napi(ts_return_type = "Promise<Buffer>")]
+    pub fn end(&self) -> AsyncTask<DecoderEnd> {
+        AsyncTask::new(DecoderEnd {
+            pipe: self.pipe.clone(),
+            thread: self.thread.clone(),
+        })
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        // Let the decoder thread exit, if it hasn't already.
+        let mut state = self.pipe.lock();
+        state.input_closed = true;
+        state.abandoned = true;
+        self.pipe.changed.notify_all();
+    }
+}
+
+pub struct DecoderWrite {
+    pipe: Arc<Pipe>,
+    data: Option<Vec<u8>>,
+}
+
+impl Task for DecoderWrite {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut state = self.pipe.lock();
+        if state.input_closed {
+            return Err(failure("already ended"));
+        }
+        loop {
+            self.pipe.drain_output(&mut state, &mut output)?;
+            if state.finished {
+                // The decoder thread only finishes early with an error, which the caller has
+                // already had.
+                return Err(failure("the decoder has already failed"));
+            }
+            if state.input.len() < PIPE_BOUND {
+                state.input.push_back(self.data.take().unwrap());
+                self.pipe.changed.notify_all();
+                break;
+            }
+            // The decoder thread is behind. Wait for it to take input or to send output.
+            state = self.pipe.wait(state);
+        }
+        self.pipe.drain_output(&mut state, &mut output)?;
+        Ok(output)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Vec<u8>) -> Result<Buffer> {
+        Ok(output.into())
+    }
+}
+
+pub struct DecoderEnd {
+    pipe: Arc<Pipe>,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Task for DecoderEnd {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut result = Ok(());
+        {
+            let mut state = self.pipe.lock();
+            state.input_closed = true;
+            self.pipe.changed.notify_all();
+            loop {
+                if result.is_ok() {
+                    result = self.pipe.drain_output(&mut state, &mut output);
+                }
+                // After an error, the rest of the output doesn't matter.
+                if result.is_err() && !state.output.is_empty() {
+                    state.output.clear();
+                    self.pipe.changed.notify_all();
+                }
+                if state.finished {
+                    break;
+                }
+                state = self.pipe.wait(state);
+            }
+        }
+        if let Some(thread) = lock(&self.thread).take() {
+            thread.join().map_err(|_| failure("decoder panicked"))?;
+        }
+        result.map(|()| output)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Vec<u8>) -> Result<Buffer> {
+        Ok(output.into())
+    }
+}
@@ -0,0 +1,114 @@
+// Tests for the native module and the stream wrappers. Build first with `npm run build`.
+
+const assert = require('assert')
+const { pipeline, Readable, Writable } = require('stream')
+const bao = require('./index.js')
+
+// Deterministic input, like make_test_input in the Rust tests.
+function makeInput (len) {
+  const input = Buffer.alloc(len)
+  for (let i = 0; i < len; i++) {
+    input[i] = (i * 7 + (i >> 10)) & 0xff
+  }
+  return input
+}
+
+function run (input, stream, pieceSize = 1000) {
+  return new Promise((resolve, reject) => {
+    const pieces = []
+    for (let i = 0; i < input.length; i += pieceSize) {
+      pieces.push(input.subarray(i, i + pieceSize))
+    }
+    const output = []
+    let hash = null
+    stream.on('hash', (h) => { hash = h })
+    pipeline(
+      Readable.from(pieces),
+      stream,
+      new Writable({
+        // A small buffer and a slow reader, to exercise backpressure.
+        highWaterMark: 1024,
+        write (chunk, encoding, callback) {
+          output.push(chunk)
+          setImmediate(callback)
+        }
+      }),
+      (err) => err ? reject(err) : resolve({ output: Buffer.concat(output), hash })
+    )
+  })
+}
+
+async function main () {
+  for (const len of [0, 1, 1024, 1025, 100000, 1000000]) {
+    const input = makeInput(len)
+
+    const hasher = new bao.Hasher()
+    hasher.update(input)
+    const hash = hasher.digest()
+
+    const encoded = await run(input, bao.createEncodeStream())
+    assert.strictEqual(encoded.hash, hash)
+    const outboard = await run(input, bao.createEncodeStream({ outboard: true }))
+    assert.strictEqual(outboard.hash, hash)
+    assert.ok(outboard.output.length < encoded.output.length || len <= 1024)
+
+    const decoded = await run(encoded.output, bao.createDecodeStream(hash), 777)
+    assert.ok(decoded.output.equals(input), `len ${len}`)
+
+    // A corrupt encoding is an error, and a truncated one too.
+    if (len > 0) {
+      const corrupt = Buffer.from(encoded.output)
+      corrupt[corrupt.length - 1] ^= 1
+      await assert.rejects(run(corrupt, bao.createDecodeStream(hash)))
+    }
+    const truncated = encoded.output.subarray(0, encoded.output.length - 1)
+    await assert.rejects(run(truncated, bao.createDecodeStream(hash)))
+
+    // Bytes after a complete encoding are an error too, rather than being dropped.
+    const trailing = Buffer.concat([encoded.output, Buffer.from([0])])
+    await assert.rejects(run(trailing, bao.createDecodeStream(hash)), /trailing data/)
+  }
+
+  // The native decoder's pipe is bounded. Writing lots of pieces, each as soon as the last one
+  // resolves, still works, with output coming back from write and end.
+  const input = makeInput(3000000)
+  const { output, hash } = await run(input, bao.createEncodeStream())
+  const decoder = new bao.Decoder(hash)
+  const pieces = []
+  for (let i = 0; i < output.length; i += 4096) {
+    pieces.push(await decoder.write(output.subarray(i, i + 4096)))
+  }
+  pieces.push(await decoder.end())
+  assert.ok(Buffer.concat(pieces).equals(input))
+  await assert.rejects(decoder.write(output))
+
+  // Trailing data written straight to the native decoder rejects a write or the end.
+  const extra = new bao.Decoder(hash)
+  await assert.rejects(async () => {
+    await extra.write(output)
+    await extra.write(Buffer.from('extra'))
+    await extra.end()
+  }, /trailing data/)
+
+  // A native call returns right away, and the event loop keeps running while it works.
+  const slow = new bao.Encoder()
+  let ticks = 0
+  const timer = setInterval(() => { ticks++ }, 0)
+  const write = slow.write(makeInput(50000000))
+  assert.ok(write instanceof Promise)
+  await write
+  clearInterval(timer)
+  assert.ok(ticks > 0)
+
+  const encoder = new bao.Encoder()
+  await assert.rejects(encoder.read())
+  await encoder.finalize()
+  await assert.rejects(encoder.finalize())
+
+  console.log('all tests passed')
+}
+
+main().catch((err) => {
+  console.error(err)
+  process.exit(1)
+})
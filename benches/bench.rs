@@ -1,22 +1,21 @@
-#![feature(test)]
-
-extern crate test;
-
-#[cfg(feature = "std")]
 use bao::{decode, encode};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use rand::prelude::*;
 use std::io::prelude::*;
 use std::io::{Cursor, SeekFrom::Start};
-use test::Bencher;
 
-// 64 bytes, just enough input to fill a single BLAKE2s block.
-const SHORT: usize = blake2s_simd::BLOCKBYTES;
+// 64 bytes, just enough input to fill a single BLAKE3 block.
+const SHORT: usize = 64;
 
-// Just enough input to occupy SIMD on a single thread. Currently 32 KiB on x86.
-const MEDIUM: usize = bao::benchmarks::CHUNK_SIZE * blake2s_simd::many::MAX_DEGREE;
+// Just enough input to occupy SIMD on a single thread. 16 KiB with AVX-512.
+const MEDIUM: usize = bao::benchmarks::CHUNK_SIZE * 16;
 
 const LONG: usize = 1 << 24; // about 17 MB
 
+const BUF_SIZE: usize = 16 * 1024;
+
+const LENGTHS: &[(&str, usize)] = &[("short", SHORT), ("medium", MEDIUM), ("long", LONG)];
+
 // This struct randomizes two things:
 // 1. The actual bytes of input.
 // 2. The page offset the input starts at.
@@ -28,8 +27,7 @@ pub struct RandomInput {
 }
 
 impl RandomInput {
-    pub fn new(b: &mut Bencher, len: usize) -> Self {
-        b.bytes += len as u64;
+    pub fn new(len: usize) -> Self {
         let page_size: usize = page_size::get();
         let mut buf = vec![0u8; len + page_size];
         let mut rng = rand::thread_rng();
@@ -54,234 +52,177 @@ impl RandomInput {
     }
 }
 
-#[bench]
-fn bench_bao_hash_slice_short(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, SHORT);
-    b.iter(|| bao::hash(input.get()));
-}
-
-#[bench]
-fn bench_bao_hash_slice_medium(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, MEDIUM);
-    b.iter(|| bao::hash(input.get()));
-}
-
-#[bench]
-fn bench_bao_hash_slice_long(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, LONG);
-    b.iter(|| bao::hash(input.get()));
-}
-
-#[bench]
-fn bench_bao_hasher_short(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, SHORT);
-    b.iter(|| {
-        let mut hasher = bao::Hasher::new();
-        hasher.update(input.get());
-        hasher.finalize()
-    });
-}
-
-#[bench]
-fn bench_bao_hasher_medium(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, MEDIUM);
-    b.iter(|| {
-        let mut hasher = bao::Hasher::new();
-        hasher.update(input.get());
-        hasher.finalize()
-    });
-}
-
-#[bench]
-fn bench_bao_hasher_long(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, LONG);
-    b.iter(|| {
-        let mut hasher = bao::Hasher::new();
-        hasher.update(input.get());
-        hasher.finalize()
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_encoder_combined_short(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, SHORT);
-    let mut output = Vec::with_capacity(encode::encoded_size(SHORT as u64) as usize);
-    b.iter(|| {
-        output.clear();
-        let mut encoder = encode::Encoder::new(Cursor::new(&mut output));
-        encoder.write_all(input.get()).unwrap();
-        encoder.finalize().unwrap()
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_encoder_combined_medium(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, MEDIUM);
-    let mut output = Vec::with_capacity(encode::encoded_size(MEDIUM as u64) as usize);
-    b.iter(|| {
-        output.clear();
-        let mut encoder = encode::Encoder::new(Cursor::new(&mut output));
-        encoder.write_all(input.get()).unwrap();
-        encoder.finalize().unwrap()
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_encoder_combined_long(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, LONG);
-    let mut output = Vec::with_capacity(encode::encoded_size(LONG as u64) as usize);
-    b.iter(|| {
-        output.clear();
-        let mut encoder = encode::Encoder::new(Cursor::new(&mut output));
-        encoder.write_all(input.get()).unwrap();
-        encoder.finalize().unwrap()
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_encoder_outboard_short(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, SHORT);
-    let mut output = Vec::with_capacity(encode::outboard_size(SHORT as u64) as usize);
-    b.iter(|| {
-        output.clear();
-        let mut encoder = encode::Encoder::new_outboard(Cursor::new(&mut output));
-        encoder.write_all(input.get()).unwrap();
-        encoder.finalize().unwrap()
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_encoder_outboard_medium(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, MEDIUM);
-    let mut output = Vec::with_capacity(encode::outboard_size(MEDIUM as u64) as usize);
-    b.iter(|| {
-        output.clear();
-        let mut encoder = encode::Encoder::new_outboard(Cursor::new(&mut output));
-        encoder.write_all(input.get()).unwrap();
-        encoder.finalize().unwrap()
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_encoder_outboard_long(b: &mut Bencher) {
-    let mut input = RandomInput::new(b, LONG);
-    let mut output = Vec::with_capacity(encode::outboard_size(LONG as u64) as usize);
-    b.iter(|| {
-        output.clear();
-        let mut encoder = encode::Encoder::new_outboard(Cursor::new(&mut output));
-        encoder.write_all(input.get()).unwrap();
-        encoder.finalize().unwrap()
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_decoder_combined_short(b: &mut Bencher) {
-    let input = RandomInput::new(b, SHORT).get().to_vec();
-    let (encoded, hash) = encode::encode(&input);
-    let mut output = [1; bao::BUF_SIZE];
-    b.iter(|| {
-        let mut decoder = decode::Decoder::new(&*encoded, &hash);
-        while decoder.read(&mut output).unwrap() > 0 {}
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_decoder_combined_medium(b: &mut Bencher) {
-    let input = RandomInput::new(b, MEDIUM).get().to_vec();
-    let (encoded, hash) = encode::encode(&input);
-    let mut output = [1; bao::BUF_SIZE];
-    b.iter(|| {
-        let mut decoder = decode::Decoder::new(&*encoded, &hash);
-        while decoder.read(&mut output).unwrap() > 0 {}
-    });
+fn bench_hash_slice(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_hash_slice");
+    for &(name, len) in LENGTHS {
+        let mut input = RandomInput::new(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(name, |b| b.iter(|| blake3::hash(input.get())));
+    }
+    group.finish();
+}
+
+fn bench_hasher(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_hasher");
+    for &(name, len) in LENGTHS {
+        let mut input = RandomInput::new(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(input.get());
+                hasher.finalize()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_encode_small(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_encode_small");
+    for &(name, len) in &[("short", SHORT), ("chunk", bao::benchmarks::CHUNK_SIZE)] {
+        let mut input = RandomInput::new(len);
+        let mut output = [0; encode::MAX_SMALL_ENCODED_SIZE];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| encode::encode_small(input.get(), &mut output))
+        });
+    }
+    group.finish();
+}
+
+fn bench_encode_decode_short(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_short");
+    group.throughput(Throughput::Bytes(SHORT as u64));
+    let mut input = RandomInput::new(SHORT);
+    group.bench_function("encode", |b| b.iter(|| encode::encode(input.get())));
+    let (encoded, hash) = encode::encode(input.get());
+    group.bench_function("decode", |b| b.iter(|| decode::decode(&encoded, &hash)));
+    group.finish();
+}
+
+fn bench_encoder(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_encoder");
+    for &(name, len) in LENGTHS {
+        let mut input = RandomInput::new(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        let mut output = Vec::with_capacity(encode::encoded_size(len as u64) as usize);
+        group.bench_function(BenchmarkId::new("combined", name), |b| {
+            b.iter(|| {
+                output.clear();
+                let mut encoder = encode::Encoder::new(Cursor::new(&mut output));
+                encoder.write_all(input.get()).unwrap();
+                encoder.finalize().unwrap()
+            })
+        });
+        let mut output = Vec::with_capacity(encode::outboard_size(len as u64) as usize);
+        group.bench_function(BenchmarkId::new("outboard", name), |b| {
+            b.iter(|| {
+                output.clear();
+                let mut encoder = encode::Encoder::new_outboard(Cursor::new(&mut output));
+                encoder.write_all(input.get()).unwrap();
+                encoder.finalize().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_decoder(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_decoder");
+    for &(name, len) in LENGTHS {
+        let input = RandomInput::new(len).get().to_vec();
+        let mut output = [1; BUF_SIZE];
+        group.throughput(Throughput::Bytes(len as u64));
+        let (encoded, hash) = encode::encode(&input);
+        group.bench_function(BenchmarkId::new("combined", name), |b| {
+            b.iter(|| {
+                let mut decoder = decode::Decoder::new(&*encoded, &hash);
+                while decoder.read(&mut output).unwrap() > 0 {}
+            })
+        });
+        let (outboard, hash) = encode::outboard(&input);
+        group.bench_function(BenchmarkId::new("outboard", name), |b| {
+            b.iter(|| {
+                let mut decoder = decode::Decoder::new_outboard(&*input, &*outboard, &hash);
+                while decoder.read(&mut output).unwrap() > 0 {}
+            })
+        });
+    }
+    group.finish();
 }
 
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_decoder_combined_long(b: &mut Bencher) {
-    let input = RandomInput::new(b, LONG).get().to_vec();
+fn bench_seek(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_seek");
+    let input = RandomInput::new(LONG).get().to_vec();
     let (encoded, hash) = encode::encode(&input);
-    let mut output = [1; bao::BUF_SIZE];
-    b.iter(|| {
-        let mut decoder = decode::Decoder::new(&*encoded, &hash);
-        while decoder.read(&mut output).unwrap() > 0 {}
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_decoder_outboard_short(b: &mut Bencher) {
-    let input = RandomInput::new(b, SHORT).get().to_vec();
-    let (outboard, hash) = encode::outboard(&input);
-    let mut output = [1; bao::BUF_SIZE];
-    b.iter(|| {
-        let mut decoder = decode::Decoder::new_outboard(&*input, &*outboard, &hash);
-        while decoder.read(&mut output).unwrap() > 0 {}
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_decoder_outboard_medium(b: &mut Bencher) {
-    let input = RandomInput::new(b, MEDIUM).get().to_vec();
-    let (outboard, hash) = encode::outboard(&input);
-    let mut output = [1; bao::BUF_SIZE];
-    b.iter(|| {
-        let mut decoder = decode::Decoder::new_outboard(&*input, &*outboard, &hash);
-        while decoder.read(&mut output).unwrap() > 0 {}
-    });
-}
 
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_decoder_outboard_long(b: &mut Bencher) {
-    let input = RandomInput::new(b, LONG).get().to_vec();
-    let (outboard, hash) = encode::outboard(&input);
-    let mut output = [1; bao::BUF_SIZE];
-    b.iter(|| {
-        let mut decoder = decode::Decoder::new_outboard(&*input, &*outboard, &hash);
-        while decoder.read(&mut output).unwrap() > 0 {}
-    });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_seek_memory(b: &mut Bencher) {
-    let input = RandomInput::new(b, LONG).get().to_vec();
-    b.bytes = 0;
-    let (encoded, hash) = encode::encode(&input);
     let mut rng = rand_xorshift::XorShiftRng::from_seed(Default::default());
     let mut decoder = decode::Decoder::new(Cursor::new(&encoded), &hash);
-    b.iter(|| {
-        let seek_offset = rng.gen_range(0, input.len() as u64);
-        decoder.seek(Start(seek_offset)).unwrap();
+    group.bench_function("memory", |b| {
+        b.iter(|| {
+            let seek_offset = rng.gen_range(0..input.len() as u64);
+            decoder.seek(Start(seek_offset)).unwrap();
+        })
     });
-}
-
-#[cfg(feature = "std")]
-#[bench]
-fn bench_bao_seek_file(b: &mut Bencher) {
-    let input = RandomInput::new(b, LONG).get().to_vec();
-    b.bytes = 0;
-    let (encoded, hash) = encode::encode(&input);
 
     let mut file = tempfile::tempfile().expect("tempfile creation error");
     file.write_all(&encoded).expect("file write error");
     file.flush().expect("file flush error");
     file.seek(Start(0)).expect("file seek error");
-
     let mut rng = rand_xorshift::XorShiftRng::from_seed(Default::default());
     let mut decoder = decode::Decoder::new(file, &hash);
-    b.iter(|| {
-        let seek_offset = rng.gen_range(0, input.len() as u64);
-        decoder.seek(Start(seek_offset)).expect("seek error");
-    });
-}
+    group.bench_function("file", |b| {
+        b.iter(|| {
+            let seek_offset = rng.gen_range(0..input.len() as u64);
+            decoder.seek(Start(seek_offset)).expect("seek error");
+        })
+    });
+    group.finish();
+}
+
+fn bench_hash_many_chunks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_hash_many_chunks");
+    for &(name, len) in &[("medium", MEDIUM), ("long", LONG)] {
+        let mut input = RandomInput::new(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let chunks: Vec<&[u8]> = input.get().chunks(bao::benchmarks::CHUNK_SIZE).collect();
+                bao::hash::hash_many_chunks(&chunks, 0)
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_parallel_decoder(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bao_parallel_decoder");
+    for &(name, len) in &[("medium", MEDIUM), ("long", LONG)] {
+        let input = RandomInput::new(len).get().to_vec();
+        let (encoded, hash) = encode::encode(&input);
+        let mut output = [1; BUF_SIZE];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut decoder = decode::ParallelDecoder::new(&*encoded, &hash);
+                while decoder.read(&mut output).unwrap() > 0 {}
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_hash_slice,
+    bench_hasher,
+    bench_encode_small,
+    bench_encode_decode_short,
+    bench_encoder,
+    bench_decoder,
+    bench_seek,
+    bench_hash_many_chunks,
+    bench_parallel_decoder,
+);
+criterion_main!(benches);
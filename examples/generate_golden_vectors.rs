@@ -0,0 +1,12 @@
+//! Print the golden test vectors as JSON. This is how `tests/golden_vectors.json` is generated:
+//!
+//! ```text
+//! cargo run --example generate_golden_vectors > tests/golden_vectors.json
+//! ```
+
+fn main() {
+    print!(
+        "{}",
+        bao::test_vectors::to_json(&bao::test_vectors::generate())
+    );
+}
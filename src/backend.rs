@@ -0,0 +1,793 @@
+//! Positional storage backends, for encoding and decoding without `Read + Write + Seek`.
+//!
+//! The encoder, the decoders, and the other types in this crate work with standard IO traits.
+//! Plenty of storage doesn't look like a stream with a cursor, though: object stores answer
+//! ranged GETs, block devices and memory maps are addressed by offset, and files support
+//! positional reads and writes. `Backend` describes that kind of storage with three methods,
+//! and `BackendIo` adapts any `Backend` into `Read + Write + Seek`, so it works everywhere a
+//! stream does.
+//!
+//! The encoder, the decoders, and the slice extractor take a `Backend` directly, with
+//! `encode::Encoder::from_backend`, `decode::Decoder::from_backend`,
+//! `encode::SliceExtractor::from_backend`, and their outboard variants, and `encode::flip_backend`
+//! flips an encoding in a `Backend` in place. All of their IO, including the flip pass at the
+//! end of encoding, turns into `get_at` and `put_at` calls at explicit offsets.
+//!
+//! `Backend` is implemented here for `File` (using positional IO, which doesn't move the file's
+//! cursor), for byte slices, which covers memory-mapped files, and for `Vec<u8>`. An object store
+//! client implements it with ranged reads and, if the store supports it, ranged writes. For a
+//! read-only store with an async client, `AsyncGetter` wraps a callback that returns a future for
+//! each byte range, the same callbacks that the `remote` module takes, and blocks on it.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::io::prelude::*;
+//! use bao::backend::BackendIo;
+//!
+//! let input = vec![0xab; 10_000];
+//! let mut encoder = bao::encode::Encoder::from_backend(Vec::new());
+//! encoder.write_all(&input)?;
+//! let hash = encoder.finalize()?;
+//! let encoded: Vec<u8> = encoder.into_inner().into_inner();
+//!
+//! // Decode straight out of a (possibly memory-mapped) slice.
+//! let mut decoder = bao::decode::Decoder::from_backend(&encoded[..], &hash);
+//! let mut output = Vec::new();
+//! decoder.read_to_end(&mut output)?;
+//! assert_eq!(input, output);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+/// Storage addressed by offset.
+pub trait Backend {
+    /// Read bytes starting at `offset` into `buf`, and return how many were read. This only
+    /// reads fewer bytes than `buf.len()` at the end of the storage, and it returns 0 for an
+    /// `offset` at or past the end.
+    fn get_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write all of `buf` starting at `offset`, extending the storage if necessary. Storage that
+    /// can't grow returns an error for writes past its end. The default implementation returns
+    /// an `Unsupported` error, for read-only backends.
+    fn put_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let _ = (offset, buf);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "backend is read-only",
+        ))
+    }
+
+    /// The current size of the storage.
+    fn len(&mut self) -> io::Result<u64>;
+
+    /// Whether the storage is empty.
+    fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+impl<B: Backend + ?Sized> Backend for &mut B {
+    fn get_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).get_at(offset, buf)
+    }
+
+    fn put_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        (**self).put_at(offset, buf)
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        (**self).len()
+    }
+}
+
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn get_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).get_at(offset, buf)
+    }
+
+    fn put_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        (**self).put_at(offset, buf)
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        (**self).len()
+    }
+}
+
+fn copy_out(storage: &[u8], offset: u64, buf: &mut [u8]) -> usize {
+    if offset >= storage.len() as u64 {
+        return 0;
+    }
+    let available = &storage[offset as usize..];
+    let n = cmp::min(available.len(), <[u8]>::len(buf));
+    buf[..n].copy_from_slice(&available[..n]);
+    n
+}
+
+/// A read-only slice.
+impl Backend for &[u8] {
+    fn get_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(copy_out(self, offset, buf))
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+}
+
+/// A fixed-size slice, like a memory-mapped file. Writes past the end are errors.
+impl Backend for &mut [u8] {
+    fn get_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(copy_out(self, offset, buf))
+    }
+
+    fn put_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let end = offset.checked_add(buf.len() as u64);
+        match end {
+            Some(end) if end <= <[u8]>::len(self) as u64 => {
+                self[offset as usize..end as usize].copy_from_slice(buf);
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "write past the end of a fixed-size backend",
+            )),
+        }
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+}
+
+/// A growable buffer. Writes past the end fill the gap with zeros.
+impl Backend for Vec<u8> {
+    fn get_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(copy_out(self, offset, buf))
+    }
+
+    fn put_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= usize::MAX as u64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "offset too large"))?;
+        if end as usize > Vec::len(self) {
+            self.resize(end as usize, 0);
+        }
+        self[offset as usize..end as usize].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(Vec::len(self) as u64)
+    }
+}
+
+/// A file, with positional reads and writes that don't use or move its cursor.
+#[cfg(any(unix, windows))]
+impl Backend for File {
+    fn get_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < <[u8]>::len(buf) {
+            match file_read_at(self, offset + filled as u64, &mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+
+    fn put_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < buf.len() {
+            match file_write_at(self, offset + written as u64, &buf[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+#[cfg(unix)]
+fn file_read_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn file_write_at(file: &File, offset: u64, buf: &[u8]) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+// On Windows, these do move the cursor, but BackendIo doesn't depend on it.
+#[cfg(windows)]
+fn file_read_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn file_write_at(file: &File, offset: u64, buf: &[u8]) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
+
+/// A read-only backend that fetches bytes through an async callback, like a ranged GET to an
+/// object store.
+///
+/// The callback takes a byte range and returns a future of the bytes, the same shape as the
+/// callbacks in the `remote` module. `get_at` blocks the current thread on that future. The
+/// future is polled on the calling thread with no runtime around it, so a client whose futures
+/// need a particular runtime, like most tokio-based HTTP clients, has to enter that runtime in
+/// the callback, or hand the request to it and wait on a channel. The length of the object is
+/// fixed when the getter is created, from a HEAD request for example, and reads are clamped to
+/// it.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+/// use bao::backend::AsyncGetter;
+///
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// // This stands in for a ranged GET request.
+/// let get_range = |range: std::ops::Range<u64>| {
+///     let bytes = encoded[range.start as usize..range.end as usize].to_vec();
+///     async move { std::io::Result::Ok(bytes) }
+/// };
+/// let getter = AsyncGetter::new(get_range, encoded.len() as u64);
+/// let mut decoder = bao::decode::Decoder::from_backend(getter, &hash);
+/// decoder.seek(std::io::SeekFrom::Start(5_000))?;
+/// let mut output = Vec::new();
+/// decoder.read_to_end(&mut output)?;
+/// assert_eq!(&input[5_000..], &output[..]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncGetter<F> {
+    get_range: F,
+    len: u64,
+}
+
+impl<F> AsyncGetter<F> {
+    /// Wrap a callback that fetches ranges of an object of `len` bytes.
+    pub fn new(get_range: F, len: u64) -> Self {
+        Self { get_range, len }
+    }
+}
+
+impl<F, Fut, B> Backend for AsyncGetter<F>
+where
+    F: FnMut(Range<u64>) -> Fut,
+    Fut: Future<Output = io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    fn get_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.len || <[u8]>::is_empty(buf) {
+            return Ok(0);
+        }
+        let end = cmp::min(self.len, offset.saturating_add(<[u8]>::len(buf) as u64));
+        let bytes = block_on((self.get_range)(offset..end))?;
+        let bytes = bytes.as_ref();
+        if (bytes.len() as u64) < end - offset {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "short read from the async getter",
+            ));
+        }
+        let n = (end - offset) as usize;
+        buf[..n].copy_from_slice(&bytes[..n]);
+        Ok(n)
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+impl<F> fmt::Debug for AsyncGetter<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AsyncGetter {{ len: {} }}", self.len)
+    }
+}
+
+// Poll a future to completion on the current thread, parking between polls.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match Pin::as_mut(&mut future).poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Storage that many threads can read by offset at once, through a shared reference, like
+/// `pread`. Reads don't need `&mut` access or a cursor, so one open file or memory map can serve
+/// every thread. This is what `decode::SharedReader` reads from, and `ReadAtCursor` adapts it to
+/// `Read + Seek` for the streaming decoders. It's implemented for byte slices (which covers
+/// memory maps, through `&map[..]`), `Vec<u8>`, and `File`, and for shared references and `Arc`s
+/// of those, and a network client with ranged reads can implement it too.
+pub trait ReadAt {
+    /// Read bytes starting at `offset` into `buf`, and return how many were read. Like
+    /// `Backend::get_at`, this only reads fewer bytes than `buf.len()` at the end of the storage,
+    /// and it returns 0 for an `offset` at or past the end.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// The current size of the storage.
+    fn size(&self) -> io::Result<u64>;
+}
+
+impl<R: ReadAt + ?Sized> ReadAt for &R {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl<R: ReadAt + ?Sized> ReadAt for Box<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl<R: ReadAt + ?Sized> ReadAt for Arc<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(copy_out(self, offset, buf))
+    }
+    fn size(&self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(copy_out(self, offset, buf))
+    }
+    fn size(&self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl ReadAt for File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < <[u8]>::len(buf) {
+            match file_read_at(self, offset + filled as u64, &mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+/// An adapter from a `ReadAt` to `Read + Seek`, with its own cursor.
+///
+/// Every cursor has its own position, and the storage underneath doesn't have one, so each
+/// thread can have its own cursor over the same shared `File` or memory map, and hand it to a
+/// `decode::Decoder` or `decode::SliceDecoder`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+/// use std::sync::Arc;
+/// use bao::backend::ReadAtCursor;
+///
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let shared = Arc::new(encoded);
+/// std::thread::scope(|scope| {
+///     for _ in 0..4 {
+///         let cursor = ReadAtCursor::new(Arc::clone(&shared));
+///         let input = &input;
+///         scope.spawn(move || {
+///             let mut output = Vec::new();
+///             bao::decode::Decoder::new(cursor, &hash)
+///                 .read_to_end(&mut output)
+///                 .unwrap();
+///             assert_eq!(input, &output);
+///         });
+///     }
+/// });
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReadAtCursor<R: ReadAt> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: ReadAt> ReadAtCursor<R> {
+    /// Wrap some storage, with the cursor at the start.
+    pub fn new(inner: R) -> Self {
+        Self { inner, position: 0 }
+    }
+
+    /// Borrow the underlying storage.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Return the underlying storage.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: ReadAt> Read for ReadAtCursor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read_at(self.position, buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: ReadAt> Seek for ReadAtCursor<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_target(self.position, pos, || self.inner.size())?;
+        Ok(self.position)
+    }
+}
+
+// Resolve a SeekFrom, calling `size` only for SeekFrom::End.
+fn seek_target(
+    position: u64,
+    pos: SeekFrom,
+    size: impl FnOnce() -> io::Result<u64>,
+) -> io::Result<u64> {
+    let (base, offset) = match pos {
+        SeekFrom::Start(n) => return Ok(n),
+        SeekFrom::End(offset) => (size()?, offset),
+        SeekFrom::Current(offset) => (position, offset),
+    };
+    let target = base as i128 + offset as i128;
+    if target < 0 || target > u64::MAX as i128 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek position",
+        ));
+    }
+    Ok(target as u64)
+}
+
+/// An adapter from a `Backend` to `Read + Write + Seek`, with its own cursor.
+#[derive(Clone, Debug)]
+pub struct BackendIo<B: Backend> {
+    backend: B,
+    position: u64,
+}
+
+impl<B: Backend> BackendIo<B> {
+    /// Wrap a backend, with the cursor at the start.
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            position: 0,
+        }
+    }
+
+    /// Borrow the underlying backend.
+    pub fn get_ref(&self) -> &B {
+        &self.backend
+    }
+
+    /// Mutably borrow the underlying backend.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Return the underlying backend.
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+}
+
+impl<B: Backend> Read for BackendIo<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.backend.get_at(self.position, buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<B: Backend> Write for BackendIo<B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.backend.put_at(self.position, buf)?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<B: Backend> Seek for BackendIo<B> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let backend = &mut self.backend;
+        self.position = seek_target(self.position, pos, || backend.len())?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode;
+    use crate::decode::make_test_input;
+    use crate::encode;
+
+    fn encode_into(backend: impl Backend, input: &[u8]) -> crate::Hash {
+        let mut encoder = encode::Encoder::from_backend(backend);
+        encoder.write_all(input).unwrap();
+        encoder.finalize().unwrap()
+    }
+
+    #[test]
+    fn test_vec_and_slices() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_encoded, expected_hash) = encode::encode(&input);
+
+            let mut encoded = Vec::new();
+            assert_eq!(expected_hash, encode_into(&mut encoded, &input));
+            assert_eq!(expected_encoded, encoded);
+
+            // A fixed-size slice works when it's big enough.
+            let mut fixed = vec![0; encoded.len()];
+            assert_eq!(expected_hash, encode_into(&mut fixed[..], &input));
+            assert_eq!(expected_encoded, fixed);
+
+            let mut decoder = decode::Decoder::from_backend(&encoded[..], &expected_hash);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+
+            let mut outboard = Vec::new();
+            let mut encoder = encode::Encoder::from_backend_outboard(&mut outboard);
+            encoder.write_all(&input).unwrap();
+            encoder.finalize().unwrap();
+            let mut decoder =
+                decode::Decoder::from_backend_outboard(&input[..], &outboard[..], &expected_hash);
+            decoder.seek(SeekFrom::Start(case as u64 / 2)).unwrap();
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(&input[case / 2..], &output[..]);
+        }
+    }
+
+    #[test]
+    fn test_flip_and_slice() {
+        let input = make_test_input(10 * crate::CHUNK_SIZE + 1);
+        let (encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+
+        // Flip a post-order encoding of three chunks in a fixed-size buffer, the way a memory map
+        // would be. See the example for encode::flip.
+        let three_chunks = &input[..2100];
+        let (three_encoded, _) = encode::encode(three_chunks);
+        let parents = &three_encoded[8..8 + 128];
+        let chunks = &three_encoded[8 + 128..];
+        let mut post_order = Vec::new();
+        post_order.extend_from_slice(&chunks[..2048]);
+        post_order.extend_from_slice(&parents[64..]);
+        post_order.extend_from_slice(&chunks[2048..]);
+        post_order.extend_from_slice(&parents[..64]);
+        post_order.extend_from_slice(&three_encoded[..8]);
+        encode::flip_backend(&mut post_order[..]).unwrap();
+        assert_eq!(three_encoded, post_order);
+
+        let slice_start = 3 * crate::CHUNK_SIZE as u64 + 5;
+        let mut expected = Vec::new();
+        encode::SliceExtractor::new(io::Cursor::new(&encoded), slice_start, 2000)
+            .read_to_end(&mut expected)
+            .unwrap();
+        for outboard_mode in [false, true] {
+            let mut slice = Vec::new();
+            if outboard_mode {
+                encode::SliceExtractor::from_backend_outboard(
+                    &input[..],
+                    &outboard[..],
+                    slice_start,
+                    2000,
+                )
+                .read_to_end(&mut slice)
+                .unwrap();
+            } else {
+                encode::SliceExtractor::from_backend(&encoded[..], slice_start, 2000)
+                    .read_to_end(&mut slice)
+                    .unwrap();
+            }
+            assert_eq!(expected, slice);
+        }
+        let mut output = Vec::new();
+        decode::SliceDecoder::new(&*expected, &hash, slice_start, 2000)
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(&input[slice_start as usize..][..2000], &output[..]);
+    }
+
+    #[test]
+    fn test_async_getter() {
+        let input = make_test_input(10 * crate::CHUNK_SIZE + 1);
+        let (encoded, hash) = encode::encode(&input);
+        let requests = std::cell::Cell::new(0);
+        let get_range = |range: Range<u64>| {
+            requests.set(requests.get() + 1);
+            assert!(range.end <= encoded.len() as u64);
+            let bytes = &encoded[range.start as usize..range.end as usize];
+            std::future::ready(io::Result::Ok(bytes))
+        };
+        let mut getter = AsyncGetter::new(get_range, encoded.len() as u64);
+        assert_eq!(encoded.len() as u64, getter.len().unwrap());
+        let mut buf = [0; 10];
+        assert_eq!(
+            3,
+            getter.get_at(encoded.len() as u64 - 3, &mut buf).unwrap()
+        );
+        assert_eq!(0, getter.get_at(encoded.len() as u64, &mut buf).unwrap());
+        let err = getter.put_at(0, &[1]).unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, err.kind());
+
+        let mut decoder = decode::Decoder::from_backend(getter, &hash);
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(input, output);
+        assert!(requests.get() > 1);
+
+        // A getter that comes back short is an error, not a silent truncation.
+        let short = |range: Range<u64>| {
+            std::future::ready(io::Result::Ok(vec![
+                0;
+                (range.end - range.start - 1) as usize
+            ]))
+        };
+        let err = AsyncGetter::new(short, 100)
+            .get_at(0, &mut [0; 10])
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_file() {
+        let input = make_test_input(10 * crate::CHUNK_SIZE + 1);
+        let (expected_encoded, expected_hash) = encode::encode(&input);
+        let file = tempfile::tempfile().unwrap();
+        let mut io = BackendIo::new(file);
+        let mut encoder = encode::Encoder::new(&mut io);
+        encoder.write_all(&input).unwrap();
+        assert_eq!(expected_hash, encoder.finalize().unwrap());
+        let mut file = io.into_inner();
+        assert_eq!(
+            expected_encoded.len() as u64,
+            Backend::len(&mut file).unwrap()
+        );
+        let mut encoded = vec![0; expected_encoded.len()];
+        assert_eq!(encoded.len(), file.get_at(0, &mut encoded).unwrap());
+        assert_eq!(expected_encoded, encoded);
+        assert_eq!(0, file.get_at(encoded.len() as u64, &mut [0; 10]).unwrap());
+    }
+
+    #[test]
+    fn test_read_at() {
+        let input = make_test_input(10 * crate::CHUNK_SIZE + 1);
+        let (encoded, hash) = encode::encode(&input);
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&encoded).unwrap();
+        // Reads through a shared reference don't move the file's own cursor.
+        let file_ref = &file;
+        assert_eq!(encoded.len() as u64, ReadAt::size(&file_ref).unwrap());
+        let sources: [&dyn ReadAt; 3] = [&file, &encoded, &&encoded[..]];
+        for &source in &sources {
+            let mut buf = [0; 10];
+            assert_eq!(10, source.read_at(5, &mut buf).unwrap());
+            assert_eq!(&encoded[5..15], &buf);
+            let end = encoded.len() as u64;
+            assert_eq!(3, source.read_at(end - 3, &mut buf).unwrap());
+            assert_eq!(0, source.read_at(end + 1, &mut buf).unwrap());
+
+            let mut decoder = decode::Decoder::new(ReadAtCursor::new(source), &hash);
+            decoder
+                .seek(SeekFrom::Start(input.len() as u64 / 2))
+                .unwrap();
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(&input[input.len() / 2..], &output[..]);
+        }
+        assert_eq!(encoded.len() as u64, file.stream_position().unwrap());
+
+        let mut cursor = ReadAtCursor::new(&encoded[..]);
+        assert_eq!(encoded.len() as u64, cursor.seek(SeekFrom::End(0)).unwrap());
+        assert_eq!(2, cursor.seek(SeekFrom::Start(2)).unwrap());
+        assert!(cursor.seek(SeekFrom::Current(-3)).is_err());
+        let mut buf = [0; 4];
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(&encoded[2..6], &buf);
+    }
+
+    #[test]
+    fn test_limits() {
+        // Fixed-size slices can't grow.
+        let mut fixed = [0; 10];
+        let err = (&mut &mut fixed[..]).put_at(5, &[1; 6]).unwrap_err();
+        assert_eq!(io::ErrorKind::WriteZero, err.kind());
+        (&mut &mut fixed[..]).put_at(4, &[1; 6]).unwrap();
+        assert_eq!([0, 0, 0, 0, 1, 1, 1, 1, 1, 1], fixed);
+
+        // Read-only slices can't be written.
+        let err = (&fixed[..]).put_at(0, &[1]).unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, err.kind());
+
+        // Vecs fill gaps with zeros.
+        let mut v = vec![1];
+        v.put_at(3, &[2]).unwrap();
+        assert_eq!(vec![1, 0, 0, 2], v);
+
+        let mut io = BackendIo::new(v);
+        assert_eq!(4, io.seek(SeekFrom::End(0)).unwrap());
+        assert_eq!(2, io.seek(SeekFrom::Current(-2)).unwrap());
+        assert!(io.seek(SeekFrom::Current(-3)).is_err());
+        let mut buf = [9; 4];
+        assert_eq!(2, io.read(&mut buf).unwrap());
+        assert_eq!([0, 2, 9, 9], buf);
+    }
+}
@@ -0,0 +1,319 @@
+//! Encoding every file in a directory at once.
+//!
+//! Ingestion pipelines usually have a whole directory of files to encode, not just one.
+//! `encode_dir` walks a directory, writes an outboard encoding of each regular file into a
+//! mirror of the directory tree, and returns an `Entry` for each file with its root hash and
+//! sizes. Files are encoded in parallel, on as many threads as
+//! `std::thread::available_parallelism` reports. `write_json` serializes the entries as a JSON
+//! manifest, which is what `bao encode-all` prints.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let input_dir = tempfile::tempdir()?;
+//! std::fs::create_dir(input_dir.path().join("sub"))?;
+//! std::fs::write(input_dir.path().join("sub").join("foo.txt"), b"foo")?;
+//! let outboard_dir = tempfile::tempdir()?;
+//!
+//! let entries = bao::batch::encode_dir(input_dir.path(), Some(outboard_dir.path()))?;
+//! assert_eq!(1, entries.len());
+//! assert_eq!("sub/foo.txt", entries[0].path);
+//! assert_eq!(blake3::hash(b"foo"), entries[0].hash);
+//! assert!(outboard_dir.path().join("sub").join("foo.txt.obao").is_file());
+//!
+//! let mut json = Vec::new();
+//! bao::batch::write_json(&entries, &mut json)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::encode::{self, EncodedOffset};
+use crate::Hash;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// The extension that `encode_dir` adds to the name of each outboard encoding.
+pub const OUTBOARD_EXTENSION: &str = "obao";
+
+/// One encoded file, as returned by `encode_dir`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Entry {
+    /// The path of the file relative to the input directory, with components separated by `/`
+    /// on every platform.
+    pub path: String,
+    /// The root hash of the file's content, which is also its BLAKE3 hash.
+    pub hash: Hash,
+    /// The length of the file, as it was when it was encoded.
+    pub content_len: u64,
+    /// The size of the file's outboard encoding, whether or not `encode_dir` wrote it.
+    pub outboard_len: u64,
+}
+
+/// Encode every regular file under `dir`, and return their entries sorted by path.
+///
+/// If `outboard_dir` is given, the outboard encoding of `dir/a/b` is written to
+/// `outboard_dir/a/b.obao`, creating directories as needed. Otherwise the files are only
+/// hashed, and `outboard_len` is the size the outboard encoding would have. If `outboard_dir` is
+/// inside `dir`, it's skipped by the walk.
+///
+/// Symlinks and other special files are skipped, so the walk can't loop. File names that aren't
+/// valid UTF-8 are an `InvalidData` error, because they can't go in a manifest. The first error
+/// stops the batch, and its message includes the path of the file that caused it.
+pub fn encode_dir(dir: impl AsRef<Path>, outboard_dir: Option<&Path>) -> io::Result<Vec<Entry>> {
+    let dir = dir.as_ref();
+    let skip = match outboard_dir {
+        Some(outboard_dir) => {
+            fs::create_dir_all(outboard_dir)?;
+            Some(outboard_dir.canonicalize()?)
+        }
+        None => None,
+    };
+    let mut files = Vec::new();
+    walk(dir, String::new(), skip.as_deref(), &mut files)?;
+    files.sort();
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new(Vec::with_capacity(files.len()));
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    thread::scope(|scope| {
+        for _ in 0..threads.min(files.len()) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = files.get(index) else {
+                        return;
+                    };
+                    let result = encode_file(dir, path, outboard_dir).map_err(|e| {
+                        failed.store(true, Ordering::Relaxed);
+                        io::Error::new(e.kind(), format!("{}: {}", path, e))
+                    });
+                    results.lock().unwrap().push((index, result));
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|&(index, _)| index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn walk(
+    dir: &Path,
+    prefix: String,
+    skip: Option<&Path>,
+    files: &mut Vec<String>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().map_err(|name| {
+            let message = format!("non-UTF-8 file name: {}", Path::new(&name).display());
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        })?;
+        let path = format!("{}{}", prefix, name);
+        // DirEntry::file_type doesn't follow symlinks.
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if skip.is_some() && Some(entry.path().canonicalize()?.as_path()) == skip {
+                continue;
+            }
+            walk(&entry.path(), path + "/", skip, files)?;
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn encode_file(dir: &Path, path: &str, outboard_dir: Option<&Path>) -> io::Result<Entry> {
+    let mut file = File::open(native_path(dir, path))?;
+    let (hash, content_len) = if let Some(outboard_dir) = outboard_dir {
+        let mut outboard_path = native_path(outboard_dir, path);
+        outboard_path.as_mut_os_string().push(".");
+        outboard_path.as_mut_os_string().push(OUTBOARD_EXTENSION);
+        fs::create_dir_all(outboard_path.parent().expect("joined path has a parent"))?;
+        let outboard = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(outboard_path)?;
+        let mut encoder = encode::Encoder::new_outboard(outboard);
+        let content_len = io::copy(&mut file, &mut encoder)?;
+        (encoder.finalize()?, content_len)
+    } else {
+        let mut hasher = blake3::Hasher::new();
+        let content_len = io::copy(&mut file, &mut hasher)?;
+        (hasher.finalize(), content_len)
+    };
+    Ok(Entry {
+        path: path.to_string(),
+        hash,
+        content_len,
+        outboard_len: EncodedOffset::new(encode::outboard_size(content_len)).to_u64()?,
+    })
+}
+
+fn native_path(base: &Path, path: &str) -> PathBuf {
+    path.split('/')
+        .fold(base.to_path_buf(), |acc, name| acc.join(name))
+}
+
+/// Write `entries` as a JSON manifest: an array with one object per file, holding its `path`, its
+/// `hash` in hex, its `content_len`, and its `outboard_len`. Each entry goes on its own line, so
+/// manifests diff well.
+pub fn write_json(entries: &[Entry], mut output: impl Write) -> io::Result<()> {
+    output.write_all(b"[")?;
+    for (i, entry) in entries.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        write!(output, "{}\n  {{\"path\": \"", separator)?;
+        for c in entry.path.chars() {
+            match c {
+                '"' => output.write_all(b"\\\"")?,
+                '\\' => output.write_all(b"\\\\")?,
+                c if (c as u32) < 0x20 => write!(output, "\\u{:04x}", c as u32)?,
+                c => write!(output, "{}", c)?,
+            }
+        }
+        write!(
+            output,
+            "\", \"hash\": \"{}\", \"content_len\": {}, \"outboard_len\": {}}}",
+            entry.hash.to_hex(),
+            entry.content_len,
+            entry.outboard_len,
+        )?;
+    }
+    output.write_all(if entries.is_empty() { b"]\n" } else { b"\n]\n" })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+
+    fn write_tree(dir: &Path) {
+        fs::create_dir_all(dir.join("a").join("b")).unwrap();
+        fs::create_dir(dir.join("empty")).unwrap();
+        fs::write(dir.join("top"), make_test_input(5000)).unwrap();
+        fs::write(dir.join("a").join("one"), b"").unwrap();
+        fs::write(
+            dir.join("a").join("b").join("two \"q\""),
+            make_test_input(1025),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_encode_dir() {
+        let input_dir = tempfile::tempdir().unwrap();
+        write_tree(input_dir.path());
+        let outboard_dir = tempfile::tempdir().unwrap();
+        let entries = encode_dir(input_dir.path(), Some(outboard_dir.path())).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(vec!["a/b/two \"q\"", "a/one", "top"], paths);
+        for entry in &entries {
+            let content = fs::read(native_path(input_dir.path(), &entry.path)).unwrap();
+            let (expected_outboard, expected_hash) = encode::outboard(&content);
+            let outboard_path = format!("{}.{}", entry.path, OUTBOARD_EXTENSION);
+            let outboard = fs::read(native_path(outboard_dir.path(), &outboard_path)).unwrap();
+            assert_eq!(expected_hash, entry.hash);
+            assert_eq!(expected_outboard, outboard);
+            assert_eq!(content.len() as u64, entry.content_len);
+            assert_eq!(outboard.len() as u64, entry.outboard_len);
+        }
+
+        // Hashing without writing outboards gives the same entries.
+        assert_eq!(entries, encode_dir(input_dir.path(), None).unwrap());
+
+        // An outboard directory inside the input directory isn't encoded, even on a rerun.
+        let nested = input_dir.path().join("outboards");
+        assert_eq!(
+            entries,
+            encode_dir(input_dir.path(), Some(&nested)).unwrap()
+        );
+        assert_eq!(
+            entries,
+            encode_dir(input_dir.path(), Some(&nested)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_encode_dir_errors() {
+        let missing = tempfile::tempdir().unwrap().path().join("missing");
+        let err = encode_dir(&missing, None).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+
+        let empty = tempfile::tempdir().unwrap();
+        assert_eq!(Vec::<Entry>::new(), encode_dir(empty.path(), None).unwrap());
+    }
+
+    #[test]
+    fn test_write_json() {
+        let mut output = Vec::new();
+        write_json(&[], &mut output).unwrap();
+        assert_eq!("[]\n", String::from_utf8(output).unwrap());
+
+        let input_dir = tempfile::tempdir().unwrap();
+        write_tree(input_dir.path());
+        let entries = encode_dir(input_dir.path(), None).unwrap();
+        let mut output = Vec::new();
+        write_json(&entries, &mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let array = json.as_array().unwrap();
+        assert_eq!(entries.len(), array.len());
+        for (entry, value) in entries.iter().zip(array) {
+            assert_eq!(entry.path, value["path"]);
+            assert_eq!(entry.hash.to_hex().as_str(), value["hash"]);
+            assert_eq!(entry.content_len, value["content_len"]);
+            assert_eq!(entry.outboard_len, value["outboard_len"]);
+        }
+        assert_eq!(entries.len() + 2, output.split(|&b| b == b'\n').count() - 1);
+    }
+
+    #[test]
+    fn test_write_json_escaping() {
+        let paths = [
+            "quote \" in the middle",
+            "\"\"",
+            "back\\slash\\",
+            "\\\"",
+            "new\nline",
+            "tab\tand\rreturn",
+            "\u{0}\u{1}\u{1f}",
+            "del \u{7f} and unicode é \u{2028} \u{1f600}",
+            "a/b/c",
+            "",
+        ];
+        let entries: Vec<Entry> = paths
+            .iter()
+            .map(|&path| Entry {
+                path: path.to_string(),
+                hash: blake3::hash(path.as_bytes()),
+                content_len: 1,
+                outboard_len: 8,
+            })
+            .collect();
+        let mut output = Vec::new();
+        write_json(&entries, &mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let parsed: Vec<&str> = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| value["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(&paths[..], &parsed[..]);
+        // Control characters never appear raw, so each entry stays on its own line.
+        let text = String::from_utf8(output).unwrap();
+        assert!(!text.chars().any(|c| c < ' ' && c != '\n'));
+        assert_eq!(entries.len() + 2, text.lines().count());
+        assert!(text.contains(r#""path": "new\u000aline""#));
+    }
+}
@@ -0,0 +1,563 @@
+//! Content-defined chunking ahead of the tree.
+//!
+//! Bao chunks are fixed at 1024 bytes, so inserting a single byte near the start of a file
+//! shifts every chunk after it, and nothing after the edit lines up with the previous version.
+//! This module splits the input into variable-size segments at content-defined boundaries first,
+//! with a FastCDC-style rolling hash, so that segment boundaries move along with the content
+//! around them. Each segment is then zero-padded to a multiple of `CHUNK_SIZE`, so every segment
+//! starts on a chunk boundary, and the padded stream is encoded as usual. After an edit, the
+//! segments that didn't change are byte-for-byte identical, chunk-aligned runs of the padded
+//! content, which block-level dedup and sync tools can match up. Note that chunk hashes still
+//! depend on chunk positions, so the tree itself changes after the edit.
+//!
+//! The segment lengths go in a `SegmentTable` on the side, which `Unpad` and `decode` need to
+//! strip the padding again. Decoding is still verified streaming, since the padded stream is a
+//! regular encoding. **The table isn't covered by the root hash.** Decoding checks that the
+//! padding is all zeros and that the table accounts for the whole content, which catches most
+//! mistakes, but callers who get the table from an untrusted source should authenticate it
+//! separately, for example with `SegmentTable::hash`.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use bao::cdc::{self, Chunker, SegmentTable};
+//!
+//! let input: Vec<u8> = (0..200_000u32)
+//!     .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+//!     .collect();
+//! let chunker = Chunker::default();
+//! let (encoded, hash, table) = cdc::encode(&input, &chunker);
+//! assert!(table.len() > 1);
+//! let bytes = table.to_bytes();
+//! let table = SegmentTable::from_bytes(&bytes)?;
+//! assert_eq!(input, cdc::decode(&encoded, &hash, &table)?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::Decoder;
+use crate::encode;
+use crate::header;
+use crate::{Hash, CHUNK_SIZE, HEADER_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+
+/// Parameters for content-defined chunking. Segments are at least `min_size` bytes and at most
+/// `max_size` bytes, except that the last one can be shorter, and they average around
+/// `avg_size` bytes. The defaults are 4 KiB, 16 KiB, and 64 KiB. Every segment is padded to a
+/// whole number of chunks, so small segments waste a lot of space, and `min_size` should be
+/// several chunks at least.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ChunkerParams"))]
+pub struct Chunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+// Deserialization goes through this, so that a deserialized Chunker has valid sizes too.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct ChunkerParams {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<ChunkerParams> for Chunker {
+    type Error = &'static str;
+
+    fn try_from(params: ChunkerParams) -> Result<Self, Self::Error> {
+        if !valid_sizes(params.min_size, params.avg_size, params.max_size) {
+            return Err("invalid segment sizes");
+        }
+        Ok(Self::new(params.min_size, params.avg_size, params.max_size))
+    }
+}
+
+fn valid_sizes(min_size: usize, avg_size: usize, max_size: usize) -> bool {
+    0 < min_size && min_size <= avg_size && avg_size <= max_size
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Self::new(4 * CHUNK_SIZE, 16 * CHUNK_SIZE, 64 * CHUNK_SIZE)
+    }
+}
+
+impl Chunker {
+    /// # Panics
+    ///
+    /// Panics unless `0 < min_size <= avg_size <= max_size`.
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Self {
+        assert!(
+            valid_sizes(min_size, avg_size, max_size),
+            "invalid segment sizes"
+        );
+        Self {
+            min_size,
+            avg_size,
+            max_size,
+        }
+    }
+
+    /// Split the input into segments, and return their lengths.
+    pub fn segments(&self, input: &[u8]) -> SegmentTable {
+        let mut lengths = Vec::new();
+        let mut position = 0;
+        while position < input.len() {
+            let len = self.cut(&input[position..]);
+            lengths.push(len as u64);
+            position += len;
+        }
+        SegmentTable { lengths }
+    }
+
+    // The length of the next segment. Following FastCDC, the mask before avg_size has more bits
+    // than the one after it, which makes a cut less likely in the first part of the segment and
+    // more likely in the second, and keeps segment sizes close to the average. Gear hashing
+    // shifts left, so the high bits depend on the most input, and the masks use those.
+    fn cut(&self, input: &[u8]) -> usize {
+        if input.len() <= self.min_size {
+            return input.len();
+        }
+        let bits = self.avg_size.ilog2();
+        let mask_small = mask(bits + 1);
+        let mask_large = mask(bits.saturating_sub(1));
+        let normal = cmp::min(self.avg_size, input.len());
+        let max = cmp::min(self.max_size, input.len());
+        let mut hash: u64 = 0;
+        for (i, &byte) in input.iter().enumerate().take(max).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < normal { mask_small } else { mask_large };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        max
+    }
+}
+
+fn mask(bits: u32) -> u64 {
+    match bits {
+        0 => 0,
+        bits if bits >= 64 => u64::MAX,
+        bits => u64::MAX << (64 - bits),
+    }
+}
+
+// Random values for the gear hash, from SplitMix64 with a fixed seed. Changing these changes
+// where the segment boundaries fall, so they're fixed forever.
+const GEAR: [u64; 256] = make_gear();
+
+const fn make_gear() -> [u64; 256] {
+    let mut gear = [0; 256];
+    let mut state: u64 = 0x6261_6f5f_6364_6321;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        gear[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    gear
+}
+
+// The padded length of a segment.
+fn padded(len: u64) -> u64 {
+    len.div_ceil(CHUNK_SIZE as u64) * CHUNK_SIZE as u64
+}
+
+/// The lengths of the segments, before padding. See the module docs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "SegmentTableParams"))]
+pub struct SegmentTable {
+    lengths: Vec<u64>,
+}
+
+// Like ChunkerParams, this makes deserialization check the lengths.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct SegmentTableParams {
+    lengths: Vec<u64>,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<SegmentTableParams> for SegmentTable {
+    type Error = io::Error;
+
+    fn try_from(params: SegmentTableParams) -> io::Result<Self> {
+        Self::from_lengths(params.lengths)
+    }
+}
+
+impl SegmentTable {
+    /// The number of segments. Empty content has none.
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    /// The segment lengths, before padding.
+    pub fn lengths(&self) -> &[u64] {
+        &self.lengths
+    }
+
+    /// The original content length.
+    pub fn content_len(&self) -> u64 {
+        self.lengths.iter().sum()
+    }
+
+    /// The length of the padded content, which is what the tree is built over.
+    pub fn padded_len(&self) -> u64 {
+        self.lengths.iter().map(|&len| padded(len)).sum()
+    }
+
+    /// Serialize the table, as each length in 8 little-endian bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.lengths
+            .iter()
+            .flat_map(|len| len.to_le_bytes())
+            .collect()
+    }
+
+    /// Parse a table from `to_bytes`. Segments have to be nonempty, and the padded length of
+    /// the whole table has to fit in a `u64`, or else this is an `InvalidData` error.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if !bytes.len().is_multiple_of(8) {
+            return Err(invalid_table());
+        }
+        let lengths: Vec<u64> = bytes
+            .chunks_exact(8)
+            .map(|len| u64::from_le_bytes(*array_ref!(len, 0, 8)))
+            .collect();
+        Self::from_lengths(lengths)
+    }
+
+    // Every table that doesn't come from Chunker::segments goes through here. Checking the
+    // padded total means that content_len and padded_len can't overflow.
+    fn from_lengths(lengths: Vec<u64>) -> io::Result<Self> {
+        let mut padded_len: u64 = 0;
+        for &len in &lengths {
+            if len == 0 {
+                return Err(invalid_table());
+            }
+            padded_len = len
+                .div_ceil(CHUNK_SIZE as u64)
+                .checked_mul(CHUNK_SIZE as u64)
+                .and_then(|padded| padded_len.checked_add(padded))
+                .ok_or_else(invalid_table)?;
+        }
+        Ok(Self { lengths })
+    }
+
+    /// The BLAKE3 hash of `to_bytes`, for callers who need to authenticate a table.
+    pub fn hash(&self) -> Hash {
+        blake3::hash(&self.to_bytes())
+    }
+}
+
+fn invalid_table() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid segment table")
+}
+
+/// Pad each segment of the input to a multiple of `CHUNK_SIZE`, giving the content that the tree
+/// is built over. This is for encoding the padded content some other way, for example as an
+/// outboard encoding.
+///
+/// # Panics
+///
+/// Panics if the table doesn't match the input length.
+pub fn pad(input: &[u8], table: &SegmentTable) -> Vec<u8> {
+    assert_eq!(
+        input.len() as u64,
+        table.content_len(),
+        "table doesn't match the input"
+    );
+    let mut padded_input = Vec::with_capacity(table.padded_len() as usize);
+    let mut position = 0;
+    for &len in &table.lengths {
+        padded_input.extend_from_slice(&input[position..][..len as usize]);
+        padded_input.resize(padded_input.len() + (padded(len) - len) as usize, 0);
+        position += len as usize;
+    }
+    padded_input
+}
+
+/// Split the input into segments, pad them, and return the combined encoding of the padded
+/// content along with its root hash and the segment table.
+pub fn encode(input: impl AsRef<[u8]>, chunker: &Chunker) -> (Vec<u8>, Hash, SegmentTable) {
+    let input = input.as_ref();
+    let table = chunker.segments(input);
+    let (encoded, hash) = encode::encode(pad(input, &table));
+    (encoded, hash, table)
+}
+
+/// Decode a combined encoding from `encode`, verifying it and stripping the padding. If the
+/// table doesn't match the length header of the encoding, this is an `InvalidData` error.
+pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash, table: &SegmentTable) -> io::Result<Vec<u8>> {
+    let encoded = encoded.as_ref();
+    // The header isn't verified yet, but the decoder checks it against the root hash, so a table
+    // that disagrees with it can't be right. Short encodings are left for the decoder to reject.
+    if let Some(header) = encoded.get(..HEADER_SIZE) {
+        if header::parse(array_ref!(header, 0, HEADER_SIZE)) != table.padded_len() {
+            return Err(invalid_table());
+        }
+    }
+    let decoder = Decoder::new(encoded, hash);
+    // The content can't be longer than the encoding, whatever the header says.
+    let capacity = cmp::min(table.content_len(), encoded.len() as u64);
+    let mut output = Vec::with_capacity(capacity as usize);
+    Unpad::new(decoder, table.clone()).read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// A reader that strips the padding from padded content, like the output of a `Decoder`. It
+/// returns an `InvalidData` error if any padding isn't zero, or if the content doesn't end where
+/// the table says it does.
+#[derive(Clone, Debug)]
+pub struct Unpad<R: Read> {
+    inner: R,
+    table: SegmentTable,
+    segment: usize,
+    content_remaining: u64,
+    padding_remaining: u64,
+    checked_eof: bool,
+}
+
+impl<R: Read> Unpad<R> {
+    pub fn new(inner: R, table: SegmentTable) -> Self {
+        let mut unpad = Self {
+            inner,
+            table,
+            segment: 0,
+            content_remaining: 0,
+            padding_remaining: 0,
+            checked_eof: false,
+        };
+        unpad.start_segment();
+        unpad
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn start_segment(&mut self) {
+        if let Some(&len) = self.table.lengths.get(self.segment) {
+            self.content_remaining = len;
+            self.padding_remaining = padded(len) - len;
+        }
+    }
+
+    fn skip_padding(&mut self) -> io::Result<()> {
+        let mut buf = [0; CHUNK_SIZE];
+        while self.padding_remaining > 0 {
+            let want = cmp::min(self.padding_remaining, buf.len() as u64) as usize;
+            self.inner.read_exact(&mut buf[..want])?;
+            if buf[..want].iter().any(|&b| b != 0) {
+                return Err(invalid_padding());
+            }
+            self.padding_remaining -= want as u64;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_padding() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "padding doesn't match the segment table",
+    )
+}
+
+impl<R: Read> Read for Unpad<R> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if output.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.content_remaining > 0 {
+                let want = cmp::min(self.content_remaining, output.len() as u64) as usize;
+                let n = self.inner.read(&mut output[..want])?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "content shorter than the segment table",
+                    ));
+                }
+                self.content_remaining -= n as u64;
+                return Ok(n);
+            }
+            if self.segment < self.table.lengths.len() {
+                self.skip_padding()?;
+                self.segment += 1;
+                self.start_segment();
+                continue;
+            }
+            // One more read to make sure the content ends here. For a Decoder, this is also what
+            // verifies the final chunk, if the table is empty.
+            if !self.checked_eof {
+                if self.inner.read(&mut [0])? != 0 {
+                    return Err(invalid_padding());
+                }
+                self.checked_eof = true;
+            }
+            return Ok(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+
+    fn random_input(len: usize, seed: u64) -> Vec<u8> {
+        let mut input = vec![0; len];
+        ChaChaRng::seed_from_u64(seed).fill_bytes(&mut input);
+        input
+    }
+
+    #[test]
+    fn test_segment_sizes() {
+        let input = random_input(1 << 20, 0);
+        let chunker = Chunker::default();
+        let table = chunker.segments(&input);
+        assert_eq!(input.len() as u64, table.content_len());
+        let (last, rest) = table.lengths().split_last().unwrap();
+        assert!(*last <= 64 * CHUNK_SIZE as u64);
+        for &len in rest {
+            assert!(len >= 4 * CHUNK_SIZE as u64);
+            assert!(len <= 64 * CHUNK_SIZE as u64);
+        }
+        // The average lands in the neighborhood of the target.
+        let average = input.len() / table.len();
+        assert!(
+            average > 8 * CHUNK_SIZE && average < 32 * CHUNK_SIZE,
+            "{}",
+            average
+        );
+    }
+
+    #[test]
+    fn test_boundaries_survive_an_insertion() {
+        let input = random_input(1 << 20, 1);
+        let mut edited = input[..1000].to_vec();
+        edited.extend_from_slice(b"inserted");
+        edited.extend_from_slice(&input[1000..]);
+        let chunker = Chunker::default();
+        let before = pad(&input, &chunker.segments(&input));
+        let after = pad(&edited, &chunker.segments(&edited));
+
+        // Most of the chunk-aligned blocks of the padded content are still there.
+        let blocks: std::collections::HashSet<&[u8]> = before.chunks(CHUNK_SIZE).collect();
+        let shared = after
+            .chunks(CHUNK_SIZE)
+            .filter(|block| blocks.contains(block))
+            .count();
+        let total = after.len() / CHUNK_SIZE;
+        assert!(shared * 10 > total * 9, "{} of {}", shared, total);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let chunker = Chunker::new(CHUNK_SIZE, 2 * CHUNK_SIZE, 8 * CHUNK_SIZE);
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = random_input(case, case as u64);
+            let (encoded, hash, table) = encode(&input, &chunker);
+            assert_eq!(
+                encoded.len() as u128,
+                encode::encoded_size(table.padded_len())
+            );
+            let table = SegmentTable::from_bytes(&table.to_bytes()).unwrap();
+            assert_eq!(input, decode(&encoded, &hash, &table).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_bad_tables() {
+        let chunker = Chunker::new(CHUNK_SIZE, 2 * CHUNK_SIZE, 8 * CHUNK_SIZE);
+        let input = random_input(20_000, 2);
+        let (encoded, hash, table) = encode(&input, &chunker);
+        assert!(table.len() > 2);
+
+        // Dropping the last segment leaves content past the end.
+        let mut lengths = table.lengths().to_vec();
+        lengths.pop();
+        let short = SegmentTable { lengths };
+        let err = decode(&encoded, &hash, &short).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Shifting a boundary makes content bytes look like padding.
+        let mut lengths = table.lengths().to_vec();
+        lengths[0] -= 1;
+        let shifted = SegmentTable { lengths };
+        let err = decode(&encoded, &hash, &shifted).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // An extra segment runs past the end.
+        let mut lengths = table.lengths().to_vec();
+        lengths.push(10);
+        let long = SegmentTable { lengths };
+        let err = decode(&encoded, &hash, &long).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let mut unpad = Unpad::new(Decoder::new(&*encoded, &hash), long);
+        let err = unpad.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+        assert!(SegmentTable::from_bytes(&[0; 7]).is_err());
+        assert!(SegmentTable::from_bytes(&[0; 8]).is_err());
+        assert_ne!(table.hash(), shifted.hash());
+    }
+
+    #[test]
+    fn test_oversized_tables() {
+        let (encoded, hash) = encode::encode(b"");
+
+        // One huge segment parses, but it doesn't match the encoding, and decoding doesn't try
+        // to allocate for it.
+        let huge = SegmentTable::from_bytes(&(u64::MAX / 2).to_le_bytes()).unwrap();
+        let err = decode(&encoded, &hash, &huge).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let err = decode(&encoded[..4], &hash, &huge).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+        // Two of them overflow the padded length, and so does one that can't be padded.
+        let mut bytes = huge.to_bytes();
+        bytes.extend_from_slice(&huge.to_bytes());
+        assert!(SegmentTable::from_bytes(&bytes).is_err());
+        assert!(SegmentTable::from_bytes(&u64::MAX.to_le_bytes()).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_checks_invariants() {
+        let chunker = Chunker::default();
+        let json = serde_json::to_string(&chunker).unwrap();
+        assert_eq!(chunker, serde_json::from_str::<Chunker>(&json).unwrap());
+        let bad = r#"{"min_size":0,"avg_size":0,"max_size":0}"#;
+        assert!(serde_json::from_str::<Chunker>(bad).is_err());
+
+        let table = chunker.segments(&random_input(100_000, 3));
+        let json = serde_json::to_string(&table).unwrap();
+        assert_eq!(table, serde_json::from_str::<SegmentTable>(&json).unwrap());
+        assert!(serde_json::from_str::<SegmentTable>(r#"{"lengths":[5,0]}"#).is_err());
+        let bad = format!(r#"{{"lengths":[{},{}]}}"#, u64::MAX / 2, u64::MAX / 2);
+        assert!(serde_json::from_str::<SegmentTable>(&bad).is_err());
+    }
+}
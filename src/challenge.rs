@@ -0,0 +1,382 @@
+//! Proof-of-storage challenges, built on random chunk sampling.
+//!
+//! A verifier who knows a root hash and the content length can check that someone else is still
+//! storing the whole encoding, without downloading it. The verifier picks a fresh random seed and
+//! sends it to the prover. Both sides use `select_chunks` to turn the seed into the same set of
+//! chunk indices, the prover answers with `prove`, and the verifier checks the answer with
+//! `verify`. A prover that has lost a fraction of the chunks fails a challenge of `count` chunks
+//! with probability at least `1 - (1 - fraction)^count`.
+//!
+//! A proof holds the selected chunks, plus the parent nodes on the paths from the root down to
+//! them. Chunks that share part of a path share those parent nodes, so a proof is smaller than
+//! the equivalent slices. The layout is just those nodes in pre-order, with no header. Both sides
+//! know which nodes are included, so it doesn't need one.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::io::Cursor;
+//!
+//! let input = vec![0xab; 1_000_000];
+//! let (encoded, hash) = bao::encode::encode(&input);
+//!
+//! // The verifier keeps the hash and the length, and picks a fresh seed for each challenge.
+//! let seed = [42; 32];
+//! let proof = bao::challenge::prove(Cursor::new(&encoded), &hash, &seed, 10)?;
+//! bao::challenge::verify(&proof, &hash, input.len() as u64, &seed, 10)?;
+//!
+//! // The same proof doesn't answer a different seed.
+//! let other_seed = [43; 32];
+//! assert!(bao::challenge::verify(&proof, &hash, input.len() as u64, &other_seed, 10).is_err());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::Error;
+use crate::encode;
+use crate::Finalization::{self, NotRoot, Root};
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::collections::BTreeSet;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+/// The size of a challenge seed, 32 bytes.
+pub const SEED_SIZE: usize = 32;
+
+const SELECTION_CONTEXT: &str = "bao 2026-10-16 challenge chunk selection";
+
+/// Deterministically select `count` distinct chunk indices from the tree with root `hash` and
+/// length `content_len`, using `seed`. The result is sorted. If `count` is at least the number of
+/// chunks, every chunk is selected.
+pub fn select_chunks(
+    hash: &Hash,
+    content_len: u64,
+    seed: &[u8; SEED_SIZE],
+    count: usize,
+) -> Vec<u64> {
+    let chunk_count = encode::count_chunks(content_len);
+    if count as u64 >= chunk_count {
+        return (0..chunk_count).collect();
+    }
+    let mut stream = blake3::Hasher::new_derive_key(SELECTION_CONTEXT)
+        .update(seed)
+        .update(hash.as_bytes())
+        .update(&content_len.to_le_bytes())
+        .finalize_xof();
+    // Reject samples from the last partial multiple of chunk_count, to avoid modulo bias.
+    let limit = u64::MAX - u64::MAX % chunk_count;
+    let mut selected = BTreeSet::new();
+    while selected.len() < count {
+        let mut sample = [0; 8];
+        stream.fill(&mut sample);
+        let sample = u64::from_le_bytes(sample);
+        if sample < limit {
+            selected.insert(sample % chunk_count);
+        }
+    }
+    selected.into_iter().collect()
+}
+
+/// Answer a challenge from a combined encoding. This reads the length from the encoding's
+/// header, selects chunks with `select_chunks`, and returns the proof. It doesn't verify
+/// anything, so corruption in the encoding shows up when the other side runs `verify`.
+pub fn prove(
+    mut encoded: impl Read + Seek,
+    hash: &Hash,
+    seed: &[u8; SEED_SIZE],
+    count: usize,
+) -> io::Result<Vec<u8>> {
+    let content_len = read_len(&mut encoded)?;
+    let chunks = select_chunks(hash, content_len, seed, count);
+    let mut prover = Prover {
+        tree: &mut encoded,
+        content: None,
+        proof: Vec::new(),
+    };
+    prover.prove_subtree(&chunks, 0, content_len, HEADER_SIZE as u128)?;
+    Ok(prover.proof)
+}
+
+/// Answer a challenge from content and its outboard encoding. See `prove`.
+pub fn prove_outboard(
+    mut content: impl Read + Seek,
+    mut outboard: impl Read + Seek,
+    hash: &Hash,
+    seed: &[u8; SEED_SIZE],
+    count: usize,
+) -> io::Result<Vec<u8>> {
+    let content_len = read_len(&mut outboard)?;
+    let chunks = select_chunks(hash, content_len, seed, count);
+    let mut prover = Prover {
+        tree: &mut outboard,
+        content: Some(&mut content),
+        proof: Vec::new(),
+    };
+    prover.prove_subtree(&chunks, 0, content_len, HEADER_SIZE as u128)?;
+    Ok(prover.proof)
+}
+
+fn read_len(reader: &mut (impl Read + Seek)) -> io::Result<u64> {
+    let mut header = [0; HEADER_SIZE];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+    Ok(crate::decode_len(&header))
+}
+
+/// Check a proof from `prove` or `prove_outboard` against the root hash and the content length.
+/// A proof that's too short is `Error::Truncated`, and any other wrong proof, including one with
+/// extra bytes at the end, is `Error::HashMismatch`.
+pub fn verify(
+    proof: &[u8],
+    hash: &Hash,
+    content_len: u64,
+    seed: &[u8; SEED_SIZE],
+    count: usize,
+) -> Result<(), Error> {
+    let chunks = select_chunks(hash, content_len, seed, count);
+    let mut remaining = proof;
+    verify_subtree(&mut remaining, &chunks, hash, 0, content_len, Root)?;
+    if !remaining.is_empty() {
+        return Err(Error::HashMismatch);
+    }
+    Ok(())
+}
+
+// Split the sorted chunk indices in a subtree between its left and right children.
+fn split_chunks(chunks: &[u64], start: u64, left_len: u64) -> (&[u64], &[u64]) {
+    let boundary = (start + left_len) / CHUNK_SIZE as u64;
+    let split = chunks.partition_point(|&index| index < boundary);
+    chunks.split_at(split)
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+struct Prover<'a> {
+    // The header and parent nodes, and also the chunks in combined mode.
+    tree: &'a mut dyn ReadSeek,
+    // The chunks in outboard mode.
+    content: Option<&'a mut dyn ReadSeek>,
+    proof: Vec<u8>,
+}
+
+impl Prover<'_> {
+    // `offset` is the subtree's position in the tree reader.
+    fn prove_subtree(
+        &mut self,
+        chunks: &[u64],
+        start: u64,
+        len: u64,
+        offset: u128,
+    ) -> io::Result<()> {
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        if len <= CHUNK_SIZE as u64 {
+            let (reader, chunk_offset) = match &mut self.content {
+                Some(content) => (&mut **content, start as u128),
+                None => (&mut *self.tree, offset),
+            };
+            reader.seek(SeekFrom::Start(
+                encode::EncodedOffset::new(chunk_offset).to_u64()?,
+            ))?;
+            let proof_len = self.proof.len();
+            self.proof.resize(proof_len + len as usize, 0);
+            return reader.read_exact(&mut self.proof[proof_len..]);
+        }
+        self.tree.seek(SeekFrom::Start(
+            encode::EncodedOffset::new(offset).to_u64()?,
+        ))?;
+        let proof_len = self.proof.len();
+        self.proof.resize(proof_len + PARENT_SIZE, 0);
+        self.tree.read_exact(&mut self.proof[proof_len..])?;
+        let left_len = encode::left_subtree_len(len);
+        let left_size = if self.content.is_some() {
+            encode::outboard_subtree_size(left_len)
+        } else {
+            encode::encoded_subtree_size(left_len)
+        };
+        let (left_chunks, right_chunks) = split_chunks(chunks, start, left_len);
+        let left_offset = offset + PARENT_SIZE as u128;
+        self.prove_subtree(left_chunks, start, left_len, left_offset)?;
+        self.prove_subtree(
+            right_chunks,
+            start + left_len,
+            len - left_len,
+            left_offset + left_size,
+        )
+    }
+}
+
+fn take<'a>(proof: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if proof.len() < len {
+        return Err(Error::Truncated);
+    }
+    let (taken, rest) = proof.split_at(len);
+    *proof = rest;
+    Ok(taken)
+}
+
+fn verify_subtree(
+    proof: &mut &[u8],
+    chunks: &[u64],
+    expected: &Hash,
+    start: u64,
+    len: u64,
+    finalization: Finalization,
+) -> Result<(), Error> {
+    if chunks.is_empty() {
+        return Ok(());
+    }
+    if len <= CHUNK_SIZE as u64 {
+        let chunk = take(proof, len as usize)?;
+        let computed = crate::chunk_cv(start / CHUNK_SIZE as u64, chunk, finalization.is_root());
+        // Hash implements constant time equality.
+        if &computed != expected {
+            return Err(Error::HashMismatch);
+        }
+        return Ok(());
+    }
+    let parent = take(proof, PARENT_SIZE)?;
+    let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+    let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+    let computed = crate::parent_cv(&left_child, &right_child, finalization.is_root());
+    // Hash implements constant time equality.
+    if &computed != expected {
+        return Err(Error::HashMismatch);
+    }
+    let left_len = encode::left_subtree_len(len);
+    let (left_chunks, right_chunks) = split_chunks(chunks, start, left_len);
+    verify_subtree(proof, left_chunks, &left_child, start, left_len, NotRoot)?;
+    verify_subtree(
+        proof,
+        right_chunks,
+        &right_child,
+        start + left_len,
+        len - left_len,
+        NotRoot,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_select_chunks() {
+        let hash = blake3::hash(b"foo");
+        let seed = [1; SEED_SIZE];
+        let len = 1000 * CHUNK_SIZE as u64;
+        let chunks = select_chunks(&hash, len, &seed, 50);
+        assert_eq!(50, chunks.len());
+        assert!(chunks.windows(2).all(|w| w[0] < w[1]));
+        assert!(chunks.iter().all(|&i| i < 1000));
+        // Deterministic, but sensitive to every input.
+        assert_eq!(chunks, select_chunks(&hash, len, &seed, 50));
+        assert_ne!(chunks, select_chunks(&hash, len, &[2; SEED_SIZE], 50));
+        assert_ne!(chunks, select_chunks(&blake3::hash(b"bar"), len, &seed, 50));
+        assert_ne!(chunks, select_chunks(&hash, len + 1, &seed, 50));
+        // Asking for everything gets everything.
+        assert_eq!(vec![0, 1, 2], select_chunks(&hash, 3000, &seed, 3));
+        assert_eq!(vec![0], select_chunks(&hash, 0, &seed, 100));
+        assert!(select_chunks(&hash, len, &seed, 0).is_empty());
+    }
+
+    #[test]
+    fn test_prove_and_verify() {
+        let seed = [7; SEED_SIZE];
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            for &count in &[0, 1, 2, 5, 100] {
+                println!("case {} count {}", case, count);
+                let proof = prove(Cursor::new(&encoded), &hash, &seed, count).unwrap();
+                let outboard_proof = prove_outboard(
+                    Cursor::new(&input),
+                    Cursor::new(&outboard),
+                    &hash,
+                    &seed,
+                    count,
+                )
+                .unwrap();
+                assert_eq!(proof, outboard_proof);
+                verify(&proof, &hash, case as u64, &seed, count).unwrap();
+
+                // The proof contains exactly the selected chunks and their paths.
+                let chunks = select_chunks(&hash, case as u64, &seed, count);
+                let chunk_bytes: usize = chunks
+                    .iter()
+                    .map(|&i| encode::chunk_size(i, case as u64))
+                    .sum();
+                assert_eq!(0, (proof.len() - chunk_bytes) % PARENT_SIZE);
+
+                if !proof.is_empty() {
+                    let mut bad = proof.clone();
+                    let last = bad.len() - 1;
+                    bad[last] ^= 1;
+                    assert_eq!(
+                        Err(Error::HashMismatch),
+                        verify(&bad, &hash, case as u64, &seed, count)
+                    );
+                    assert_eq!(
+                        Err(Error::Truncated),
+                        verify(&proof[..last], &hash, case as u64, &seed, count)
+                    );
+                }
+                let mut long = proof.clone();
+                long.push(0);
+                assert_eq!(
+                    Err(Error::HashMismatch),
+                    verify(&long, &hash, case as u64, &seed, count)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupt_storage() {
+        let input = make_test_input(100 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let seed = [3; SEED_SIZE];
+        let chunks = select_chunks(&hash, input.len() as u64, &seed, 5);
+        // Corrupt a selected chunk, and the challenge fails.
+        let layout = crate::layout::TreeLayout::new(input.len() as u64);
+        encoded[layout.chunk(chunks[2]).encoded_offset as usize] ^= 1;
+        let proof = prove(Cursor::new(&encoded), &hash, &seed, 5).unwrap();
+        assert_eq!(
+            Err(Error::HashMismatch),
+            verify(&proof, &hash, input.len() as u64, &seed, 5)
+        );
+    }
+
+    #[test]
+    fn test_shared_paths_are_compact() {
+        let input = make_test_input(1 << 20);
+        let (encoded, hash) = encode::encode(&input);
+        let seed = [9; SEED_SIZE];
+        let count = 64;
+        let proof = prove(Cursor::new(&encoded), &hash, &seed, count).unwrap();
+        let slices_len: usize = select_chunks(&hash, input.len() as u64, &seed, count)
+            .iter()
+            .map(|&i| {
+                let mut slice = Vec::new();
+                encode::SliceExtractor::new(
+                    Cursor::new(&encoded),
+                    i * CHUNK_SIZE as u64,
+                    CHUNK_SIZE as u64,
+                )
+                .read_to_end(&mut slice)
+                .unwrap();
+                slice.len()
+            })
+            .sum();
+        assert!(proof.len() < slices_len);
+    }
+}
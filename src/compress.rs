@@ -0,0 +1,926 @@
+//! An extended encoding format with each chunk compressed individually.
+//!
+//! Cold storage wants smaller encodings, but compressing a whole encoding gives up verified
+//! random access. This format compresses each 1024-byte chunk on its own and builds the tree out
+//! of the compressed bytes, so a reader can still seek to any chunk and verify it without
+//! decompressing anything else. Compression comes from a `Codec`, which adapts whatever
+//! compression library the caller uses. This crate doesn't depend on any of them. `encode`
+//! works all at once, and `Encoder` streams the input.
+//!
+//! The layout is:
+//!
+//! - The 8-byte little-endian content length, the same as a regular encoding.
+//! - A table with the stored size of each chunk, 2 bytes little-endian per chunk.
+//! - The chaining value of the chunk tree, 32 bytes.
+//! - The parent nodes, in the same pre-order layout as an outboard encoding of the content.
+//! - The stored chunks, back to back.
+//!
+//! A chunk is stored uncompressed if compressing it doesn't make it smaller. A stored size equal
+//! to the chunk's uncompressed size means it's uncompressed, and a smaller size means it's
+//! compressed. Each chunk is hashed as a BLAKE3 chunk with its usual index, over its stored bytes,
+//! and parent nodes are computed as usual, up to the chaining value of the whole tree. That value
+//! is never finalized as a root. Instead, the root hash is a parent node over the tree's chaining
+//! value and a hash of the length header and the size table, which `CompressedFile::open`
+//! verifies up front. The stored bytes alone don't say whether a chunk is compressed, because
+//! that depends on the chunk's uncompressed size, so it's the header and table in the root hash
+//! that commit to the choice. Without them, rewriting the length header could make a compressed
+//! final chunk read as uncompressed bytes, or the reverse, under the same root hash. The root
+//! hash is **not** the Bao hash of the content, even when every chunk is stored uncompressed.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::io::prelude::*;
+//! use bao::compress::{Codec, CompressedFile};
+//!
+//! // A run-length encoding of zero bytes, standing in for a real compressor like zstd.
+//! struct Zeros;
+//!
+//! impl Codec for Zeros {
+//!     fn compress(&mut self, chunk: &[u8], output: &mut Vec<u8>) {
+//!         if chunk.iter().all(|&b| b == 0) {
+//!             output.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+//!         } else {
+//!             output.extend_from_slice(chunk);
+//!         }
+//!     }
+//!
+//!     fn decompress(&mut self, compressed: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+//!         let len = u16::from_le_bytes([compressed[0], compressed[1]]);
+//!         output.resize(len as usize, 0);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let input = vec![0; 100_000];
+//! let (encoded, hash) = bao::compress::encode(&input, Zeros);
+//! assert!(encoded.len() < 10_000);
+//!
+//! let mut file = CompressedFile::open(std::io::Cursor::new(&encoded), &hash, Zeros)?;
+//! file.seek(std::io::SeekFrom::Start(50_000))?;
+//! let mut buf = [1; 100];
+//! file.read_exact(&mut buf)?;
+//! assert_eq!([0; 100], buf);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::{Error, Limits};
+use crate::encode;
+use crate::Finalization::{self, NotRoot};
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
+
+const TABLE_ENTRY_SIZE: usize = 2;
+
+// CompressedFile keeps the offset of every this-many-th stored chunk, and finds the rest by
+// summing at most this many table entries. That's 8 bytes of memory per MiB of content, and a
+// 2 KiB read per chunk lookup.
+const INDEX_INTERVAL: usize = 1024;
+
+// The key derivation context for hashing the length header and the size table.
+const METADATA_CONTEXT: &str = "bao compress 2026-10-16 header and size table";
+
+/// A compression algorithm, applied to one chunk at a time.
+pub trait Codec {
+    /// Append the compressed form of `chunk` to `output`. The output is discarded if it isn't
+    /// smaller than the chunk, so incompressible data costs nothing extra.
+    fn compress(&mut self, chunk: &[u8], output: &mut Vec<u8>);
+
+    /// Replace the contents of `output` with the decompressed form of `compressed`. Compressed
+    /// bytes are always verified before they get here, so errors only happen when the encoder's
+    /// codec doesn't match this one. Returning the wrong number of bytes is also an error, which
+    /// the caller catches.
+    fn decompress(&mut self, compressed: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+}
+
+impl<C: Codec + ?Sized> Codec for &mut C {
+    fn compress(&mut self, chunk: &[u8], output: &mut Vec<u8>) {
+        (**self).compress(chunk, output)
+    }
+
+    fn decompress(&mut self, compressed: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        (**self).decompress(compressed, output)
+    }
+}
+
+/// Compress and encode an input all at once, returning the encoding and its root hash.
+pub fn encode(input: impl AsRef<[u8]>, mut codec: impl Codec) -> (Vec<u8>, Hash) {
+    let input = input.as_ref();
+    let content_len = input.len() as u64;
+    let count = encode::count_chunks(content_len);
+    let mut stored_chunks = Vec::with_capacity(count as usize);
+    let mut compressed = Vec::new();
+    // The empty input still has one (empty) chunk.
+    let chunks = input.chunks(CHUNK_SIZE).chain(if input.is_empty() {
+        Some(&[][..])
+    } else {
+        None
+    });
+    for chunk in chunks {
+        stored_chunks.push(store_chunk(&mut codec, chunk, &mut compressed).to_vec());
+    }
+    debug_assert_eq!(count, stored_chunks.len() as u64);
+
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&crate::encode_len(content_len));
+    for stored in &stored_chunks {
+        encoded.extend_from_slice(&(stored.len() as u16).to_le_bytes());
+    }
+    let metadata_len = encoded.len();
+    encoded.extend_from_slice(&[0; HASH_SIZE]);
+    let tree_cv = write_parents(&stored_chunks, 0, content_len, NotRoot, &mut encoded);
+    encoded[metadata_len..][..HASH_SIZE].copy_from_slice(tree_cv.as_bytes());
+    let metadata_hash = metadata_hasher()
+        .update(&encoded[..metadata_len])
+        .finalize();
+    let hash = root_hash(&metadata_hash, &tree_cv);
+    for stored in &stored_chunks {
+        encoded.extend_from_slice(stored);
+    }
+    (encoded, hash)
+}
+
+// Compress a chunk into `compressed`, and return the bytes to store, which are the chunk itself if
+// compressing doesn't make it smaller.
+fn store_chunk<'a>(
+    codec: &mut impl Codec,
+    chunk: &'a [u8],
+    compressed: &'a mut Vec<u8>,
+) -> &'a [u8] {
+    compressed.clear();
+    codec.compress(chunk, compressed);
+    if compressed.len() < chunk.len() {
+        compressed
+    } else {
+        chunk
+    }
+}
+
+// The hasher for the header and the size table, which the root hash commits to along with the
+// chunk tree. See the module docs.
+fn metadata_hasher() -> blake3::Hasher {
+    blake3::Hasher::new_derive_key(METADATA_CONTEXT)
+}
+
+fn root_hash(metadata_hash: &Hash, tree_cv: &Hash) -> Hash {
+    crate::parent_cv(tree_cv, metadata_hash, true)
+}
+
+// Append the parent nodes of a subtree in pre-order, and return the subtree's hash.
+fn write_parents(
+    stored_chunks: &[Vec<u8>],
+    first_chunk: u64,
+    subtree_len: u64,
+    finalization: Finalization,
+    output: &mut Vec<u8>,
+) -> Hash {
+    if subtree_len <= CHUNK_SIZE as u64 {
+        return crate::chunk_cv(first_chunk, &stored_chunks[0], finalization.is_root());
+    }
+    let left_len = encode::left_subtree_len(subtree_len);
+    let left_chunks = encode::count_chunks(left_len);
+    let parent_start = output.len();
+    output.extend_from_slice(&[0; PARENT_SIZE]);
+    let (left_stored, right_stored) = stored_chunks.split_at(left_chunks as usize);
+    let left_hash = write_parents(left_stored, first_chunk, left_len, NotRoot, output);
+    let right_hash = write_parents(
+        right_stored,
+        first_chunk + left_chunks,
+        subtree_len - left_len,
+        NotRoot,
+        output,
+    );
+    output[parent_start..][..HASH_SIZE].copy_from_slice(left_hash.as_bytes());
+    output[parent_start + HASH_SIZE..][..HASH_SIZE].copy_from_slice(right_hash.as_bytes());
+    crate::parent_cv(&left_hash, &right_hash, finalization.is_root())
+}
+
+/// An incremental encoder, which compresses and writes each chunk as it fills, and produces the
+/// same output as `encode` without holding the input in memory. Note that you must call
+/// `finalize` after you're done writing.
+///
+/// The header, the size table, and the parent nodes come before the stored chunks, but their size
+/// isn't known until the input ends. So the stored chunks go out first, and `finalize` moves them
+/// forward to make room, then reads them back to build the tree. That's two more passes over the
+/// stored bytes. Until then the encoder keeps the size table in memory, which is 2 KiB per MiB of
+/// content.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+/// # use bao::compress::Codec;
+/// # struct Zeros;
+/// # impl Codec for Zeros {
+/// #     fn compress(&mut self, chunk: &[u8], output: &mut Vec<u8>) {
+/// #         if chunk.iter().all(|&b| b == 0) {
+/// #             output.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+/// #         }
+/// #     }
+/// #     fn decompress(&mut self, compressed: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+/// #         output.resize(u16::from_le_bytes([compressed[0], compressed[1]]) as usize, 0);
+/// #         Ok(())
+/// #     }
+/// # }
+///
+/// let input = vec![0; 100_000];
+/// let mut encoder = bao::compress::Encoder::new(std::io::Cursor::new(Vec::new()), Zeros);
+/// encoder.write_all(&input)?;
+/// let hash = encoder.finalize()?;
+/// let encoded = encoder.into_inner().into_inner();
+/// assert_eq!(bao::compress::encode(&input, Zeros), (encoded, hash));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Encoder<T: Read + Write + Seek, C: Codec> {
+    inner: T,
+    codec: C,
+    chunk: Vec<u8>,
+    compressed: Vec<u8>,
+    table: Vec<u8>,
+    content_len: u64,
+    stored_len: u64,
+    output: encode::OutputBuffer,
+    move_window_size: usize,
+    finalized: bool,
+}
+
+impl<T: Read + Write + Seek, C: Codec> Encoder<T, C> {
+    /// Create a new `Encoder`. This writes from the current position of `inner`, which should be
+    /// at its start.
+    pub fn new(inner: T, codec: C) -> Self {
+        Self {
+            inner,
+            codec,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            compressed: Vec::with_capacity(CHUNK_SIZE),
+            table: Vec::new(),
+            content_len: 0,
+            stored_len: 0,
+            output: encode::OutputBuffer::new(encode::DEFAULT_BUFFER_SIZE),
+            move_window_size: encode::DEFAULT_FLIP_WINDOW_SIZE,
+            finalized: false,
+        }
+    }
+
+    /// Set the size of the output buffer. See `encode::Encoder::set_buffer_size`.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.output.capacity = size;
+    }
+
+    /// Set the size of the windows that `finalize` moves the stored chunks in. The default is
+    /// `encode::DEFAULT_FLIP_WINDOW_SIZE`.
+    pub fn set_move_window_size(&mut self, size: usize) {
+        assert!(size > 0, "window size must be nonzero");
+        self.move_window_size = size;
+    }
+
+    /// The number of content bytes written so far.
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// Finalize the encoding, after all the input has been written, and return the root hash.
+    /// Writing or finalizing again will panic.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        assert!(!self.finalized, "already finalized");
+        self.finalized = true;
+        // The empty input still has one (empty) chunk.
+        if !self.chunk.is_empty() || self.content_len == 0 {
+            self.push_chunk()?;
+        }
+        self.output.flush(&mut self.inner)?;
+        let count = encode::count_chunks(self.content_len);
+        debug_assert_eq!(count, (self.table.len() / TABLE_ENTRY_SIZE) as u64);
+        let metadata_len = HEADER_SIZE as u64 + self.table.len() as u64;
+        let parents_len = encode::outboard_size(self.content_len) - HEADER_SIZE as u128;
+        let prefix_len =
+            encode::EncodedOffset::new(metadata_len as u128 + HASH_SIZE as u128 + parents_len)
+                .to_u64()?;
+        self.move_stored_chunks(prefix_len)?;
+
+        let header = crate::encode_len(self.content_len);
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&self.table)?;
+        let metadata_hash = metadata_hasher()
+            .update(&header)
+            .update(&self.table)
+            .finalize();
+        let mut parent_offset = metadata_len + HASH_SIZE as u64;
+        let mut stored_offset = prefix_len;
+        let tree_cv =
+            self.write_tree(0, self.content_len, &mut parent_offset, &mut stored_offset)?;
+        debug_assert_eq!(prefix_len, parent_offset);
+        self.inner.seek(SeekFrom::Start(metadata_len))?;
+        self.inner.write_all(tree_cv.as_bytes())?;
+        self.inner.flush()?;
+        Ok(root_hash(&metadata_hash, &tree_cv))
+    }
+
+    /// Return the underlying writer, after `finalize`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if any input has been written but `finalize` hasn't been called. Use `abort`
+    /// to give up on an unfinished encoding.
+    pub fn into_inner(self) -> T {
+        assert!(
+            self.finalized || self.content_len == 0,
+            "not finalized, use abort() instead"
+        );
+        self.inner
+    }
+
+    /// Give up on an unfinished encoding and return the underlying writer, discarding buffered
+    /// output. See `encode::Encoder::abort`.
+    pub fn abort(self) -> T {
+        self.inner
+    }
+
+    // Compress the buffered chunk and write out its stored bytes.
+    fn push_chunk(&mut self) -> io::Result<()> {
+        let stored = store_chunk(&mut self.codec, &self.chunk, &mut self.compressed);
+        self.output.write_all(&mut self.inner, stored)?;
+        self.table
+            .extend_from_slice(&(stored.len() as u16).to_le_bytes());
+        self.stored_len += stored.len() as u64;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    // Move the stored chunks from the start of the output to `offset`, back to front, so that
+    // each window is read before anything overwrites it.
+    fn move_stored_chunks(&mut self, offset: u64) -> io::Result<()> {
+        let mut window = vec![0; cmp::min(self.move_window_size as u64, self.stored_len) as usize];
+        let mut end = self.stored_len;
+        while end > 0 {
+            let len = cmp::min(window.len() as u64, end);
+            let start = end - len;
+            let window = &mut window[..len as usize];
+            self.inner.seek(SeekFrom::Start(start))?;
+            self.inner.read_exact(window)?;
+            self.inner.seek(SeekFrom::Start(start + offset))?;
+            self.inner.write_all(window)?;
+            end = start;
+        }
+        Ok(())
+    }
+
+    // Write the parent nodes of a subtree in pre-order, reading its stored chunks back, and
+    // return the subtree's hash. Like `write_parents`, with the chunks in `inner`.
+    fn write_tree(
+        &mut self,
+        first_chunk: u64,
+        subtree_len: u64,
+        parent_offset: &mut u64,
+        stored_offset: &mut u64,
+    ) -> io::Result<Hash> {
+        if subtree_len <= CHUNK_SIZE as u64 {
+            let entry = &self.table[first_chunk as usize * TABLE_ENTRY_SIZE..][..TABLE_ENTRY_SIZE];
+            self.compressed
+                .resize(u16::from_le_bytes([entry[0], entry[1]]) as usize, 0);
+            self.inner.seek(SeekFrom::Start(*stored_offset))?;
+            self.inner.read_exact(&mut self.compressed)?;
+            *stored_offset += self.compressed.len() as u64;
+            return Ok(crate::chunk_cv(first_chunk, &self.compressed, false));
+        }
+        let left_len = encode::left_subtree_len(subtree_len);
+        let right_chunk = first_chunk + encode::count_chunks(left_len);
+        let parent_start = *parent_offset;
+        *parent_offset += PARENT_SIZE as u64;
+        let left_hash = self.write_tree(first_chunk, left_len, parent_offset, stored_offset)?;
+        let right_hash = self.write_tree(
+            right_chunk,
+            subtree_len - left_len,
+            parent_offset,
+            stored_offset,
+        )?;
+        self.inner.seek(SeekFrom::Start(parent_start))?;
+        self.inner.write_all(left_hash.as_bytes())?;
+        self.inner.write_all(right_hash.as_bytes())?;
+        Ok(crate::parent_cv(&left_hash, &right_hash, false))
+    }
+}
+
+impl<T: Read + Write + Seek, C: Codec> Write for Encoder<T, C> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        assert!(!self.finalized, "already finalized");
+        let want = cmp::min(input.len(), CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&input[..want]);
+        self.content_len += want as u64;
+        // Compression doesn't depend on what comes next, so a full chunk can go right away.
+        if self.chunk.len() == CHUNK_SIZE {
+            self.push_chunk()?;
+        }
+        Ok(want)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush(&mut self.inner)?;
+        self.inner.flush()
+    }
+}
+
+impl<T: Read + Write + Seek, C: Codec> fmt::Debug for Encoder<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing content, it might be secret.
+        write!(
+            f,
+            "Encoder {{ content_len: {}, finalized: {} }}",
+            self.content_len, self.finalized,
+        )
+    }
+}
+
+/// Decode and decompress an encoding all at once.
+pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash, codec: impl Codec) -> io::Result<Vec<u8>> {
+    let mut file = CompressedFile::open(Cursor::new(encoded.as_ref()), hash, codec)?;
+    let mut output = Vec::with_capacity(cmp::min(file.len(), 1 << 20) as usize);
+    file.read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// Verified, seekable reads from a compressed encoding.
+///
+/// Opening verifies the final chunk, so `len` is always trustworthy. Every read verifies the
+/// chunks it touches before decompressing them. Parent nodes aren't cached, so each chunk costs
+/// a walk down the tree, but reading within the current chunk is free.
+///
+/// Opening reads the whole size table once, to verify it, but doesn't keep it. Memory use is
+/// one offset per 1024 chunks, and looking up where a chunk is stored reads at most 1024 table
+/// entries.
+pub struct CompressedFile<T: Read + Seek, C: Codec> {
+    inner: T,
+    codec: C,
+    tree_cv: Hash,
+    content_len: u64,
+    // The offset of every INDEX_INTERVAL-th stored chunk in the encoding, starting with the
+    // first.
+    checkpoints: Vec<u64>,
+    position: u64,
+    stored: Vec<u8>,
+    chunk: Vec<u8>,
+    chunk_index: Option<u64>,
+}
+
+impl<T: Read + Seek, C: Codec> CompressedFile<T, C> {
+    /// Read and verify the header and the size table, and verify the final chunk.
+    pub fn open(inner: T, hash: &Hash, codec: C) -> io::Result<Self> {
+        Self::open_with_limits(inner, hash, codec, &Limits::default())
+    }
+
+    /// Like `open`, but with `Limits` on the length header. The size table is as long as the
+    /// header says, so this bounds the reading and hashing that `open` does up front.
+    pub fn open_with_limits(
+        mut inner: T,
+        hash: &Hash,
+        codec: C,
+        limits: &Limits,
+    ) -> io::Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+        limits.check_header(&header)?;
+        let content_len = crate::decode_len(&header);
+        let count = encode::count_chunks(content_len);
+        let table_len = count as u128 * TABLE_ENTRY_SIZE as u128;
+        let parents_len = encode::outboard_size(content_len) - HEADER_SIZE as u128;
+        let mut offset = HEADER_SIZE as u128 + table_len + HASH_SIZE as u128 + parents_len;
+        // Stream the table through the hasher a block at a time, rather than trusting the
+        // header for an allocation size, and keep only the checkpoints.
+        let mut metadata_hasher = metadata_hasher();
+        metadata_hasher.update(&header);
+        let mut checkpoints = Vec::new();
+        let mut block = [0; INDEX_INTERVAL * TABLE_ENTRY_SIZE];
+        let mut index = 0;
+        while index < count {
+            let entries = cmp::min(count - index, INDEX_INTERVAL as u64) as usize;
+            let block = &mut block[..entries * TABLE_ENTRY_SIZE];
+            inner.read_exact(block)?;
+            metadata_hasher.update(block);
+            checkpoints.push(encode::EncodedOffset::new(offset).to_u64()?);
+            for entry in block.chunks_exact(TABLE_ENTRY_SIZE) {
+                let stored_len = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+                // A stored size that can't be right would fail verification anyway, but failing
+                // here keeps the read buffers small.
+                if stored_len > encode::chunk_size(index, content_len) {
+                    return Err(Error::HashMismatch.into());
+                }
+                offset += stored_len as u128;
+                index += 1;
+            }
+        }
+        // Every offset we'll seek to must fit in a u64.
+        encode::EncodedOffset::new(offset).to_u64()?;
+        let mut tree_cv = [0; HASH_SIZE];
+        inner.read_exact(&mut tree_cv)?;
+        let tree_cv = Hash::from(tree_cv);
+        // Hash implements constant time equality.
+        if root_hash(&metadata_hasher.finalize(), &tree_cv) != *hash {
+            return Err(Error::HashMismatch.into());
+        }
+        let mut file = Self {
+            inner,
+            codec,
+            tree_cv,
+            content_len,
+            checkpoints,
+            position: 0,
+            stored: Vec::with_capacity(CHUNK_SIZE),
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            chunk_index: None,
+        };
+        // Verify the final chunk, which verifies the length. This is the "final chunk
+        // requirement" from the spec.
+        file.load_chunk(count - 1)?;
+        Ok(file)
+    }
+
+    /// The content length. This has been verified.
+    pub fn len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// Returns `true` if the content is empty.
+    pub fn is_empty(&self) -> bool {
+        self.content_len == 0
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn table_start(&self) -> u64 {
+        HEADER_SIZE as u64
+    }
+
+    fn parents_start(&self) -> u64 {
+        let count = encode::count_chunks(self.content_len);
+        self.table_start() + count * TABLE_ENTRY_SIZE as u64 + HASH_SIZE as u64
+    }
+
+    // The offset and stored size of a chunk, from the nearest checkpoint and the table entries
+    // after it. The table was verified in open, and rereading it here is safe even if the
+    // underlying storage has changed since, because the stored bytes are verified against the
+    // chunk's hash, which fixes their length.
+    fn stored_location(&mut self, chunk_index: u64) -> io::Result<(u64, usize)> {
+        let checkpoint = (chunk_index / INDEX_INTERVAL as u64) as usize;
+        let first = checkpoint as u64 * INDEX_INTERVAL as u64;
+        let entries = (chunk_index - first) as usize + 1;
+        let mut block = [0; INDEX_INTERVAL * TABLE_ENTRY_SIZE];
+        let block = &mut block[..entries * TABLE_ENTRY_SIZE];
+        let table_offset = self.table_start() + first * TABLE_ENTRY_SIZE as u64;
+        self.inner.seek(SeekFrom::Start(table_offset))?;
+        self.inner.read_exact(block)?;
+        let mut offset = self.checkpoints[checkpoint];
+        let mut stored_len = 0;
+        for entry in block.chunks_exact(TABLE_ENTRY_SIZE) {
+            offset += stored_len as u64;
+            stored_len = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+        }
+        Ok((offset, stored_len))
+    }
+
+    // Verify and decompress a chunk into self.chunk.
+    fn load_chunk(&mut self, chunk_index: u64) -> io::Result<()> {
+        if self.chunk_index == Some(chunk_index) {
+            return Ok(());
+        }
+        self.chunk_index = None;
+        let chunk_start = chunk_index * CHUNK_SIZE as u64;
+        let mut subtree_start = 0;
+        let mut subtree_len = self.content_len;
+        // The parent nodes have the same layout as an outboard encoding, without its header.
+        let mut offset = self.parents_start() as u128;
+        // The tree's own root is never finalized. See the module docs.
+        let mut expected = self.tree_cv;
+        while subtree_len > CHUNK_SIZE as u64 {
+            let mut parent = [0; PARENT_SIZE];
+            self.inner.seek(SeekFrom::Start(
+                encode::EncodedOffset::new(offset).to_u64()?,
+            ))?;
+            self.inner.read_exact(&mut parent)?;
+            let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+            let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+            let computed = crate::parent_cv(&left_child, &right_child, false);
+            // Hash implements constant time equality.
+            if computed != expected {
+                return Err(Error::HashMismatch.into());
+            }
+            let left_len = encode::left_subtree_len(subtree_len);
+            offset += PARENT_SIZE as u128;
+            if chunk_start < subtree_start + left_len {
+                subtree_len = left_len;
+                expected = left_child;
+            } else {
+                offset += encode::outboard_subtree_size(left_len);
+                subtree_start += left_len;
+                subtree_len -= left_len;
+                expected = right_child;
+            }
+        }
+        let (stored_start, stored_len) = self.stored_location(chunk_index)?;
+        if stored_len > subtree_len as usize {
+            return Err(Error::HashMismatch.into());
+        }
+        self.stored.resize(stored_len, 0);
+        self.inner.seek(SeekFrom::Start(stored_start))?;
+        self.inner.read_exact(&mut self.stored)?;
+        let computed = crate::chunk_cv(chunk_index, &self.stored, false);
+        // Hash implements constant time equality.
+        if computed != expected {
+            return Err(Error::HashMismatch.into());
+        }
+        let chunk_len = subtree_len as usize;
+        if stored_len == chunk_len {
+            self.chunk.clear();
+            self.chunk.extend_from_slice(&self.stored);
+        } else {
+            self.codec.decompress(&self.stored, &mut self.chunk)?;
+            if self.chunk.len() != chunk_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chunk decompressed to the wrong size",
+                ));
+            }
+        }
+        self.chunk_index = Some(chunk_index);
+        Ok(())
+    }
+}
+
+impl<T: Read + Seek, C: Codec> Read for CompressedFile<T, C> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if output.is_empty() || self.position >= self.content_len {
+            return Ok(0);
+        }
+        let chunk_index = self.position / CHUNK_SIZE as u64;
+        self.load_chunk(chunk_index)?;
+        let skip = (self.position % CHUNK_SIZE as u64) as usize;
+        let take = cmp::min(output.len(), self.chunk.len() - skip);
+        output[..take].copy_from_slice(&self.chunk[skip..][..take]);
+        self.position += take as u64;
+        Ok(take)
+    }
+}
+
+impl<T: Read + Seek, C: Codec> Seek for CompressedFile<T, C> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.position = n;
+                return Ok(n);
+            }
+            SeekFrom::End(offset) => (self.content_len, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        self.position = crate::decode::add_offset(base, offset)?;
+        Ok(self.position)
+    }
+}
+
+impl<T: Read + Seek, C: Codec> fmt::Debug for CompressedFile<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes or content, they might be secret.
+        write!(
+            f,
+            "CompressedFile {{ content_len: {}, position: {} }}",
+            self.content_len, self.position,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+
+    // Run-length encoding, as (count, byte) pairs.
+    struct Rle;
+
+    impl Codec for Rle {
+        fn compress(&mut self, chunk: &[u8], output: &mut Vec<u8>) {
+            let mut i = 0;
+            while i < chunk.len() {
+                let byte = chunk[i];
+                let mut run = 1;
+                while i + run < chunk.len() && chunk[i + run] == byte && run < 255 {
+                    run += 1;
+                }
+                output.push(run as u8);
+                output.push(byte);
+                i += run;
+            }
+        }
+
+        fn decompress(&mut self, compressed: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+            output.clear();
+            for pair in compressed.chunks(2) {
+                if pair.len() < 2 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad RLE"));
+                }
+                output.extend(std::iter::repeat_n(pair[1], pair[0] as usize));
+            }
+            Ok(())
+        }
+    }
+
+    // Compressible and incompressible chunks, alternating.
+    fn make_input(len: usize) -> Vec<u8> {
+        let mut input = make_test_input(len);
+        for (i, chunk) in input.chunks_mut(CHUNK_SIZE).enumerate() {
+            if i % 2 == 0 {
+                for b in chunk {
+                    *b = i as u8;
+                }
+            }
+        }
+        input
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_input(case);
+            let (encoded, hash) = encode(&input, Rle);
+            assert_eq!(input, decode(&encoded, &hash, Rle).unwrap());
+            if case > 2 {
+                // Smaller than the same format with every chunk stored as is.
+                let count = encode::count_chunks(case as u64) as usize;
+                let uncompressed = encode::encoded_size(case as u64) as usize
+                    + count * TABLE_ENTRY_SIZE
+                    + HASH_SIZE;
+                assert!(encoded.len() < uncompressed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoder() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_input(case);
+            let expected = encode(&input, Rle);
+            // Small windows, so that moving the stored chunks takes several of them.
+            let mut encoder = Encoder::new(Cursor::new(Vec::new()), Rle);
+            encoder.set_buffer_size(3 * CHUNK_SIZE);
+            encoder.set_move_window_size(CHUNK_SIZE + 1);
+            for piece in input.chunks(700) {
+                encoder.write_all(piece).unwrap();
+            }
+            assert_eq!(case as u64, encoder.content_len());
+            let hash = encoder.finalize().unwrap();
+            assert_eq!(expected, (encoder.into_inner().into_inner(), hash));
+        }
+    }
+
+    #[test]
+    fn test_incompressible() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode(&input, Rle);
+            // Every chunk is stored as is, so the only overhead is the metadata and the parents.
+            let count = encode::count_chunks(case as u64) as usize;
+            let overhead = count * TABLE_ENTRY_SIZE + HASH_SIZE;
+            assert_eq!(
+                encode::encoded_size(case as u64) as usize + overhead,
+                encoded.len()
+            );
+            assert_eq!(input, decode(&encoded, &hash, Rle).unwrap());
+            // The root hash covers the size table, so it never collides with the Bao hash.
+            assert_ne!(blake3::hash(&input), hash);
+        }
+    }
+
+    #[test]
+    fn test_seek() {
+        let input = make_input(20 * CHUNK_SIZE + 7);
+        let (encoded, hash) = encode(&input, Rle);
+        let mut file = CompressedFile::open(Cursor::new(&encoded), &hash, Rle).unwrap();
+        assert_eq!(input.len() as u64, file.len());
+        for &offset in &[
+            0,
+            5 * CHUNK_SIZE + 3,
+            input.len() - 1,
+            CHUNK_SIZE,
+            input.len(),
+        ] {
+            file.seek(SeekFrom::Start(offset as u64)).unwrap();
+            let mut output = Vec::new();
+            file.read_to_end(&mut output).unwrap();
+            assert_eq!(&input[offset..], &output[..]);
+        }
+        assert_eq!(
+            10,
+            file.seek(SeekFrom::End(-(input.len() as i64 - 10)))
+                .unwrap()
+        );
+        assert_eq!(15, file.seek(SeekFrom::Current(5)).unwrap());
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        // Enough chunks for a few checkpoints, with the last one partly full.
+        let input = make_input(2 * INDEX_INTERVAL * CHUNK_SIZE + 3 * CHUNK_SIZE + 7);
+        let (encoded, hash) = encode(&input, Rle);
+        let mut file = CompressedFile::open(Cursor::new(&encoded), &hash, Rle).unwrap();
+        assert_eq!(3, file.checkpoints.len());
+        for &chunk in &[
+            0,
+            1,
+            INDEX_INTERVAL - 1,
+            INDEX_INTERVAL,
+            INDEX_INTERVAL + 1,
+            2 * INDEX_INTERVAL - 1,
+            2 * INDEX_INTERVAL,
+            2 * INDEX_INTERVAL + 3,
+        ] {
+            let offset = chunk * CHUNK_SIZE;
+            file.seek(SeekFrom::Start(offset as u64)).unwrap();
+            let mut output = vec![0; cmp::min(CHUNK_SIZE, input.len() - offset)];
+            file.read_exact(&mut output).unwrap();
+            assert_eq!(
+                &input[offset..][..output.len()],
+                &output[..],
+                "chunk {}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn test_seek_out_of_range() {
+        let input = make_input(3 * CHUNK_SIZE);
+        let (encoded, hash) = encode(&input, Rle);
+        let mut file = CompressedFile::open(Cursor::new(&encoded), &hash, Rle).unwrap();
+        file.seek(SeekFrom::Start(u64::MAX)).unwrap();
+        let err = file.seek(SeekFrom::Current(1)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = file
+            .seek(SeekFrom::End(-(input.len() as i64) - 1))
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        // Failed seeks leave the position alone.
+        assert_eq!(u64::MAX, file.stream_position().unwrap());
+    }
+
+    #[test]
+    fn test_corruption() {
+        let input = make_input(10 * CHUNK_SIZE);
+        let (encoded, hash) = encode(&input, Rle);
+        // Flip each byte in turn, checking that reading everything fails. That covers the
+        // header, the table, every parent, and every stored chunk.
+        for i in 0..encoded.len() {
+            let mut bad = encoded.clone();
+            bad[i] ^= 1;
+            assert!(decode(&bad, &hash, Rle).is_err(), "byte {}", i);
+        }
+        // A truncated encoding fails cleanly.
+        let err = decode(&encoded[..encoded.len() - 1], &hash, Rle).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_header_commits_to_compression() {
+        // Two chunks of zeros, which RLE stores in 10 and 4 bytes.
+        let input = vec![0; 1500];
+        let (encoded, hash) = encode(&input, Rle);
+        let table = &encoded[HEADER_SIZE..][..2 * TABLE_ENTRY_SIZE];
+        assert_eq!([10, 0, 4, 0], table);
+        // Rewrite the header so that the final chunk's length equals its stored size. The tree
+        // has the same shape, so without the header in the root hash, the final chunk would
+        // verify and read back as its 4 stored bytes.
+        let mut bad = encoded.clone();
+        bad[..HEADER_SIZE].copy_from_slice(&crate::encode_len(CHUNK_SIZE as u64 + 4));
+        let err = CompressedFile::open(Cursor::new(&bad), &hash, Rle).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let err = decode(&bad, &hash, Rle).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_limits_reject_huge_header() {
+        let (mut encoded, hash) = encode(make_input(3 * CHUNK_SIZE), Rle);
+        encoded[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        let limits = Limits {
+            max_content_len: 1 << 30,
+            ..Limits::default()
+        };
+        let err = CompressedFile::open_with_limits(Cursor::new(&encoded), &hash, Rle, &limits)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+    }
+}
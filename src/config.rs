@@ -0,0 +1,1011 @@
+//! Tree hashing profiles, for interop with other deployed BLAKE3 tree formats.
+//!
+//! Regular Bao encodings hash the tree exactly the way BLAKE3 does, so the root hash of an
+//! encoding is the BLAKE3 hash of its content. Some deployments built on the same tree hash
+//! their trees a little differently. Some use one of BLAKE3's other modes, keyed hashing or key
+//! derivation, to separate their roots from everyone else's. Others hash the root node with the
+//! content length appended, like the original Bao design did. A `Config` selects the mode and
+//! the root finalization, and its methods encode, hash, and decode with that profile. The tree
+//! shape and the encoding layout are the same in every profile, so only the hashes change,
+//! unless the config also sets a digest length or an alignment (see below).
+//!
+//! The profiles:
+//!
+//! - `Config::new()`: standard Bao. The root hash is `blake3::hash(content)`, and the encoding is
+//!   byte for byte the same as `encode::encode`.
+//! - `Config::keyed(key)`: the root hash is `blake3::keyed_hash(key, content)`, and every parent
+//!   node is keyed too, so the encoding can't be verified without the key.
+//! - `Config::derive_key(context)`: the tree from `blake3::Hasher::new_derive_key(context)`.
+//! - `with_length_suffix()`, on top of any of those: the root hash is the hash of the root node
+//!   (the parent node, or the only chunk) followed by the 8-byte length header, in the same mode,
+//!   like the original Bao design. The rest of the tree is the same.
+//!
+//! The methods on `Config` work in memory. For streaming, pass the config to the regular types
+//! instead: `Encoder::set_config` (or `EncoderState::set_config`), and `with_config` on
+//! `Decoder`, `SliceDecoder`, `DecoderState`, and `SliceExtractor`. These take the same path
+//! through the tree as the standard profile, and only the hashing, the sizes of parent nodes,
+//! and the alignment padding are parameterized. Everything else in the crate, like
+//! `ParallelDecoder`, manifests, and the `file` and `remote` modules, is standard-only.
+//!
+//! Every profile also supports digests shorter than 32 bytes, for space-constrained indexes,
+//! with `with_digest_len`. With `n`-byte digests, every chaining value in the tree is cut to its
+//! first `n` bytes, and zero-padded back to 32 bytes where it feeds into its parent, so parent
+//! nodes in the encoding are `2 * n` bytes. The root hash is cut to `n` bytes too. Where the API
+//! deals in `Hash`, like `Encoder::finalize` and `Decoder::new`, a truncated root is a `Hash`
+//! with the bytes past `n` zeroed, and `padded_hash` makes one from a digest. The `_truncated`
+//! methods take the digest length as a const generic and deal in arrays instead.
+//! `truncated_encoded_size` and `truncated_outboard_size` give the encoding sizes. Collision
+//! resistance is half the digest length, so 16-byte digests give 64-bit security, which is only
+//! appropriate where an attacker can't choose the content. 32-byte digests are the default.
+//!
+//! Separately from the hashing, `with_alignment` changes the layout of combined encodings so
+//! that every chunk starts at a multiple of the alignment, for example 4096 bytes for `O_DIRECT`
+//! reads of chunk data straight out of the encoded file. Zero padding goes before each chunk,
+//! after the header and parent nodes that come before it (or after the previous chunk), and
+//! decoders skip it, rejecting padding that isn't zero. With an alignment up to `CHUNK_SIZE`,
+//! only the chunks right after parent nodes need padding. Past that, every chunk is padded out
+//! to a whole alignment block, so a 4096-byte alignment makes a combined encoding about four
+//! times the size of the content. `aligned_encoded_size` gives the sizes. Outboard encodings
+//! have no chunks, and the alignment doesn't change them or the root hash.
+//!
+//! Hashes from different profiles aren't interchangeable. A decoder has to use the same profile
+//! as the encoder, or else every hash will mismatch.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use bao::config::Config;
+//!
+//! let input = vec![0xab; 5000];
+//! let config = Config::keyed(&[42; 32]);
+//! let (encoded, hash) = config.encode(&input);
+//! assert_eq!(blake3::keyed_hash(&[42; 32], &input), hash);
+//! assert_eq!(input, config.decode(&encoded, &hash)?);
+//!
+//! // The standard profile can't decode a keyed encoding.
+//! assert!(Config::new().decode(&encoded, &hash).is_err());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::Decoder;
+use crate::encode::{self, EncodedOffset, Encoder, EncoderState};
+use crate::Finalization::{self, NotRoot, Root};
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE};
+use arrayref::array_ref;
+use blake3::hazmat::{self, HasherExt};
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Hash,
+    KeyedHash([u8; 32]),
+    DeriveKey(hazmat::ContextKey),
+}
+
+/// A tree hashing profile. See the module docs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    mode: Mode,
+    length_suffix: bool,
+    digest_len: usize,
+    alignment: usize,
+}
+
+impl Config {
+    /// The standard Bao profile, which is BLAKE3's regular hash mode.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::with_mode(Mode::Hash)
+    }
+
+    /// BLAKE3's keyed hash mode.
+    pub fn keyed(key: &[u8; 32]) -> Self {
+        Self::with_mode(Mode::KeyedHash(*key))
+    }
+
+    /// BLAKE3's key derivation mode, with a hardcoded, globally unique context string like
+    /// `blake3::derive_key` takes. The content takes the place of the key material.
+    pub fn derive_key(context: &str) -> Self {
+        Self::with_mode(Mode::DeriveKey(hazmat::hash_derive_key_context(context)))
+    }
+
+    fn with_mode(mode: Mode) -> Self {
+        Self {
+            mode,
+            length_suffix: false,
+            digest_len: HASH_SIZE,
+            alignment: 1,
+        }
+    }
+
+    /// Hash the root node with the length header appended to get the root hash, instead of
+    /// finalizing it with the root flag.
+    pub fn with_length_suffix(mut self) -> Self {
+        self.length_suffix = true;
+        self
+    }
+
+    /// Cut every chaining value and the root hash to `digest_len` bytes. See the module docs.
+    /// The default is 32, the full hash.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `digest_len` is 0 or greater than 32.
+    pub fn with_digest_len(mut self, digest_len: usize) -> Self {
+        check_digest_len(digest_len);
+        self.digest_len = digest_len;
+        self
+    }
+
+    /// Pad combined encodings so that every chunk starts at a multiple of `alignment` bytes.
+    /// See the module docs. An alignment of 1 is no padding, the default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `alignment` isn't a power of two.
+    pub fn with_alignment(mut self, alignment: usize) -> Self {
+        assert!(
+            alignment.is_power_of_two(),
+            "alignment must be a power of two"
+        );
+        self.alignment = alignment;
+        self
+    }
+
+    /// The length of the digests, from `with_digest_len`.
+    pub fn digest_len(&self) -> usize {
+        self.digest_len
+    }
+
+    /// Whether this is the standard profile, where everything in the `encode` and `decode`
+    /// modules applies as is.
+    pub fn is_standard(&self) -> bool {
+        *self == Self::new()
+    }
+
+    fn hazmat_mode(&self) -> hazmat::Mode<'_> {
+        match &self.mode {
+            Mode::Hash => hazmat::Mode::Hash,
+            Mode::KeyedHash(key) => hazmat::Mode::KeyedHash(key),
+            Mode::DeriveKey(context_key) => hazmat::Mode::DeriveKeyMaterial(context_key),
+        }
+    }
+
+    fn hasher(&self) -> blake3::Hasher {
+        match &self.mode {
+            Mode::Hash => blake3::Hasher::new(),
+            Mode::KeyedHash(key) => blake3::Hasher::new_keyed(key),
+            Mode::DeriveKey(context_key) => blake3::Hasher::new_from_context_key(context_key),
+        }
+    }
+
+    // Truncated hashes keep their full size, with the bytes past the digest length zeroed, so
+    // they can go straight into parent nodes, and so they compare in constant time like any
+    // other Hash.
+    fn truncate(&self, hash: Hash) -> Hash {
+        let mut bytes = *hash.as_bytes();
+        bytes[self.digest_len..].fill(0);
+        bytes.into()
+    }
+
+    // The streaming encoders and decoders hash chunks and parent nodes through the next three
+    // methods, so that every profile works with streaming, slicing, and outboard encodings. The
+    // content length only matters at the root, for the length suffix.
+
+    // A hasher for the chunk at `chunk_index`, to feed the chunk into and finish with
+    // `chunk_hash`.
+    pub(crate) fn chunk_hasher(&self, chunk_index: u64) -> blake3::Hasher {
+        let mut hasher = self.hasher();
+        hasher.set_input_offset(chunk_index * CHUNK_SIZE as u64);
+        hasher
+    }
+
+    pub(crate) fn chunk_hash(
+        &self,
+        hasher: &blake3::Hasher,
+        finalization: Finalization,
+        content_len: u64,
+    ) -> Hash {
+        self.truncate(match finalization {
+            NotRoot => hasher.finalize_non_root().into(),
+            Root if self.length_suffix => hasher
+                .clone()
+                .update(&crate::encode_len(content_len))
+                .finalize(),
+            Root => hasher.finalize(),
+        })
+    }
+
+    // The children are truncated already. With a length suffix, the root node is hashed the way
+    // it appears in the encoding, 2 * digest_len bytes.
+    pub(crate) fn parent_hash(
+        &self,
+        left: &Hash,
+        right: &Hash,
+        finalization: Finalization,
+        content_len: u64,
+    ) -> Hash {
+        let (left, right) = (left.as_bytes(), right.as_bytes());
+        self.truncate(match finalization {
+            NotRoot => hazmat::merge_subtrees_non_root(left, right, self.hazmat_mode()).into(),
+            Root if self.length_suffix => self
+                .hasher()
+                .update(&left[..self.digest_len])
+                .update(&right[..self.digest_len])
+                .update(&crate::encode_len(content_len))
+                .finalize(),
+            Root => hazmat::merge_subtrees_root(left, right, self.hazmat_mode()),
+        })
+    }
+
+    // Parent nodes in the encoding are the first digest_len bytes of each child, back to back.
+    // In memory they're still a ParentNode, with these bytes at the front.
+    pub(crate) fn parent_size(&self) -> usize {
+        2 * self.digest_len
+    }
+
+    pub(crate) fn parent_children(&self, parent: &crate::ParentNode) -> (Hash, Hash) {
+        let n = self.digest_len;
+        (padded_hash(&parent[..n]), padded_hash(&parent[n..2 * n]))
+    }
+
+    pub(crate) fn write_parent(&self, left: &Hash, right: &Hash) -> crate::ParentNode {
+        let n = self.digest_len;
+        let mut parent = [0; crate::PARENT_SIZE];
+        parent[..n].copy_from_slice(&left.as_bytes()[..n]);
+        parent[n..2 * n].copy_from_slice(&right.as_bytes()[..n]);
+        parent
+    }
+
+    pub(crate) fn encoded_size(&self, content_len: u64) -> u128 {
+        self.subtree_end(HEADER_SIZE as u128, content_len)
+    }
+
+    pub(crate) fn outboard_size(&self, content_len: u64) -> u128 {
+        truncated_outboard_size(content_len, self.digest_len)
+    }
+
+    pub(crate) fn outboard_subtree_size(&self, content_len: u64) -> u128 {
+        (encode::count_chunks(content_len) - 1) as u128 * self.parent_size() as u128
+    }
+
+    // The zero padding that goes before a chunk of chunk_len bytes, which would otherwise start
+    // at position in the combined encoding. Empty content has an empty chunk, and that doesn't
+    // get padding.
+    pub(crate) fn chunk_padding(&self, position: u128, chunk_len: u64) -> u128 {
+        if chunk_len == 0 {
+            return 0;
+        }
+        pad_to(position, self.alignment as u128) - position
+    }
+
+    // Where a combined subtree of content_len bytes ends, if it starts at start. Without
+    // alignment, that's start plus the subtree size. With alignment, the padding depends on
+    // where the subtree starts, so only the caller knows how big it is.
+    pub(crate) fn subtree_end(&self, mut start: u128, mut content_len: u64) -> u128 {
+        // Every left subtree is full, so only the right spine needs walking.
+        while content_len > CHUNK_SIZE as u64 {
+            let left_len = encode::left_subtree_len(content_len);
+            start = self.full_subtree_end(start + self.parent_size() as u128, left_len);
+            content_len -= left_len;
+        }
+        start + self.chunk_padding(start, content_len) + content_len as u128
+    }
+
+    // subtree_end for a full subtree, 2^k whole chunks. The first chunk comes after k parent
+    // nodes. Every chunk j after that comes right after chunk j - 1 and tz(j) parent nodes,
+    // where tz is the number of trailing zeros. Chunk j - 1 starts at a multiple of the
+    // alignment, so the distance from there to chunk j only depends on tz(j), and there are
+    // 2^(k-1-t) chunks with tz(j) == t. That makes this O(depth) instead of O(chunks).
+    fn full_subtree_end(&self, start: u128, content_len: u64) -> u128 {
+        debug_assert!(content_len.is_power_of_two() && content_len >= CHUNK_SIZE as u64);
+        let parent_size = self.parent_size() as u128;
+        let alignment = self.alignment as u128;
+        let k = (content_len / CHUNK_SIZE as u64).trailing_zeros();
+        let mut end = pad_to(start + k as u128 * parent_size, alignment) + CHUNK_SIZE as u128;
+        for t in 0..k {
+            let step = pad_to(CHUNK_SIZE as u128 + t as u128 * parent_size, alignment);
+            end += (1 << (k - 1 - t)) * step;
+        }
+        end
+    }
+
+    // The padding before a chunk in the combined encoding. The flip writes chunks back to front,
+    // so it works this out from where the chunk before it ends.
+    pub(crate) fn chunk_padding_before(&self, chunk_index: u64, content_len: u64) -> u128 {
+        if self.alignment == 1 {
+            return 0;
+        }
+        let parents = encode::pre_order_parent_nodes(chunk_index, content_len) as u128
+            * self.parent_size() as u128;
+        let previous_end = if chunk_index == 0 {
+            HEADER_SIZE as u128
+        } else {
+            self.chunk_encoded_offset(chunk_index - 1, content_len) + CHUNK_SIZE as u128
+        };
+        let chunk_len = encode::chunk_size(chunk_index, content_len) as u64;
+        self.chunk_padding(previous_end + parents, chunk_len)
+    }
+
+    // Where a chunk starts in the combined encoding, after any padding. Like
+    // encode::chunk_encoded_offset, but for this profile's parent nodes and alignment.
+    pub(crate) fn chunk_encoded_offset(&self, chunk_index: u64, content_len: u64) -> u128 {
+        let chunk_start = chunk_index * CHUNK_SIZE as u64;
+        let mut position = HEADER_SIZE as u128;
+        let mut subtree_start = 0;
+        let mut subtree_len = content_len;
+        while subtree_len > CHUNK_SIZE as u64 {
+            position += self.parent_size() as u128;
+            let left_len = encode::left_subtree_len(subtree_len);
+            if chunk_start < subtree_start + left_len {
+                subtree_len = left_len;
+            } else {
+                position = self.full_subtree_end(position, left_len);
+                subtree_start += left_len;
+                subtree_len -= left_len;
+            }
+        }
+        position + self.chunk_padding(position, subtree_len)
+    }
+
+    /// The root hash of `input`.
+    pub fn hash(&self, input: &[u8]) -> Hash {
+        let mut state = EncoderState::new_outboard();
+        state.set_config(*self);
+        let mut input = input;
+        while !input.is_empty() {
+            let consumed = state.push(input).consumed();
+            input = &input[consumed..];
+        }
+        state.finalize().1
+    }
+
+    /// Encode `input` in the combined format, and return the encoding and its root hash.
+    pub fn encode(&self, input: &[u8]) -> (Vec<u8>, Hash) {
+        self.encode_streaming(input, false)
+    }
+
+    /// Encode `input` in the outboard format, and return the encoding and its root hash.
+    pub fn encode_outboard(&self, input: &[u8]) -> (Vec<u8>, Hash) {
+        self.encode_streaming(input, true)
+    }
+
+    fn encode_streaming(&self, input: &[u8], outboard: bool) -> (Vec<u8>, Hash) {
+        let output = io::Cursor::new(Vec::new());
+        let mut encoder = if outboard {
+            Encoder::new_outboard(output)
+        } else {
+            Encoder::new(output)
+        };
+        encoder.set_config(*self);
+        encoder
+            .write_all(input)
+            .expect("in-memory writes can't fail");
+        let hash = encoder.finalize().expect("in-memory writes can't fail");
+        (encoder.into_inner().into_inner(), hash)
+    }
+
+    /// Decode a combined encoding from `encode` and verify it against `hash`. Like
+    /// `decode::decode`, this returns `InvalidData` errors for hash mismatches, and
+    /// `UnexpectedEof` if the encoding is too short. Trailing bytes after the encoding are also
+    /// `InvalidData`, because this decodes all at once. The root hash can only be checked at the
+    /// end, so nothing is returned until everything is verified.
+    pub fn decode(&self, encoded: &[u8], hash: &Hash) -> io::Result<Vec<u8>> {
+        if encoded.len() < HEADER_SIZE {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let content_len = crate::decode_len(array_ref!(encoded, 0, HEADER_SIZE));
+        let encoded_len = self.encoded_size(content_len);
+        if EncodedOffset::new(encoded_len)
+            .to_usize()
+            .is_none_or(|n| encoded.len() < n)
+        {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if encoded.len() as u128 > encoded_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing bytes after the encoding",
+            ));
+        }
+        let mut output = Vec::with_capacity(content_len as usize);
+        Decoder::new(encoded, hash)
+            .with_config(*self)
+            .read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    /// Like `hash`, but with `N`-byte digests. See the module docs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` is 0 or greater than 32.
+    pub fn hash_truncated<const N: usize>(&self, input: &[u8]) -> [u8; N] {
+        digest(&self.with_digest_len(N).hash(input))
+    }
+
+    /// Like `encode`, but with `N`-byte digests. Parent nodes are `2 * N` bytes, so the
+    /// encoding is `truncated_encoded_size(input.len(), N)` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` is 0 or greater than 32.
+    pub fn encode_truncated<const N: usize>(&self, input: &[u8]) -> (Vec<u8>, [u8; N]) {
+        let (encoded, hash) = self.with_digest_len(N).encode(input);
+        (encoded, digest(&hash))
+    }
+
+    /// Like `encode_outboard`, but with `N`-byte digests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` is 0 or greater than 32.
+    pub fn encode_outboard_truncated<const N: usize>(&self, input: &[u8]) -> (Vec<u8>, [u8; N]) {
+        let (encoded, hash) = self.with_digest_len(N).encode_outboard(input);
+        (encoded, digest(&hash))
+    }
+
+    /// Like `decode`, but with `N`-byte digests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` is 0 or greater than 32.
+    pub fn decode_truncated<const N: usize>(
+        &self,
+        encoded: &[u8],
+        hash: &[u8; N],
+    ) -> io::Result<Vec<u8>> {
+        self.with_digest_len(N).decode(encoded, &padded_hash(hash))
+    }
+}
+
+/// The `Hash` form of a truncated digest, zero-padded to 32 bytes, for the decoders and anything
+/// else that takes a `Hash`. See the module docs.
+///
+/// # Panics
+///
+/// Panics if `digest` is empty or longer than 32 bytes.
+pub fn padded_hash(digest: &[u8]) -> Hash {
+    check_digest_len(digest.len());
+    let mut bytes = [0; HASH_SIZE];
+    bytes[..digest.len()].copy_from_slice(digest);
+    bytes.into()
+}
+
+fn digest<const N: usize>(hash: &Hash) -> [u8; N] {
+    let mut digest = [0; N];
+    digest.copy_from_slice(&hash.as_bytes()[..N]);
+    digest
+}
+
+/// The size of a combined encoding with `digest_len`-byte digests, from `Config::encode_truncated`.
+/// This is the same as `encode::encoded_size` for 32-byte digests.
+pub fn truncated_encoded_size(content_len: u64, digest_len: usize) -> u128 {
+    content_len as u128 + truncated_outboard_size(content_len, digest_len)
+}
+
+/// The size of an outboard encoding with `digest_len`-byte digests, from
+/// `Config::encode_outboard_truncated`. This is the same as `encode::outboard_size` for 32-byte
+/// digests.
+pub fn truncated_outboard_size(content_len: u64, digest_len: usize) -> u128 {
+    let parents = encode::count_chunks(content_len) as u128 - 1;
+    HEADER_SIZE as u128 + parents * 2 * digest_len as u128
+}
+
+/// The size of a combined encoding with `digest_len`-byte digests and chunks aligned to
+/// `alignment` bytes, from a `Config` with `with_alignment`. This is the same as
+/// `truncated_encoded_size` for an alignment of 1.
+///
+/// # Panics
+///
+/// Panics if `alignment` isn't a power of two.
+pub fn aligned_encoded_size(content_len: u64, digest_len: usize, alignment: usize) -> u128 {
+    Config::new()
+        .with_digest_len(digest_len)
+        .with_alignment(alignment)
+        .encoded_size(content_len)
+}
+
+// Round position up to a multiple of alignment, a power of two.
+fn pad_to(position: u128, alignment: u128) -> u128 {
+    (position + alignment - 1) & !(alignment - 1)
+}
+
+fn check_digest_len(digest_len: usize) {
+    assert!(
+        0 < digest_len && digest_len <= HASH_SIZE,
+        "digest length must be between 1 and 32"
+    );
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing keys, they're secret.
+        let mode = match self.mode {
+            Mode::Hash => "Hash",
+            Mode::KeyedHash(_) => "KeyedHash",
+            Mode::DeriveKey(_) => "DeriveKey",
+        };
+        write!(
+            f,
+            "Config {{ mode: {}, length_suffix: {}, digest_len: {}, alignment: {} }}",
+            mode, self.length_suffix, self.digest_len, self.alignment
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::{make_test_input, DecodeNext, DecoderState, Error, SliceDecoder};
+    use crate::PARENT_SIZE;
+    use std::cmp;
+
+    const KEY: &[u8; 32] = b"whats the Elvish word for friend";
+    const CONTEXT: &str = "bao config tests";
+
+    fn profiles() -> Vec<Config> {
+        let modes = [
+            Config::new(),
+            Config::keyed(KEY),
+            Config::derive_key(CONTEXT),
+        ];
+        let mut profiles = modes.to_vec();
+        profiles.extend(modes.iter().map(|mode| mode.with_length_suffix()));
+        profiles
+    }
+
+    #[test]
+    fn test_standard_profile() {
+        let config = Config::new();
+        assert!(config.is_standard());
+        assert!(!config.with_length_suffix().is_standard());
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            assert_eq!(encode::encode(&input), config.encode(&input));
+            assert_eq!(encode::outboard(&input), config.encode_outboard(&input));
+            assert_eq!(blake3::hash(&input), config.hash(&input));
+        }
+    }
+
+    #[test]
+    fn test_modes_match_blake3() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            assert_eq!(
+                blake3::keyed_hash(KEY, &input),
+                Config::keyed(KEY).hash(&input)
+            );
+            let expected = blake3::Hasher::new_derive_key(CONTEXT)
+                .update(&input)
+                .finalize();
+            assert_eq!(expected, Config::derive_key(CONTEXT).hash(&input));
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for config in profiles() {
+            println!("config {:?}", config);
+            let mut hashes = Vec::new();
+            for &case in crate::test::TEST_CASES {
+                let input = make_test_input(case);
+                let (encoded, hash) = config.encode(&input);
+                assert_eq!(hash, config.hash(&input));
+                assert_eq!(hash, config.encode_outboard(&input).1);
+                assert_eq!(encode::encoded_size(case as u64), encoded.len() as u128);
+                assert_eq!(input, config.decode(&encoded, &hash).unwrap());
+                hashes.push(hash);
+            }
+            // Every length gets a different root.
+            for (i, hash) in hashes.iter().enumerate() {
+                assert!(!hashes[..i].contains(hash));
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming() {
+        let configs = profiles().into_iter().flat_map(|config| {
+            [
+                config,
+                config.with_digest_len(16),
+                config.with_alignment(64),
+                config.with_digest_len(16).with_alignment(4096),
+            ]
+        });
+        for config in configs {
+            println!("config {:?}", config);
+            for &case in crate::test::TEST_CASES {
+                println!("case {}", case);
+                let input = make_test_input(case);
+                let (encoded, hash) = config.encode(&input);
+                let (outboard, outboard_hash) = config.encode_outboard(&input);
+                assert_eq!(hash, outboard_hash);
+                let n = config.digest_len();
+                assert_eq!(padded_hash(&hash.as_bytes()[..n]), hash);
+
+                // EncoderState, flipped separately.
+                let mut state = EncoderState::new();
+                state.set_config(config);
+                let mut post_order = Vec::new();
+                let mut remaining = &input[..];
+                while !remaining.is_empty() {
+                    let output = state.push(remaining);
+                    post_order.extend_from_slice(output.tree_bytes());
+                    post_order.extend_from_slice(output.content());
+                    remaining = &remaining[output.consumed()..];
+                }
+                let (output, state_hash) = state.finalize();
+                post_order.extend_from_slice(output.tree_bytes());
+                let mut post_order = io::Cursor::new(post_order);
+                encode::flip_with_config(&mut post_order, &config).unwrap();
+                assert_eq!((&encoded, hash), (post_order.get_ref(), state_hash));
+
+                // Decoder, combined and outboard, seeking to the middle.
+                let mut decoder =
+                    Decoder::new(io::Cursor::new(&encoded), &hash).with_config(config);
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(input, output);
+                let middle = case as u64 / 2;
+                decoder.seek(io::SeekFrom::Start(middle)).unwrap();
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(&input[middle as usize..], &output[..]);
+                // Skipping throws away whole subtrees without seeking.
+                let mut decoder = Decoder::new(&*encoded, &hash).with_config(config);
+                assert_eq!(middle, decoder.skip(middle).unwrap());
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(&input[middle as usize..], &output[..]);
+                let mut decoder = Decoder::new_outboard(
+                    io::Cursor::new(&input),
+                    io::Cursor::new(&outboard),
+                    &hash,
+                )
+                .with_config(config);
+                decoder.seek(io::SeekFrom::Start(middle)).unwrap();
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(&input[middle as usize..], &output[..]);
+
+                // DecoderState.
+                let mut state = DecoderState::new(&hash).with_config(config);
+                let mut output = Vec::new();
+                loop {
+                    match state.next() {
+                        DecodeNext::NeedEncoded { offset, len } => {
+                            let bytes = &encoded[offset as usize..][..len];
+                            output.extend_from_slice(state.feed(bytes).unwrap());
+                        }
+                        DecodeNext::NeedContent { .. } => unreachable!("not outboard"),
+                        DecodeNext::Done => break,
+                    }
+                }
+                assert_eq!(input, output);
+
+                // Slices, from both encodings.
+                let slice_len = 2 * CHUNK_SIZE as u64;
+                let mut slice = Vec::new();
+                encode::SliceExtractor::new(io::Cursor::new(&encoded), middle, slice_len)
+                    .with_config(config)
+                    .read_to_end(&mut slice)
+                    .unwrap();
+                let mut outboard_slice = Vec::new();
+                encode::SliceExtractor::new_outboard(
+                    io::Cursor::new(&input),
+                    io::Cursor::new(&outboard),
+                    middle,
+                    slice_len,
+                )
+                .with_config(config)
+                .read_to_end(&mut outboard_slice)
+                .unwrap();
+                assert_eq!(slice, outboard_slice);
+                let mut decoder =
+                    SliceDecoder::new(&*slice, &hash, middle, slice_len).with_config(config);
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                let slice_end = cmp::min(case as u64, middle + slice_len) as usize;
+                assert_eq!(&input[middle as usize..slice_end], &output[..]);
+
+                // Other profiles' decoders reject it. Without the alignment, the hashing is the
+                // same, and the encoding might not have any padding.
+                for other in profiles() {
+                    let other = other.with_digest_len(n);
+                    if other != config.with_alignment(1) {
+                        let mut decoder = Decoder::new(&*encoded, &hash).with_config(other);
+                        assert!(decoder.read_to_end(&mut Vec::new()).is_err());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_profiles_differ() {
+        let input = make_test_input(5 * CHUNK_SIZE);
+        let hashes: Vec<Hash> = profiles().iter().map(|c| c.hash(&input)).collect();
+        for (i, hash) in hashes.iter().enumerate() {
+            assert!(!hashes[..i].contains(hash));
+        }
+        // Only the root is different with a length suffix.
+        let (encoded, _) = Config::keyed(KEY).encode(&input);
+        let (suffixed, _) = Config::keyed(KEY).with_length_suffix().encode(&input);
+        assert_eq!(encoded, suffixed);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        for config in profiles() {
+            println!("config {:?}", config);
+            let input = make_test_input(3 * CHUNK_SIZE + 1);
+            let (encoded, hash) = config.encode(&input);
+            for &i in &[HEADER_SIZE, HEADER_SIZE + PARENT_SIZE, encoded.len() - 1] {
+                let mut bad = encoded.clone();
+                bad[i] ^= 1;
+                let err = config.decode(&bad, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+            let err = config.decode(&encoded[..encoded.len() - 1], &hash);
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.unwrap_err().kind());
+            let mut extra = encoded.clone();
+            extra.push(0);
+            let err = config.decode(&extra, &hash);
+            assert_eq!(io::ErrorKind::InvalidData, err.unwrap_err().kind());
+            // Another profile's hash doesn't verify.
+            let other = Config::derive_key("some other context");
+            let err = other.decode(&encoded, &hash);
+            assert_eq!(io::ErrorKind::InvalidData, err.unwrap_err().kind());
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        for config in profiles() {
+            println!("config {:?}", config);
+            for &case in crate::test::TEST_CASES {
+                let input = make_test_input(case);
+                // Full length digests are the same as the regular ones.
+                let (encoded, hash) = config.encode(&input);
+                assert_eq!(
+                    (encoded, *hash.as_bytes()),
+                    config.encode_truncated::<32>(&input)
+                );
+
+                let (encoded, hash) = config.encode_truncated::<16>(&input);
+                assert_eq!(hash, config.hash_truncated::<16>(&input));
+                let (outboard, outboard_hash) = config.encode_outboard_truncated::<16>(&input);
+                assert_eq!(hash, outboard_hash);
+                let len = case as u64;
+                assert_eq!(truncated_encoded_size(len, 16), encoded.len() as u128);
+                assert_eq!(truncated_outboard_size(len, 16), outboard.len() as u128);
+                assert_eq!(input, config.decode_truncated(&encoded, &hash).unwrap());
+
+                // Truncated roots aren't prefixes of the full ones, except for a single chunk.
+                let full = config.hash(&input);
+                let is_prefix = full.as_bytes()[..16] == hash;
+                assert_eq!(case <= CHUNK_SIZE, is_prefix);
+
+                if case > CHUNK_SIZE {
+                    for &i in &[HEADER_SIZE, HEADER_SIZE + 31, encoded.len() - 1] {
+                        let mut bad = encoded.clone();
+                        bad[i] ^= 1;
+                        let err = config.decode_truncated(&bad, &hash).unwrap_err();
+                        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                    }
+                    // A regular encoding doesn't decode with truncated digests.
+                    let (regular, _) = config.encode(&input);
+                    assert!(config.decode_truncated(&regular, &hash).is_err());
+                }
+            }
+        }
+    }
+
+    // Build an aligned encoding by hand, by padding a regular encoding before each chunk.
+    fn align_by_hand(regular: &[u8], content_len: u64, alignment: usize) -> Vec<u8> {
+        let mut aligned = Vec::new();
+        let mut position = 0;
+        for chunk in crate::layout::TreeLayout::new(content_len).chunks() {
+            let chunk_start = chunk.encoded_offset as usize;
+            aligned.extend_from_slice(&regular[position..chunk_start]);
+            if chunk.len > 0 {
+                while aligned.len() % alignment != 0 {
+                    aligned.push(0);
+                }
+            }
+            aligned.extend_from_slice(&regular[chunk_start..][..chunk.len]);
+            position = chunk_start + chunk.len;
+        }
+        aligned
+    }
+
+    #[test]
+    fn test_alignment() {
+        for &alignment in &[1, 2, 64, 512, 1024, 4096] {
+            let config = Config::keyed(KEY).with_alignment(alignment);
+            println!("config {:?}", config);
+            assert!(!Config::new().with_alignment(4096).is_standard());
+            for &case in crate::test::TEST_CASES {
+                let input = make_test_input(case);
+                let len = case as u64;
+                let (regular, regular_hash) = Config::keyed(KEY).encode(&input);
+                let (encoded, hash) = config.encode(&input);
+                assert_eq!(regular_hash, hash);
+                assert_eq!(align_by_hand(&regular, len, alignment), encoded);
+                for (index, chunk) in input.chunks(CHUNK_SIZE).enumerate() {
+                    let offset = config.chunk_encoded_offset(index as u64, len) as usize;
+                    assert_eq!(0, offset % alignment);
+                    assert_eq!(chunk, &encoded[offset..][..chunk.len()]);
+                }
+                assert_eq!(
+                    aligned_encoded_size(len, HASH_SIZE, alignment),
+                    encoded.len() as u128
+                );
+                assert_eq!(input, config.decode(&encoded, &hash).unwrap());
+                // Outboard encodings don't change.
+                assert_eq!(
+                    Config::keyed(KEY).encode_outboard(&input),
+                    config.encode_outboard(&input)
+                );
+
+                let (encoded, digest) = config.encode_truncated::<16>(&input);
+                assert_eq!(
+                    aligned_encoded_size(len, 16, alignment),
+                    encoded.len() as u128
+                );
+                assert_eq!(input, config.decode_truncated(&encoded, &digest).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_alignment_padding_checked() {
+        let config = Config::new().with_alignment(4096);
+        let input = make_test_input(5 * CHUNK_SIZE);
+        let (encoded, hash) = config.encode(&input);
+        // The header and three parent nodes come first, and then padding.
+        let padding = HEADER_SIZE + 3 * PARENT_SIZE;
+        assert!(encoded[padding..4096].iter().all(|&b| b == 0));
+        let mut bad = encoded.clone();
+        bad[padding] = 1;
+        let err = config.decode(&bad, &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let mut decoder = Decoder::new(&*bad, &hash).with_config(config);
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(Error::NonzeroPadding.to_string(), err.to_string());
+        let mut state = DecoderState::new(&hash).with_config(config);
+        let err = loop {
+            match state.next() {
+                DecodeNext::NeedEncoded { offset, len } => {
+                    if let Err(e) = state.feed(&bad[offset as usize..][..len]) {
+                        break e;
+                    }
+                }
+                _ => panic!("should fail before the first chunk"),
+            }
+        };
+        assert_eq!(Error::NonzeroPadding, err);
+        // The second chunk comes right after the first, padded out to the next block. Seeking
+        // to it checks that padding too.
+        let mut bad = encoded.clone();
+        bad[4096 + CHUNK_SIZE] = 1;
+        let mut decoder = Decoder::new(io::Cursor::new(&bad), &hash).with_config(config);
+        decoder
+            .seek(io::SeekFrom::Start(CHUNK_SIZE as u64))
+            .unwrap();
+        let err = decoder.read(&mut [0; CHUNK_SIZE]).unwrap_err();
+        assert_eq!(Error::NonzeroPadding.to_string(), err.to_string());
+        // An unaligned decoder doesn't understand the padding.
+        assert!(Config::new().decode(&encoded, &hash).is_err());
+        // And an aligned decoder doesn't accept an unaligned encoding.
+        let (regular, _) = Config::new().encode(&input);
+        assert!(config.decode(&regular, &hash).is_err());
+    }
+
+    #[test]
+    fn test_aligned_encoded_size_huge() {
+        // This has to be quick, even for the largest content.
+        let len = u64::MAX;
+        let regular = truncated_encoded_size(len, HASH_SIZE);
+        assert_eq!(regular, aligned_encoded_size(len, HASH_SIZE, 1));
+        assert!(aligned_encoded_size(len, HASH_SIZE, 512) > regular);
+        // Every chunk takes a whole 4096-byte block.
+        assert!(aligned_encoded_size(len, HASH_SIZE, 4096) > 4 * len as u128);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_alignment_not_power_of_two_panics() {
+        let _ = Config::new().with_alignment(3000);
+    }
+
+    #[test]
+    fn test_truncated_vectors() {
+        // Pinned roots for the test vector inputs of lengths 0, 1024, 1025, and 13312, with
+        // 16-byte digests.
+        let expected = [
+            "af1349b9f5f9a1a6a0404dea36dcc949",
+            "f749c19181983b839cd97fe121cebaf0",
+            "2ec4599e4e5890e2707596a7ea36347b",
+            "7a8da48eb3326afe5c7bbe9221c811bb",
+        ];
+        let lengths = [0, CHUNK_SIZE, CHUNK_SIZE + 1, 13 * CHUNK_SIZE];
+        for (&len, hex) in lengths.iter().zip(&expected) {
+            let input = crate::test_vectors::input(len);
+            let digest = Config::new().hash_truncated::<16>(&input);
+            let digest_hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+            assert_eq!(*hex, digest_hex);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_digest_len_panics() {
+        Config::new().hash_truncated::<0>(b"foo");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_long_digest_len_panics() {
+        Config::new().hash_truncated::<33>(b"foo");
+    }
+
+    #[test]
+    fn test_length_suffix_vectors() {
+        // Pinned roots for the test vector inputs of lengths 0, 1024, 1025, and 13312, for other
+        // implementations to check against. The profiles without a length suffix are just BLAKE3,
+        // which test_modes_match_blake3 covers.
+        let expected: &[(Config, [&str; 4])] = &[
+            (
+                Config::new().with_length_suffix(),
+                [
+                    "71e0a99173564931c0b8acc52d2685a8e39c64dc52e3d02390fdac2a12b155cb",
+                    "87c5e6e38f21fb99a2f7f13291e570fc451675a257e461565f3a9113a70cc255",
+                    "688d264e09c265657e770860f7e8b17beabcc6de3998c428ca63b416776d8c72",
+                    "c8d0fa4c54770aa92ac7835f6da69fbcdcdd297002120496793ed10b72bd25c6",
+                ],
+            ),
+            (
+                Config::keyed(KEY).with_length_suffix(),
+                [
+                    "4d4fcfcc8681c9d3c5a97ad326d1c418a0f40f6607f0c996e63091108c2e626d",
+                    "25cbc7b3dd2137bcf81d232b5317c05c66edf412c9742af11fa912eac88ccc4a",
+                    "e82a0af5033669c47351dd3019e0251a7a46344cd48456b22941a8faf05e4836",
+                    "2356d9f75eafef4f004406f74135371e1a0798080b8c6204d7fd6558803b0031",
+                ],
+            ),
+            (
+                Config::derive_key(CONTEXT).with_length_suffix(),
+                [
+                    "5ebff68d102ed12bf7da4ed72592de62e7711e8be8d3c02be31c67449c977b99",
+                    "7761bbf616d8370043a6b3d92712675fcf3660e85d67e329a27962c1f8e0fd6b",
+                    "b9dbfc3dfda0dc9a6fbb2dcb79ed8da8114630b549860a542b5a031d43e12406",
+                    "d420499aa26daac078146314dfdd0a0bc46838bbf0a2a14abc58ec688212f72b",
+                ],
+            ),
+        ];
+        let lengths = [0, CHUNK_SIZE, CHUNK_SIZE + 1, 13 * CHUNK_SIZE];
+        for (config, hashes) in expected {
+            for (&len, hash) in lengths.iter().zip(hashes) {
+                let input = crate::test_vectors::input(len);
+                assert_eq!(*hash, &*config.hash(&input).to_hex());
+            }
+        }
+
+        // Spot check the definition: a single chunk root is the hash of the chunk and the header.
+        let input = crate::test_vectors::input(CHUNK_SIZE);
+        let expected = blake3::Hasher::new()
+            .update(&input)
+            .update(&crate::encode_len(CHUNK_SIZE as u64))
+            .finalize();
+        assert_eq!(expected, Config::new().with_length_suffix().hash(&input));
+    }
+}
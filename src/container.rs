@@ -0,0 +1,315 @@
+//! An optional versioned container around an encoding.
+//!
+//! A bare encoding doesn't say how it was made. Decoding it with the wrong parameters, like the
+//! wrong chunk group size, looks exactly like corruption: a hash mismatch. The container puts an
+//! 8-byte prefix in front of the encoding that records its format, so a reader can detect a
+//! format it doesn't support and say so.
+//!
+//! The prefix is:
+//!
+//! - The 4 magic bytes `MAGIC`.
+//! - The container version, currently 1.
+//! - A flags byte. Bit 0 is set for an outboard encoding. The other bits are reserved and must
+//!   be zero.
+//! - The `chunk_group_log` from the `group` module. 0 means a regular encoding.
+//! - The hash function. 0 means BLAKE3, the only one currently supported.
+//!
+//! The encoding follows, unchanged. Containers are opt-in. Nothing else in this crate reads or
+//! writes them.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use bao::container::{Format, Layout};
+//!
+//! let format = Format::new(Layout::Combined).with_chunk_group_log(4);
+//! let (contained, hash) = bao::container::encode(b"hello", &format);
+//! assert_eq!(format, bao::container::read_prefix(&contained[..])?);
+//! assert_eq!(b"hello", &bao::container::decode(&contained, &hash)?[..]);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::group;
+use crate::Hash;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+/// The magic bytes at the start of every container.
+pub const MAGIC: [u8; 4] = *b"bao\0";
+
+/// The container version this crate writes, and the only one it reads.
+pub const VERSION: u8 = 1;
+
+/// The size of the container prefix, 8 bytes.
+pub const PREFIX_SIZE: usize = 8;
+
+const OUTBOARD_FLAG: u8 = 1;
+const BLAKE3_CODE: u8 = 0;
+
+/// Errors from parsing a container prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The input doesn't start with `MAGIC`, so it probably isn't a container at all.
+    BadMagic,
+    /// The container version isn't `VERSION`.
+    UnsupportedVersion(u8),
+    /// The flags byte has reserved bits set.
+    UnsupportedFlags(u8),
+    /// The chunk group size is larger than `group::MAX_CHUNK_GROUP_LOG`.
+    UnsupportedChunkGroup(u8),
+    /// The hash function isn't BLAKE3.
+    UnsupportedHash(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BadMagic => write!(f, "not a bao container"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported container version {}", v),
+            Error::UnsupportedFlags(x) => write!(f, "unsupported container flags {:#04x}", x),
+            Error::UnsupportedChunkGroup(x) => write!(f, "unsupported chunk group log {}", x),
+            Error::UnsupportedHash(x) => write!(f, "unsupported hash function {}", x),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Whether the encoding includes the content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Layout {
+    Combined,
+    Outboard,
+}
+
+/// The hash function the tree is built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HashFunction {
+    Blake3,
+}
+
+/// The format of an encoding, as recorded in a container prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Format {
+    pub layout: Layout,
+    pub chunk_group_log: u8,
+    pub hash_function: HashFunction,
+}
+
+impl Format {
+    /// A regular BLAKE3 encoding with the given layout, without chunk groups.
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            chunk_group_log: 0,
+            hash_function: HashFunction::Blake3,
+        }
+    }
+
+    /// Use chunk groups. See the `group` module.
+    pub fn with_chunk_group_log(mut self, chunk_group_log: u8) -> Self {
+        self.chunk_group_log = chunk_group_log;
+        self
+    }
+
+    /// Serialize the container prefix.
+    pub fn to_prefix(&self) -> [u8; PREFIX_SIZE] {
+        let flags = match self.layout {
+            Layout::Combined => 0,
+            Layout::Outboard => OUTBOARD_FLAG,
+        };
+        let hash_code = match self.hash_function {
+            HashFunction::Blake3 => BLAKE3_CODE,
+        };
+        let mut prefix = [0; PREFIX_SIZE];
+        prefix[..4].copy_from_slice(&MAGIC);
+        prefix[4] = VERSION;
+        prefix[5] = flags;
+        prefix[6] = self.chunk_group_log;
+        prefix[7] = hash_code;
+        prefix
+    }
+
+    /// Parse a container prefix.
+    pub fn from_prefix(prefix: &[u8; PREFIX_SIZE]) -> Result<Self, Error> {
+        if prefix[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if prefix[4] != VERSION {
+            return Err(Error::UnsupportedVersion(prefix[4]));
+        }
+        let layout = match prefix[5] {
+            0 => Layout::Combined,
+            OUTBOARD_FLAG => Layout::Outboard,
+            flags => return Err(Error::UnsupportedFlags(flags)),
+        };
+        if prefix[6] > group::MAX_CHUNK_GROUP_LOG {
+            return Err(Error::UnsupportedChunkGroup(prefix[6]));
+        }
+        let hash_function = match prefix[7] {
+            BLAKE3_CODE => HashFunction::Blake3,
+            code => return Err(Error::UnsupportedHash(code)),
+        };
+        Ok(Self {
+            layout,
+            chunk_group_log: prefix[6],
+            hash_function,
+        })
+    }
+}
+
+/// Write a container prefix.
+pub fn write_prefix(mut writer: impl Write, format: &Format) -> io::Result<()> {
+    writer.write_all(&format.to_prefix())
+}
+
+/// Read and parse a container prefix, leaving the reader at the start of the encoding. An
+/// unsupported prefix is an `InvalidData` error wrapping an `Error`.
+pub fn read_prefix(mut reader: impl Read) -> io::Result<Format> {
+    let mut prefix = [0; PREFIX_SIZE];
+    reader.read_exact(&mut prefix)?;
+    Ok(Format::from_prefix(&prefix)?)
+}
+
+/// Encode the input in the given format, with the container prefix in front, and return it along
+/// with the root hash.
+pub fn encode(input: impl AsRef<[u8]>, format: &Format) -> (Vec<u8>, Hash) {
+    let (encoding, hash) = match format.layout {
+        Layout::Combined => group::encode(input, format.chunk_group_log),
+        Layout::Outboard => group::outboard(input, format.chunk_group_log),
+    };
+    let mut contained = Vec::with_capacity(PREFIX_SIZE + encoding.len());
+    contained.extend_from_slice(&format.to_prefix());
+    contained.extend_from_slice(&encoding);
+    (contained, hash)
+}
+
+/// Decode a combined encoding in a container, in whatever format the prefix says. An outboard
+/// container is an `InvalidInput` error. Use `decode_outboard` for those.
+pub fn decode(contained: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
+    let mut reader = contained.as_ref();
+    let format = read_prefix(&mut reader)?;
+    if format.layout != Layout::Combined {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "outboard container",
+        ));
+    }
+    let mut output = Vec::new();
+    group::decode(reader, &mut output, hash, format.chunk_group_log)?;
+    Ok(output)
+}
+
+/// Decode content with an outboard encoding in a container. A combined container is an
+/// `InvalidInput` error.
+pub fn decode_outboard(
+    content: impl Read,
+    contained_outboard: impl AsRef<[u8]>,
+    hash: &Hash,
+) -> io::Result<Vec<u8>> {
+    let mut outboard = contained_outboard.as_ref();
+    let format = read_prefix(&mut outboard)?;
+    if format.layout != Layout::Outboard {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "combined container",
+        ));
+    }
+    let mut output = Vec::new();
+    group::decode_outboard(content, outboard, &mut output, hash, format.chunk_group_log)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+
+    #[test]
+    fn test_prefix_round_trip() {
+        for &layout in &[Layout::Combined, Layout::Outboard] {
+            for log in 0..=group::MAX_CHUNK_GROUP_LOG {
+                let format = Format::new(layout).with_chunk_group_log(log);
+                let prefix = format.to_prefix();
+                assert_eq!(format, Format::from_prefix(&prefix).unwrap());
+                let mut written = Vec::new();
+                write_prefix(&mut written, &format).unwrap();
+                assert_eq!(format, read_prefix(&written[..]).unwrap());
+            }
+        }
+        // The layout of the prefix is part of the format, so pin it down.
+        let format = Format::new(Layout::Outboard).with_chunk_group_log(4);
+        assert_eq!(*b"bao\0\x01\x01\x04\x00", format.to_prefix());
+    }
+
+    #[test]
+    fn test_bad_prefixes() {
+        let good = Format::new(Layout::Combined).to_prefix();
+        let cases = [
+            (0, b'B', Error::BadMagic),
+            (4, 2, Error::UnsupportedVersion(2)),
+            (5, 2, Error::UnsupportedFlags(2)),
+            (6, 17, Error::UnsupportedChunkGroup(17)),
+            (7, 1, Error::UnsupportedHash(1)),
+        ];
+        for &(index, value, error) in &cases {
+            let mut bad = good;
+            bad[index] = value;
+            assert_eq!(Err(error), Format::from_prefix(&bad));
+            let io_error = read_prefix(&bad[..]).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, io_error.kind());
+        }
+        // A bare encoding isn't a container.
+        let (encoded, _) = crate::encode::encode(b"hello");
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            read_prefix(&encoded[..]).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            for log in 0..3 {
+                let format = Format::new(Layout::Combined).with_chunk_group_log(log);
+                let (contained, hash) = encode(&input, &format);
+                assert_eq!(&contained[..PREFIX_SIZE], &format.to_prefix());
+                assert_eq!(group::encode(&input, log).0, &contained[PREFIX_SIZE..]);
+                assert_eq!(input, decode(&contained, &hash).unwrap());
+                let err = decode_outboard(&input[..], &contained, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+                let format = Format::new(Layout::Outboard).with_chunk_group_log(log);
+                let (contained, hash) = encode(&input, &format);
+                assert_eq!(
+                    input,
+                    decode_outboard(&input[..], &contained, &hash).unwrap()
+                );
+                let err = decode(&contained, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_format_serde() {
+        let format = Format::new(Layout::Outboard).with_chunk_group_log(4);
+        let json = serde_json::to_string(&format).unwrap();
+        assert_eq!(format, serde_json::from_str::<Format>(&json).unwrap());
+    }
+}
@@ -0,0 +1,778 @@
+//! Encrypt content chunk by chunk with XChaCha20-Poly1305, and encode the ciphertext.
+//!
+//! This module is behind the `crypto` feature. The plaintext is split into pieces of
+//! `PLAINTEXT_CHUNK_SIZE` bytes, and each piece is sealed on its own with XChaCha20-Poly1305,
+//! which adds a `TAG_SIZE` tag. That makes each sealed piece exactly one chunk of ciphertext, so
+//! the chunk boundaries line up, and the encoding is an ordinary encoding of the ciphertext.
+//! Seeking and slicing work the same way they do for any other encoding, and each chunk that a
+//! slice contains can be decrypted and authenticated on its own with `decrypt_chunk`.
+//!
+//! Every encoding gets a fresh random nonce. The 24-byte XChaCha20-Poly1305 nonce for each chunk
+//! is that nonce, followed by the chunk index as 8 little-endian bytes, followed by 1 if it's the
+//! final chunk and 0 otherwise. This is the STREAM construction of Hoang, Reyhanitabar, Rogaway,
+//! and Vizár: the index means chunks can't be reordered or moved between positions, the flag
+//! means the ciphertext can't be truncated at a chunk boundary, and the random nonce means that
+//! reusing a key for many files never reuses a chunk nonce. Empty content is still one chunk,
+//! holding just a tag.
+//!
+//! The nonce and the root hash of the ciphertext make up a `Seal`, which the caller stores
+//! alongside the encoding, the way it would store a root hash. The decoders verify each chunk
+//! against the root hash, and then decrypt and authenticate it with the key. The root hash isn't
+//! secret and isn't authenticated by itself. Swapping in a different encoding with its own root
+//! hash fails when its chunks don't authenticate.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::io::prelude::*;
+//!
+//! let key = [42; bao::crypto::KEY_SIZE];
+//! let input = b"some secret input";
+//! let (encoded, seal) = bao::crypto::encode(input, &key);
+//! assert!(!encoded.windows(6).any(|w| w == b"secret"));
+//!
+//! // The seal can be stored as bytes next to the encoding.
+//! let seal = bao::crypto::Seal::from_bytes(&seal.to_bytes());
+//! let mut decoded = Vec::new();
+//! let mut decoder = bao::crypto::Decoder::new(&*encoded, &seal, &key);
+//! decoder.read_to_end(&mut decoded)?;
+//! assert_eq!(input, &decoded[..]);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode;
+use crate::encode;
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE};
+use arrayref::array_refs;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+/// The size of a key, 32 bytes.
+pub const KEY_SIZE: usize = 32;
+/// The size of a nonce, 15 bytes. That's big enough to pick at random for every encoding. The
+/// chunk index and the final chunk flag fill out the rest of each chunk's 24-byte nonce.
+pub const NONCE_SIZE: usize = 15;
+/// The size of the Poly1305 tag at the end of each chunk, 16 bytes.
+pub const TAG_SIZE: usize = 16;
+/// The amount of plaintext in each chunk, 1008 bytes, so that with its tag it fills a chunk.
+pub const PLAINTEXT_CHUNK_SIZE: usize = CHUNK_SIZE - TAG_SIZE;
+/// The size of a `Seal` in bytes, a nonce and a root hash.
+pub const SEAL_SIZE: usize = NONCE_SIZE + HASH_SIZE;
+
+/// What a decoder needs besides the key: the random nonce prefix that the encoder picked for
+/// every chunk's nonce, and the root hash of the encoding of the ciphertext. Store it alongside
+/// the encoding, like a root hash. It isn't secret.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Seal {
+    /// The random nonce prefix, shared by every chunk of the encoding. See the module docs for
+    /// how each chunk's full nonce is built from it.
+    pub nonce: [u8; NONCE_SIZE],
+    /// The root hash of the ciphertext encoding, which the decoders verify each chunk against.
+    pub hash: Hash,
+}
+
+impl Seal {
+    /// The seal as `SEAL_SIZE` bytes: the `NONCE_SIZE`-byte nonce, followed by the
+    /// `HASH_SIZE`-byte root hash.
+    pub fn to_bytes(&self) -> [u8; SEAL_SIZE] {
+        let mut bytes = [0; SEAL_SIZE];
+        bytes[..NONCE_SIZE].copy_from_slice(&self.nonce);
+        bytes[NONCE_SIZE..].copy_from_slice(self.hash.as_bytes());
+        bytes
+    }
+
+    /// Parse a seal from the bytes of `to_bytes`. Any bytes parse, and a wrong seal is an error
+    /// from the decoder instead.
+    pub fn from_bytes(bytes: &[u8; SEAL_SIZE]) -> Self {
+        let (nonce, hash) = array_refs!(bytes, NONCE_SIZE, HASH_SIZE);
+        Self {
+            nonce: *nonce,
+            hash: (*hash).into(),
+        }
+    }
+}
+
+// A key and a nonce, ready to seal and open chunks.
+#[derive(Clone)]
+struct Cipher {
+    aead: XChaCha20Poly1305,
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl Cipher {
+    fn new(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE]) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(key.into()),
+            nonce: *nonce,
+        }
+    }
+
+    fn chunk_nonce(&self, index: u64, is_final: bool) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..NONCE_SIZE].copy_from_slice(&self.nonce);
+        nonce[NONCE_SIZE..][..8].copy_from_slice(&index.to_le_bytes());
+        nonce[NONCE_SIZE + 8] = is_final as u8;
+        nonce
+    }
+
+    // Encrypt the plaintext in `buf` in place and append its tag.
+    fn seal(&self, index: u64, is_final: bool, buf: &mut Vec<u8>) {
+        debug_assert!(buf.len() <= PLAINTEXT_CHUNK_SIZE);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(&self.chunk_nonce(index, is_final), b"", buf)
+            .expect("chunks are far below the length limit");
+        buf.extend_from_slice(&tag);
+    }
+
+    // Authenticate and decrypt the ciphertext in `buf` in place, and strip its tag.
+    fn open(&self, index: u64, is_final: bool, buf: &mut Vec<u8>) -> io::Result<()> {
+        if buf.len() < TAG_SIZE || buf.len() > CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "ciphertext chunk has the wrong length",
+            ));
+        }
+        let tag = Tag::clone_from_slice(&buf[buf.len() - TAG_SIZE..]);
+        buf.truncate(buf.len() - TAG_SIZE);
+        self.aead
+            .decrypt_in_place_detached(&self.chunk_nonce(index, is_final), b"", buf, &tag)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "chunk authentication failed"))
+    }
+}
+
+/// Pick a random nonce from the operating system's RNG.
+pub fn random_nonce() -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce).expect("the OS RNG failed");
+    nonce
+}
+
+/// The length of the ciphertext for `plaintext_len` bytes of plaintext, which is also the
+/// content length of the encoding.
+pub fn ciphertext_len(plaintext_len: u64) -> u64 {
+    plaintext_len + TAG_SIZE as u64 * plaintext_chunks(plaintext_len)
+}
+
+fn plaintext_chunks(plaintext_len: u64) -> u64 {
+    cmp::max(1, plaintext_len.div_ceil(PLAINTEXT_CHUNK_SIZE as u64))
+}
+
+// The inverse of `ciphertext_len`, or an error if no plaintext has this ciphertext length. Only
+// empty content has a final chunk with nothing but a tag.
+fn plaintext_len(ciphertext_len: u64) -> io::Result<u64> {
+    let chunks = encode::count_chunks(ciphertext_len);
+    let final_len = ciphertext_len - (chunks - 1) * CHUNK_SIZE as u64;
+    if final_len < TAG_SIZE as u64 || (chunks > 1 && final_len == TAG_SIZE as u64) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ciphertext chunk has the wrong length",
+        ));
+    }
+    Ok(ciphertext_len - chunks * TAG_SIZE as u64)
+}
+
+/// Encrypt chunk number `index` of the content with this `key` and `nonce`. The plaintext can be
+/// at most `PLAINTEXT_CHUNK_SIZE` bytes, and only the final chunk can be shorter than that.
+/// `is_final` has to be set for the final chunk, and only for that one.
+///
+/// This is what the `Encoder` in this module does for each chunk. For the whole content at once,
+/// use `encrypt`.
+pub fn encrypt_chunk(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    index: u64,
+    is_final: bool,
+    plaintext: &[u8],
+) -> Vec<u8> {
+    assert!(
+        plaintext.len() <= PLAINTEXT_CHUNK_SIZE,
+        "plaintext too long"
+    );
+    let mut buf = plaintext.to_vec();
+    Cipher::new(key, nonce).seal(index, is_final, &mut buf);
+    buf
+}
+
+/// Authenticate and decrypt chunk number `index` of the content with this `key` and `nonce`.
+/// `is_final` has to be set for the final chunk, and only for that one. A chunk that fails to
+/// authenticate is an `InvalidData` error.
+///
+/// This is useful for decrypting the output of a `SliceDecoder`, for example, one chunk at a
+/// time. The decoders in this module do this themselves.
+pub fn decrypt_chunk(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    index: u64,
+    is_final: bool,
+    ciphertext: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut buf = ciphertext.to_vec();
+    Cipher::new(key, nonce).open(index, is_final, &mut buf)?;
+    Ok(buf)
+}
+
+/// Encrypt all of `plaintext` with this `key` and `nonce`. This is the content of the encoding
+/// that the `Encoder` in this module produces, which an outboard encoding needs alongside it.
+pub fn encrypt(plaintext: &[u8], key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE]) -> Vec<u8> {
+    let cipher = Cipher::new(key, nonce);
+    let chunks = plaintext_chunks(plaintext.len() as u64);
+    let mut ciphertext = Vec::with_capacity(ciphertext_len(plaintext.len() as u64) as usize);
+    let mut buf = Vec::with_capacity(CHUNK_SIZE);
+    for index in 0..chunks {
+        let start = index as usize * PLAINTEXT_CHUNK_SIZE;
+        let end = cmp::min(start + PLAINTEXT_CHUNK_SIZE, plaintext.len());
+        buf.clear();
+        buf.extend_from_slice(&plaintext[start..end]);
+        cipher.seal(index, index == chunks - 1, &mut buf);
+        ciphertext.extend_from_slice(&buf);
+    }
+    ciphertext
+}
+
+/// Authenticate and decrypt all of `ciphertext` with this `key` and `nonce`. The ciphertext isn't
+/// verified against any root hash, only authenticated.
+pub fn decrypt(
+    ciphertext: &[u8],
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+) -> io::Result<Vec<u8>> {
+    let cipher = Cipher::new(key, nonce);
+    let len = plaintext_len(ciphertext.len() as u64)?;
+    let chunks = plaintext_chunks(len);
+    let mut plaintext = Vec::with_capacity(len as usize);
+    let mut buf = Vec::with_capacity(CHUNK_SIZE);
+    for index in 0..chunks {
+        let start = index as usize * CHUNK_SIZE;
+        let end = cmp::min(start + CHUNK_SIZE, ciphertext.len());
+        buf.clear();
+        buf.extend_from_slice(&ciphertext[start..end]);
+        cipher.open(index, index == chunks - 1, &mut buf)?;
+        plaintext.extend_from_slice(&buf);
+    }
+    Ok(plaintext)
+}
+
+/// Encrypt and encode an input all at once, with a random nonce, returning the combined encoding
+/// of the ciphertext and its `Seal`.
+pub fn encode(input: impl AsRef<[u8]>, key: &[u8; KEY_SIZE]) -> (Vec<u8>, Seal) {
+    let nonce = random_nonce();
+    let ciphertext = encrypt(input.as_ref(), key, &nonce);
+    let (encoded, hash) = encode::encode(&ciphertext);
+    (encoded, Seal { nonce, hash })
+}
+
+/// Decode, authenticate, and decrypt a combined encoding all at once.
+pub fn decode(encoded: impl AsRef<[u8]>, seal: &Seal, key: &[u8; KEY_SIZE]) -> io::Result<Vec<u8>> {
+    let ciphertext = decode::decode(encoded, &seal.hash)?;
+    decrypt(&ciphertext, key, &seal.nonce)
+}
+
+/// An incremental encoder that encrypts its input. Like `encode::Encoder`, it supports both
+/// combined and outboard encodings, and the outboard "content" is the ciphertext, which the
+/// caller has to store separately. Use `encrypt` with the nonce from `nonce` to produce it.
+pub struct Encoder<T: Read + Write + Seek> {
+    inner: encode::Encoder<T>,
+    cipher: Cipher,
+    chunk_index: u64,
+    buf: Vec<u8>,
+}
+
+impl<T: Read + Write + Seek> Encoder<T> {
+    /// Create an encoder with a random nonce.
+    pub fn new(inner: T, key: &[u8; KEY_SIZE]) -> Self {
+        Self::with_nonce(encode::Encoder::new(inner), key, &random_nonce())
+    }
+
+    /// Create an outboard encoder with a random nonce.
+    pub fn new_outboard(inner: T, key: &[u8; KEY_SIZE]) -> Self {
+        Self::with_nonce(encode::Encoder::new_outboard(inner), key, &random_nonce())
+    }
+
+    /// Wrap an `encode::Encoder`, with a nonce chosen by the caller. **Never use the same key and
+    /// nonce for two different inputs.** That reuses the chunk nonces, which reveals the XOR of
+    /// the plaintexts and lets an attacker forge tags. Prefer `new` unless you have a nonce
+    /// source that's guaranteed unique.
+    pub fn with_nonce(
+        inner: encode::Encoder<T>,
+        key: &[u8; KEY_SIZE],
+        nonce: &[u8; NONCE_SIZE],
+    ) -> Self {
+        Self {
+            inner,
+            cipher: Cipher::new(key, nonce),
+            chunk_index: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// The nonce this encoder encrypts with.
+    pub fn nonce(&self) -> &[u8; NONCE_SIZE] {
+        &self.cipher.nonce
+    }
+
+    /// Finish the encoding and return its `Seal`. See `encode::Encoder::finalize`.
+    pub fn finalize(&mut self) -> io::Result<Seal> {
+        // The buffered plaintext is the final chunk, even if it's empty.
+        self.cipher.seal(self.chunk_index, true, &mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        let hash = self.inner.finalize()?;
+        Ok(Seal {
+            nonce: self.cipher.nonce,
+            hash,
+        })
+    }
+
+    /// Return the underlying writer, after `finalize`. See `encode::Encoder::into_inner`.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
+    /// Give up on an unfinished encoding and return the underlying writer. See
+    /// `encode::Encoder::abort`.
+    pub fn abort(self) -> T {
+        self.inner.abort()
+    }
+}
+
+impl<T: Read + Write + Seek> Write for Encoder<T> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        if input.is_empty() {
+            return Ok(0);
+        }
+        // A full chunk stays buffered until more input shows that it isn't the final one.
+        if self.buf.len() == PLAINTEXT_CHUNK_SIZE {
+            self.cipher.seal(self.chunk_index, false, &mut self.buf);
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+            self.chunk_index += 1;
+        }
+        let take = cmp::min(input.len(), PLAINTEXT_CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&input[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Read + Write + Seek> fmt::Debug for Encoder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Leave out the key, the plaintext, and the hashes in the inner state.
+        write!(f, "Encoder {{ chunk_index: {} }}", self.chunk_index)
+    }
+}
+
+/// An incremental decoder that decrypts its output. It wraps `decode::Decoder`, so each chunk is
+/// verified against the root hash, and then authenticated and decrypted, before any of it is
+/// returned. It reads one byte past each chunk, to tell whether it's the final one. It supports
+/// seeking when the underlying reader does. The first seek authenticates the final chunk, which
+/// authenticates the length, the way `decode::Decoder` verifies it.
+pub struct Decoder<T: Read, O: Read> {
+    inner: decode::Decoder<T, O>,
+    cipher: Cipher,
+    position: u64,
+    // The plaintext length, once the final chunk is authenticated.
+    len: Option<u64>,
+    // The index of the next chunk to read from `inner`.
+    chunk_index: u64,
+    // Ciphertext read from `inner` past the last chunk.
+    pending: Vec<u8>,
+    // The plaintext of the last chunk, and how much of it has been returned.
+    buf: Vec<u8>,
+    buf_position: usize,
+    finished: bool,
+}
+
+impl<T: Read> Decoder<T, T> {
+    pub fn new(inner: T, seal: &Seal, key: &[u8; KEY_SIZE]) -> Self {
+        Self::from_decoder(decode::Decoder::new(inner, &seal.hash), seal, key)
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Read, O: Read> Decoder<T, O> {
+    /// Decode the ciphertext in `inner` with the outboard encoding in `outboard`.
+    pub fn new_outboard(inner: T, outboard: O, seal: &Seal, key: &[u8; KEY_SIZE]) -> Self {
+        Self::from_decoder(
+            decode::Decoder::new_outboard(inner, outboard, &seal.hash),
+            seal,
+            key,
+        )
+    }
+
+    /// Wrap a `decode::Decoder` that has already been configured, with `with_limits` for
+    /// example. The decoder must have been created with `seal.hash`, and it must not have read
+    /// anything yet.
+    pub fn from_decoder(inner: decode::Decoder<T, O>, seal: &Seal, key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            inner,
+            cipher: Cipher::new(key, &seal.nonce),
+            position: 0,
+            len: None,
+            chunk_index: 0,
+            pending: Vec::with_capacity(CHUNK_SIZE + 1),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            buf_position: 0,
+            finished: false,
+        }
+    }
+
+    // Read, authenticate, and decrypt the next chunk into `buf`.
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let want = CHUNK_SIZE + 1 - self.pending.len();
+        (&mut self.inner)
+            .take(want as u64)
+            .read_to_end(&mut self.pending)?;
+        let is_final = self.pending.len() <= CHUNK_SIZE;
+        let len = cmp::min(self.pending.len(), CHUNK_SIZE);
+        self.buf.clear();
+        self.buf.extend(self.pending.drain(..len));
+        self.buf_position = 0;
+        self.cipher
+            .open(self.chunk_index, is_final, &mut self.buf)?;
+        self.chunk_index += 1;
+        self.finished = is_final;
+        Ok(())
+    }
+}
+
+impl<T: Read, O: Read> Read for Decoder<T, O> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if self.buf_position == self.buf.len() {
+            if self.finished || output.is_empty() {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+        let available = &self.buf[self.buf_position..];
+        let n = cmp::min(output.len(), available.len());
+        output[..n].copy_from_slice(&available[..n]);
+        self.buf_position += n;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> Decoder<T, O> {
+    // Authenticate the final chunk, and return the plaintext length. This moves `inner`.
+    fn authenticated_len(&mut self) -> io::Result<u64> {
+        if let Some(len) = self.len {
+            return Ok(len);
+        }
+        let ciphertext_len = self.inner.seek(SeekFrom::End(0))?;
+        let len = plaintext_len(ciphertext_len)?;
+        self.chunk_index = encode::count_chunks(ciphertext_len) - 1;
+        self.inner
+            .seek(SeekFrom::Start(self.chunk_index * CHUNK_SIZE as u64))?;
+        self.pending.clear();
+        self.read_chunk()?;
+        self.len = Some(len);
+        Ok(len)
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.authenticated_len()?;
+        let position = match pos {
+            SeekFrom::Start(n) => n,
+            SeekFrom::End(n) => decode::add_offset(len, n)?,
+            SeekFrom::Current(n) => decode::add_offset(self.position, n)?,
+        };
+        self.position = position;
+        self.pending.clear();
+        self.buf.clear();
+        self.buf_position = 0;
+        // Past the end, there's nothing left to read, and the length is already authenticated.
+        if position >= len {
+            self.finished = true;
+            return Ok(position);
+        }
+        self.chunk_index = position / PLAINTEXT_CHUNK_SIZE as u64;
+        self.inner
+            .seek(SeekFrom::Start(self.chunk_index * CHUNK_SIZE as u64))?;
+        self.read_chunk()?;
+        self.buf_position = (position % PLAINTEXT_CHUNK_SIZE as u64) as usize;
+        Ok(position)
+    }
+}
+
+impl<T: Read, O: Read> fmt::Debug for Decoder<T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Leave out the key, the plaintext, and the hashes in the inner state.
+        write!(f, "Decoder {{ chunk_index: {} }}", self.chunk_index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use std::io::Cursor;
+
+    const KEY: [u8; KEY_SIZE] = [42; KEY_SIZE];
+    const NONCE: [u8; NONCE_SIZE] = [7; NONCE_SIZE];
+
+    fn encode_with_nonce(input: &[u8], nonce: &[u8; NONCE_SIZE]) -> (Vec<u8>, Seal) {
+        let inner = encode::Encoder::new(Cursor::new(Vec::new()));
+        let mut encoder = Encoder::with_nonce(inner, &KEY, nonce);
+        encoder.write_all(input).unwrap();
+        let seal = encoder.finalize().unwrap();
+        (encoder.into_inner().into_inner(), seal)
+    }
+
+    #[test]
+    fn test_known_answers() {
+        // Pin the format, so that it can't change without breaking this test. These are the root
+        // hashes of the ciphertext, which cover the chunk layout, the nonces, and the tags.
+        let cases = [
+            (
+                0,
+                "663e1827d2dd0580aea32f7b2cde5ae6cf9c055c4e7c1cb15c7f48398f896928",
+            ),
+            (
+                1,
+                "3d8b10839cf2e9162daca7cbe39cef7ecfd8ae34683ae17d7aba8c7ccad94b7d",
+            ),
+            (
+                1008,
+                "6c6dd7281039065c894466f26fe5a3632a3633f8ebc5bfd54b68789e8f2b4773",
+            ),
+            (
+                1009,
+                "f834ee97dfcc56a2d67e0c5de24f0182dfb521d6f84834ec1bb8997dd5f8e5e4",
+            ),
+            (
+                3524,
+                "f6db8542ef03301bdb26e0e74abc0deaf3b82b162247892b60f7abffa36152db",
+            ),
+        ];
+        for &(len, expected) in &cases {
+            let (_, seal) = encode_with_nonce(&make_test_input(len), &NONCE);
+            assert_eq!(expected, &*seal.hash.to_hex(), "len {}", len);
+        }
+    }
+
+    #[test]
+    fn test_chunk_nonces() {
+        // Each chunk is plain XChaCha20-Poly1305, with no associated data, under the nonce from
+        // the module docs.
+        let plaintext = make_test_input(2 * PLAINTEXT_CHUNK_SIZE + 10);
+        let ciphertext = encrypt(&plaintext, &KEY, &NONCE);
+        assert_eq!(
+            ciphertext_len(plaintext.len() as u64),
+            ciphertext.len() as u64
+        );
+        assert_eq!(2 * CHUNK_SIZE + 10 + TAG_SIZE, ciphertext.len());
+        let aead = XChaCha20Poly1305::new(&KEY.into());
+        for (index, chunk) in plaintext.chunks(PLAINTEXT_CHUNK_SIZE).enumerate() {
+            let mut nonce = [0; 24];
+            nonce[..15].copy_from_slice(&NONCE);
+            nonce[15..23].copy_from_slice(&(index as u64).to_le_bytes());
+            nonce[23] = (index == 2) as u8;
+            let mut expected = chunk.to_vec();
+            let tag = aead
+                .encrypt_in_place_detached(&nonce.into(), b"", &mut expected)
+                .unwrap();
+            expected.extend_from_slice(&tag);
+            let start = index * CHUNK_SIZE;
+            let end = cmp::min(start + CHUNK_SIZE, ciphertext.len());
+            assert_eq!(expected, ciphertext[start..end]);
+            assert_eq!(
+                expected,
+                encrypt_chunk(&KEY, &NONCE, index as u64, index == 2, chunk)
+            );
+            let decrypted = decrypt_chunk(&KEY, &NONCE, index as u64, index == 2, &expected);
+            assert_eq!(chunk, &decrypted.unwrap()[..]);
+        }
+        assert_eq!(plaintext, decrypt(&ciphertext, &KEY, &NONCE).unwrap());
+    }
+
+    #[test]
+    fn test_lens() {
+        for &len in &[0, 1, 1007, 1008, 1009, 2016, 2017, 100_000] {
+            let ciphertext_len = ciphertext_len(len);
+            assert_eq!(len, plaintext_len(ciphertext_len).unwrap());
+            assert_eq!(
+                encrypt(&make_test_input(len as usize), &KEY, &NONCE).len() as u64,
+                ciphertext_len
+            );
+        }
+        // A final chunk too short for a tag, or an empty one after other chunks, can't happen.
+        for &bad in &[0, 15, CHUNK_SIZE + 15, CHUNK_SIZE + 16] {
+            plaintext_len(bad as u64).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut cases = crate::test::TEST_CASES.to_vec();
+        cases.extend_from_slice(&[PLAINTEXT_CHUNK_SIZE, 3 * PLAINTEXT_CHUNK_SIZE]);
+        for case in cases {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, seal) = encode(&input, &KEY);
+            // The encoding is an ordinary encoding of the ciphertext.
+            let ciphertext = decode::decode(&encoded, &seal.hash).unwrap();
+            assert_eq!(ciphertext_len(case as u64), ciphertext.len() as u64);
+            assert_eq!(input, decrypt(&ciphertext, &KEY, &seal.nonce).unwrap());
+
+            assert_eq!(input, decode(&encoded, &seal, &KEY).unwrap());
+            assert_eq!(
+                (encoded.clone(), seal),
+                encode_with_nonce(&input, &seal.nonce)
+            );
+
+            let mut decoder = Decoder::new(&*encoded, &seal, &KEY);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+
+            let wrong_key = [0; KEY_SIZE];
+            let err = decode(&encoded, &seal, &wrong_key).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let mut decoder = Decoder::new(&*encoded, &seal, &wrong_key);
+            let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_random_nonces() {
+        let (encoded1, seal1) = encode(b"same input", &KEY);
+        let (encoded2, seal2) = encode(b"same input", &KEY);
+        assert_ne!(seal1.nonce, seal2.nonce);
+        assert_ne!(encoded1, encoded2);
+        let encoder = Encoder::new(Cursor::new(Vec::new()), &KEY);
+        assert_ne!(seal1.nonce, *encoder.nonce());
+    }
+
+    #[test]
+    fn test_seal_bytes() {
+        let (_, seal) = encode(b"foo", &KEY);
+        let bytes = seal.to_bytes();
+        assert_eq!(SEAL_SIZE, bytes.len());
+        assert_eq!(seal, Seal::from_bytes(&bytes));
+    }
+
+    #[test]
+    fn test_outboard() {
+        let input = make_test_input(10 * CHUNK_SIZE + 1);
+        let mut encoder = Encoder::new_outboard(Cursor::new(Vec::new()), &KEY);
+        encoder.write_all(&input).unwrap();
+        let seal = encoder.finalize().unwrap();
+        let outboard = encoder.into_inner().into_inner();
+        let ciphertext = encrypt(&input, &KEY, &seal.nonce);
+        let mut decoder = Decoder::new_outboard(
+            Cursor::new(&ciphertext),
+            Cursor::new(&outboard),
+            &seal,
+            &KEY,
+        );
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_seek() {
+        for &len in &[0, 1, PLAINTEXT_CHUNK_SIZE, 10 * PLAINTEXT_CHUNK_SIZE + 1] {
+            println!("len {}", len);
+            let input = make_test_input(len);
+            let (encoded, seal) = encode(&input, &KEY);
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &seal, &KEY);
+            let offsets = [
+                3 * PLAINTEXT_CHUNK_SIZE + 5,
+                0,
+                len.saturating_sub(1),
+                len,
+                len + 1,
+                PLAINTEXT_CHUNK_SIZE,
+            ];
+            for &offset in &offsets {
+                let position = decoder.seek(SeekFrom::Start(offset as u64)).unwrap();
+                assert_eq!(offset as u64, position);
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(&input[cmp::min(offset, len)..], &output[..]);
+            }
+            assert_eq!(len as u64, decoder.seek(SeekFrom::End(0)).unwrap());
+            if len > 10 {
+                decoder.seek(SeekFrom::End(-10)).unwrap();
+                let mut buf = [0; 4];
+                decoder.read_exact(&mut buf).unwrap();
+                let position = decoder.seek(SeekFrom::Current(-2)).unwrap();
+                assert_eq!(len as u64 - 8, position);
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(&input[len - 8..], &output[..]);
+            }
+            let err = decoder.seek(SeekFrom::End(-(len as i64) - 1)).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_corrupt_ciphertext() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (mut encoded, seal) = encode(&input, &KEY);
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        let err = decode(&encoded, &seal, &KEY).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_forged_ciphertext() {
+        // Without the key, changing the ciphertext and rehashing it gives a valid encoding, but
+        // its chunks don't authenticate.
+        let input = make_test_input(3 * PLAINTEXT_CHUNK_SIZE + 10);
+        let (encoded, seal) = encode(&input, &KEY);
+        let ciphertext = decode::decode(&encoded, &seal.hash).unwrap();
+        let forge = |ciphertext: &[u8]| {
+            let (forged, hash) = encode::encode(ciphertext);
+            let seal = Seal { hash, ..seal };
+            let err = decode(&forged, &seal, &KEY).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let mut decoder = Decoder::new(&*forged, &seal, &KEY);
+            let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        };
+
+        // Flipping a bit.
+        let mut flipped = ciphertext.clone();
+        flipped[100] ^= 1;
+        forge(&flipped);
+        // Swapping two chunks.
+        let mut swapped = ciphertext[CHUNK_SIZE..2 * CHUNK_SIZE].to_vec();
+        swapped.extend_from_slice(&ciphertext[..CHUNK_SIZE]);
+        swapped.extend_from_slice(&ciphertext[2 * CHUNK_SIZE..]);
+        forge(&swapped);
+        // Truncating at a chunk boundary, so that a middle chunk becomes the final one.
+        forge(&ciphertext[..2 * CHUNK_SIZE]);
+        // Moving the final chunk, so that it's no longer final.
+        let mut extended = ciphertext.clone();
+        extended.extend_from_slice(&ciphertext[2 * CHUNK_SIZE..]);
+        forge(&extended);
+        // Moving the whole thing to a different nonce.
+        let renonced = Seal {
+            nonce: [0; NONCE_SIZE],
+            ..seal
+        };
+        decode(&encoded, &renonced, &KEY).unwrap_err();
+    }
+}
@@ -35,34 +35,72 @@
 //! # }
 //! ```
 
+use crate::backend;
+use crate::backend::{BackendIo, ReadAt};
+use crate::config::Config;
 use crate::encode;
 use crate::encode::NextRead;
-use crate::{Finalization, Hash, CHUNK_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE};
+use crate::{
+    Finalization, Hash, Progress, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE,
+};
 use arrayref::array_ref;
 use arrayvec::ArrayVec;
 use std::cmp;
+use std::collections::{BTreeMap, HashMap};
 use std::error;
 use std::fmt;
 use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::ops::Range;
+use std::sync::{mpsc, Arc, PoisonError, RwLock};
 
 /// Decode an entire slice in the default combined mode into a bytes vector.
 /// This is a convenience wrapper around `Decoder`.
 pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
+    decode_with_limits(encoded, hash, &Limits::default())
+}
+
+/// Like `decode`, but applying `Limits` to the encoding.
+pub fn decode_with_limits(
+    encoded: impl AsRef<[u8]>,
+    hash: &Hash,
+    limits: &Limits,
+) -> io::Result<Vec<u8>> {
     let bytes = encoded.as_ref();
     if bytes.len() < HEADER_SIZE {
         return Err(Error::Truncated.into());
     }
-    let content_len = crate::decode_len(array_ref!(bytes, 0, HEADER_SIZE));
-    // Sanity check the length before making a potentially large allocation.
-    if (bytes.len() as u128) < encode::encoded_size(content_len) {
-        return Err(Error::Truncated.into());
+    let header = array_ref!(bytes, 0, HEADER_SIZE);
+    limits.check_header(header)?;
+    let content_len = crate::decode_len(header);
+    // Sanity check the length before making a potentially large allocation. An encoding too large
+    // to address on this target can't be in memory either, so that's truncation too, and it makes
+    // the `as usize` casts below lossless.
+    match encode::try_encoded_size(content_len) {
+        Some(size) if size <= bytes.len() => {}
+        _ => return Err(Error::Truncated.into()),
+    }
+    // A single chunk is the root, so one hash verifies it, without any decoder machinery.
+    if content_len <= CHUNK_SIZE as u64 {
+        let content = &bytes[HEADER_SIZE..][..content_len as usize];
+        // Hash implements constant time equality.
+        if blake3::hash(content) != *hash {
+            trace_event!(
+                warn,
+                encoded_offset = HEADER_SIZE as u128,
+                content_offset = 0u64,
+                "verification failed"
+            );
+            return Err(Error::HashMismatch.into());
+        }
+        trace_event!(trace, index = 0u64, "chunk verified");
+        return Ok(content.to_vec());
     }
     // There's no way to avoid zeroing this vector without unsafe code, because
     // Decoder::initializer is the default (safe) zeroing implementation anyway.
     let mut vec = vec![0; content_len as usize];
-    let mut reader = Decoder::new(bytes, hash);
+    let mut reader = Decoder::new(bytes, hash).with_limits(*limits);
     reader.read_exact(&mut vec)?;
     // One more read to confirm EOF. This is redundant in most cases, but in
     // the empty encoding case read_exact won't do any reads at all, and the Ok
@@ -94,6 +132,24 @@ impl VerifyState {
         }
     }
 
+    fn config(&self) -> &Config {
+        self.parser.config()
+    }
+
+    fn set_config(&mut self, config: Config) {
+        self.parser.set_config(config);
+    }
+
+    // The hash of the chunk that read_next asked for, to pass to feed_chunk.
+    fn chunk_hash(&self, index: u64, finalization: Finalization, chunk: &[u8]) -> Hash {
+        let content_len = self.parser.content_len().expect("chunk before header");
+        self.config().chunk_hash(
+            self.config().chunk_hasher(index).update(chunk),
+            finalization,
+            content_len,
+        )
+    }
+
     fn content_position(&self) -> u64 {
         self.parser.content_position()
     }
@@ -124,38 +180,74 @@ impl VerifyState {
         self.parser.len_next()
     }
 
+    fn next_nonfinal_subtree(&self) -> Option<(u64, u64)> {
+        self.parser.next_nonfinal_subtree()
+    }
+
     fn feed_header(&mut self, header: &[u8; HEADER_SIZE]) {
         self.parser.feed_header(header);
     }
 
-    fn feed_parent(&mut self, parent: &crate::ParentNode) -> Result<(), Error> {
+    fn encoding_position(&self) -> u128 {
+        self.parser.encoding_position()
+    }
+
+    fn chunk_padding(&self) -> usize {
+        self.parser.chunk_padding()
+    }
+
+    // Returns the verified children, for callers that cache them. With a shorter digest length,
+    // the parent node bytes are at the front of the array.
+    fn feed_parent(&mut self, parent: &crate::ParentNode) -> Result<(Hash, Hash), Error> {
         let finalization = self.parser.finalization();
         let expected_hash: &Hash = self.stack.last().expect("unexpectedly empty stack");
-        let left_child: Hash = (*array_ref!(parent, 0, 32)).into();
-        let right_child: Hash = (*array_ref!(parent, 32, 32)).into();
-        let computed_hash: Hash =
-            blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+        let (left_child, right_child) = self.config().parent_children(parent);
+        let content_len = self.parser.content_len().expect("parent before header");
+        let computed_hash =
+            self.config()
+                .parent_hash(&left_child, &right_child, finalization, content_len);
         // Hash implements constant time equality.
         if expected_hash != &computed_hash {
+            self.report_failure();
             return Err(Error::HashMismatch);
         }
+        self.feed_verified_parent(&left_child, &right_child);
+        Ok((left_child, right_child))
+    }
+
+    // The caller must have verified these children against this same tree, at this same position.
+    fn feed_verified_parent(&mut self, left_child: &Hash, right_child: &Hash) {
         self.stack.pop();
-        self.stack.push(right_child.into());
-        self.stack.push(left_child.into());
+        self.stack.push(*right_child);
+        self.stack.push(*left_child);
         self.parser.advance_parent();
-        Ok(())
     }
 
     fn feed_chunk(&mut self, chunk_hash: &Hash) -> Result<(), Error> {
         let expected_hash = self.stack.last().expect("unexpectedly empty stack");
         // Hash implements constant time equality.
         if chunk_hash != expected_hash {
+            self.report_failure();
             return Err(Error::HashMismatch);
         }
+        trace_event!(
+            trace,
+            index = self.parser.content_position() / CHUNK_SIZE as u64,
+            "chunk verified"
+        );
         self.stack.pop();
         self.parser.advance_chunk();
         Ok(())
     }
+
+    fn report_failure(&self) {
+        trace_event!(
+            warn,
+            encoded_offset = self.parser.encoding_position(),
+            content_offset = self.parser.content_position(),
+            "verification failed"
+        );
+    }
 }
 
 // It's important to manually implement Debug for VerifyState, because it holds hashes that
@@ -176,11 +268,20 @@ impl fmt::Debug for VerifyState {
 /// Two errors are possible when decoding, apart from the usual IO issues: the content bytes might
 /// not have the right hash, or the encoding might not be as long as it's supposed to be. In
 /// `std::io::Read` interfaces where we have to return `std::io::Error`, these variants are
-/// converted to `ErrorKind::InvalidData` and `ErrorKind::UnexpectedEof` respectively.
+/// converted to `ErrorKind::InvalidData` and `ErrorKind::UnexpectedEof` respectively. The other
+/// errors, `TooLong` and `TooDeep`, only happen when the caller sets `Limits`, and
+/// `NonzeroPadding` only happens with a `Config` that sets an alignment. They're also converted to
+/// `ErrorKind::InvalidData`.
+///
+/// More variants may be added in minor releases, as new options add new ways to fail.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Error {
     HashMismatch,
     Truncated,
+    TooLong,
+    TooDeep,
+    NonzeroPadding,
 }
 
 impl fmt::Display for Error {
@@ -188,6 +289,9 @@ impl fmt::Display for Error {
         match *self {
             Error::HashMismatch => write!(f, "hash mismatch"),
             Error::Truncated => write!(f, "truncated encoding"),
+            Error::TooLong => write!(f, "length header exceeds the limit"),
+            Error::TooDeep => write!(f, "tree depth exceeds the limit"),
+            Error::NonzeroPadding => write!(f, "nonzero alignment padding"),
         }
     }
 }
@@ -199,10 +303,345 @@ impl From<Error> for io::Error {
         match e {
             Error::HashMismatch => io::Error::new(io::ErrorKind::InvalidData, "hash mismatch"),
             Error::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, "truncated encoding"),
+            Error::TooLong => io::Error::new(
+                io::ErrorKind::InvalidData,
+                "length header exceeds the limit",
+            ),
+            Error::TooDeep => {
+                io::Error::new(io::ErrorKind::InvalidData, "tree depth exceeds the limit")
+            }
+            Error::NonzeroPadding => {
+                io::Error::new(io::ErrorKind::InvalidData, "nonzero alignment padding")
+            }
+        }
+    }
+}
+
+/// Resource limits for decoding untrusted encodings.
+///
+/// An encoding's length header decides how big the tree is, and it isn't verified until the
+/// final chunk. Services that decode encodings from untrusted sources can set limits to bound
+/// the work and memory a hostile header can ask for, and the decoders check the header against
+/// them before doing anything else. `Limits::default()` doesn't limit anything. Every decoding
+/// entry point takes limits: `with_limits` on `Decoder`, `SliceDecoder`, `ParallelDecoder`, and
+/// `DecoderState`, a `_with_limits` variant of each function like `decode_with_limits`,
+/// `verify_with_limits`, and `audit_with_limits`, and `open_with_limits` or `new_with_limits` on
+/// `SharedReader` and on the readers in the other modules, like `file::VerifiedFile`,
+/// `verified::VerifiedBytes`, and `compress::CompressedFile`. Calling `with_limits` more than
+/// once, or along with `with_max_len`, keeps the stricter value of each limit.
+///
+/// Apart from the limits here, a decoder's memory use doesn't depend on the encoding. The
+/// verification stack is a fixed array of `MAX_DEPTH` hashes, and `Decoder` and `SliceDecoder`
+/// buffer a single chunk. The caller controls everything else, like the size of the parent
+/// cache from `Decoder::with_parent_cache`.
+///
+/// # Example
+///
+/// ```
+/// use bao::decode::{Decoder, Error, Limits};
+/// use std::io::prelude::*;
+///
+/// let (encoded, hash) = bao::encode::encode(vec![0; 100_000]);
+/// let limits = Limits {
+///     max_content_len: 50_000,
+///     ..Limits::default()
+/// };
+/// let mut decoder = Decoder::new(&encoded[..], &hash).with_limits(limits);
+/// let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+/// assert_eq!(Error::TooLong.to_string(), err.to_string());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Limits {
+    /// The largest content length the length header can claim, or else `Error::TooLong`.
+    pub max_content_len: u64,
+    /// The most levels of parent nodes the tree can have, or else `Error::TooDeep`. A tree of
+    /// `2^n` chunks has a depth of `n`, so this is another way to limit the content length, in
+    /// terms of how many parent nodes a decoder verifies on the way to each chunk.
+    pub max_depth: usize,
+    /// The most memory `ParallelDecoder` uses to buffer encoded subtrees and their content at
+    /// once. It makes its batches smaller to fit, but it always needs room for at least one
+    /// chunk and its copy, `2 * CHUNK_SIZE`. The other decoders buffer a single chunk regardless.
+    pub chunk_buffer: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_content_len: u64::MAX,
+            max_depth: MAX_DEPTH,
+            chunk_buffer: usize::MAX,
+        }
+    }
+}
+
+impl Limits {
+    // The length header isn't verified until we get to the final chunk, so this is only a check
+    // on what the header claims. But that's the number we'd allocate or loop on.
+    pub(crate) fn check_header(&self, header: &[u8; HEADER_SIZE]) -> Result<(), Error> {
+        self.check_len(crate::decode_len(header))
+    }
+
+    // The stricter of each limit in two sets, which is how the decoders' with_limits and
+    // with_max_len compose.
+    pub(crate) fn stricter(&self, other: &Limits) -> Limits {
+        Limits {
+            max_content_len: cmp::min(self.max_content_len, other.max_content_len),
+            max_depth: cmp::min(self.max_depth, other.max_depth),
+            chunk_buffer: cmp::min(self.chunk_buffer, other.chunk_buffer),
+        }
+    }
+
+    // The same check, for callers that have already parsed the header.
+    pub(crate) fn check_len(&self, content_len: u64) -> Result<(), Error> {
+        if content_len > self.max_content_len {
+            return Err(Error::TooLong);
+        }
+        if tree_depth(content_len) > self.max_depth {
+            return Err(Error::TooDeep);
+        }
+        Ok(())
+    }
+}
+
+// The number of levels of parent nodes above the deepest chunk.
+fn tree_depth(content_len: u64) -> usize {
+    let chunks = encode::count_chunks(content_len);
+    (u64::BITS - (chunks - 1).leading_zeros()) as usize
+}
+
+/// What a `DecoderState` needs next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeNext {
+    /// Feed `len` bytes from `offset` in the encoding. In outboard mode, this is the outboard
+    /// encoding, and these bytes are the length header or a parent node.
+    NeedEncoded { offset: u128, len: usize },
+    /// Outboard mode only: feed `len` bytes of content from `offset`, a chunk.
+    NeedContent { offset: u64, len: usize },
+    /// All the content is verified.
+    Done,
+}
+
+/// The verification logic of a `Decoder`, without any IO.
+///
+/// `next` says which bytes of the encoding the state needs, and `feed` takes exactly those
+/// bytes and returns any content they verified. The state never blocks, so any IO model can
+/// drive it, including async code, C FFI, and wasm. It reads the encoding from front to back,
+/// so the offsets in `NeedEncoded` are always increasing, and a caller streaming the encoding
+/// in order can ignore them.
+///
+/// Like the `Decoder`, the `DecoderState` checks each chunk against the tree before returning
+/// it, and it doesn't return `Done` until it has verified the final chunk, which is what
+/// verifies the length header.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use bao::decode::{DecodeNext, DecoderState};
+///
+/// let input = vec![0xab; 5000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+///
+/// let mut state = DecoderState::new(&hash);
+/// let mut content = Vec::new();
+/// loop {
+///     match state.next() {
+///         DecodeNext::NeedEncoded { offset, len } => {
+///             let bytes = &encoded[offset as usize..][..len];
+///             content.extend_from_slice(state.feed(bytes)?);
+///         }
+///         DecodeNext::NeedContent { .. } => unreachable!("not outboard"),
+///         DecodeNext::Done => break,
+///     }
+/// }
+/// assert_eq!(input, content);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct DecoderState {
+    verify: VerifyState,
+    outboard: bool,
+    limits: Limits,
+}
+
+impl DecoderState {
+    /// A state for the combined encoding.
+    pub fn new(hash: &Hash) -> Self {
+        Self {
+            verify: VerifyState::new(hash),
+            outboard: false,
+            limits: Limits::default(),
+        }
+    }
+
+    /// A state for the outboard encoding. It asks for content bytes with `NeedContent` and for
+    /// everything else with `NeedEncoded`.
+    pub fn new_outboard(hash: &Hash) -> Self {
+        let mut state = Self::new(hash);
+        state.outboard = true;
+        state
+    }
+
+    /// Apply `Limits` to the length header. See `Decoder::with_limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = self.limits.stricter(&limits);
+        self
+    }
+
+    /// Verify the encoding with a profile from the `config` module. See `Decoder::with_config`.
+    /// With an alignment, the `NeedEncoded` for each chunk of a combined encoding includes the
+    /// padding before it, and `feed` returns the chunk without it.
+    pub fn with_config(mut self, config: Config) -> Self {
+        // Outboard encodings have no chunks to align.
+        if self.outboard {
+            self.verify.set_config(config.with_alignment(1));
+        } else {
+            self.verify.set_config(config);
+        }
+        self
+    }
+
+    /// The number of content bytes verified so far.
+    pub fn content_position(&self) -> u64 {
+        self.verify.content_position()
+    }
+
+    /// The bytes the state needs next.
+    pub fn next(&self) -> DecodeNext {
+        let position = self.verify.encoding_position();
+        // Sequential decoding never seeks, so the chunks before this position are exactly the
+        // content before it.
+        let outboard_position = position - self.content_position() as u128;
+        let encoded = |len| DecodeNext::NeedEncoded {
+            offset: if self.outboard {
+                outboard_position
+            } else {
+                position
+            },
+            len,
+        };
+        match self.verify.read_next() {
+            NextRead::Header => DecodeNext::NeedEncoded {
+                offset: 0,
+                len: HEADER_SIZE,
+            },
+            NextRead::Parent => encoded(self.verify.config().parent_size()),
+            NextRead::Chunk { size, .. } if self.outboard => DecodeNext::NeedContent {
+                offset: self.content_position(),
+                len: size,
+            },
+            NextRead::Chunk { size, .. } => encoded(self.verify.chunk_padding() + size),
+            NextRead::Done => DecodeNext::Done,
+        }
+    }
+
+    /// Feed the bytes that `next` asked for, and return the content they verified, if any. Only
+    /// chunks return content. If this returns an error, the state doesn't advance, and it asks
+    /// for the same bytes again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` isn't exactly the length that `next` asked for, or after `Done`.
+    pub fn feed<'a>(&mut self, bytes: &'a [u8]) -> Result<&'a [u8], Error> {
+        match self.verify.read_next() {
+            NextRead::Header => {
+                assert_eq!(HEADER_SIZE, bytes.len(), "wrong length");
+                let header = array_ref!(bytes, 0, HEADER_SIZE);
+                self.limits.check_header(header)?;
+                self.verify.feed_header(header);
+                Ok(&[])
+            }
+            NextRead::Parent => {
+                assert_eq!(
+                    self.verify.config().parent_size(),
+                    bytes.len(),
+                    "wrong length"
+                );
+                let mut parent = [0; PARENT_SIZE];
+                parent[..bytes.len()].copy_from_slice(bytes);
+                self.verify.feed_parent(&parent)?;
+                Ok(&[])
+            }
+            NextRead::Chunk {
+                size,
+                finalization,
+                skip,
+                index,
+            } => {
+                let padding = self.verify.chunk_padding();
+                assert_eq!(padding + size, bytes.len(), "wrong length");
+                debug_assert_eq!(0, skip, "sequential decoding doesn't seek");
+                let (padding, chunk) = bytes.split_at(padding);
+                if padding.iter().any(|&b| b != 0) {
+                    return Err(Error::NonzeroPadding);
+                }
+                let hash = self.verify.chunk_hash(index, finalization, chunk);
+                self.verify.feed_chunk(&hash)?;
+                Ok(chunk)
+            }
+            NextRead::Done => panic!("already done"),
         }
     }
 }
 
+// A least-recently-used cache of verified parent nodes, keyed by their position in the combined
+// encoding. Positions identify nodes uniquely, including the root, and the cache belongs to a
+// single decoder, so a hit is as good as verifying the node again.
+#[derive(Clone)]
+struct ParentCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<u128, (Hash, Hash, u64)>,
+    by_age: BTreeMap<u64, u128>,
+}
+
+impl ParentCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            by_age: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, position: u128) -> Option<(Hash, Hash)> {
+        let entry = self.entries.get_mut(&position)?;
+        self.by_age.remove(&entry.2);
+        entry.2 = self.tick;
+        self.by_age.insert(self.tick, position);
+        self.tick += 1;
+        Some((entry.0, entry.1))
+    }
+
+    fn insert(&mut self, position: u128, left_child: Hash, right_child: Hash) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(old) = self
+            .entries
+            .insert(position, (left_child, right_child, self.tick))
+        {
+            self.by_age.remove(&old.2);
+        } else if self.entries.len() > self.capacity {
+            let (_, oldest) = self.by_age.pop_first().expect("nonempty");
+            self.entries.remove(&oldest);
+        }
+        self.by_age.insert(self.tick, position);
+        self.tick += 1;
+    }
+}
+
+// The extra state a Decoder keeps in tolerant mode.
+#[derive(Clone, Debug)]
+struct Tolerance {
+    filler: u8,
+    filler_remaining: u64,
+    corrupted: Vec<Range<u64>>,
+}
+
 // Shared between Decoder and SliceDecoder.
 #[derive(Clone)]
 struct DecoderShared<T: Read, O: Read> {
@@ -212,6 +651,10 @@ struct DecoderShared<T: Read, O: Read> {
     buf: [u8; CHUNK_SIZE],
     buf_start: usize,
     buf_end: usize,
+    tolerance: Option<Tolerance>,
+    limits: Limits,
+    parent_cache: Option<ParentCache>,
+    progress: Option<Progress>,
 }
 
 impl<T: Read, O: Read> DecoderShared<T, O> {
@@ -223,13 +666,22 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
             buf: [0; CHUNK_SIZE],
             buf_start: 0,
             buf_end: 0,
+            tolerance: None,
+            limits: Limits::default(),
+            parent_cache: None,
+            progress: None,
         }
     }
 
     fn adjusted_content_position(&self) -> u64 {
         // If the current buffer_len is non-empty, then it contains the bytes
-        // immediately prior to the next read.
-        self.state.content_position() - self.buf_len() as u64
+        // immediately prior to the next read. Likewise for any filler bytes
+        // we still owe the caller in tolerant mode.
+        self.state.content_position() - self.buf_len() as u64 - self.filler_len()
+    }
+
+    fn filler_len(&self) -> u64 {
+        self.tolerance.as_ref().map_or(0, |t| t.filler_remaining)
     }
 
     fn buf_len(&self) -> usize {
@@ -239,6 +691,9 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
     fn clear_buf(&mut self) {
         self.buf_start = 0;
         self.buf_end = 0;
+        if let Some(tolerance) = &mut self.tolerance {
+            tolerance.filler_remaining = 0;
+        }
     }
 
     // These bytes are always verified before going in the buffer.
@@ -249,6 +704,62 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
         take
     }
 
+    fn take_filler_bytes(&mut self, output: &mut [u8]) -> usize {
+        let tolerance = self.tolerance.as_mut().expect("filler without tolerance");
+        let take = cmp::min(tolerance.filler_remaining, output.len() as u64) as usize;
+        for byte in &mut output[..take] {
+            *byte = tolerance.filler;
+        }
+        tolerance.filler_remaining -= take as u64;
+        take
+    }
+
+    // In tolerant mode, give up on the subtree under the parent or chunk that just failed
+    // verification, and owe the caller filler bytes in place of its content. Otherwise, or if
+    // that subtree contains the final chunk, return the error. `parent_read` indicates that
+    // only the subtree's parent node has been read so far, and the rest of its encoding needs
+    // to be read and thrown away.
+    fn skip_corrupt_subtree(&mut self, error: Error, parent_read: bool) -> io::Result<()> {
+        if self.tolerance.is_none() {
+            return Err(error.into());
+        }
+        let (subtree_start, subtree_len) = match self.state.next_nonfinal_subtree() {
+            Some(subtree) => subtree,
+            None => return Err(error.into()),
+        };
+        if parent_read {
+            let config = *self.state.config();
+            let parent_size = config.parent_size() as u128;
+            if let Some(outboard) = &mut self.outboard {
+                let outboard_len = config.outboard_subtree_size(subtree_len) - parent_size;
+                discard(outboard, outboard_len)?;
+                discard(&mut self.input, subtree_len as u128)?;
+            } else {
+                // The parent node didn't advance the state, so the position is still the start
+                // of the subtree.
+                let position = self.state.encoding_position();
+                let encoded_len = config.subtree_end(position, subtree_len) - position;
+                discard(&mut self.input, encoded_len - parent_size)?;
+            }
+        }
+        // We've already consumed the subtree's encoding, so we don't need to execute the
+        // underlying seek. This is the same trick the SliceDecoder uses.
+        let skip_start = self.state.content_position();
+        let skip_end = subtree_start + subtree_len;
+        let bookkeeping = self.state.seek_next(skip_end);
+        self.state.seek_bookkeeping_done(bookkeeping);
+        debug_assert_eq!(skip_end, self.state.content_position());
+        let tolerance = self.tolerance.as_mut().unwrap();
+        tolerance.filler_remaining = skip_end - skip_start;
+        match tolerance.corrupted.last_mut() {
+            Some(last) if last.start <= skip_start && skip_start <= last.end => {
+                last.end = cmp::max(last.end, skip_end);
+            }
+            _ => tolerance.corrupted.push(skip_start..skip_end),
+        }
+        Ok(())
+    }
+
     fn get_and_feed_header(&mut self) -> io::Result<()> {
         debug_assert_eq!(0, self.buf_len());
         let mut header = [0; HEADER_SIZE];
@@ -257,6 +768,7 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
         } else {
             self.input.read_exact(&mut header)?;
         }
+        self.limits.check_header(&header)?;
         self.state.feed_header(&header);
         Ok(())
     }
@@ -264,17 +776,39 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
     fn get_parent(&mut self) -> io::Result<crate::ParentNode> {
         debug_assert_eq!(0, self.buf_len());
         let mut parent = [0; PARENT_SIZE];
+        let parent_bytes = &mut parent[..self.state.config().parent_size()];
         if let Some(outboard) = &mut self.outboard {
-            outboard.read_exact(&mut parent)?;
+            outboard.read_exact(parent_bytes)?;
         } else {
-            self.input.read_exact(&mut parent)?;
+            self.input.read_exact(parent_bytes)?;
         }
         Ok(parent)
     }
 
     fn get_and_feed_parent(&mut self) -> io::Result<()> {
+        let position = self.state.encoding_position();
         let parent = self.get_parent()?;
-        self.state.feed_parent(&parent)?;
+        let (left_child, right_child) = self.state.feed_parent(&parent)?;
+        if let Some(cache) = &mut self.parent_cache {
+            cache.insert(position, left_child, right_child);
+        }
+        Ok(())
+    }
+
+    // Read the alignment padding before the next chunk, if any, and check that it's zero. No hash
+    // covers the padding, so without this check the same content would have many encodings.
+    fn read_padding(&mut self) -> io::Result<()> {
+        debug_assert_eq!(0, self.buf_len());
+        let mut padding = self.state.chunk_padding();
+        while padding > 0 {
+            let take = cmp::min(padding, CHUNK_SIZE);
+            let bytes = &mut self.buf[..take];
+            self.input.read_exact(bytes)?;
+            if bytes.iter().any(|&b| b != 0) {
+                return Err(Error::NonzeroPadding.into());
+            }
+            padding -= take;
+        }
         Ok(())
     }
 
@@ -295,24 +829,27 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
             // approach optimizes parent reads better.
             self.get_and_feed_parent()?;
         }
+        self.read_padding()?;
         let buf_slice = &mut self.buf[..size];
         self.input.read_exact(buf_slice)?;
-        let hash = blake3::guts::ChunkState::new(index)
-            .update(buf_slice)
-            .finalize(finalization.is_root());
+        let hash = self.state.chunk_hash(index, finalization, buf_slice);
         self.state.feed_chunk(&hash)?;
+        Progress::report(&self.progress, self.state.content_position());
         self.buf_start = skip;
         self.buf_end = size;
         Ok(())
     }
 
     fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
-        // Explicitly short-circuit zero-length reads. We're within our rights
-        // to buffer an internal chunk in this case, or to make progress if
-        // there's an empty chunk, but this matches the current behavior of
-        // SliceExtractor for zero-length slices. This might change in the
-        // future.
+        // A zero-length read still verifies the next chunk, if nothing is
+        // buffered, and holds onto it for the next read. This matches
+        // SliceExtractor, which includes a chunk in zero-length slices. In
+        // particular, a zero-length read of the empty encoding verifies the
+        // root, so Ok(0) never means that the hash hasn't been checked.
         if output.is_empty() {
+            if self.buf_len() == 0 && self.filler_len() == 0 {
+                self.buffer_next_chunk()?;
+            }
             return Ok(0);
         }
 
@@ -321,6 +858,11 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
             return Ok(self.take_buffered_bytes(output));
         }
 
+        // Likewise if we owe the caller filler bytes for a corrupt subtree.
+        if self.filler_len() > 0 {
+            return Ok(self.take_filler_bytes(output));
+        }
+
         // Otherwise try to verify a new chunk.
         loop {
             match self.state.read_next() {
@@ -333,7 +875,19 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
                     self.get_and_feed_header()?;
                 }
                 NextRead::Parent => {
-                    self.get_and_feed_parent()?;
+                    let position = self.state.encoding_position();
+                    let parent = self.get_parent()?;
+                    match self.state.feed_parent(&parent) {
+                        Ok((left_child, right_child)) => {
+                            if let Some(cache) = &mut self.parent_cache {
+                                cache.insert(position, left_child, right_child);
+                            }
+                        }
+                        Err(e) => {
+                            self.skip_corrupt_subtree(e, true)?;
+                            return Ok(self.take_filler_bytes(output));
+                        }
+                    }
                 }
                 NextRead::Chunk {
                     size,
@@ -342,6 +896,7 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
                     index,
                 } => {
                     debug_assert_eq!(self.buf_len(), 0);
+                    self.read_padding()?;
 
                     // If we can, read the chunk directly into the `output`
                     // buffer, to avoid extra copies. If there's a verification
@@ -359,10 +914,14 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
                     // Hash it and push its hash into the VerifyState. This
                     // returns an error if the hash is bad. Otherwise, the
                     // chunk is verifiied.
-                    let chunk_hash = blake3::guts::ChunkState::new(index)
-                        .update(read_buf)
-                        .finalize(finalization.is_root());
-                    self.state.feed_chunk(&chunk_hash)?;
+                    let chunk_hash = self.state.chunk_hash(index, finalization, read_buf);
+                    if let Err(e) = self.state.feed_chunk(&chunk_hash) {
+                        // In tolerant mode, overwrite any invalid bytes we
+                        // read directly into the output with filler.
+                        self.skip_corrupt_subtree(e, false)?;
+                        return Ok(self.take_filler_bytes(output));
+                    }
+                    Progress::report(&self.progress, self.state.content_position());
 
                     // If the output buffer was large enough for direct output,
                     // we're done. Otherwise, we need to update the internal
@@ -379,6 +938,123 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
         }
     }
 
+    // Like read(), but always reads into the internal buffer, and never past the end of the
+    // current chunk. Usually that means verifying the next chunk, but in tolerant mode it might
+    // mean filling the buffer with filler bytes instead. The buffer is left empty at EOF.
+    fn buffer_next_chunk(&mut self) -> io::Result<()> {
+        debug_assert_eq!(0, self.buf_len());
+        loop {
+            if let Some(tolerance) = &mut self.tolerance {
+                if tolerance.filler_remaining > 0 {
+                    // Corrupt subtrees always end on a chunk boundary.
+                    let position = self.state.content_position() - tolerance.filler_remaining;
+                    let skip = (position % CHUNK_SIZE as u64) as usize;
+                    let take = cmp::min(tolerance.filler_remaining, (CHUNK_SIZE - skip) as u64);
+                    for byte in &mut self.buf[..take as usize] {
+                        *byte = tolerance.filler;
+                    }
+                    tolerance.filler_remaining -= take;
+                    self.buf_start = 0;
+                    self.buf_end = take as usize;
+                    return Ok(());
+                }
+            }
+            match self.state.read_next() {
+                NextRead::Done => return Ok(()),
+                NextRead::Header => self.get_and_feed_header()?,
+                NextRead::Parent => {
+                    let parent = self.get_parent()?;
+                    if let Err(e) = self.state.feed_parent(&parent) {
+                        self.skip_corrupt_subtree(e, true)?;
+                    }
+                }
+                NextRead::Chunk {
+                    size,
+                    finalization,
+                    skip,
+                    index,
+                } => {
+                    self.read_padding()?;
+                    self.input.read_exact(&mut self.buf[..size])?;
+                    let chunk_hash = self
+                        .state
+                        .chunk_hash(index, finalization, &self.buf[..size]);
+                    match self.state.feed_chunk(&chunk_hash) {
+                        Ok(()) => {
+                            Progress::report(&self.progress, self.state.content_position());
+                            self.buf_start = skip;
+                            self.buf_end = size;
+                            return Ok(());
+                        }
+                        Err(e) => self.skip_corrupt_subtree(e, false)?,
+                    }
+                }
+            }
+        }
+    }
+
+    // Skip forward without seeking the underlying readers. Whole subtrees inside the skipped range
+    // get read and thrown away without hashing them. Their parent nodes already vouch for their
+    // hashes, and we never return their bytes. The subtrees on the right edge of the tree still get
+    // verified, since the final chunk is what verifies the length header. Returns the number of
+    // bytes skipped, which is short only at EOF.
+    fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let mut remaining = n;
+        loop {
+            let take = cmp::min(self.buf_len() as u64, remaining);
+            self.buf_start += take as usize;
+            remaining -= take;
+            if let Some(tolerance) = &mut self.tolerance {
+                let take = cmp::min(tolerance.filler_remaining, remaining);
+                tolerance.filler_remaining -= take;
+                remaining -= take;
+            }
+            if remaining == 0 {
+                return Ok(n);
+            }
+            debug_assert_eq!(0, self.buf_len());
+            let next = self.state.read_next();
+            match next {
+                NextRead::Done => return Ok(n - remaining),
+                NextRead::Header => {
+                    self.get_and_feed_header()?;
+                    continue;
+                }
+                NextRead::Parent | NextRead::Chunk { .. } => {}
+            }
+            let position = self.state.content_position();
+            match self.state.next_nonfinal_subtree() {
+                Some((start, len)) if start == position && len <= remaining => {
+                    let config = *self.state.config();
+                    if let Some(outboard) = &mut self.outboard {
+                        discard(outboard, config.outboard_subtree_size(len))?;
+                        discard(&mut self.input, len as u128)?;
+                    } else {
+                        let position = self.state.encoding_position();
+                        discard(
+                            &mut self.input,
+                            config.subtree_end(position, len) - position,
+                        )?;
+                    }
+                    let bookkeeping = self.state.seek_next(start + len);
+                    self.state.seek_bookkeeping_done(bookkeeping);
+                    remaining -= len;
+                }
+                // Otherwise descend one parent node, or verify the next chunk and skip what we
+                // need from the buffer at the top of the loop.
+                _ => match next {
+                    NextRead::Parent => {
+                        let parent = self.get_parent()?;
+                        if let Err(e) = self.state.feed_parent(&parent) {
+                            self.skip_corrupt_subtree(e, true)?;
+                        }
+                    }
+                    _ => self.buffer_next_chunk()?,
+                },
+            }
+        }
+    }
+
     // Returns Ok(true) to indicate the seek is finished. Note that both the
     // Decoder and the SliceDecoder will use this method (which doesn't depend on
     // io::Seek), but only the Decoder will call handle_seek_bookkeeping first.
@@ -432,13 +1108,37 @@ impl<T: Read + Seek, O: Read + Seek> DecoderShared<T, O> {
             }
         } else {
             if let Some(encoding_position) = bookkeeping.underlying_seek() {
-                let position_u64: u64 = encode::cast_offset(encoding_position)?;
+                let position_u64: u64 = encode::EncodedOffset::new(encoding_position).to_u64()?;
                 self.input.seek(SeekFrom::Start(position_u64))?;
             }
         }
         let next = self.state.seek_bookkeeping_done(bookkeeping);
         Ok(next)
     }
+
+    // Like handle_seek_read, but parents in the cache skip the underlying read and the hashing.
+    // Seeking is where a random access workload rereads the same parents, because leftward seeks
+    // start over from the root.
+    fn handle_seek_read_cached(&mut self, next: NextRead) -> io::Result<bool> {
+        if let NextRead::Parent = next {
+            let position = self.state.encoding_position();
+            let cached = self
+                .parent_cache
+                .as_mut()
+                .and_then(|cache| cache.get(position));
+            if let Some((left_child, right_child)) = cached {
+                let parent_size = self.state.config().parent_size() as i64;
+                if let Some(outboard) = &mut self.outboard {
+                    outboard.seek(SeekFrom::Current(parent_size))?;
+                } else {
+                    self.input.seek(SeekFrom::Current(parent_size))?;
+                }
+                self.state.feed_verified_parent(&left_child, &right_child);
+                return Ok(false);
+            }
+        }
+        self.handle_seek_read(next)
+    }
 }
 
 impl<T: Read, O: Read> fmt::Debug for DecoderShared<T, O> {
@@ -464,6 +1164,11 @@ impl<T: Read, O: Read> fmt::Debug for DecoderShared<T, O> {
 /// [`std::io::Seek`](https://doc.rust-lang.org/std/io/trait.Seek.html) if the
 /// underlying reader does, but it's also compatible with non-seekable readers.
 ///
+/// `Decoder` never reports EOF before it has verified the final chunk against the root hash,
+/// and that includes empty content, where the only thing to verify is the root itself. A read
+/// into an empty buffer verifies the next chunk too, so the first read on the encoding of empty
+/// content, of any length, returns an error if the hash doesn't match.
+///
 /// # Example
 ///
 /// ```
@@ -508,12 +1213,198 @@ impl<T: Read> Decoder<T, T> {
     }
 }
 
+impl<B: backend::Backend> Decoder<BackendIo<B>, BackendIo<B>> {
+    /// Create a `Decoder` that reads a combined encoding from a `Backend`, like a memory map, a
+    /// block device, or an `AsyncGetter` over an object store. Seeking only moves a cursor, and
+    /// every read is a positional `get_at`.
+    pub fn from_backend(backend: B, hash: &Hash) -> Self {
+        Self::new(BackendIo::new(backend), hash)
+    }
+}
+
+impl<C: backend::Backend, B: backend::Backend> Decoder<BackendIo<C>, BackendIo<B>> {
+    /// Like `from_backend`, but for content and its outboard encoding.
+    pub fn from_backend_outboard(content: C, outboard: B, hash: &Hash) -> Self {
+        Self::new_outboard(BackendIo::new(content), BackendIo::new(outboard), hash)
+    }
+}
+
 impl<T: Read, O: Read> Decoder<T, O> {
     pub fn new_outboard(inner: T, outboard: O, hash: &Hash) -> Self {
         Self {
             shared: DecoderShared::new(inner, Some(outboard), hash),
         }
     }
+
+    /// Limit the content length this decoder will accept. If the encoding's length header claims
+    /// more than `max_len` bytes, reading returns an `InvalidData` error (`Error::TooLong`) before
+    /// anything else happens. Services that decode untrusted encodings can use this to cap how
+    /// much work an encoding can ask for.
+    ///
+    /// This is shorthand for `with_limits` with only `Limits::max_content_len` set.
+    pub fn with_max_len(self, max_len: u64) -> Self {
+        self.with_limits(Limits {
+            max_content_len: max_len,
+            ..Limits::default()
+        })
+    }
+
+    /// Apply `Limits` to the encoding. Limits only ever tighten: each one is combined with any
+    /// earlier `with_limits` or `with_max_len`, and the stricter value wins, in either order.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.shared.limits = self.shared.limits.stricter(&limits);
+        self
+    }
+
+    /// Verify the encoding with a profile from the `config` module, for example `Config::keyed`,
+    /// instead of the standard one. This has to match the profile the encoder used. With
+    /// `Config::with_digest_len`, the hash is the padded one from `config::padded_hash`. The
+    /// alignment from `Config::with_alignment` only applies to combined encodings.
+    pub fn with_config(mut self, config: Config) -> Self {
+        // Outboard encodings have no chunks to align.
+        if self.shared.outboard.is_some() {
+            self.shared.state.set_config(config.with_alignment(1));
+        } else {
+            self.shared.state.set_config(config);
+        }
+        self
+    }
+
+    /// Switch this decoder into tolerant mode, for callers who would rather have degraded output
+    /// than an error, like media players.
+    ///
+    /// In tolerant mode, when a chunk or a parent node fails verification, the decoder returns
+    /// `filler` bytes in place of all the content under that node, and carries on reading from
+    /// the next subtree. The content ranges replaced so far are available from
+    /// `corrupted_ranges`. The decoder never returns unverified content bytes.
+    ///
+    /// Corruption along the right edge of the tree, that is, in the final chunk or any parent
+    /// node above it, is still an error. Those nodes are the only thing that verifies the length
+    /// header, and skipping them could mean returning an unbounded amount of filler for a bogus
+    /// length. For the same reason, a corrupt root node is always an error. Seeking isn't
+    /// tolerant either, and a seek that needs to verify a corrupt node returns an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    ///
+    /// let input = vec![1; 10_000];
+    /// let (mut encoded, hash) = bao::encode::encode(&input);
+    /// // Corrupt the first chunk. It starts after the header and four parent nodes.
+    /// encoded[8 + 4 * 64] ^= 1;
+    ///
+    /// let mut output = Vec::new();
+    /// let mut decoder = bao::decode::Decoder::new(&*encoded, &hash).tolerant(0);
+    /// decoder.read_to_end(&mut output)?;
+    /// assert_eq!(vec![0..1024], decoder.corrupted_ranges());
+    /// assert_eq!(&[0; 1024][..], &output[..1024]);
+    /// assert_eq!(&input[1024..], &output[1024..]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn tolerant(mut self, filler: u8) -> Self {
+        self.shared.tolerance = Some(Tolerance {
+            filler,
+            filler_remaining: 0,
+            corrupted: Vec::new(),
+        });
+        self
+    }
+
+    /// Read the rest of the current chunk, verifying the next one first if needed, and return it
+    /// as a slice of the decoder's internal buffer. Returns `None` at EOF.
+    ///
+    /// Each call returns everything up to the next chunk boundary, so after a seek to the middle
+    /// of a chunk, or after a `read` call that stopped in the middle of one, the first call returns
+    /// a partial chunk. Apart from that, every call returns a full chunk of 1024 bytes, except for
+    /// the final chunk, which may be shorter. This suits consumers that work with whole frames, and
+    /// it avoids copying each chunk into a caller's buffer. The decoder doesn't read any further
+    /// from the underlying reader until the next call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let input = vec![0; 2500];
+    /// let (encoded, hash) = bao::encode::encode(&input);
+    /// let mut decoder = bao::decode::Decoder::new(&*encoded, &hash);
+    /// let mut chunk_lengths = Vec::new();
+    /// while let Some(chunk) = decoder.read_next_chunk()? {
+    ///     chunk_lengths.push(chunk.len());
+    /// }
+    /// assert_eq!(vec![1024, 1024, 452], chunk_lengths);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        let shared = &mut self.shared;
+        if shared.buf_len() == 0 {
+            shared.buffer_next_chunk()?;
+            if shared.buf_len() == 0 {
+                return Ok(None);
+            }
+        }
+        let (start, end) = (shared.buf_start, shared.buf_end);
+        shared.buf_start = end;
+        Ok(Some(&shared.buf[start..end]))
+    }
+
+    /// Skip over the next `n` bytes of content, without requiring `Seek`, and return the number of
+    /// bytes skipped. That's `n`, unless EOF comes first.
+    ///
+    /// This is for decoding from pipes, like the output of `gunzip` or `ssh`, where seeking isn't
+    /// possible but the caller only wants part of the content. The skipped part of the encoding
+    /// still has to be read, but whole subtrees that fall inside the skip are thrown away in bulk
+    /// without hashing them. The rest of the tree, including the final chunk, is verified as
+    /// usual.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    ///
+    /// let input = vec![0xab; 100_000];
+    /// let (encoded, hash) = bao::encode::encode(&input);
+    /// // A slice reader doesn't implement Seek.
+    /// let mut decoder = bao::decode::Decoder::new(&encoded[..], &hash);
+    /// assert_eq!(90_000, decoder.skip(90_000)?);
+    /// let mut rest = Vec::new();
+    /// decoder.read_to_end(&mut rest)?;
+    /// assert_eq!(&input[90_000..], &rest[..]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        self.shared.skip(n)
+    }
+
+    /// The current content position, the offset of the next byte that `read` will return.
+    pub fn position(&self) -> u64 {
+        self.shared.adjusted_content_position()
+    }
+
+    /// Call `callback` each time a chunk is verified, with the content position of the end of
+    /// that chunk. Chunks verified while seeking count too.
+    pub fn with_progress_callback(
+        mut self,
+        callback: impl Fn(u64) + Send + Sync + 'static,
+    ) -> Self {
+        self.shared.progress = Some(Progress::new(callback));
+        self
+    }
+
+    /// The content ranges that failed verification and were replaced with filler, in the order
+    /// they were read. A range that touches or overlaps the one before it gets merged into it.
+    /// This is always empty if the decoder isn't in tolerant mode.
+    pub fn corrupted_ranges(&self) -> &[Range<u64>] {
+        match &self.shared.tolerance {
+            Some(tolerance) => &tolerance.corrupted,
+            None => &[],
+        }
+    }
 }
 
 impl<T: Read, O: Read> Read for Decoder<T, O> {
@@ -522,10 +1413,97 @@ impl<T: Read, O: Read> Read for Decoder<T, O> {
     }
 }
 
+impl<T: Read + Send + 'static, O: Read + Send + 'static> Decoder<T, O> {
+    /// Move this decoder to a background thread that verifies up to `chunks` chunks ahead of the
+    /// caller, and return a reader for its output. A `chunks` value of 0 is treated as 1.
+    ///
+    /// While the caller works with one chunk, the background thread reads and verifies the next
+    /// ones, which hides the latency of the underlying reader and the cost of hashing, for
+    /// example in sequential playback from a network stream. As always, only verified bytes come
+    /// out. The reader doesn't support seeking. If it's dropped while the background thread is
+    /// blocked in the underlying reader, the thread exits when that read returns.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    ///
+    /// let input = vec![0; 1_000_000];
+    /// let (encoded, hash) = bao::encode::encode(&input);
+    /// let mut output = Vec::new();
+    /// let mut reader = bao::decode::Decoder::new(std::io::Cursor::new(encoded), &hash)
+    ///     .with_readahead(16);
+    /// reader.read_to_end(&mut output)?;
+    /// assert_eq!(input, output);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_readahead(mut self, chunks: usize) -> ReadaheadDecoder {
+        // The background thread holds one more chunk while it waits to send it.
+        let (sender, receiver) = mpsc::sync_channel(cmp::max(chunks, 1) - 1);
+        std::thread::spawn(move || loop {
+            let message = match self.read_next_chunk() {
+                Ok(Some(chunk)) => Ok(Some(chunk.to_vec())),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            };
+            let done = !matches!(message, Ok(Some(_)));
+            if sender.send(message).is_err() || done {
+                return;
+            }
+        });
+        ReadaheadDecoder {
+            receiver,
+            chunk: Vec::new(),
+            chunk_pos: 0,
+            state: ReadaheadState::Running,
+        }
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> Decoder<T, O> {
+    /// Keep up to `capacity` verified parent nodes in a least-recently-used cache.
+    ///
+    /// Every seek to the left starts over from the root of the tree, so a workload with lots of
+    /// random seeks rereads and rehashes the same upper-level parent nodes over and over. With a
+    /// cache, seeking skips over cached parents in the underlying reader instead. Each entry
+    /// takes about 100 bytes. The tree has one parent node for each chunk, but the nodes near the
+    /// root are the ones that get reused, and a capacity in the hundreds covers those for most
+    /// files.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    /// use std::io::SeekFrom;
+    ///
+    /// let input = vec![0; 1_000_000];
+    /// let (encoded, hash) = bao::encode::encode(&input);
+    /// let mut decoder = bao::decode::Decoder::new(std::io::Cursor::new(&encoded), &hash)
+    ///     .with_parent_cache(256);
+    /// let mut buf = [0; 100];
+    /// for &offset in &[900_000, 100, 500_000, 200] {
+    ///     decoder.seek(SeekFrom::Start(offset))?;
+    ///     decoder.read_exact(&mut buf)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_parent_cache(mut self, capacity: usize) -> Self {
+        self.shared.parent_cache = Some(ParentCache::new(capacity));
+        self
+    }
+}
+
 impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        trace_span!("decode.seek");
         // Clear the internal buffer when seeking. The buffered bytes won't be
-        // valid reads at the new offset.
+        // valid reads at the new offset. Note the current position first,
+        // because it accounts for the buffer.
+        let current_position = self.shared.adjusted_content_position();
         self.shared.clear_buf();
 
         // Get the absolute seek offset. If the caller passed in
@@ -540,7 +1518,7 @@ impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
                     match self.shared.state.len_next() {
                         encode::LenNext::Seek(bookkeeping) => {
                             let next_read = self.shared.handle_seek_bookkeeping(bookkeeping)?;
-                            let done = self.shared.handle_seek_read(next_read)?;
+                            let done = self.shared.handle_seek_read_cached(next_read)?;
                             debug_assert!(!done);
                         }
                         encode::LenNext::Len(len) => break len,
@@ -548,9 +1526,7 @@ impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
                 };
                 add_offset(content_len, offset)?
             }
-            SeekFrom::Current(offset) => {
-                add_offset(self.shared.adjusted_content_position(), offset)?
-            }
+            SeekFrom::Current(offset) => add_offset(current_position, offset)?,
         };
 
         // Now with the absolute seek offset, we perform the real (possibly
@@ -558,15 +1534,32 @@ impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
         loop {
             let bookkeeping = self.shared.state.seek_next(seek_to);
             let next_read = self.shared.handle_seek_bookkeeping(bookkeeping)?;
-            let done = self.shared.handle_seek_read(next_read)?;
+            let done = self.shared.handle_seek_read_cached(next_read)?;
             if done {
+                trace_event!(
+                    debug,
+                    from = current_position,
+                    to = seek_to,
+                    "seek performed"
+                );
                 return Ok(seek_to);
             }
         }
     }
 }
 
-fn add_offset(position: u64, offset: i64) -> io::Result<u64> {
+// Read and throw away `len` bytes. This is how a tolerant Decoder skips over a corrupt subtree
+// without requiring Seek.
+fn discard(reader: impl Read, len: u128) -> io::Result<()> {
+    let len = encode::EncodedOffset::new(len).to_u64()?;
+    let copied = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if copied < len {
+        return Err(Error::Truncated.into());
+    }
+    Ok(())
+}
+
+pub(crate) fn add_offset(position: u64, offset: i64) -> io::Result<u64> {
     let sum = position as i128 + offset as i128;
     if sum < 0 {
         Err(io::Error::new(
@@ -648,6 +1641,27 @@ impl<T: Read> SliceDecoder<T> {
         }
     }
 
+    /// Limit the content length this decoder will accept, like `Decoder::with_max_len`.
+    pub fn with_max_len(self, max_len: u64) -> Self {
+        self.with_limits(Limits {
+            max_content_len: max_len,
+            ..Limits::default()
+        })
+    }
+
+    /// Apply `Limits` to the encoding, like `Decoder::with_limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.shared.limits = self.shared.limits.stricter(&limits);
+        self
+    }
+
+    /// Verify the slice with a profile from the `config` module, like `Decoder::with_config`.
+    /// Slices never have alignment padding, so the alignment doesn't matter here.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.shared.state.set_config(config.with_alignment(1));
+        self
+    }
+
     /// Return the underlying reader.
     pub fn into_inner(self) -> T {
         self.shared.input
@@ -686,6 +1700,11 @@ impl<T: Read> Read for SliceDecoder<T> {
             Ok(0)
         } else {
             let cap = cmp::min(self.slice_remaining, output.len() as u64) as usize;
+            // The slice doesn't include anything past its end, so don't let a zero-length read
+            // go looking for the next chunk.
+            if cap == 0 {
+                return Ok(0);
+            }
             let capped_output = &mut output[..cap];
             let n = self.shared.read(capped_output)?;
             self.slice_remaining -= n as u64;
@@ -694,9 +1713,1252 @@ impl<T: Read> Read for SliceDecoder<T> {
     }
 }
 
-#[cfg(test)]
-pub(crate) fn make_test_input(len: usize) -> Vec<u8> {
-    // Fill the input with incrementing bytes, so that reads from different sections are very
+// Subtrees no bigger than this get verified in one piece, by a single thread. This is the same
+// as the largest subtree that BLAKE3 itself hands to a single thread in its default build.
+const PARALLEL_SUBTREE_LEN: u64 = 64 * CHUNK_SIZE as u64;
+
+// How many subtrees each thread gets in a batch. A larger batch means fewer thread spawns and
+// more memory.
+const PARALLEL_SUBTREES_PER_THREAD: usize = 4;
+
+/// A decoder that verifies the combined encoding on multiple threads.
+///
+/// `Decoder` verifies one chunk at a time, and hashing is usually the bottleneck for large
+/// reads. `ParallelDecoder` reads a batch of subtrees from the underlying reader, verifies them
+/// on separate threads, and then returns their content in order. Reads from the underlying reader
+/// are still sequential, so this doesn't require `Seek`, and the memory it needs is bounded by the
+/// batch size, regardless of the content length. As with `Decoder`, bytes are only returned after
+/// they've been verified.
+///
+/// This is only worth it for large encodings. `ParallelDecoder` doesn't support the outboard
+/// mode, or seeking.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0; 1_000_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut output = Vec::new();
+/// let mut decoder = bao::decode::ParallelDecoder::new(&*encoded, &hash);
+/// decoder.read_to_end(&mut output)?;
+/// assert_eq!(input, output);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ParallelDecoder<T: Read> {
+    input: T,
+    threads: usize,
+    root_hash: Hash,
+    // None until we've read the header. The tree is traversed in pre-order, so this is a stack of
+    // (content start, content len, hash, finalization) for the subtrees we haven't read yet.
+    stack: Option<Vec<(u64, u64, Hash, Finalization)>>,
+    output: Vec<u8>,
+    output_pos: usize,
+    limits: Limits,
+}
+
+impl<T: Read> ParallelDecoder<T> {
+    /// Create a decoder that uses as many threads as `std::thread::available_parallelism`
+    /// reports.
+    pub fn new(inner: T, hash: &Hash) -> Self {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_threads(inner, hash, threads)
+    }
+
+    /// Create a decoder that uses `threads` threads. A `threads` value of 0 is treated as 1.
+    pub fn with_threads(inner: T, hash: &Hash, threads: usize) -> Self {
+        Self {
+            input: inner,
+            threads: cmp::max(threads, 1),
+            root_hash: *hash,
+            stack: None,
+            output: Vec::new(),
+            output_pos: 0,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Limit the content length this decoder will accept, like `Decoder::with_max_len`.
+    pub fn with_max_len(self, max_len: u64) -> Self {
+        self.with_limits(Limits {
+            max_content_len: max_len,
+            ..Limits::default()
+        })
+    }
+
+    /// Apply `Limits` to the encoding, like `Decoder::with_limits`. `chunk_buffer` caps the
+    /// memory for each batch, which makes for smaller batches, and so less parallelism.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = self.limits.stricter(&limits);
+        self
+    }
+
+    // The largest subtree verified in one piece, and the most subtrees in a batch. Each subtree
+    // needs room for its encoding and its content, and the batch has to fit in chunk_buffer.
+    fn batch_shape(&self) -> (u64, usize) {
+        let subtree_memory = |len| (len + encode::encoded_subtree_size(len) as u64) as usize;
+        let mut subtree_len = PARALLEL_SUBTREE_LEN;
+        while subtree_len > CHUNK_SIZE as u64
+            && subtree_memory(subtree_len) > self.limits.chunk_buffer
+        {
+            subtree_len /= 2;
+        }
+        let max_subtrees = self.threads * PARALLEL_SUBTREES_PER_THREAD;
+        let fit = self.limits.chunk_buffer / subtree_memory(subtree_len);
+        (subtree_len, fit.clamp(1, max_subtrees))
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> T {
+        self.input
+    }
+
+    // Walk the tree until we've collected a batch of small enough subtrees, verifying the parent
+    // nodes above them as we go, and read the encoded bytes of each subtree.
+    fn read_batch(&mut self) -> io::Result<Vec<BatchSubtree>> {
+        if self.stack.is_none() {
+            let mut header = [0; HEADER_SIZE];
+            self.input.read_exact(&mut header)?;
+            self.limits.check_header(&header)?;
+            let content_len = crate::decode_len(&header);
+            self.stack = Some(vec![(0, content_len, self.root_hash, Finalization::Root)]);
+        }
+        let (subtree_len, max_subtrees) = self.batch_shape();
+        let stack = self.stack.as_mut().unwrap();
+        let mut batch = Vec::new();
+        while batch.len() < max_subtrees {
+            let (start, len, hash, finalization) = match stack.pop() {
+                Some(subtree) => subtree,
+                None => break,
+            };
+            if len <= subtree_len {
+                let encoded_len = encode::encoded_subtree_size(len) as usize;
+                let mut encoded = vec![0; encoded_len];
+                self.input.read_exact(&mut encoded)?;
+                batch.push((start, len, hash, finalization, encoded));
+                continue;
+            }
+            let mut parent = [0; PARENT_SIZE];
+            self.input.read_exact(&mut parent)?;
+            let (left_child, right_child) = verify_parent(&parent, &hash, finalization)?;
+            let left_len = encode::left_subtree_len(len);
+            stack.push((
+                start + left_len,
+                len - left_len,
+                right_child,
+                Finalization::NotRoot,
+            ));
+            stack.push((start, left_len, left_child, Finalization::NotRoot));
+        }
+        Ok(batch)
+    }
+
+    fn fill_output(&mut self) -> io::Result<()> {
+        let batch = self.read_batch()?;
+        let total_len: u64 = batch.iter().map(|subtree| subtree.1).sum();
+        self.output.clear();
+        self.output.resize(total_len as usize, 0);
+        self.output_pos = 0;
+
+        // Carve up the output buffer, one piece per subtree, and deal the subtrees out to the
+        // threads in contiguous groups.
+        let mut jobs = Vec::new();
+        let mut output: &mut [u8] = &mut self.output;
+        for (start, len, hash, finalization, encoded) in &batch {
+            let (piece, rest) = output.split_at_mut(*len as usize);
+            output = rest;
+            jobs.push((*start, *len, hash, *finalization, &encoded[..], piece));
+        }
+        if jobs.len() <= 1 {
+            return verify_subtrees(jobs).map_err(Into::into);
+        }
+        let group_size = jobs.len().div_ceil(self.threads);
+        let mut groups = Vec::new();
+        while jobs.len() > group_size {
+            let rest = jobs.split_off(group_size);
+            groups.push(jobs);
+            jobs = rest;
+        }
+        groups.push(jobs);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = groups
+                .into_iter()
+                .map(|group| scope.spawn(move || verify_subtrees(group)))
+                .collect();
+            for handle in handles {
+                handle.join().expect("verifier thread panicked")?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<T: Read> Read for ParallelDecoder<T> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        // An empty batch means we're at EOF. It's not possible to get there without verifying the
+        // final chunk, because the stack isn't empty until every subtree has been verified. Like
+        // `Decoder`, a zero-length read still verifies the next batch, if there isn't one already.
+        while self.output_pos == self.output.len() {
+            if self.stack.as_ref().is_some_and(|stack| stack.is_empty()) {
+                return Ok(0);
+            }
+            self.fill_output()?;
+        }
+        let take = cmp::min(output.len(), self.output.len() - self.output_pos);
+        output[..take].copy_from_slice(&self.output[self.output_pos..][..take]);
+        self.output_pos += take;
+        Ok(take)
+    }
+}
+
+impl<T: Read> fmt::Debug for ParallelDecoder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        write!(
+            f,
+            "ParallelDecoder {{ threads: {}, buffered: {} }}",
+            self.threads,
+            self.output.len() - self.output_pos,
+        )
+    }
+}
+
+/// A reader that verifies chunks on a background thread, ahead of the caller. See
+/// `Decoder::with_readahead`.
+pub struct ReadaheadDecoder {
+    // Each message is a verified chunk, None at EOF, or the error that stopped the decoder.
+    receiver: mpsc::Receiver<io::Result<Option<Vec<u8>>>>,
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    state: ReadaheadState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReadaheadState {
+    Running,
+    Done,
+    Failed,
+}
+
+impl ReadaheadDecoder {
+    // Wait for the next chunk, unless the current one has bytes left. Returns false at EOF.
+    fn fill_chunk(&mut self) -> io::Result<bool> {
+        while self.chunk_pos == self.chunk.len() {
+            match self.state {
+                ReadaheadState::Running => {}
+                ReadaheadState::Done => return Ok(false),
+                ReadaheadState::Failed => {
+                    return Err(io::Error::other("readahead decoder already failed"));
+                }
+            }
+            // If the channel is closed without a None, the background thread panicked.
+            let message = self
+                .receiver
+                .recv()
+                .unwrap_or_else(|_| Err(io::Error::other("readahead thread panicked")));
+            match message {
+                Ok(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.chunk_pos = 0;
+                }
+                Ok(None) => self.state = ReadaheadState::Done,
+                Err(e) => {
+                    self.state = ReadaheadState::Failed;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Read for ReadaheadDecoder {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        // Like `Decoder`, a zero-length read still waits for the next chunk to be verified.
+        if !self.fill_chunk()? {
+            return Ok(0);
+        }
+        let take = cmp::min(output.len(), self.chunk.len() - self.chunk_pos);
+        output[..take].copy_from_slice(&self.chunk[self.chunk_pos..][..take]);
+        self.chunk_pos += take;
+        Ok(take)
+    }
+}
+
+impl fmt::Debug for ReadaheadDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ReadaheadDecoder {{ state: {:?}, buffered: {} }}",
+            self.state,
+            self.chunk.len() - self.chunk_pos,
+        )
+    }
+}
+
+fn verify_parent(
+    parent: &crate::ParentNode,
+    expected: &Hash,
+    finalization: Finalization,
+) -> Result<(Hash, Hash), Error> {
+    let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+    let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+    let computed = crate::parent_cv(&left_child, &right_child, finalization.is_root());
+    // Hash implements constant time equality.
+    if &computed != expected {
+        return Err(Error::HashMismatch);
+    }
+    Ok((left_child, right_child))
+}
+
+type BatchSubtree = (u64, u64, Hash, Finalization, Vec<u8>);
+
+type SubtreeJob<'a> = (u64, u64, &'a Hash, Finalization, &'a [u8], &'a mut [u8]);
+
+fn verify_subtrees(jobs: Vec<SubtreeJob>) -> Result<(), Error> {
+    for (start, len, hash, finalization, encoded, output) in jobs {
+        verify_subtree(start, len, hash, finalization, encoded, output)?;
+    }
+    Ok(())
+}
+
+// Verify the combined encoding of a whole subtree in memory, and copy out its content.
+fn verify_subtree(
+    start: u64,
+    len: u64,
+    expected: &Hash,
+    finalization: Finalization,
+    encoded: &[u8],
+    output: &mut [u8],
+) -> Result<(), Error> {
+    if len <= CHUNK_SIZE as u64 {
+        let computed = crate::chunk_cv(start / CHUNK_SIZE as u64, encoded, finalization.is_root());
+        // Hash implements constant time equality.
+        if &computed != expected {
+            return Err(Error::HashMismatch);
+        }
+        output.copy_from_slice(encoded);
+        return Ok(());
+    }
+    let (left_child, right_child) =
+        verify_parent(array_ref!(encoded, 0, PARENT_SIZE), expected, finalization)?;
+    let left_len = encode::left_subtree_len(len);
+    let left_encoded_len = encode::encoded_subtree_size(left_len) as usize;
+    let (left_encoded, right_encoded) = encoded[PARENT_SIZE..].split_at(left_encoded_len);
+    let (left_output, right_output) = output.split_at_mut(left_len as usize);
+    verify_subtree(
+        start,
+        left_len,
+        &left_child,
+        Finalization::NotRoot,
+        left_encoded,
+        left_output,
+    )?;
+    verify_subtree(
+        start + left_len,
+        len - left_len,
+        &right_child,
+        Finalization::NotRoot,
+        right_encoded,
+        right_output,
+    )
+}
+
+/// Whether a node in an `AuditReport` verified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NodeStatus {
+    Verified,
+    HashMismatch,
+    /// A parent node above this one failed to verify, so there's no trusted hash to check this
+    /// node against.
+    Unverifiable,
+}
+
+/// The kind of a node in an `AuditReport`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NodeKind {
+    Parent,
+    Chunk,
+}
+
+/// One parent node or chunk in an `AuditReport`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditNode {
+    pub kind: NodeKind,
+    /// The node's offset in the encoding. For an outboard encoding, that's the offset in the
+    /// outboard file for parent nodes, and the offset in the content for chunks.
+    pub encoded_offset: u128,
+    /// The node's size in the encoding, 64 bytes for a parent node.
+    pub len: usize,
+    /// The content under this node. For a parent node, that's its whole subtree, which is what
+    /// a scrubber has to repair if the node is corrupt.
+    pub content_range: Range<u64>,
+    pub status: NodeStatus,
+}
+
+/// The result of `audit`, with the status of every node in the tree, in pre-order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditReport {
+    /// The content length from the header. This is only trustworthy if the final chunk and the
+    /// parent nodes above it verified. If the header itself is corrupt, the tree has the wrong
+    /// shape, and nodes starting from the first one that depends on the length fail to verify.
+    pub content_len: u64,
+    pub nodes: Vec<AuditNode>,
+    /// Whether the encoding ended early. Nodes past the end are missing from `nodes`.
+    pub truncated: bool,
+}
+
+impl AuditReport {
+    /// Returns `true` if every node verified and the encoding was complete.
+    pub fn is_ok(&self) -> bool {
+        !self.truncated && self.failures().next().is_none()
+    }
+
+    /// The nodes that didn't verify, including the ones under a failed parent node.
+    pub fn failures(&self) -> impl Iterator<Item = &AuditNode> {
+        self.nodes
+            .iter()
+            .filter(|node| node.status != NodeStatus::Verified)
+    }
+}
+
+/// Read an entire combined encoding and report whether each node in it verifies, without
+/// returning any content.
+///
+/// Unlike decoding, an audit doesn't stop at the first corrupt node. It reads the whole encoding
+/// and records the offset, size, and status of every parent node and chunk, which is what a
+/// storage provider scrubbing its disks needs to log and repair. IO errors are still returned as
+/// errors, apart from hitting EOF early, which shows up as `truncated` in the report.
+///
+/// The report holds every node in memory, which is about 5% of the content size.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use bao::decode::{NodeKind, NodeStatus};
+///
+/// let input = vec![0; 10_000];
+/// let (mut encoded, hash) = bao::encode::encode(&input);
+/// let last = encoded.len() - 1;
+/// encoded[last] ^= 1;
+///
+/// let report = bao::decode::audit(&*encoded, &hash)?;
+/// assert!(!report.is_ok());
+/// let failures: Vec<_> = report.failures().collect();
+/// assert_eq!(1, failures.len());
+/// assert_eq!(NodeKind::Chunk, failures[0].kind);
+/// assert_eq!(NodeStatus::HashMismatch, failures[0].status);
+/// assert_eq!(9 * 1024..10_000, failures[0].content_range);
+/// # Ok(())
+/// # }
+/// ```
+pub fn audit(encoded: impl Read, hash: &Hash) -> io::Result<AuditReport> {
+    audit_with_limits(encoded, hash, &Limits::default())
+}
+
+/// Like `audit`, but with `Limits` on the length header. A header over the limits is an error,
+/// rather than something to report, since there's no telling how much work it would take.
+pub fn audit_with_limits(
+    mut encoded: impl Read,
+    hash: &Hash,
+    limits: &Limits,
+) -> io::Result<AuditReport> {
+    Auditor::run(&mut encoded, None, hash, limits)
+}
+
+/// Like `audit`, but for content and its outboard encoding.
+pub fn audit_outboard(
+    content: impl Read,
+    outboard: impl Read,
+    hash: &Hash,
+) -> io::Result<AuditReport> {
+    audit_outboard_with_limits(content, outboard, hash, &Limits::default())
+}
+
+/// Like `audit_outboard`, but with `Limits`. See `audit_with_limits`.
+pub fn audit_outboard_with_limits(
+    mut content: impl Read,
+    mut outboard: impl Read,
+    hash: &Hash,
+    limits: &Limits,
+) -> io::Result<AuditReport> {
+    Auditor::run(&mut outboard, Some(&mut content), hash, limits)
+}
+
+struct Auditor<'a> {
+    // The header and parent nodes, and also the chunks in combined mode.
+    tree: &'a mut dyn Read,
+    // The chunks in outboard mode.
+    content: Option<&'a mut dyn Read>,
+    tree_offset: u128,
+    content_offset: u128,
+    report: AuditReport,
+}
+
+impl<'a> Auditor<'a> {
+    fn run(
+        tree: &'a mut dyn Read,
+        content: Option<&'a mut dyn Read>,
+        hash: &Hash,
+        limits: &Limits,
+    ) -> io::Result<AuditReport> {
+        let mut auditor = Self {
+            tree,
+            content,
+            tree_offset: HEADER_SIZE as u128,
+            content_offset: 0,
+            report: AuditReport {
+                content_len: 0,
+                nodes: Vec::new(),
+                truncated: false,
+            },
+        };
+        let mut header = [0; HEADER_SIZE];
+        if !read_fully(auditor.tree, &mut header)? {
+            auditor.report.truncated = true;
+            return Ok(auditor.report);
+        }
+        limits.check_header(&header)?;
+        auditor.report.content_len = crate::decode_len(&header);
+        let content_len = auditor.report.content_len;
+        auditor.audit_subtree(Some(hash), 0, content_len, Finalization::Root)?;
+        Ok(auditor.report)
+    }
+
+    // Returns false if the encoding was truncated.
+    fn audit_subtree(
+        &mut self,
+        expected: Option<&Hash>,
+        start: u64,
+        len: u64,
+        finalization: Finalization,
+    ) -> io::Result<bool> {
+        if len <= CHUNK_SIZE as u64 {
+            let mut chunk = [0; CHUNK_SIZE];
+            let chunk = &mut chunk[..len as usize];
+            let (reader, offset) = match &mut self.content {
+                Some(content) => (&mut **content, &mut self.content_offset),
+                None => (&mut *self.tree, &mut self.tree_offset),
+            };
+            let encoded_offset = *offset;
+            if !read_fully(reader, chunk)? {
+                self.report.truncated = true;
+                return Ok(false);
+            }
+            *offset += len as u128;
+            let status = match expected {
+                Some(expected) => {
+                    let computed =
+                        crate::chunk_cv(start / CHUNK_SIZE as u64, chunk, finalization.is_root());
+                    // Hash implements constant time equality.
+                    if &computed == expected {
+                        NodeStatus::Verified
+                    } else {
+                        NodeStatus::HashMismatch
+                    }
+                }
+                None => NodeStatus::Unverifiable,
+            };
+            self.report.nodes.push(AuditNode {
+                kind: NodeKind::Chunk,
+                encoded_offset,
+                len: len as usize,
+                content_range: start..start + len,
+                status,
+            });
+            return Ok(true);
+        }
+        let mut parent = [0; PARENT_SIZE];
+        let encoded_offset = self.tree_offset;
+        if !read_fully(self.tree, &mut parent)? {
+            self.report.truncated = true;
+            return Ok(false);
+        }
+        self.tree_offset += PARENT_SIZE as u128;
+        let (status, children) = match expected {
+            Some(expected) => match verify_parent(&parent, expected, finalization) {
+                Ok((left_child, right_child)) => {
+                    (NodeStatus::Verified, Some((left_child, right_child)))
+                }
+                Err(_) => (NodeStatus::HashMismatch, None),
+            },
+            None => (NodeStatus::Unverifiable, None),
+        };
+        self.report.nodes.push(AuditNode {
+            kind: NodeKind::Parent,
+            encoded_offset,
+            len: PARENT_SIZE,
+            content_range: start..start + len,
+            status,
+        });
+        let left_len = encode::left_subtree_len(len);
+        let left_child = children.as_ref().map(|(left, _)| left);
+        let right_child = children.as_ref().map(|(_, right)| right);
+        Ok(
+            self.audit_subtree(left_child, start, left_len, Finalization::NotRoot)?
+                && self.audit_subtree(
+                    right_child,
+                    start + left_len,
+                    len - left_len,
+                    Finalization::NotRoot,
+                )?,
+        )
+    }
+}
+
+/// Verify a whole combined encoding against `hash` without keeping any of the content, and
+/// return the verified content length.
+///
+/// This is the scrubbing operation: it checks the length header, every parent node, and every
+/// chunk, and it stops at the first problem. Unlike reading a `Decoder` to the end, it never
+/// copies chunk bytes into an output buffer. Each chunk is read into one reusable buffer, hashed,
+/// and dropped. Like the `Decoder`, it doesn't look past the end of the encoding, so trailing
+/// bytes aren't an error. Use `validate_canonical` to reject those too, or `audit` to find every
+/// corrupt chunk rather than just the first.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (mut encoded, hash) = bao::encode::encode(vec![0xab; 10_000]);
+/// assert_eq!(10_000, bao::decode::verify(&*encoded, &hash)?);
+///
+/// let last_index = encoded.len() - 1;
+/// encoded[last_index] ^= 1;
+/// let err = bao::decode::verify(&*encoded, &hash).unwrap_err();
+/// assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+/// # Ok(())
+/// # }
+/// ```
+pub fn verify(encoded: impl Read, hash: &Hash) -> io::Result<u64> {
+    verify_with_limits(encoded, hash, &Limits::default())
+}
+
+/// Like `verify`, but with `Limits` on the length header.
+pub fn verify_with_limits(mut encoded: impl Read, hash: &Hash, limits: &Limits) -> io::Result<u64> {
+    let state = DecoderState::new(hash).with_limits(*limits);
+    drive_verify(state, &mut encoded, None)
+}
+
+/// Like `verify`, but for content and its outboard encoding.
+pub fn verify_outboard(content: impl Read, outboard: impl Read, hash: &Hash) -> io::Result<u64> {
+    verify_outboard_with_limits(content, outboard, hash, &Limits::default())
+}
+
+/// Like `verify_outboard`, but with `Limits` on the length header.
+pub fn verify_outboard_with_limits(
+    mut content: impl Read,
+    mut outboard: impl Read,
+    hash: &Hash,
+    limits: &Limits,
+) -> io::Result<u64> {
+    let state = DecoderState::new_outboard(hash).with_limits(*limits);
+    drive_verify(state, &mut outboard, Some(&mut content))
+}
+
+/// A way that an encoding differs from the canonical encoding of its root hash, from
+/// `validate_canonical`. Offsets are positions in the encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Violation {
+    /// The encoding ends at `offset`, before the end of the length header or of a node.
+    Truncated { offset: u128 },
+    /// The parent node or chunk at `offset` doesn't match its hash. A wrong length header shows
+    /// up as a mismatch in one of the nodes along the right edge of the tree.
+    HashMismatch { offset: u128 },
+    /// The encoding continues past `offset`, where the final chunk ends. This covers anything
+    /// appended to a valid encoding, including an over-long final chunk or extra parent nodes,
+    /// since the length header fixes the position of every node.
+    TrailingBytes { offset: u128 },
+}
+
+impl Violation {
+    /// Get the `Violation` out of an error returned by `validate_canonical`, or `None` if the
+    /// error came from the underlying reader.
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref::<Self>().copied()
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::Truncated { offset } => write!(f, "encoding truncated at offset {}", offset),
+            Violation::HashMismatch { offset } => write!(f, "hash mismatch at offset {}", offset),
+            Violation::TrailingBytes { offset } => {
+                write!(f, "trailing bytes after offset {}", offset)
+            }
+        }
+    }
+}
+
+impl error::Error for Violation {}
+
+impl From<Violation> for io::Error {
+    fn from(violation: Violation) -> io::Error {
+        let kind = match violation {
+            Violation::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, violation)
+    }
+}
+
+/// Check that `encoded` is exactly the canonical combined encoding of `hash`, and return the
+/// content length.
+///
+/// For every root hash, there's only one byte string that encodes it. `verify` and the decoders
+/// stop reading at the end of the final chunk, so they also accept that byte string with
+/// anything appended to it. This function reads one byte further, and rejects anything that
+/// isn't the canonical encoding with an error that holds a `Violation`, which
+/// `Violation::from_io_error` gets back out. That's the guarantee a content-addressed store needs,
+/// to make sure it stores exactly one encoding for each hash. Truncation is an `UnexpectedEof`
+/// error, and the other violations are `InvalidData`.
+///
+/// # Example
+///
+/// ```
+/// use bao::decode::Violation;
+///
+/// let (mut encoded, hash) = bao::encode::encode(vec![0xab; 10_000]);
+/// assert_eq!(10_000, bao::decode::validate_canonical(&*encoded, &hash).unwrap());
+///
+/// let end = encoded.len() as u128;
+/// encoded.push(0);
+/// assert_eq!(10_000, bao::decode::verify(&*encoded, &hash).unwrap());
+/// let err = bao::decode::validate_canonical(&*encoded, &hash).unwrap_err();
+/// assert_eq!(
+///     Some(Violation::TrailingBytes { offset: end }),
+///     Violation::from_io_error(&err),
+/// );
+/// ```
+pub fn validate_canonical(encoded: impl Read, hash: &Hash) -> io::Result<u64> {
+    validate_canonical_with_limits(encoded, hash, &Limits::default())
+}
+
+/// Like `validate_canonical`, but with `Limits` on the length header. A header over the limits
+/// is the limit's error, not a `Violation`.
+pub fn validate_canonical_with_limits(
+    mut encoded: impl Read,
+    hash: &Hash,
+    limits: &Limits,
+) -> io::Result<u64> {
+    let mut state = DecoderState::new(hash).with_limits(*limits);
+    let mut buf = [0; CHUNK_SIZE];
+    loop {
+        let (offset, len) = match state.next() {
+            DecodeNext::NeedEncoded { offset, len } => (offset, len),
+            DecodeNext::NeedContent { .. } => unreachable!("combined mode"),
+            DecodeNext::Done => break,
+        };
+        let n = read_up_to(&mut encoded, &mut buf[..len])?;
+        if n < len {
+            let offset = offset + n as u128;
+            return Err(Violation::Truncated { offset }.into());
+        }
+        match state.feed(&buf[..len]) {
+            Ok(_) => {}
+            Err(Error::HashMismatch) => return Err(Violation::HashMismatch { offset }.into()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let content_len = state.content_position();
+    if read_up_to(&mut encoded, &mut [0])? > 0 {
+        let offset = encode::encoded_size(content_len);
+        return Err(Violation::TrailingBytes { offset }.into());
+    }
+    Ok(content_len)
+}
+
+// Feed a DecoderState everything it asks for, reading sequentially, and throw away the content.
+fn drive_verify(
+    mut state: DecoderState,
+    tree: &mut dyn Read,
+    mut content: Option<&mut dyn Read>,
+) -> io::Result<u64> {
+    let mut buf = [0; CHUNK_SIZE];
+    loop {
+        let (reader, len): (&mut dyn Read, usize) = match state.next() {
+            DecodeNext::NeedEncoded { len, .. } => (&mut *tree, len),
+            DecodeNext::NeedContent { len, .. } => {
+                (content.as_deref_mut().expect("outboard state"), len)
+            }
+            DecodeNext::Done => return Ok(state.content_position()),
+        };
+        reader.read_exact(&mut buf[..len])?;
+        state.feed(&buf[..len])?;
+    }
+}
+
+/// Decode a combined encoding without knowing its hash in advance, writing the content to
+/// `output` and returning the root hash recomputed from it.
+///
+/// This is a single pass for importing encodings from untrusted peers. Every parent node has to
+/// match the hashes of its children, and the length header has to match the shape of the tree,
+/// so the result is a well-formed encoding of whatever content it holds, with the returned hash.
+/// The caller decides whether that hash is the one they wanted. Content is written as it's
+/// read, before the root hash is known, so if this returns an error, or the hash isn't the
+/// expected one, the caller should throw away the output.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+///
+/// let mut content = Vec::new();
+/// let recomputed = bao::decode::hash_and_extract(&*encoded, &mut content)?;
+/// assert_eq!(hash, recomputed);
+/// assert_eq!(input, content);
+/// # Ok(())
+/// # }
+/// ```
+pub fn hash_and_extract(encoded: impl Read, output: impl Write) -> io::Result<Hash> {
+    hash_and_extract_with_limits(encoded, output, &Limits::default())
+}
+
+/// Like `hash_and_extract`, but with `Limits` on the length header. Without a known hash, the
+/// limits are the only thing that bounds how much output an encoding can ask for.
+pub fn hash_and_extract_with_limits(
+    mut encoded: impl Read,
+    mut output: impl Write,
+    limits: &Limits,
+) -> io::Result<Hash> {
+    let mut header = [0; HEADER_SIZE];
+    encoded.read_exact(&mut header)?;
+    limits.check_header(&header)?;
+    let content_len = crate::decode_len(&header);
+    extract_subtree(
+        &mut encoded,
+        &mut output,
+        0,
+        content_len,
+        Finalization::Root,
+    )
+}
+
+fn extract_subtree(
+    encoded: &mut impl Read,
+    output: &mut impl Write,
+    start: u64,
+    len: u64,
+    finalization: Finalization,
+) -> io::Result<Hash> {
+    if len <= CHUNK_SIZE as u64 {
+        let mut chunk = [0; CHUNK_SIZE];
+        let chunk = &mut chunk[..len as usize];
+        encoded.read_exact(chunk)?;
+        output.write_all(chunk)?;
+        return Ok(crate::chunk_cv(
+            start / CHUNK_SIZE as u64,
+            chunk,
+            finalization.is_root(),
+        ));
+    }
+    let mut parent = [0; PARENT_SIZE];
+    encoded.read_exact(&mut parent)?;
+    let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+    let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+    let left_len = encode::left_subtree_len(len);
+    let computed_left = extract_subtree(encoded, output, start, left_len, Finalization::NotRoot)?;
+    // Hash implements constant time equality.
+    if computed_left != left_child {
+        return Err(Error::HashMismatch.into());
+    }
+    let computed_right = extract_subtree(
+        encoded,
+        output,
+        start + left_len,
+        len - left_len,
+        Finalization::NotRoot,
+    )?;
+    if computed_right != right_child {
+        return Err(Error::HashMismatch.into());
+    }
+    Ok(crate::parent_cv(
+        &left_child,
+        &right_child,
+        finalization.is_root(),
+    ))
+}
+
+/// A reader for the epochs from `encode::EpochWriter`.
+///
+/// Each call to `read_epoch` reads the next epoch's encoding from the stream and verifies it
+/// against the epoch's root hash, which the subscriber gets from the publisher over an
+/// authenticated channel. The reader also tracks the chain value with `encode::epoch_chain`, so
+/// a subscriber can compare it to a signed chain value from the publisher. The length header of
+/// each epoch has to fit in `chunks_per_epoch` chunks, or else this is an `InvalidData` error
+/// (`Error::TooLong`), so a bad stream can't claim an enormous epoch.
+///
+/// See `encode::EpochWriter` for an example.
+#[derive(Clone, Debug)]
+pub struct EpochReader<R: Read> {
+    inner: R,
+    max_epoch_len: u64,
+    index: u64,
+    chain: Option<Hash>,
+}
+
+impl<R: Read> EpochReader<R> {
+    /// `chunks_per_epoch` should be the same as the writer's.
+    pub fn new(inner: R, chunks_per_epoch: usize) -> Self {
+        Self {
+            inner,
+            max_epoch_len: chunks_per_epoch as u64 * CHUNK_SIZE as u64,
+            index: 0,
+            chain: None,
+        }
+    }
+
+    /// Read and verify the next epoch, and return its content, or `None` if the stream ended
+    /// cleanly between epochs. `root` is the root hash of the next epoch. If this returns an
+    /// error, the stream is out of sync, and the reader shouldn't be used again.
+    pub fn read_epoch(&mut self, root: &Hash) -> io::Result<Option<Vec<u8>>> {
+        let mut header = [0; HEADER_SIZE];
+        let mut filled = 0;
+        while filled < HEADER_SIZE {
+            match self.inner.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut decoder = Decoder::new(io::Cursor::new(header).chain(&mut self.inner), root)
+            .with_max_len(self.max_epoch_len);
+        let mut content = Vec::new();
+        decoder.read_to_end(&mut content)?;
+        self.chain = Some(encode::epoch_chain(self.chain.as_ref(), self.index, root));
+        self.index += 1;
+        Ok(Some(content))
+    }
+
+    /// The chain value after the last epoch read, or `None` if there isn't one yet.
+    pub fn chain(&self) -> Option<Hash> {
+        self.chain
+    }
+
+    /// The number of epochs read so far, which is also the index of the next one.
+    pub fn epochs_read(&self) -> u64 {
+        self.index
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+// The most verified parents a SharedReader caches, 4 MiB worth. When the cache fills up, it
+// starts over, like file::VerifiedFile's.
+const MAX_SHARED_PARENTS: usize = 1 << 16;
+
+/// Verified random access to an encoding, shared between threads.
+///
+/// This is like `file::VerifiedFile`, but `read_at` takes `&self`, and a `SharedReader` is cheap
+/// to clone, so any number of threads can read from the same encoding at once. The underlying
+/// storage is a `backend::ReadAt`, like a `File` or a byte vector. The verified parent nodes go
+/// in a cache that all the clones share, behind a `RwLock`, so a parent that one thread has
+/// verified doesn't need to be read again by the others. Chunks aren't cached.
+///
+/// Opening a `SharedReader` verifies the final chunk, so `len` is always trustworthy. After that,
+/// `read_at` verifies everything it returns. Corruption shows up as an `InvalidData` error from
+/// the read that touched it.
+///
+/// `SharedReader` implements `ReadAt` itself, so `backend::ReadAtCursor` turns a clone of it into
+/// a verified `Read + Seek` with its own position.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 100_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let reader = bao::decode::SharedReader::open(encoded, &hash)?;
+/// std::thread::scope(|scope| {
+///     for i in 0..4 {
+///         let reader = reader.clone();
+///         scope.spawn(move || {
+///             let mut buf = [0; 1000];
+///             let n = reader.read_at(i * 20_000, &mut buf).unwrap();
+///             assert_eq!(&buf[..n], &[0xab; 1000][..]);
+///         });
+///     }
+/// });
+/// # Ok(())
+/// # }
+/// ```
+pub struct SharedReader<T: ReadAt, O: ReadAt = T> {
+    shared: Arc<SharedReaderInner<T, O>>,
+}
+
+struct SharedReaderInner<T, O> {
+    input: T,
+    outboard: Option<O>,
+    root_hash: Hash,
+    content_len: u64,
+    // Verified children of each parent node, keyed by the parent's offset in the encoding (or in
+    // the outboard encoding).
+    parents: RwLock<HashMap<u128, (Hash, Hash)>>,
+}
+
+impl<T: ReadAt> SharedReader<T, T> {
+    /// Open a combined encoding.
+    pub fn open(inner: T, hash: &Hash) -> io::Result<Self> {
+        Self::open_with_limits(inner, hash, &Limits::default())
+    }
+
+    /// Like `open`, but with `Limits` on the length header.
+    pub fn open_with_limits(inner: T, hash: &Hash, limits: &Limits) -> io::Result<Self> {
+        Self::open_inner(inner, None, hash, limits)
+    }
+}
+
+impl<T: ReadAt, O: ReadAt> SharedReader<T, O> {
+    /// Open content along with its outboard encoding.
+    pub fn open_outboard(content: T, outboard: O, hash: &Hash) -> io::Result<Self> {
+        Self::open_outboard_with_limits(content, outboard, hash, &Limits::default())
+    }
+
+    /// Like `open_outboard`, but with `Limits` on the length header.
+    pub fn open_outboard_with_limits(
+        content: T,
+        outboard: O,
+        hash: &Hash,
+        limits: &Limits,
+    ) -> io::Result<Self> {
+        Self::open_inner(content, Some(outboard), hash, limits)
+    }
+
+    fn open_inner(input: T, outboard: Option<O>, hash: &Hash, limits: &Limits) -> io::Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        match &outboard {
+            Some(outboard) => read_exact_at(outboard, 0, &mut header)?,
+            None => read_exact_at(&input, 0, &mut header)?,
+        }
+        limits.check_header(&header)?;
+        let reader = Self {
+            shared: Arc::new(SharedReaderInner {
+                input,
+                outboard,
+                root_hash: *hash,
+                content_len: crate::decode_len(&header),
+                parents: RwLock::new(HashMap::new()),
+            }),
+        };
+        // Verify the final chunk, which verifies the length. This is the "final chunk
+        // requirement" from the spec.
+        let mut chunk_buf = [0; CHUNK_SIZE];
+        reader.verify_chunk(encode::count_chunks(reader.len()) - 1, &mut chunk_buf)?;
+        Ok(reader)
+    }
+
+    /// The content length. This has been verified.
+    pub fn len(&self) -> u64 {
+        self.shared.content_len
+    }
+
+    /// Returns `true` if the content is empty.
+    pub fn is_empty(&self) -> bool {
+        self.shared.content_len == 0
+    }
+
+    /// Read content bytes starting at `offset` into `buf`, and return how many were read. This
+    /// only reads fewer bytes than `buf.len()` at the end of the content, and it returns 0 for an
+    /// `offset` at or past the end.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let content_len = self.shared.content_len;
+        if offset >= content_len {
+            return Ok(0);
+        }
+        let total = cmp::min(buf.len() as u64, content_len - offset) as usize;
+        let mut chunk_buf = [0; CHUNK_SIZE];
+        let mut done = 0;
+        while done < total {
+            let position = offset + done as u64;
+            let chunk_index = position / CHUNK_SIZE as u64;
+            let chunk_len = self.verify_chunk(chunk_index, &mut chunk_buf)?;
+            let skip = (position % CHUNK_SIZE as u64) as usize;
+            let take = cmp::min(chunk_len - skip, total - done);
+            buf[done..][..take].copy_from_slice(&chunk_buf[skip..][..take]);
+            done += take;
+        }
+        Ok(total)
+    }
+
+    fn subtree_size(&self, content_len: u64) -> u128 {
+        if self.shared.outboard.is_some() {
+            encode::outboard_subtree_size(content_len)
+        } else {
+            encode::encoded_subtree_size(content_len)
+        }
+    }
+
+    // Return the verified children of the parent node at `offset`, reading and verifying it if
+    // it isn't cached. A poisoned lock is fine to keep using, because the cache only ever holds
+    // verified entries.
+    fn parent_children(
+        &self,
+        offset: u128,
+        expected: &Hash,
+        finalization: Finalization,
+    ) -> io::Result<(Hash, Hash)> {
+        let parents = &self.shared.parents;
+        if let Some(children) = parents
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&offset)
+        {
+            return Ok(*children);
+        }
+        let mut parent = [0; PARENT_SIZE];
+        let offset_u64 = encode::EncodedOffset::new(offset).to_u64()?;
+        match &self.shared.outboard {
+            Some(outboard) => read_exact_at(outboard, offset_u64, &mut parent)?,
+            None => read_exact_at(&self.shared.input, offset_u64, &mut parent)?,
+        }
+        let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        let computed = crate::parent_cv(&left_child, &right_child, finalization.is_root());
+        // Hash implements constant time equality.
+        if &computed != expected {
+            return Err(Error::HashMismatch.into());
+        }
+        let mut parents = parents.write().unwrap_or_else(PoisonError::into_inner);
+        if parents.len() >= MAX_SHARED_PARENTS {
+            parents.clear();
+        }
+        parents.insert(offset, (left_child, right_child));
+        Ok((left_child, right_child))
+    }
+
+    // Read and verify a chunk into chunk_buf, and return its length.
+    fn verify_chunk(
+        &self,
+        chunk_index: u64,
+        chunk_buf: &mut [u8; CHUNK_SIZE],
+    ) -> io::Result<usize> {
+        let chunk_start = chunk_index * CHUNK_SIZE as u64;
+        let mut subtree_start = 0;
+        let mut subtree_len = self.shared.content_len;
+        let mut offset = HEADER_SIZE as u128;
+        let mut expected = self.shared.root_hash;
+        let mut finalization = Finalization::Root;
+        while subtree_len > CHUNK_SIZE as u64 {
+            let (left_child, right_child) =
+                self.parent_children(offset, &expected, finalization)?;
+            let left_len = encode::left_subtree_len(subtree_len);
+            offset += PARENT_SIZE as u128;
+            if chunk_start < subtree_start + left_len {
+                subtree_len = left_len;
+                expected = left_child;
+            } else {
+                offset += self.subtree_size(left_len);
+                subtree_start += left_len;
+                subtree_len -= left_len;
+                expected = right_child;
+            }
+            finalization = Finalization::NotRoot;
+        }
+        let chunk_len = subtree_len as usize;
+        let chunk_offset = if self.shared.outboard.is_some() {
+            chunk_start
+        } else {
+            encode::EncodedOffset::new(offset).to_u64()?
+        };
+        read_exact_at(
+            &self.shared.input,
+            chunk_offset,
+            &mut chunk_buf[..chunk_len],
+        )?;
+        let computed =
+            crate::chunk_cv(chunk_index, &chunk_buf[..chunk_len], finalization.is_root());
+        // Hash implements constant time equality.
+        if computed != expected {
+            return Err(Error::HashMismatch.into());
+        }
+        Ok(chunk_len)
+    }
+}
+
+// The verified content is itself positional storage, so a `backend::ReadAtCursor` over a
+// SharedReader is a verified `Read + Seek`, and SharedReaders can stack.
+impl<T: ReadAt, O: ReadAt> ReadAt for SharedReader<T, O> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        SharedReader::read_at(self, offset, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len())
+    }
+}
+
+impl<T: ReadAt, O: ReadAt> Clone for SharedReader<T, O> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T: ReadAt, O: ReadAt> fmt::Debug for SharedReader<T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        write!(
+            f,
+            "SharedReader {{ is_outboard: {}, content_len: {}, cached_parents: {} }}",
+            self.shared.outboard.is_some(),
+            self.shared.content_len,
+            self.shared
+                .parents
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .len(),
+        )
+    }
+}
+
+// Like read_exact, but for ReadAt.
+fn read_exact_at(source: &impl ReadAt, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    if source.read_at(offset, buf)? < buf.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+// Like read_exact, but returns false instead of an error at EOF.
+fn read_fully(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<bool> {
+    Ok(read_up_to(reader, buf)? == buf.len())
+}
+
+// Like read_exact, but returns how much was read before EOF, instead of an error.
+fn read_up_to(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+pub(crate) fn make_test_input(len: usize) -> Vec<u8> {
+    // Fill the input with incrementing bytes, so that reads from different sections are very
     // unlikely to accidentally match.
     let mut ret = Vec::new();
     let mut counter = 0u64;
@@ -710,101 +2972,1246 @@ pub(crate) fn make_test_input(len: usize) -> Vec<u8> {
         } else {
             ret.extend_from_slice(&(counter as u64).to_be_bytes());
         }
-        counter += 1;
+        counter += 1;
+    }
+    ret.truncate(len);
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::encode;
+
+    #[test]
+    fn test_decode() {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = { encode::encode(&input) };
+            let output = decode(&encoded, &hash).unwrap();
+            assert_eq!(input, output);
+            assert_eq!(output.len(), output.capacity());
+        }
+    }
+
+    #[test]
+    fn test_decode_outboard() {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (outboard, hash) = { encode::outboard(&input) };
+            let mut output = Vec::new();
+            let mut reader = Decoder::new_outboard(&input[..], &outboard[..], &hash);
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+        }
+    }
+
+    #[test]
+    fn test_decoders_corrupted() {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            // Don't tweak the header in this test, because that usually causes a panic.
+            let mut tweaks = Vec::new();
+            if encoded.len() > HEADER_SIZE {
+                tweaks.push(HEADER_SIZE);
+            }
+            if encoded.len() > HEADER_SIZE + PARENT_SIZE {
+                tweaks.push(HEADER_SIZE + PARENT_SIZE);
+            }
+            if encoded.len() > CHUNK_SIZE {
+                tweaks.push(CHUNK_SIZE);
+            }
+            for tweak in tweaks {
+                println!("tweak {}", tweak);
+                let mut bad_encoded = encoded.clone();
+                bad_encoded[tweak] ^= 1;
+
+                let err = decode(&bad_encoded, &hash).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seek() {
+        for input_len in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            println!();
+            println!("input_len {}", input_len);
+            let input = make_test_input(input_len);
+            let (encoded, hash) = encode::encode(&input);
+            for &seek in crate::test::TEST_CASES {
+                println!("seek {}", seek);
+                // Test all three types of seeking.
+                let mut seek_froms = Vec::new();
+                seek_froms.push(SeekFrom::Start(seek as u64));
+                seek_froms.push(SeekFrom::End(seek as i64 - input_len as i64));
+                seek_froms.push(SeekFrom::Current(seek as i64));
+                for seek_from in seek_froms {
+                    println!("seek_from {:?}", seek_from);
+                    let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+                    let mut output = Vec::new();
+                    decoder.seek(seek_from).expect("seek error");
+                    decoder.read_to_end(&mut output).expect("decoder error");
+                    let input_start = cmp::min(seek, input.len());
+                    assert_eq!(
+                        &input[input_start..],
+                        &output[..],
+                        "output doesn't match input"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_repeated_random_seeks() {
+        // A chunk number like this (37) with consecutive zeroes should exercise some of the more
+        // interesting geometry cases.
+        let input_len = 0b100101 * CHUNK_SIZE;
+        println!("\n\ninput_len {}", input_len);
+        let mut prng = ChaChaRng::from_seed([0; 32]);
+        let input = make_test_input(input_len);
+        let (encoded, hash) = encode::encode(&input);
+        let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+        // Do a thousand random seeks and chunk-sized reads.
+        for _ in 0..1000 {
+            let seek = prng.gen_range(0..input_len + 1);
+            println!("\nseek {}", seek);
+            decoder
+                .seek(SeekFrom::Start(seek as u64))
+                .expect("seek error");
+            // Clone the encoder before reading, to test repeated seeks on the same encoder.
+            let mut output = Vec::new();
+            decoder
+                .clone()
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut output)
+                .expect("decoder error");
+            let input_start = cmp::min(seek, input_len);
+            let input_end = cmp::min(input_start + CHUNK_SIZE, input_len);
+            assert_eq!(
+                &input[input_start..input_end],
+                &output[..],
+                "output doesn't match input"
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_zero_length() {
+        // There are different ways of structuring a decoder, and many of them are vulnerable to a
+        // mistake where as soon as the decoder reads zero length, it believes it's finished. But
+        // it's not finished, because it hasn't verified the hash! There must be something to
+        // distinguish the state "just decoded the zero length" from the state "verified the hash
+        // of the empty root node", and a decoder must not return EOF before the latter.
+
+        let (zero_encoded, zero_hash) = encode::encode(b"");
+        let one_hash = blake3::hash(b"x");
+
+        // Decoding the empty tree with the right hash should succeed.
+        let mut output = Vec::new();
+        let mut decoder = Decoder::new(&*zero_encoded, &zero_hash);
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(&output, &[0u8; 0]);
+
+        // Decoding the empty tree with any other hash should fail.
+        let mut output = Vec::new();
+        let mut decoder = Decoder::new(&*zero_encoded, &one_hash);
+        let result = decoder.read_to_end(&mut output);
+        assert!(result.is_err(), "a bad hash is supposed to fail!");
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_empty_content_verifies_root() {
+        let (encoded, hash) = encode::encode(b"");
+        let (outboard, _) = encode::outboard(b"");
+        assert_eq!(HEADER_SIZE, encoded.len());
+        let bad_hash = blake3::hash(b"x");
+        let check = |result: io::Result<u64>, good: bool| match result {
+            Ok(n) => assert!(good && n == 0),
+            Err(e) => assert!(!good && e.kind() == io::ErrorKind::InvalidData),
+        };
+        for (root, good) in [(hash, true), (bad_hash, false)] {
+            println!("good {}", good);
+            check(decode(&encoded, &root).map(|v| v.len() as u64), good);
+            check(verify(&*encoded, &root), good);
+            check(verify_outboard(&b""[..], &*outboard, &root), good);
+            check(
+                SharedReader::open(encoded.clone(), &root).map(|r| r.len()),
+                good,
+            );
+
+            // Every kind of read, including a zero-length one, has to check the root before it
+            // can say there's nothing there.
+            let mut decoder = Decoder::new(&*encoded, &root);
+            check(decoder.read(&mut []).map(|n| n as u64), good);
+            let mut decoder = Decoder::new(&*encoded, &root);
+            check(decoder.read(&mut [0; 10]).map(|n| n as u64), good);
+            let mut decoder = Decoder::new_outboard(&b""[..], &*outboard, &root);
+            check(decoder.read(&mut []).map(|n| n as u64), good);
+            let mut decoder = Decoder::new(&*encoded, &root);
+            let next = decoder
+                .read_next_chunk()
+                .map(|chunk| chunk.map_or(0, |c| c.len()));
+            check(next.map(|n| n as u64), good);
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &root);
+            check(decoder.seek(SeekFrom::End(0)), good);
+            let mut decoder = ParallelDecoder::new(&*encoded, &root);
+            check(decoder.read(&mut []).map(|n| n as u64), good);
+
+            // Slices of empty content, at any position and of any length, are just the header.
+            for (start, len) in [(0, 0), (0, 1), (5, 0), (5, 5)] {
+                let mut slice = Vec::new();
+                encode::SliceExtractor::new(Cursor::new(&encoded), start, len)
+                    .read_to_end(&mut slice)
+                    .unwrap();
+                assert_eq!(encoded, slice);
+                let mut decoder = SliceDecoder::new(&*slice, &root, start, len);
+                check(decoder.read_to_end(&mut Vec::new()).map(|n| n as u64), good);
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_length_read_verifies_next_chunk() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let mut decoder = Decoder::new(&*encoded, &hash);
+        assert_eq!(0, decoder.read(&mut []).unwrap());
+        // The chunk stays buffered for the next read.
+        assert_eq!(0, decoder.position());
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(input, output);
+
+        // Corrupt the first chunk, which comes after the header and two parents.
+        encoded[HEADER_SIZE + 2 * PARENT_SIZE] ^= 1;
+        let mut decoder = Decoder::new(&*encoded, &hash);
+        let err = decoder.read(&mut []).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let mut decoder = ParallelDecoder::new(&*encoded, &hash);
+        let err = decoder.read(&mut []).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // A zero-length slice includes the chunk at its start, and a zero-length read at the end
+        // of a slice doesn't read past it.
+        let (encoded, _) = encode::encode(&input);
+        for start in [0, CHUNK_SIZE as u64, 10 * CHUNK_SIZE as u64] {
+            let mut slice = Vec::new();
+            encode::SliceExtractor::new(Cursor::new(&encoded), start, 0)
+                .read_to_end(&mut slice)
+                .unwrap();
+            let mut decoder = SliceDecoder::new(&*slice, &hash, start, 0);
+            assert_eq!(0, decoder.read(&mut [0; 10]).unwrap());
+            assert_eq!(0, decoder.read(&mut []).unwrap());
+            let last = slice.len() - 1;
+            slice[last] ^= 1;
+            let mut decoder = SliceDecoder::new(&*slice, &hash, start, 0);
+            assert!(decoder.read(&mut [0; 10]).is_err());
+        }
+        let mut slice = Vec::new();
+        encode::SliceExtractor::new(Cursor::new(&encoded), 0, CHUNK_SIZE as u64)
+            .read_to_end(&mut slice)
+            .unwrap();
+        let mut decoder = SliceDecoder::new(&*slice, &hash, 0, CHUNK_SIZE as u64);
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(0, decoder.read(&mut []).unwrap());
+        assert_eq!(&input[..CHUNK_SIZE], &output[..]);
+    }
+
+    #[test]
+    fn test_seeking_around_invalid_data() {
+        for &case in crate::test::TEST_CASES {
+            // Skip the cases with only one or two chunks, so we have valid
+            // reads before and after the tweak.
+            if case <= 2 * CHUNK_SIZE {
+                continue;
+            }
+
+            println!("\ncase {}", case);
+            let input = make_test_input(case);
+            let (mut encoded, hash) = encode::encode(&input);
+            println!("encoded len {}", encoded.len());
+
+            // Tweak a bit at the start of a chunk about halfway through. Loop
+            // over prior parent nodes and chunks to figure out where the
+            // target chunk actually starts.
+            let tweak_chunk = encode::count_chunks(case as u64) / 2;
+            let tweak_position = tweak_chunk as usize * CHUNK_SIZE;
+            println!("tweak position {}", tweak_position);
+            let mut tweak_encoded_offset = HEADER_SIZE;
+            for chunk in 0..tweak_chunk {
+                tweak_encoded_offset +=
+                    encode::pre_order_parent_nodes(chunk, case as u64) as usize * PARENT_SIZE;
+                tweak_encoded_offset += CHUNK_SIZE;
+            }
+            tweak_encoded_offset +=
+                encode::pre_order_parent_nodes(tweak_chunk, case as u64) as usize * PARENT_SIZE;
+            println!("tweak encoded offset {}", tweak_encoded_offset);
+            encoded[tweak_encoded_offset] ^= 1;
+
+            // Read all the bits up to that tweak. Because it's right after a chunk boundary, the
+            // read should succeed.
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+            let mut output = vec![0; tweak_position as usize];
+            decoder.read_exact(&mut output).unwrap();
+            assert_eq!(&input[..tweak_position], &*output);
+
+            // Further reads at this point should fail.
+            let mut buf = [0; CHUNK_SIZE];
+            let res = decoder.read(&mut buf);
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            // But now if we seek past the bad chunk, things should succeed again.
+            let new_start = tweak_position + CHUNK_SIZE;
+            decoder.seek(SeekFrom::Start(new_start as u64)).unwrap();
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(&input[new_start..], &*output);
+        }
+    }
+
+    #[test]
+    fn test_invalid_eof_seek() {
+        // The decoder must validate the final chunk as part of seeking to or
+        // past EOF.
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+
+            // Seeking to EOF should succeed with the right hash.
+            let mut output = Vec::new();
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+            decoder.seek(SeekFrom::Start(case as u64)).unwrap();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(&output, &[0u8; 0]);
+
+            // Seeking to EOF should fail if the root hash is wrong.
+            let mut bad_hash_bytes = *hash.as_bytes();
+            bad_hash_bytes[0] ^= 1;
+            let bad_hash = bad_hash_bytes.into();
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &bad_hash);
+            let result = decoder.seek(SeekFrom::Start(case as u64));
+            assert!(result.is_err(), "a bad hash is supposed to fail!");
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            // It should also fail if the final chunk has been corrupted.
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                let mut decoder = Decoder::new(Cursor::new(&bad_encoded), &hash);
+                let result = decoder.seek(SeekFrom::Start(case as u64));
+                assert!(result.is_err(), "a bad hash is supposed to fail!");
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            }
+        }
+    }
+
+    #[test]
+    fn test_slices() {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            // Also make an outboard encoding, to test that case.
+            let (outboard, outboard_hash) = encode::outboard(&input);
+            assert_eq!(hash, outboard_hash);
+            for &slice_start in crate::test::TEST_CASES {
+                let expected_start = cmp::min(input.len(), slice_start);
+                let slice_lens = [0, 1, 2, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1];
+                for &slice_len in slice_lens.iter() {
+                    println!("\ncase {} start {} len {}", case, slice_start, slice_len);
+                    let expected_end = cmp::min(input.len(), slice_start + slice_len);
+                    let expected_output = &input[expected_start..expected_end];
+                    let mut slice = Vec::new();
+                    let mut extractor = encode::SliceExtractor::new(
+                        Cursor::new(&encoded),
+                        slice_start as u64,
+                        slice_len as u64,
+                    );
+                    extractor.read_to_end(&mut slice).unwrap();
+
+                    // Make sure the outboard extractor produces the same output.
+                    let mut slice_from_outboard = Vec::new();
+                    let mut extractor = encode::SliceExtractor::new_outboard(
+                        Cursor::new(&input),
+                        Cursor::new(&outboard),
+                        slice_start as u64,
+                        slice_len as u64,
+                    );
+                    extractor.read_to_end(&mut slice_from_outboard).unwrap();
+                    assert_eq!(slice, slice_from_outboard);
+
+                    let mut output = Vec::new();
+                    let mut reader =
+                        SliceDecoder::new(&*slice, &hash, slice_start as u64, slice_len as u64);
+                    reader.read_to_end(&mut output).unwrap();
+                    assert_eq!(expected_output, &*output);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupted_slice() {
+        let input = make_test_input(20_000);
+        let slice_start = 5_000;
+        let slice_len = 10_000;
+        let (encoded, hash) = encode::encode(&input);
+
+        // Slice out the middle 10_000 bytes;
+        let mut slice = Vec::new();
+        let mut extractor = encode::SliceExtractor::new(
+            Cursor::new(&encoded),
+            slice_start as u64,
+            slice_len as u64,
+        );
+        extractor.read_to_end(&mut slice).unwrap();
+
+        // First confirm that the regular decode works.
+        let mut output = Vec::new();
+        let mut reader = SliceDecoder::new(&*slice, &hash, slice_start as u64, slice_len as u64);
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(&input[slice_start..][..slice_len], &*output);
+
+        // Also confirm that the outboard slice extractor gives the same slice.
+        let (outboard, outboard_hash) = encode::outboard(&input);
+        assert_eq!(hash, outboard_hash);
+        let mut slice_from_outboard = Vec::new();
+        let mut extractor = encode::SliceExtractor::new_outboard(
+            Cursor::new(&input),
+            Cursor::new(&outboard),
+            slice_start as u64,
+            slice_len as u64,
+        );
+        extractor.read_to_end(&mut slice_from_outboard).unwrap();
+        assert_eq!(slice, slice_from_outboard);
+
+        // Now confirm that flipping bits anywhere in the slice other than the
+        // length header will corrupt it. Tweaking the length header doesn't
+        // always break slice decoding, because the only thing its guaranteed
+        // to break is the final chunk, and this slice doesn't include the
+        // final chunk.
+        let mut i = HEADER_SIZE;
+        while i < slice.len() {
+            let mut slice_clone = slice.clone();
+            slice_clone[i] ^= 1;
+            let mut reader =
+                SliceDecoder::new(&*slice_clone, &hash, slice_start as u64, slice_len as u64);
+            output.clear();
+            let err = reader.read_to_end(&mut output).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            i += 32;
+        }
+    }
+
+    #[test]
+    fn test_slice_entire() {
+        // If a slice starts at the beginning (actually anywere in the first chunk) and includes
+        // entire length of the content (or at least one byte in the last chunk), the slice should
+        // be exactly the same as the entire encoded tree. This can act as a cheap way to convert
+        // an outboard tree to a combined one.
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, _) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            let mut slice = Vec::new();
+            let mut extractor = encode::SliceExtractor::new_outboard(
+                Cursor::new(&input),
+                Cursor::new(&outboard),
+                0,
+                case as u64,
+            );
+            extractor.read_to_end(&mut slice).unwrap();
+            assert_eq!(encoded, slice);
+        }
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let v = vec![1u8, 2, 3];
+        let hash = [0; 32].into();
+
+        let decoder = Decoder::new(io::Cursor::new(v.clone()), &hash);
+        let slice_decoder = SliceDecoder::new(decoder.into_inner(), &hash, 0, 0);
+        assert_eq!(slice_decoder.into_inner().into_inner(), v);
+    }
+
+    fn read_in_small_pieces(mut reader: impl Read) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(output);
+            }
+            output.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn test_tolerant_corrupt_chunks() {
+        let case = 8 * CHUNK_SIZE + 1;
+        let input = make_test_input(case);
+        let (encoded, hash) = encode::encode(&input);
+        for chunk in 0..8 {
+            println!("chunk {}", chunk);
+            let mut bad_encoded = encoded.clone();
+            let offset = encode::chunk_encoded_offset(chunk, case as u64);
+            bad_encoded[offset as usize + 1] ^= 1;
+            let range = chunk * CHUNK_SIZE as u64..(chunk + 1) * CHUNK_SIZE as u64;
+            let mut expected = input.clone();
+            for byte in &mut expected[range.start as usize..range.end as usize] {
+                *byte = 0xff;
+            }
+
+            let mut output = Vec::new();
+            let mut decoder = Decoder::new(&*bad_encoded, &hash).tolerant(0xff);
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(expected, output);
+            assert_eq!(vec![range.clone()], decoder.corrupted_ranges());
+
+            // Small reads go through the internal buffer rather than reading directly into the
+            // output.
+            let mut decoder = Decoder::new(&*bad_encoded, &hash).tolerant(0xff);
+            assert_eq!(expected, read_in_small_pieces(&mut decoder).unwrap());
+            assert_eq!(vec![range], decoder.corrupted_ranges());
+
+            // Without tolerance, decoding fails as usual.
+            let err = decode(&bad_encoded, &hash).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_tolerant_corrupt_parent() {
+        // The parent node after the root covers the first 8 chunks, and the final chunk is outside
+        // of it. Adjacent corrupt ranges get merged.
+        let case = 8 * CHUNK_SIZE + 1;
+        let input = make_test_input(case);
+        let mut expected = input.clone();
+        for byte in &mut expected[..8 * CHUNK_SIZE] {
+            *byte = 0;
+        }
+        let (mut encoded, hash) = encode::encode(&input);
+        encoded[HEADER_SIZE + PARENT_SIZE] ^= 1;
+        let chunk_7 = encode::chunk_encoded_offset(7, case as u64) as usize;
+        encoded[chunk_7] ^= 1;
+        let mut decoder = Decoder::new(&*encoded, &hash).tolerant(0);
+        assert_eq!(expected, read_in_small_pieces(&mut decoder).unwrap());
+        assert_eq!(vec![0..8 * CHUNK_SIZE as u64], decoder.corrupted_ranges());
+
+        let (mut outboard, _) = encode::outboard(&input);
+        outboard[HEADER_SIZE + PARENT_SIZE] ^= 1;
+        let mut output = Vec::new();
+        let mut decoder = Decoder::new_outboard(&*input, &*outboard, &hash).tolerant(0);
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(expected, output);
+        assert_eq!(vec![0..8 * CHUNK_SIZE as u64], decoder.corrupted_ranges());
+    }
+
+    #[test]
+    fn test_tolerant_right_edge_is_fatal() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            // Corrupting the root node, or the last byte of the final chunk, is always an error.
+            if case == 0 {
+                continue;
+            }
+            for &offset in &[HEADER_SIZE, encoded.len() - 1] {
+                let mut bad_encoded = encoded.clone();
+                bad_encoded[offset] ^= 1;
+                let mut decoder = Decoder::new(&*bad_encoded, &hash).tolerant(0);
+                let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_tolerant_after_seek() {
+        let case = 4 * CHUNK_SIZE;
+        let input = make_test_input(case);
+        let (mut encoded, hash) = encode::encode(&input);
+        let chunk_1 = encode::chunk_encoded_offset(1, case as u64) as usize;
+        encoded[chunk_1] ^= 1;
+        let mut decoder = Decoder::new(Cursor::new(&encoded), &hash).tolerant(0);
+        decoder.seek(SeekFrom::Start(1500)).unwrap();
+        let mut output = [0xff; 600];
+        decoder.read_exact(&mut output).unwrap();
+        assert_eq!(&[0; 548][..], &output[..548]);
+        assert_eq!(&input[2048..2100], &output[548..]);
+        assert_eq!(vec![1500..2048], decoder.corrupted_ranges());
+        // The seek position accounts for filler bytes we haven't returned yet.
+        decoder.seek(SeekFrom::Start(1700)).unwrap();
+        let mut output = [0xff; 10];
+        decoder.read_exact(&mut output).unwrap();
+        assert_eq!([0; 10], output);
+        assert_eq!(1710, decoder.stream_position().unwrap());
+        assert_eq!(vec![1500..2048], decoder.corrupted_ranges());
+    }
+
+    fn read_all_chunks<T: Read, O: Read>(decoder: &mut Decoder<T, O>) -> io::Result<Vec<Vec<u8>>> {
+        let mut chunks = Vec::new();
+        while let Some(chunk) = decoder.read_next_chunk()? {
+            chunks.push(chunk.to_vec());
+        }
+        Ok(chunks)
+    }
+
+    #[test]
+    fn test_read_next_chunk() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            let expected: Vec<Vec<u8>> = input.chunks(CHUNK_SIZE).map(|c| c.to_vec()).collect();
+
+            let mut decoder = Decoder::new(&*encoded, &hash);
+            assert_eq!(expected, read_all_chunks(&mut decoder).unwrap());
+            // EOF is sticky.
+            assert!(decoder.read_next_chunk().unwrap().is_none());
+
+            let mut decoder = Decoder::new_outboard(&*input, &*outboard, &hash);
+            assert_eq!(expected, read_all_chunks(&mut decoder).unwrap());
+
+            // A partial read leaves the rest of the chunk for read_next_chunk. With one byte of
+            // input, there's no rest.
+            if case > 1 {
+                let mut decoder = Decoder::new(&*encoded, &hash);
+                let mut first_byte = [0];
+                decoder.read_exact(&mut first_byte).unwrap();
+                let rest = decoder.read_next_chunk().unwrap().unwrap().to_vec();
+                assert_eq!(&expected[0], &[&first_byte[..], &rest[..]].concat());
+                assert_eq!(&expected[1..], &read_all_chunks(&mut decoder).unwrap()[..]);
+            }
+        }
     }
-    ret.truncate(len);
-    ret
-}
 
-#[cfg(test)]
-mod test {
-    use rand::prelude::*;
-    use rand_chacha::ChaChaRng;
-    use std::io;
-    use std::io::prelude::*;
-    use std::io::Cursor;
+    #[test]
+    fn test_read_next_chunk_after_seek() {
+        let input = make_test_input(3 * CHUNK_SIZE + 10);
+        let (encoded, hash) = encode::encode(&input);
+        let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+        decoder.seek(SeekFrom::Start(1500)).unwrap();
+        let chunks = read_all_chunks(&mut decoder).unwrap();
+        assert_eq!(&input[1500..2048], &chunks[0][..]);
+        assert_eq!(&input[2048..3072], &chunks[1][..]);
+        assert_eq!(&input[3072..], &chunks[2][..]);
+        assert_eq!(3, chunks.len());
+    }
 
-    use super::*;
-    use crate::encode;
+    #[test]
+    fn test_read_next_chunk_corrupt() {
+        let case = 4 * CHUNK_SIZE;
+        let input = make_test_input(case);
+        let (mut encoded, hash) = encode::encode(&input);
+        let chunk_2 = encode::chunk_encoded_offset(2, case as u64) as usize;
+        encoded[chunk_2] ^= 1;
+
+        let mut decoder = Decoder::new(&*encoded, &hash);
+        assert_eq!(
+            &input[..CHUNK_SIZE],
+            decoder.read_next_chunk().unwrap().unwrap()
+        );
+        assert_eq!(
+            &input[CHUNK_SIZE..][..CHUNK_SIZE],
+            decoder.read_next_chunk().unwrap().unwrap()
+        );
+        let err = decoder.read_next_chunk().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // In tolerant mode the corrupt chunk comes back as filler, and it's still chunk aligned.
+        let mut decoder = Decoder::new(&*encoded, &hash).tolerant(0);
+        let chunks = read_all_chunks(&mut decoder).unwrap();
+        assert_eq!(4, chunks.len());
+        assert_eq!(vec![0; CHUNK_SIZE], chunks[2]);
+        assert_eq!(&input[3 * CHUNK_SIZE..], &chunks[3][..]);
+    }
 
     #[test]
-    fn test_decode() {
-        for &case in crate::test::TEST_CASES {
+    fn test_parallel_decoder() {
+        let mut cases = crate::test::TEST_CASES.to_vec();
+        cases.extend_from_slice(&[
+            PARALLEL_SUBTREE_LEN as usize,
+            PARALLEL_SUBTREE_LEN as usize + 1,
+            1_000_000,
+        ]);
+        for &case in &cases {
             println!("case {}", case);
             let input = make_test_input(case);
-            let (encoded, hash) = { encode::encode(&input) };
-            let output = decode(&encoded, &hash).unwrap();
-            assert_eq!(input, output);
-            assert_eq!(output.len(), output.capacity());
+            let (encoded, hash) = encode::encode(&input);
+            for &threads in &[1, 3] {
+                let mut output = Vec::new();
+                let mut decoder = ParallelDecoder::with_threads(&*encoded, &hash, threads);
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(input, output);
+            }
+            let mut decoder = ParallelDecoder::new(&*encoded, &hash);
+            assert_eq!(input, read_in_small_pieces(&mut decoder).unwrap());
         }
     }
 
     #[test]
-    fn test_decode_outboard() {
-        for &case in crate::test::TEST_CASES {
+    fn test_parallel_decoder_corrupt() {
+        let case = 1_000_000;
+        let input = make_test_input(case);
+        let (encoded, hash) = encode::encode(&input);
+        // Corrupt the root node, a chunk in the middle, and the last byte. The last one checks
+        // that the final chunk gets verified before EOF.
+        for &offset in &[HEADER_SIZE, encoded.len() / 2, encoded.len() - 1] {
+            println!("offset {}", offset);
+            let mut bad_encoded = encoded.clone();
+            bad_encoded[offset] ^= 1;
+            let mut decoder = ParallelDecoder::with_threads(&*bad_encoded, &hash, 4);
+            let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+        // A truncated encoding is an error too.
+        let mut decoder = ParallelDecoder::new(&encoded[..encoded.len() - 1], &hash);
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_readahead() {
+        for case in crate::test_vectors::test_cases(8) {
             println!("case {}", case);
             let input = make_test_input(case);
-            let (outboard, hash) = { encode::outboard(&input) };
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            for &chunks in &[0, 1, 4] {
+                let mut output = Vec::new();
+                Decoder::new(Cursor::new(encoded.clone()), &hash)
+                    .with_readahead(chunks)
+                    .read_to_end(&mut output)
+                    .unwrap();
+                assert_eq!(input, output);
+            }
+            let mut decoder =
+                Decoder::new_outboard(Cursor::new(input.clone()), Cursor::new(outboard), &hash)
+                    .with_readahead(2);
+            assert_eq!(input, read_in_small_pieces(&mut decoder).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_readahead_corrupt() {
+        let input = make_test_input(10 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        let mut decoder = Decoder::new(Cursor::new(encoded), &hash).with_readahead(4);
+        let mut output = Vec::new();
+        let err = decoder.read_to_end(&mut output).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(&input[..9 * CHUNK_SIZE], &output[..]);
+        // The error doesn't turn into EOF.
+        assert!(decoder.read(&mut [0; 10]).is_err());
+    }
+
+    #[test]
+    fn test_readahead_is_bounded() {
+        // Count how much of the encoding the background thread has read.
+        struct CountingReader {
+            inner: Cursor<Vec<u8>>,
+            count: Arc<std::sync::atomic::AtomicUsize>,
+        }
+
+        impl Read for CountingReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.inner.read(buf)?;
+                self.count.fetch_add(n, std::sync::atomic::Ordering::SeqCst);
+                Ok(n)
+            }
+        }
+
+        let input = make_test_input(100 * CHUNK_SIZE);
+        let (encoded, hash) = encode::encode(&input);
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let reader = CountingReader {
+            inner: Cursor::new(encoded.clone()),
+            count: Arc::clone(&count),
+        };
+        let chunks = 4;
+        let mut decoder = Decoder::new(reader, &hash).with_readahead(chunks);
+        let mut first = [0; 1];
+        decoder.read_exact(&mut first).unwrap();
+        // Besides the chunk the caller is reading, the background thread verifies `chunks` more
+        // and then waits. Give it plenty of time to go too far, if it's going to.
+        let len = input.len() as u64;
+        let ahead = encode::chunk_encoded_offset(chunks as u64, len) + CHUNK_SIZE as u128;
+        let too_far = encode::chunk_encoded_offset(chunks as u64 + 1, len);
+        let loaded = |count: &std::sync::atomic::AtomicUsize| {
+            count.load(std::sync::atomic::Ordering::SeqCst) as u128
+        };
+        for _ in 0..100 {
+            if loaded(&count) >= ahead {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(loaded(&count) >= ahead);
+        assert!(loaded(&count) <= too_far);
+        let mut output = first.to_vec();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_max_len() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+        let len = input.len() as u64;
+        let mut extractor =
+            encode::SliceExtractor::new(Cursor::new(&encoded), CHUNK_SIZE as u64, 1);
+        let mut slice = Vec::new();
+        extractor.read_to_end(&mut slice).unwrap();
+
+        // Exactly at the limit is fine.
+        let mut output = Vec::new();
+        Decoder::new(&*encoded, &hash)
+            .with_max_len(len)
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(input, output);
+
+        fn assert_too_long(result: io::Result<usize>) {
+            let err = result.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert_eq!(Error::TooLong.to_string(), err.to_string());
+        }
+        let mut output = Vec::new();
+        assert_too_long(
+            Decoder::new(&*encoded, &hash)
+                .with_max_len(len - 1)
+                .read_to_end(&mut output),
+        );
+        assert_too_long(
+            Decoder::new_outboard(&*input, &*outboard, &hash)
+                .with_max_len(len - 1)
+                .read_to_end(&mut output),
+        );
+        assert_too_long(
+            SliceDecoder::new(&*slice, &hash, CHUNK_SIZE as u64, 1)
+                .with_max_len(len - 1)
+                .read_to_end(&mut output),
+        );
+        assert_too_long(
+            ParallelDecoder::new(&*encoded, &hash)
+                .with_max_len(len - 1)
+                .read_to_end(&mut output),
+        );
+        // Seeking reads the header too.
+        let mut decoder = Decoder::new(Cursor::new(&encoded), &hash).with_max_len(len - 1);
+        let err = decoder.seek(SeekFrom::End(0)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        // Nothing gets returned, even though the first chunk is valid.
+        assert!(output.is_empty());
+
+        // Limits compose with with_max_len in either order, and the stricter one wins.
+        let depth_only = Limits {
+            max_depth: MAX_DEPTH,
+            ..Limits::default()
+        };
+        assert_too_long(
+            Decoder::new(&*encoded, &hash)
+                .with_max_len(len - 1)
+                .with_limits(depth_only)
+                .read_to_end(&mut output),
+        );
+        assert_too_long(
+            SliceDecoder::new(&*slice, &hash, CHUNK_SIZE as u64, 1)
+                .with_limits(depth_only)
+                .with_max_len(len - 1)
+                .read_to_end(&mut output),
+        );
+        assert_too_long(
+            ParallelDecoder::new(&*encoded, &hash)
+                .with_max_len(len - 1)
+                .with_max_len(len)
+                .read_to_end(&mut output),
+        );
+        assert!(output.is_empty());
+
+        // A huge length header fails immediately, instead of failing at EOF.
+        let mut bad_encoded = encoded.clone();
+        bad_encoded[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        assert_too_long(
+            Decoder::new(&*bad_encoded, &hash)
+                .with_max_len(1 << 30)
+                .read(&mut [0]),
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        assert_eq!(0, tree_depth(0));
+        assert_eq!(0, tree_depth(CHUNK_SIZE as u64));
+        assert_eq!(1, tree_depth(CHUNK_SIZE as u64 + 1));
+        assert_eq!(2, tree_depth(4 * CHUNK_SIZE as u64));
+        assert_eq!(3, tree_depth(4 * CHUNK_SIZE as u64 + 1));
+        assert_eq!(MAX_DEPTH, tree_depth(u64::MAX));
+
+        // Five chunks is a depth of three.
+        let input = make_test_input(5 * CHUNK_SIZE);
+        let (encoded, hash) = encode::encode(&input);
+        let limits = |max_depth| Limits {
+            max_depth,
+            ..Limits::default()
+        };
+        assert_eq!(
+            input,
+            decode_with_limits(&encoded, &hash, &limits(3)).unwrap()
+        );
+        fn assert_too_deep(result: io::Result<usize>) {
+            let err = result.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert_eq!(Error::TooDeep.to_string(), err.to_string());
+        }
+        let mut output = Vec::new();
+        assert_too_deep(
+            Decoder::new(&*encoded, &hash)
+                .with_limits(limits(2))
+                .read_to_end(&mut output),
+        );
+        assert_too_deep(
+            SliceDecoder::new(&*encoded, &hash, 0, 1)
+                .with_limits(limits(2))
+                .read_to_end(&mut output),
+        );
+        assert_too_deep(
+            ParallelDecoder::new(&*encoded, &hash)
+                .with_limits(limits(2))
+                .read_to_end(&mut output),
+        );
+        assert!(output.is_empty());
+        let err = decode_with_limits(&encoded, &hash, &limits(2)).unwrap_err();
+        assert_eq!(Error::TooDeep.to_string(), err.to_string());
+
+        // decode_with_limits checks the length before allocating.
+        let max_content_len = Limits {
+            max_content_len: 100,
+            ..Limits::default()
+        };
+        let err = decode_with_limits(&encoded, &hash, &max_content_len).unwrap_err();
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+    }
+
+    #[test]
+    fn test_parallel_chunk_buffer() {
+        let input = make_test_input(300 * CHUNK_SIZE + 1);
+        let (encoded, hash) = encode::encode(&input);
+        let smallest = 2 * CHUNK_SIZE;
+        for &chunk_buffer in &[0, smallest, 10_000, 100_000, 1 << 20, usize::MAX] {
+            println!("chunk_buffer {}", chunk_buffer);
+            let limits = Limits {
+                chunk_buffer,
+                ..Limits::default()
+            };
+            let mut decoder =
+                ParallelDecoder::with_threads(&*encoded, &hash, 4).with_limits(limits);
+            let (subtree_len, max_subtrees) = decoder.batch_shape();
+            let subtree_memory =
+                subtree_len as usize + encode::encoded_subtree_size(subtree_len) as usize;
+            let batch_memory = subtree_memory * max_subtrees;
+            assert!(batch_memory <= cmp::max(chunk_buffer, smallest));
             let mut output = Vec::new();
-            let mut reader = Decoder::new_outboard(&input[..], &outboard[..], &hash);
-            reader.read_to_end(&mut output).unwrap();
+            let mut buf = [0; 1000];
+            loop {
+                let n = decoder.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                // The buffered content is at most half the batch.
+                assert!(2 * decoder.output.len() <= batch_memory);
+                output.extend_from_slice(&buf[..n]);
+            }
             assert_eq!(input, output);
         }
     }
 
+    // Counts the bytes read through it.
+    struct CountingReader<T> {
+        inner: T,
+        count: std::rc::Rc<std::cell::Cell<u64>>,
+    }
+
+    impl<T: Read> Read for CountingReader<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.count.set(self.count.get() + n as u64);
+            Ok(n)
+        }
+    }
+
+    impl<T: Seek> Seek for CountingReader<T> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    fn random_reads(
+        decoder: &mut impl ReadSeekTest,
+        input: &[u8],
+        rng: &mut ChaChaRng,
+    ) -> io::Result<()> {
+        let mut buf = [0; 100];
+        for _ in 0..200 {
+            let offset = rng.gen_range(0..input.len() - buf.len());
+            decoder.seek(SeekFrom::Start(offset as u64))?;
+            decoder.read_exact(&mut buf)?;
+            assert_eq!(&input[offset..][..buf.len()], &buf[..]);
+        }
+        Ok(())
+    }
+
+    trait ReadSeekTest: Read + Seek {}
+
+    impl<T: Read + Seek> ReadSeekTest for T {}
+
+    // Hides Seek, and counts the bytes read.
+    struct PipeReader<'a> {
+        inner: &'a [u8],
+        bytes_read: u64,
+    }
+
+    impl Read for PipeReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.bytes_read += n as u64;
+            Ok(n)
+        }
+    }
+
     #[test]
-    fn test_decoders_corrupted() {
+    fn test_skip() {
         for &case in crate::test::TEST_CASES {
-            println!("case {}", case);
             let input = make_test_input(case);
             let (encoded, hash) = encode::encode(&input);
-            // Don't tweak the header in this test, because that usually causes a panic.
-            let mut tweaks = Vec::new();
-            if encoded.len() > HEADER_SIZE {
-                tweaks.push(HEADER_SIZE);
-            }
-            if encoded.len() > HEADER_SIZE + PARENT_SIZE {
-                tweaks.push(HEADER_SIZE + PARENT_SIZE);
+            let (outboard, _) = encode::outboard(&input);
+            for &first_read in &[0, 1, CHUNK_SIZE] {
+                for &skip in &[
+                    0,
+                    1,
+                    CHUNK_SIZE - 1,
+                    CHUNK_SIZE,
+                    5 * CHUNK_SIZE,
+                    case,
+                    case + 1,
+                ] {
+                    println!("case {} first_read {} skip {}", case, first_read, skip);
+                    let first_read = cmp::min(first_read, case);
+                    let expected_skip = cmp::min(skip, case - first_read);
+                    let expected_rest = &input[first_read + expected_skip..];
+
+                    let mut decoder = Decoder::new(&encoded[..], &hash);
+                    decoder.read_exact(&mut vec![0; first_read]).unwrap();
+                    let skipped = decoder.skip(skip as u64).unwrap();
+                    assert_eq!(expected_skip as u64, skipped);
+                    assert_eq!((first_read + expected_skip) as u64, decoder.position());
+                    let mut rest = Vec::new();
+                    decoder.read_to_end(&mut rest).unwrap();
+                    assert_eq!(expected_rest, &rest[..]);
+
+                    let mut decoder = Decoder::new_outboard(&input[..], &outboard[..], &hash);
+                    decoder.read_exact(&mut vec![0; first_read]).unwrap();
+                    assert_eq!(expected_skip as u64, decoder.skip(skip as u64).unwrap());
+                    let mut rest = Vec::new();
+                    decoder.read_to_end(&mut rest).unwrap();
+                    assert_eq!(expected_rest, &rest[..]);
+                }
             }
-            if encoded.len() > CHUNK_SIZE {
-                tweaks.push(CHUNK_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_skip_doesnt_verify_skipped_subtrees() {
+        let input = make_test_input(16 * CHUNK_SIZE + 1);
+        let (mut encoded, hash) = encode::encode(&input);
+        // Corrupt the first chunk. Skipping the whole left half of the tree throws it away
+        // unread, but everything gets consumed from the reader.
+        let first_chunk_start = HEADER_SIZE + 5 * PARENT_SIZE;
+        encoded[first_chunk_start] ^= 1;
+        let mut reader = PipeReader {
+            inner: &encoded,
+            bytes_read: 0,
+        };
+        let mut decoder = Decoder::new(&mut reader, &hash);
+        assert_eq!(
+            16 * CHUNK_SIZE as u64,
+            decoder.skip(16 * CHUNK_SIZE as u64).unwrap()
+        );
+        let mut rest = Vec::new();
+        decoder.read_to_end(&mut rest).unwrap();
+        assert_eq!(&input[16 * CHUNK_SIZE..], &rest[..]);
+        assert_eq!(encoded.len() as u64, reader.bytes_read);
+
+        // Skipping only part of the corrupt chunk has to verify it.
+        let mut decoder = Decoder::new(&encoded[..], &hash);
+        assert!(decoder.skip(100).is_err());
+
+        // The final chunk is always verified.
+        let last = encoded.len() - 1;
+        encoded[first_chunk_start] ^= 1;
+        encoded[last] ^= 1;
+        let mut decoder = Decoder::new(&encoded[..], &hash);
+        assert!(decoder.skip(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_position_and_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let input = make_test_input(3 * CHUNK_SIZE + 10);
+        let (encoded, hash) = encode::encode(&input);
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_clone = reports.clone();
+        let mut decoder = Decoder::new(Cursor::new(&encoded), &hash)
+            .with_progress_callback(move |n| reports_clone.lock().unwrap().push(n));
+        assert_eq!(0, decoder.position());
+        let mut buf = [0; 100];
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!(100, decoder.position());
+        assert_eq!(vec![1024], *reports.lock().unwrap());
+
+        // A seek verifies the chunk it lands in.
+        decoder.seek(SeekFrom::Start(2500)).unwrap();
+        assert_eq!(2500, decoder.position());
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!(2600, decoder.position());
+        decoder.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(input.len() as u64, decoder.position());
+        assert_eq!(
+            vec![1024, 3072, input.len() as u64],
+            *reports.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parent_cache() {
+        let input = make_test_input(1_000_000);
+        let (encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+        let mut bytes_read = Vec::new();
+        for &capacity in &[None, Some(0), Some(5), Some(10_000)] {
+            println!("capacity {:?}", capacity);
+            let count = std::rc::Rc::new(std::cell::Cell::new(0));
+            let reader = CountingReader {
+                inner: Cursor::new(&encoded),
+                count: count.clone(),
+            };
+            let mut decoder = Decoder::new(reader, &hash);
+            if let Some(capacity) = capacity {
+                decoder = decoder.with_parent_cache(capacity);
             }
-            for tweak in tweaks {
-                println!("tweak {}", tweak);
-                let mut bad_encoded = encoded.clone();
-                bad_encoded[tweak] ^= 1;
+            random_reads(&mut decoder, &input, &mut ChaChaRng::seed_from_u64(0)).unwrap();
+            bytes_read.push(count.get());
 
-                let err = decode(&bad_encoded, &hash).unwrap_err();
-                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let mut decoder =
+                Decoder::new_outboard(Cursor::new(&input), Cursor::new(&outboard), &hash);
+            if let Some(capacity) = capacity {
+                decoder = decoder.with_parent_cache(capacity);
             }
+            random_reads(&mut decoder, &input, &mut ChaChaRng::seed_from_u64(0)).unwrap();
         }
+        println!("bytes read {:?}", bytes_read);
+        // A cache smaller than the depth of the tree evicts every parent before it's reused, but
+        // it still has to give correct results.
+        assert_eq!(bytes_read[0], bytes_read[1]);
+        assert!(bytes_read[2] <= bytes_read[1]);
+        assert!(bytes_read[3] < bytes_read[2]);
     }
 
     #[test]
-    fn test_seek() {
-        for &input_len in crate::test::TEST_CASES {
-            println!();
-            println!("input_len {}", input_len);
-            let input = make_test_input(input_len);
+    fn test_parent_cache_skips_verification() {
+        let input = make_test_input(8 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let mut decoder = Decoder::new(Cursor::new(&mut encoded), &hash).with_parent_cache(100);
+        let mut buf = [0; 1];
+        decoder
+            .seek(SeekFrom::Start(7 * CHUNK_SIZE as u64))
+            .unwrap();
+        decoder.read_exact(&mut buf).unwrap();
+        // The root is cached now, so corrupting it doesn't break seeks that start over from the
+        // root. Without the cache, this would fail.
+        decoder.shared.input.get_mut()[HEADER_SIZE] ^= 1;
+        decoder.seek(SeekFrom::Start(0)).unwrap();
+        decoder
+            .seek(SeekFrom::Start(6 * CHUNK_SIZE as u64))
+            .unwrap();
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!(input[6 * CHUNK_SIZE], buf[0]);
+    }
+
+    #[test]
+    fn test_parent_cache_eviction() {
+        let mut cache = ParentCache::new(2);
+        let hash = |i: u8| Hash::from([i; 32]);
+        cache.insert(1, hash(1), hash(1));
+        cache.insert(2, hash(2), hash(2));
+        assert!(cache.get(1).is_some());
+        // 2 is the least recently used now.
+        cache.insert(3, hash(3), hash(3));
+        assert!(cache.get(2).is_none());
+        assert_eq!(Some((hash(1), hash(1))), cache.get(1));
+        assert_eq!(Some((hash(3), hash(3))), cache.get(3));
+        assert_eq!(2, cache.entries.len());
+        assert_eq!(2, cache.by_age.len());
+    }
+
+    #[test]
+    fn test_audit() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
             let (encoded, hash) = encode::encode(&input);
-            for &seek in crate::test::TEST_CASES {
-                println!("seek {}", seek);
-                // Test all three types of seeking.
-                let mut seek_froms = Vec::new();
-                seek_froms.push(SeekFrom::Start(seek as u64));
-                seek_froms.push(SeekFrom::End(seek as i64 - input_len as i64));
-                seek_froms.push(SeekFrom::Current(seek as i64));
-                for seek_from in seek_froms {
-                    println!("seek_from {:?}", seek_from);
-                    let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
-                    let mut output = Vec::new();
-                    decoder.seek(seek_from).expect("seek error");
-                    decoder.read_to_end(&mut output).expect("decoder error");
-                    let input_start = cmp::min(seek, input.len());
+            let (outboard, _) = encode::outboard(&input);
+            let report = audit(&*encoded, &hash).unwrap();
+            assert!(report.is_ok());
+            assert_eq!(case as u64, report.content_len);
+            let chunks = encode::count_chunks(case as u64);
+            assert_eq!(2 * chunks - 1, report.nodes.len() as u64);
+            // The nodes tile the encoding, in order.
+            let mut offset = HEADER_SIZE as u128;
+            for node in &report.nodes {
+                assert_eq!(offset, node.encoded_offset);
+                offset += node.len as u128;
+                if node.kind == NodeKind::Chunk {
+                    let start = node.encoded_offset as usize;
+                    let content = &input[node.content_range.start as usize..]
+                        [..node.content_range.end as usize - node.content_range.start as usize];
+                    assert_eq!(content, &encoded[start..][..node.len]);
+                }
+            }
+            assert_eq!(encoded.len() as u128, offset);
+
+            let outboard_report = audit_outboard(&*input, &*outboard, &hash).unwrap();
+            assert!(outboard_report.is_ok());
+            assert_eq!(report.nodes.len(), outboard_report.nodes.len());
+            for (node, outboard_node) in report.nodes.iter().zip(&outboard_report.nodes) {
+                assert_eq!(node.content_range, outboard_node.content_range);
+                if node.kind == NodeKind::Chunk {
                     assert_eq!(
-                        &input[input_start..],
-                        &output[..],
-                        "output doesn't match input"
+                        node.content_range.start as u128,
+                        outboard_node.encoded_offset
                     );
                 }
             }
@@ -812,279 +4219,511 @@ mod test {
     }
 
     #[test]
-    fn test_repeated_random_seeks() {
-        // A chunk number like this (37) with consecutive zeroes should exercise some of the more
-        // interesting geometry cases.
-        let input_len = 0b100101 * CHUNK_SIZE;
-        println!("\n\ninput_len {}", input_len);
-        let mut prng = ChaChaRng::from_seed([0; 32]);
-        let input = make_test_input(input_len);
+    fn test_audit_corruption() {
+        let input = make_test_input(4 * CHUNK_SIZE);
         let (encoded, hash) = encode::encode(&input);
-        let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
-        // Do a thousand random seeks and chunk-sized reads.
-        for _ in 0..1000 {
-            let seek = prng.gen_range(0..input_len + 1);
-            println!("\nseek {}", seek);
-            decoder
-                .seek(SeekFrom::Start(seek as u64))
-                .expect("seek error");
-            // Clone the encoder before reading, to test repeated seeks on the same encoder.
+        // The tree is a root parent over two parents over two chunks each.
+        let layout = crate::layout::TreeLayout::new(input.len() as u64);
+
+        // A corrupt chunk fails on its own.
+        let mut bad = encoded.clone();
+        bad[layout.chunk(1).encoded_offset as usize] ^= 1;
+        let report = audit(&*bad, &hash).unwrap();
+        let statuses: Vec<NodeStatus> = report.nodes.iter().map(|n| n.status).collect();
+        use NodeStatus::*;
+        assert_eq!(
+            vec![
+                Verified,
+                Verified,
+                Verified,
+                HashMismatch,
+                Verified,
+                Verified,
+                Verified
+            ],
+            statuses,
+        );
+
+        // A corrupt parent makes its subtree unverifiable, and the rest of the tree still gets
+        // checked.
+        let mut bad = encoded.clone();
+        bad[HEADER_SIZE + PARENT_SIZE] ^= 1;
+        let report = audit(&*bad, &hash).unwrap();
+        let statuses: Vec<NodeStatus> = report.nodes.iter().map(|n| n.status).collect();
+        assert_eq!(
+            vec![
+                Verified,
+                HashMismatch,
+                Unverifiable,
+                Unverifiable,
+                Verified,
+                Verified,
+                Verified
+            ],
+            statuses,
+        );
+        let failures: Vec<&AuditNode> = report.failures().collect();
+        assert_eq!(0..2 * CHUNK_SIZE as u64, failures[0].content_range);
+
+        // Truncation stops the walk.
+        let report = audit(&encoded[..encoded.len() - 1], &hash).unwrap();
+        assert!(report.truncated);
+        assert!(!report.is_ok());
+        assert_eq!(6, report.nodes.len());
+        assert!(report.failures().next().is_none());
+        let report = audit(&encoded[..4], &hash).unwrap();
+        assert!(report.truncated);
+        assert!(report.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_hash_and_extract() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
             let mut output = Vec::new();
-            decoder
-                .clone()
-                .take(CHUNK_SIZE as u64)
-                .read_to_end(&mut output)
-                .expect("decoder error");
-            let input_start = cmp::min(seek, input_len);
-            let input_end = cmp::min(input_start + CHUNK_SIZE, input_len);
-            assert_eq!(
-                &input[input_start..input_end],
-                &output[..],
-                "output doesn't match input"
-            );
+            assert_eq!(hash, hash_and_extract(&*encoded, &mut output).unwrap());
+            assert_eq!(input, output);
+
+            // Corruption anywhere after the header breaks the structure, except in a
+            // single-chunk encoding, where there's no structure and the hash just changes.
+            if case > 0 {
+                let mut bad = encoded.clone();
+                let last = bad.len() - 1;
+                bad[last] ^= 1;
+                let result = hash_and_extract(&*bad, io::sink());
+                if case <= CHUNK_SIZE {
+                    assert_ne!(hash, result.unwrap());
+                } else {
+                    assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+                }
+            }
+
+            let err = hash_and_extract(&encoded[..encoded.len() - 1], io::sink()).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
         }
     }
 
     #[test]
-    fn test_invalid_zero_length() {
-        // There are different ways of structuring a decoder, and many of them are vulnerable to a
-        // mistake where as soon as the decoder reads zero length, it believes it's finished. But
-        // it's not finished, because it hasn't verified the hash! There must be something to
-        // distinguish the state "just decoded the zero length" from the state "verified the hash
-        // of the empty root node", and a decoder must not return EOF before the latter.
-
-        let (zero_encoded, zero_hash) = encode::encode(b"");
-        let one_hash = blake3::hash(b"x");
-
-        // Decoding the empty tree with the right hash should succeed.
-        let mut output = Vec::new();
-        let mut decoder = Decoder::new(&*zero_encoded, &zero_hash);
-        decoder.read_to_end(&mut output).unwrap();
-        assert_eq!(&output, &[]);
+    fn test_hash_and_extract_corrupt_parents() {
+        let input = make_test_input(5 * CHUNK_SIZE);
+        let (encoded, _) = encode::encode(&input);
+        for i in HEADER_SIZE..HEADER_SIZE + PARENT_SIZE {
+            let mut bad = encoded.clone();
+            bad[i] ^= 1;
+            let err = hash_and_extract(&*bad, io::sink()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+        // A different length header doesn't match the tree.
+        let mut bad = encoded.clone();
+        bad[0] ^= 1;
+        assert!(hash_and_extract(&*bad, io::sink()).is_err());
+    }
 
-        // Decoding the empty tree with any other hash should fail.
+    fn drive_decoder_state(
+        mut state: DecoderState,
+        encoded: &[u8],
+        content: &[u8],
+    ) -> Result<Vec<u8>, Error> {
         let mut output = Vec::new();
-        let mut decoder = Decoder::new(&*zero_encoded, &one_hash);
-        let result = decoder.read_to_end(&mut output);
-        assert!(result.is_err(), "a bad hash is supposed to fail!");
-        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut last_offset = None;
+        loop {
+            let bytes = match state.next() {
+                DecodeNext::NeedEncoded { offset, len } => {
+                    // Offsets only go forward.
+                    assert!(last_offset < Some(offset));
+                    last_offset = Some(offset);
+                    &encoded[offset as usize..][..len]
+                }
+                DecodeNext::NeedContent { offset, len } => &content[offset as usize..][..len],
+                DecodeNext::Done => return Ok(output),
+            };
+            output.extend_from_slice(state.feed(bytes)?);
+            assert_eq!(output.len() as u64, state.content_position());
+        }
     }
 
     #[test]
-    fn test_seeking_around_invalid_data() {
+    fn test_decoder_state() {
         for &case in crate::test::TEST_CASES {
-            // Skip the cases with only one or two chunks, so we have valid
-            // reads before and after the tweak.
-            if case <= 2 * CHUNK_SIZE {
-                continue;
-            }
-
-            println!("\ncase {}", case);
+            println!("case {}", case);
             let input = make_test_input(case);
-            let (mut encoded, hash) = encode::encode(&input);
-            println!("encoded len {}", encoded.len());
+            let (encoded, hash) = encode::encode(&input);
+            let state = DecoderState::new(&hash);
+            assert_eq!(input, drive_decoder_state(state, &encoded, &[]).unwrap());
+            let (outboard, outboard_hash) = encode::outboard(&input);
+            assert_eq!(hash, outboard_hash);
+            let state = DecoderState::new_outboard(&hash);
+            assert_eq!(
+                input,
+                drive_decoder_state(state, &outboard, &input).unwrap()
+            );
 
-            // Tweak a bit at the start of a chunk about halfway through. Loop
-            // over prior parent nodes and chunks to figure out where the
-            // target chunk actually starts.
-            let tweak_chunk = encode::count_chunks(case as u64) / 2;
-            let tweak_position = tweak_chunk as usize * CHUNK_SIZE;
-            println!("tweak position {}", tweak_position);
-            let mut tweak_encoded_offset = HEADER_SIZE;
-            for chunk in 0..tweak_chunk {
-                tweak_encoded_offset +=
-                    encode::pre_order_parent_nodes(chunk, case as u64) as usize * PARENT_SIZE;
-                tweak_encoded_offset += CHUNK_SIZE;
+            // Corrupting the last byte hits the final chunk.
+            if case > 0 {
+                let mut bad = encoded.clone();
+                *bad.last_mut().unwrap() ^= 1;
+                let result = drive_decoder_state(DecoderState::new(&hash), &bad, &[]);
+                assert_eq!(Error::HashMismatch, result.unwrap_err());
             }
-            tweak_encoded_offset +=
-                encode::pre_order_parent_nodes(tweak_chunk, case as u64) as usize * PARENT_SIZE;
-            println!("tweak encoded offset {}", tweak_encoded_offset);
-            encoded[tweak_encoded_offset] ^= 1;
+        }
+    }
 
-            // Read all the bits up to that tweak. Because it's right after a chunk boundary, the
-            // read should succeed.
-            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
-            let mut output = vec![0; tweak_position as usize];
-            decoder.read_exact(&mut output).unwrap();
-            assert_eq!(&input[..tweak_position], &*output);
+    #[test]
+    fn test_decoder_state_errors() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (encoded, hash) = encode::encode(&input);
+        let mut state = DecoderState::new(&hash);
+        state.feed(&encoded[..HEADER_SIZE]).unwrap();
+        let next = state.next();
+        assert_eq!(
+            DecodeNext::NeedEncoded {
+                offset: HEADER_SIZE as u128,
+                len: PARENT_SIZE,
+            },
+            next
+        );
+        // A bad parent doesn't advance the state.
+        let mut parent = *array_ref!(encoded, HEADER_SIZE, PARENT_SIZE);
+        parent[0] ^= 1;
+        assert_eq!(Error::HashMismatch, state.feed(&parent).unwrap_err());
+        assert_eq!(next, state.next());
+        parent[0] ^= 1;
+        state.feed(&parent).unwrap();
 
-            // Further reads at this point should fail.
-            let mut buf = [0; CHUNK_SIZE];
-            let res = decoder.read(&mut buf);
-            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let limits = Limits {
+            max_content_len: CHUNK_SIZE as u64,
+            ..Limits::default()
+        };
+        let mut state = DecoderState::new(&hash).with_limits(limits);
+        let err = state.feed(&encoded[..HEADER_SIZE]).unwrap_err();
+        assert_eq!(Error::TooLong, err);
+    }
 
-            // But now if we seek past the bad chunk, things should succeed again.
-            let new_start = tweak_position + CHUNK_SIZE;
-            decoder.seek(SeekFrom::Start(new_start as u64)).unwrap();
-            let mut output = Vec::new();
-            decoder.read_to_end(&mut output).unwrap();
-            assert_eq!(&input[new_start..], &*output);
-        }
+    #[test]
+    #[should_panic]
+    fn test_decoder_state_wrong_length_panics() {
+        let (encoded, hash) = encode::encode(make_test_input(3 * CHUNK_SIZE));
+        let mut state = DecoderState::new(&hash);
+        let _ = state.feed(&encoded[..HEADER_SIZE + 1]);
     }
 
     #[test]
-    fn test_invalid_eof_seek() {
-        // The decoder must validate the final chunk as part of seeking to or
-        // past EOF.
-        for &case in crate::test::TEST_CASES {
-            let input = make_test_input(case);
-            let (encoded, hash) = encode::encode(&input);
+    fn test_epoch_reader() {
+        let input = make_test_input(7 * CHUNK_SIZE + 100);
+        let mut writer = encode::EpochWriter::new(Vec::new(), 2);
+        writer.write_all(&input[..3 * CHUNK_SIZE]).unwrap();
+        writer.finish_epoch().unwrap();
+        writer.write_all(&input[3 * CHUNK_SIZE..]).unwrap();
+        writer.finish_epoch().unwrap();
+        let epochs = writer.take_epochs();
+        let stream = writer.into_inner();
 
-            // Seeking to EOF should succeed with the right hash.
-            let mut output = Vec::new();
-            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
-            decoder.seek(SeekFrom::Start(case as u64)).unwrap();
-            decoder.read_to_end(&mut output).unwrap();
-            assert_eq!(&output, &[]);
+        let mut reader = EpochReader::new(&*stream, 2);
+        let mut output = Vec::new();
+        for epoch in &epochs {
+            let content = reader.read_epoch(&epoch.root).unwrap().unwrap();
+            assert_eq!(epoch.content_len, content.len() as u64);
+            assert_eq!(Some(epoch.chain), reader.chain());
+            output.extend_from_slice(&content);
+        }
+        assert_eq!(input, output);
+        assert_eq!(epochs.len() as u64, reader.epochs_read());
+        assert_eq!(None, reader.read_epoch(&epochs[0].root).unwrap());
 
-            // Seeking to EOF should fail if the root hash is wrong.
-            let mut bad_hash_bytes = *hash.as_bytes();
-            bad_hash_bytes[0] ^= 1;
-            let bad_hash = bad_hash_bytes.into();
-            let mut decoder = Decoder::new(Cursor::new(&encoded), &bad_hash);
-            let result = decoder.seek(SeekFrom::Start(case as u64));
-            assert!(result.is_err(), "a bad hash is supposed to fail!");
-            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // The wrong root fails.
+        let mut reader = EpochReader::new(&*stream, 2);
+        let err = reader.read_epoch(&epochs[1].root).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
 
-            // It should also fail if the final chunk has been corrupted.
-            if case > 0 {
-                let mut bad_encoded = encoded.clone();
-                *bad_encoded.last_mut().unwrap() ^= 1;
-                let mut decoder = Decoder::new(Cursor::new(&bad_encoded), &hash);
-                let result = decoder.seek(SeekFrom::Start(case as u64));
-                assert!(result.is_err(), "a bad hash is supposed to fail!");
-                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
-            }
+        // So does corruption in a later epoch.
+        let mut corrupt = stream.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut reader = EpochReader::new(&*corrupt, 2);
+        for epoch in &epochs[..epochs.len() - 1] {
+            reader.read_epoch(&epoch.root).unwrap();
         }
+        let err = reader.read_epoch(&epochs.last().unwrap().root).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // A reader with smaller epochs rejects the length header.
+        let mut reader = EpochReader::new(&*stream, 1);
+        let err = reader.read_epoch(&epochs[0].root).unwrap_err();
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+
+        // A truncated header is an error, not the end of the stream.
+        let mut reader = EpochReader::new(&stream[..3], 2);
+        let err = reader.read_epoch(&epochs[0].root).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
     }
 
     #[test]
-    fn test_slices() {
+    fn test_shared_reader() {
         for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
             let input = make_test_input(case);
             let (encoded, hash) = encode::encode(&input);
-            // Also make an outboard encoding, to test that case.
-            let (outboard, outboard_hash) = encode::outboard(&input);
-            assert_eq!(hash, outboard_hash);
-            for &slice_start in crate::test::TEST_CASES {
-                let expected_start = cmp::min(input.len(), slice_start);
-                let slice_lens = [0, 1, 2, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1];
-                for &slice_len in slice_lens.iter() {
-                    println!("\ncase {} start {} len {}", case, slice_start, slice_len);
-                    let expected_end = cmp::min(input.len(), slice_start + slice_len);
-                    let expected_output = &input[expected_start..expected_end];
-                    let mut slice = Vec::new();
-                    let mut extractor = encode::SliceExtractor::new(
-                        Cursor::new(&encoded),
-                        slice_start as u64,
-                        slice_len as u64,
-                    );
-                    extractor.read_to_end(&mut slice).unwrap();
-
-                    // Make sure the outboard extractor produces the same output.
-                    let mut slice_from_outboard = Vec::new();
-                    let mut extractor = encode::SliceExtractor::new_outboard(
-                        Cursor::new(&input),
-                        Cursor::new(&outboard),
-                        slice_start as u64,
-                        slice_len as u64,
-                    );
-                    extractor.read_to_end(&mut slice_from_outboard).unwrap();
-                    assert_eq!(slice, slice_from_outboard);
-
-                    let mut output = Vec::new();
-                    let mut reader =
-                        SliceDecoder::new(&*slice, &hash, slice_start as u64, slice_len as u64);
-                    reader.read_to_end(&mut output).unwrap();
-                    assert_eq!(expected_output, &*output);
+            let (outboard, _) = encode::outboard(&input);
+            let combined = SharedReader::open(&*encoded, &hash).unwrap();
+            let separate = SharedReader::open_outboard(&*input, &*outboard, &hash).unwrap();
+            assert_eq!(case as u64, combined.len());
+            assert_eq!(case as u64, separate.len());
+            for &offset in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, case / 2, case] {
+                for &len in &[0, 1, 10, CHUNK_SIZE, 3 * CHUNK_SIZE + 5] {
+                    let start = cmp::min(offset, case);
+                    let end = cmp::min(offset + len, case);
+                    let mut buf = vec![0xff; len];
+                    let n = combined.read_at(offset as u64, &mut buf).unwrap();
+                    assert_eq!(end - start, n);
+                    assert_eq!(&input[start..end], &buf[..n]);
+                    let mut buf = vec![0xff; len];
+                    let n = separate.read_at(offset as u64, &mut buf).unwrap();
+                    assert_eq!(end - start, n);
+                    assert_eq!(&input[start..end], &buf[..n]);
                 }
             }
+            let mut buf = [0];
+            assert_eq!(0, combined.read_at(case as u64 + 100, &mut buf).unwrap());
         }
     }
 
     #[test]
-    fn test_corrupted_slice() {
-        let input = make_test_input(20_000);
-        let slice_start = 5_000;
-        let slice_len = 10_000;
+    fn test_shared_reader_threads() {
+        let case = 100 * CHUNK_SIZE + 7;
+        let input = make_test_input(case);
         let (encoded, hash) = encode::encode(&input);
+        let reader = SharedReader::open(Arc::new(encoded), &hash).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let reader = reader.clone();
+                let input = &input;
+                scope.spawn(move || {
+                    let mut buf = [0; 3 * CHUNK_SIZE];
+                    let mut offset = thread * 1000;
+                    while offset < case {
+                        let n = reader.read_at(offset as u64, &mut buf).unwrap();
+                        let end = cmp::min(offset + buf.len(), case);
+                        assert_eq!(&input[offset..end], &buf[..n]);
+                        offset += 5000;
+                    }
+                });
+            }
+        });
+        // The threads shared one parent cache, which now holds the whole tree.
+        let parents = reader.shared.parents.read().unwrap().len() as u64;
+        assert_eq!(encode::count_chunks(case as u64) - 1, parents);
+    }
 
-        // Slice out the middle 10_000 bytes;
-        let mut slice = Vec::new();
-        let mut extractor = encode::SliceExtractor::new(
-            Cursor::new(&encoded),
-            slice_start as u64,
-            slice_len as u64,
-        );
-        extractor.read_to_end(&mut slice).unwrap();
-
-        // First confirm that the regular decode works.
+    #[test]
+    fn test_shared_reader_cursor() {
+        let case = 10 * CHUNK_SIZE + 1;
+        let input = make_test_input(case);
+        let (outboard, hash) = encode::outboard(&input);
+        let reader = SharedReader::open_outboard(&*input, &*outboard, &hash).unwrap();
+        assert_eq!(case as u64, ReadAt::size(&reader).unwrap());
+        let mut cursor = crate::backend::ReadAtCursor::new(reader.clone());
+        cursor.seek(SeekFrom::End(-10)).unwrap();
         let mut output = Vec::new();
-        let mut reader = SliceDecoder::new(&*slice, &hash, slice_start as u64, slice_len as u64);
-        reader.read_to_end(&mut output).unwrap();
-        assert_eq!(&input[slice_start..][..slice_len], &*output);
+        cursor.read_to_end(&mut output).unwrap();
+        assert_eq!(&input[case - 10..], &output[..]);
+        cursor.seek(SeekFrom::Start(100)).unwrap();
+        let mut buf = [0; 2000];
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(&input[100..2100], &buf[..]);
+    }
 
-        // Also confirm that the outboard slice extractor gives the same slice.
-        let (outboard, outboard_hash) = encode::outboard(&input);
-        assert_eq!(hash, outboard_hash);
-        let mut slice_from_outboard = Vec::new();
-        let mut extractor = encode::SliceExtractor::new_outboard(
-            Cursor::new(&input),
-            Cursor::new(&outboard),
-            slice_start as u64,
-            slice_len as u64,
-        );
-        extractor.read_to_end(&mut slice_from_outboard).unwrap();
-        assert_eq!(slice, slice_from_outboard);
+    #[test]
+    fn test_shared_reader_corruption() {
+        let case = 8 * CHUNK_SIZE + 1;
+        let input = make_test_input(case);
+        let (mut encoded, hash) = encode::encode(&input);
 
-        // Now confirm that flipping bits anywhere in the slice other than the
-        // length header will corrupt it. Tweaking the length header doesn't
-        // always break slice decoding, because the only thing its guaranteed
-        // to break is the final chunk, and this slice doesn't include the
-        // final chunk.
-        let mut i = HEADER_SIZE;
-        while i < slice.len() {
-            let mut slice_clone = slice.clone();
-            slice_clone[i] ^= 1;
-            let mut reader =
-                SliceDecoder::new(&*slice_clone, &hash, slice_start as u64, slice_len as u64);
-            output.clear();
-            let err = reader.read_to_end(&mut output).unwrap_err();
-            assert_eq!(io::ErrorKind::InvalidData, err.kind());
-            i += 32;
-        }
+        // A corrupt final chunk fails at open time.
+        let mut bad_encoded = encoded.clone();
+        *bad_encoded.last_mut().unwrap() ^= 1;
+        let err = SharedReader::open(bad_encoded, &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Otherwise only reads that touch the corruption fail.
+        let chunk_3 = encode::chunk_encoded_offset(3, case as u64) as usize;
+        encoded[chunk_3] ^= 1;
+        let reader = SharedReader::open(encoded, &hash).unwrap();
+        let mut buf = [0; CHUNK_SIZE];
+        let err = reader.read_at(3 * CHUNK_SIZE as u64, &mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let n = reader.read_at(5 * CHUNK_SIZE as u64, &mut buf).unwrap();
+        assert_eq!(&input[5 * CHUNK_SIZE..][..n], &buf[..n]);
+
+        // A truncated encoding is an UnexpectedEof at open time.
+        let (encoded, _) = encode::encode(&input);
+        let err = SharedReader::open(&encoded[..encoded.len() - 1], &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
     }
 
     #[test]
-    fn test_slice_entire() {
-        // If a slice starts at the beginning (actually anywere in the first chunk) and includes
-        // entire length of the content (or at least one byte in the last chunk), the slice should
-        // be exactly the same as the entire encoded tree. This can act as a cheap way to convert
-        // an outboard tree to a combined one.
+    fn test_verify() {
         for &case in crate::test::TEST_CASES {
             println!("case {}", case);
             let input = make_test_input(case);
-            let (encoded, _) = encode::encode(&input);
+            let (encoded, hash) = encode::encode(&input);
             let (outboard, _) = encode::outboard(&input);
-            let mut slice = Vec::new();
-            let mut extractor = encode::SliceExtractor::new_outboard(
-                Cursor::new(&input),
-                Cursor::new(&outboard),
-                0,
+            assert_eq!(case as u64, verify(&*encoded, &hash).unwrap());
+            assert_eq!(
                 case as u64,
+                verify_outboard(&*input, &*outboard, &hash).unwrap()
             );
-            extractor.read_to_end(&mut slice).unwrap();
-            assert_eq!(encoded, slice);
+
+            // Every single bit flip is caught, as long as it's inside the encoding.
+            for &i in &[0, HEADER_SIZE, encoded.len() / 2, encoded.len() - 1] {
+                if i >= encoded.len() {
+                    continue;
+                }
+                let mut bad = encoded.clone();
+                bad[i] ^= 1;
+                assert!(verify(&*bad, &hash).is_err());
+            }
+            if case > 0 {
+                let mut bad = input.clone();
+                bad[case / 2] ^= 1;
+                let err = verify_outboard(&*bad, &*outboard, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+
+            // Truncation is UnexpectedEof.
+            let err = verify(&encoded[..encoded.len() - 1], &hash).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
         }
     }
 
     #[test]
-    fn test_into_inner() {
-        let v = vec![1u8, 2, 3];
-        let hash = [0; 32].into();
+    fn test_validate_canonical() {
+        let violation = |encoded: &[u8], hash: &Hash| {
+            let err = validate_canonical(encoded, hash).unwrap_err();
+            Violation::from_io_error(&err).expect("not a violation")
+        };
+        for case in crate::test_vectors::test_cases(4) {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            assert_eq!(case as u64, validate_canonical(&*encoded, &hash).unwrap());
+            let end = encoded.len() as u128;
 
-        let decoder = Decoder::new(io::Cursor::new(v.clone()), &hash);
-        let slice_decoder = SliceDecoder::new(decoder.into_inner(), &hash, 0, 0);
-        assert_eq!(slice_decoder.into_inner().into_inner(), v);
+            // Anything appended is rejected, whether it's one byte, more content, or a parent.
+            for extra in [&[0][..], &[0xab; 100], &[0; PARENT_SIZE]] {
+                let mut long = encoded.clone();
+                long.extend_from_slice(extra);
+                assert_eq!(case as u64, verify(&*long, &hash).unwrap());
+                assert_eq!(
+                    Violation::TrailingBytes { offset: end },
+                    violation(&long, &hash)
+                );
+            }
+
+            // Truncation anywhere says where the encoding ended.
+            for cut in [0, HEADER_SIZE - 1, HEADER_SIZE, encoded.len() - 1] {
+                if cut >= encoded.len() || (case == 0 && cut == HEADER_SIZE) {
+                    continue;
+                }
+                let err = validate_canonical(&encoded[..cut], &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+                assert_eq!(
+                    Some(Violation::Truncated {
+                        offset: cut as u128
+                    }),
+                    Violation::from_io_error(&err)
+                );
+            }
+
+            // Corruption points to the first node that doesn't match.
+            if case > 0 {
+                let mut bad = encoded.clone();
+                bad[encoded.len() - 1] ^= 1;
+                let last_chunk = encode::count_chunks(case as u64) - 1;
+                let offset = encode::chunk_encoded_offset(last_chunk, case as u64);
+                let err = validate_canonical(&*bad, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                assert_eq!(
+                    Some(Violation::HashMismatch { offset }),
+                    Violation::from_io_error(&err)
+                );
+            }
+        }
+
+        // An over-long final chunk is trailing bytes as far as the header is concerned.
+        let input = make_test_input(1500);
+        let (mut encoded, hash) = encode::encode(&input[..1400]);
+        let end = encoded.len() as u128;
+        encoded.extend_from_slice(&input[1400..]);
+        assert_eq!(
+            Violation::TrailingBytes { offset: end },
+            violation(&encoded, &hash)
+        );
+        // A wrong length header fails at the right edge of the tree. Here the tree has the same
+        // shape, so that's the final chunk.
+        encoded[..HEADER_SIZE].copy_from_slice(&crate::encode_len(1500));
+        assert_eq!(
+            Violation::HashMismatch {
+                offset: encode::chunk_encoded_offset(1, 1500)
+            },
+            violation(&encoded, &hash)
+        );
+        // Errors from the reader aren't violations.
+        let err = io::Error::other("oops");
+        assert_eq!(None, Violation::from_io_error(&err));
+        assert_eq!(
+            "trailing bytes after offset 5",
+            Violation::TrailingBytes { offset: 5 }.to_string()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_audit_serde() {
+        let (mut encoded, hash) = encode::encode(make_test_input(3 * CHUNK_SIZE));
+        encoded[HEADER_SIZE + 2 * PARENT_SIZE] ^= 1;
+        let report = audit(&*encoded, &hash).unwrap();
+        assert!(!report.is_ok());
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(report, serde_json::from_str::<AuditReport>(&json).unwrap());
+    }
+
+    #[test]
+    fn test_limits_reject_huge_header() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let (mut outboard, _) = encode::outboard(&input);
+        encoded[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        outboard[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        let limits = Limits {
+            max_content_len: 1 << 30,
+            ..Limits::default()
+        };
+        fn assert_too_long<T: fmt::Debug>(result: io::Result<T>) {
+            let err = result.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert_eq!(Error::TooLong.to_string(), err.to_string());
+        }
+        assert_too_long(audit_with_limits(&*encoded, &hash, &limits));
+        assert_too_long(audit_outboard_with_limits(
+            &*input, &*outboard, &hash, &limits,
+        ));
+        assert_too_long(verify_with_limits(&*encoded, &hash, &limits));
+        assert_too_long(verify_outboard_with_limits(
+            &*input, &*outboard, &hash, &limits,
+        ));
+        assert_too_long(validate_canonical_with_limits(&*encoded, &hash, &limits));
+        assert_too_long(hash_and_extract_with_limits(&*encoded, io::sink(), &limits));
+        assert_too_long(SharedReader::open_with_limits(
+            encoded.clone(),
+            &hash,
+            &limits,
+        ));
+        assert_too_long(SharedReader::open_outboard_with_limits(
+            input.clone(),
+            outboard.clone(),
+            &hash,
+            &limits,
+        ));
     }
 }
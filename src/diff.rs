@@ -0,0 +1,748 @@
+//! Find the chunks that differ between two versions of some content, from their outboard trees,
+//! and patch the old version into the new one.
+//!
+//! `tree_diff_trusted_lens` compares two outboard encodings, and it only reads parent nodes, not
+//! content. Subtrees whose hashes match are skipped in one step, so comparing two large files that
+//! differ in a few places reads just the parents along the paths down to those places. That's the
+//! first half of an rsync-style delta transfer: a receiver who has the old version and its
+//! outboard encoding gets the new outboard encoding, diffs the trees, and then asks for slices
+//! covering only the ranges that changed. The slices carry their own proofs, and `apply` verifies
+//! each one against the new root hash before splicing it into the old content.
+//!
+//! Every parent node that the diff reads is verified against its root hash, so a corrupt or
+//! mismatched outboard encoding is an `InvalidData` error. Chunks aren't hashed, because the
+//! outboard encodings don't contain them, and that means the diff can't verify the length headers
+//! either. Only the final chunk verifies the length, and a forged header can describe a tree that
+//! matches every parent the diff reads. So the caller passes in both lengths, and they have to
+//! come from somewhere trusted. The receiver knows the length of its own copy, and
+//! `Delta::verified_len` gets the new length from a slice of the new content's final chunk.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::io::Cursor;
+//!
+//! let old = vec![0; 100_000];
+//! let mut new = old.clone();
+//! new[50_000] = 1;
+//! new.extend_from_slice(&[2; 5_000]);
+//! let (old_outboard, old_hash) = bao::encode::outboard(&old);
+//! let (new_outboard, new_hash) = bao::encode::outboard(&new);
+//!
+//! // The sender provides the final chunk of the new content, which verifies the new length.
+//! let final_chunk = bao::diff::Delta::extract_outboard(
+//!     Cursor::new(&new),
+//!     Cursor::new(&new_outboard),
+//!     bao::diff::ChunkRange::final_chunk(new.len() as u64),
+//! )?;
+//! let new_len = final_chunk.verified_len(&new_hash)?;
+//!
+//! let mut ranges = bao::diff::tree_diff_trusted_lens(
+//!     &old_hash,
+//!     old.len() as u64,
+//!     Cursor::new(&old_outboard),
+//!     &new_hash,
+//!     new_len,
+//!     Cursor::new(&new_outboard),
+//! )?;
+//! assert_eq!(2, ranges.len());
+//!
+//! // The sender extracts a slice for each changed range, and the receiver verifies them and
+//! // splices them into its copy of the old content. `apply` needs the final chunk, even if it
+//! // didn't change.
+//! if ranges.last().is_none_or(|last| last.end < final_chunk.range.end) {
+//!     ranges.push(final_chunk.range);
+//! }
+//! let mut deltas = Vec::new();
+//! for &range in &ranges {
+//!     deltas.push(bao::diff::Delta::extract_outboard(
+//!         Cursor::new(&new),
+//!         Cursor::new(&new_outboard),
+//!         range,
+//!     )?);
+//! }
+//! let mut patched = Vec::new();
+//! bao::diff::apply(Cursor::new(&old), &deltas, &new_hash, &mut patched)?;
+//! assert_eq!(new, patched);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::Error;
+use crate::encode;
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::ops::Range;
+
+/// A range of chunk indices, `start` inclusive and `end` exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ChunkRange {
+    /// The content bytes that the chunks cover. The end of the last range might be past the end
+    /// of the content, which is fine for slicing.
+    pub fn byte_range(&self) -> Range<u64> {
+        self.start * CHUNK_SIZE as u64..self.end * CHUNK_SIZE as u64
+    }
+
+    /// The range holding just the final chunk of content with length `content_len`. Empty content
+    /// still has one chunk, and its slice verifies the length like any other final chunk.
+    pub fn final_chunk(content_len: u64) -> Self {
+        let end = encode::count_chunks(content_len);
+        Self {
+            start: end - 1,
+            end,
+        }
+    }
+}
+
+/// Compare the tree with root `root_a`, length `len_a` and outboard encoding `outboard_a` to the
+/// one with root `root_b`, length `len_b` and outboard encoding `outboard_b`, and return the chunk
+/// ranges whose hashes differ, sorted and merged. Chunks that only exist in the longer tree are
+/// included. Both trees hash the chunk at each index with the same chunk counter, so identical
+/// hashes mean identical chunks.
+///
+/// When the two content lengths are different, a chunk can differ in hash but not in content,
+/// because the root node is hashed differently from the others. For example, if one version is a
+/// single chunk and the other is longer, chunk 0 is always included.
+///
+/// The lengths are trusted. Reading the outboard encodings can't verify them, because they don't
+/// contain the final chunks, so a length from an untrusted header could give a wrong diff with no
+/// error. If a length header doesn't match the length passed in, that's an `InvalidData` error.
+pub fn tree_diff_trusted_lens(
+    root_a: &Hash,
+    len_a: u64,
+    mut outboard_a: impl Read + Seek,
+    root_b: &Hash,
+    len_b: u64,
+    mut outboard_b: impl Read + Seek,
+) -> io::Result<Vec<ChunkRange>> {
+    let a = Node::root(&mut outboard_a, root_a, len_a)?;
+    let b = Node::root(&mut outboard_b, root_b, len_b)?;
+    let mut differ = Differ {
+        a: &mut outboard_a,
+        b: &mut outboard_b,
+        ranges: Vec::new(),
+    };
+    differ.diff_subtrees(0, a, b)?;
+    Ok(differ.ranges)
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+// A subtree of one of the two trees.
+#[derive(Clone, Copy)]
+struct Node {
+    len: u64,
+    hash: Hash,
+    is_root: bool,
+    // The position of the subtree in its outboard encoding.
+    offset: u128,
+}
+
+impl Node {
+    fn root(outboard: &mut dyn ReadSeek, hash: &Hash, len: u64) -> io::Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        outboard.seek(SeekFrom::Start(0))?;
+        outboard.read_exact(&mut header)?;
+        if crate::decode_len(&header) != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "length header doesn't match the trusted length",
+            ));
+        }
+        Ok(Self {
+            len,
+            hash: *hash,
+            is_root: true,
+            offset: HEADER_SIZE as u128,
+        })
+    }
+
+    fn is_chunk(&self) -> bool {
+        self.len <= CHUNK_SIZE as u64
+    }
+
+    // Read and verify the parent node, and return the left and right children.
+    fn children(&self, outboard: &mut dyn ReadSeek) -> io::Result<(Node, Node)> {
+        let mut parent = [0; PARENT_SIZE];
+        outboard.seek(SeekFrom::Start(
+            encode::EncodedOffset::new(self.offset).to_u64()?,
+        ))?;
+        outboard.read_exact(&mut parent)?;
+        let left_hash: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let right_hash: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        let computed = crate::parent_cv(&left_hash, &right_hash, self.is_root);
+        // Hash implements constant time equality.
+        if computed != self.hash {
+            return Err(Error::HashMismatch.into());
+        }
+        let left_len = encode::left_subtree_len(self.len);
+        let left = Node {
+            len: left_len,
+            hash: left_hash,
+            is_root: false,
+            offset: self.offset + PARENT_SIZE as u128,
+        };
+        let right = Node {
+            len: self.len - left_len,
+            hash: right_hash,
+            is_root: false,
+            offset: left.offset + encode::outboard_subtree_size(left_len),
+        };
+        Ok((left, right))
+    }
+}
+
+struct Differ<'a> {
+    a: &'a mut dyn ReadSeek,
+    b: &'a mut dyn ReadSeek,
+    ranges: Vec<ChunkRange>,
+}
+
+impl Differ<'_> {
+    // Both subtrees start at content position `start`.
+    fn diff_subtrees(&mut self, start: u64, a: Node, b: Node) -> io::Result<()> {
+        // Subtrees at the same position with the same length have the same shape, and their
+        // hashes are comparable, unless only one of them is a root.
+        if a.len == b.len && a.is_root == b.is_root && a.hash == b.hash {
+            return Ok(());
+        }
+        if a.is_chunk() && b.is_chunk() {
+            self.push(start, cmp::max(a.len, b.len));
+            return Ok(());
+        }
+        let a_left_len = (!a.is_chunk()).then(|| encode::left_subtree_len(a.len));
+        let b_left_len = (!b.is_chunk()).then(|| encode::left_subtree_len(b.len));
+        if a_left_len == b_left_len {
+            let (a_left, a_right) = a.children(self.a)?;
+            let (b_left, b_right) = b.children(self.b)?;
+            self.diff_subtrees(start, a_left, b_left)?;
+            return self.diff_subtrees(start + a_left.len, a_right, b_right);
+        }
+        // The shapes diverge. The longer subtree's left child is at least as long as the whole
+        // shorter subtree, so compare the shorter one to that, and everything to the right of it
+        // only exists on one side.
+        if a.len < b.len {
+            let (b_left, b_right) = b.children(self.b)?;
+            self.diff_subtrees(start, a, b_left)?;
+            self.push(start + b_left.len, b_right.len);
+        } else {
+            let (a_left, a_right) = a.children(self.a)?;
+            self.diff_subtrees(start, a_left, b)?;
+            self.push(start + a_left.len, a_right.len);
+        }
+        Ok(())
+    }
+
+    // Add the chunks of the subtree at `start` with length `len`, merging with the previous
+    // range if they touch. Subtrees are visited in order, so the ranges stay sorted.
+    fn push(&mut self, start: u64, len: u64) {
+        let range = ChunkRange {
+            start: start / CHUNK_SIZE as u64,
+            end: start / CHUNK_SIZE as u64 + encode::count_chunks(len),
+        };
+        match self.ranges.last_mut() {
+            Some(last) if last.end >= range.start => last.end = cmp::max(last.end, range.end),
+            _ => self.ranges.push(range),
+        }
+    }
+}
+
+/// One changed range of the new content, along with the slice of the new encoding that covers
+/// it. The slice is what `SliceExtractor` produces for the range's `byte_range`, so it carries the
+/// proof that its chunks belong to the new tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delta {
+    pub range: ChunkRange,
+    pub slice: Vec<u8>,
+}
+
+impl Delta {
+    /// Extract the slice for `range` from a combined encoding of the new content.
+    pub fn extract(encoded: impl Read + Seek, range: ChunkRange) -> io::Result<Self> {
+        let bytes = range.byte_range();
+        let mut slice = Vec::new();
+        encode::SliceExtractor::new(encoded, bytes.start, bytes.end - bytes.start)
+            .read_to_end(&mut slice)?;
+        Ok(Self { range, slice })
+    }
+
+    /// Extract the slice for `range` from the new content and its outboard encoding.
+    pub fn extract_outboard(
+        content: impl Read + Seek,
+        outboard: impl Read + Seek,
+        range: ChunkRange,
+    ) -> io::Result<Self> {
+        let bytes = range.byte_range();
+        let mut slice = Vec::new();
+        encode::SliceExtractor::new_outboard(
+            content,
+            outboard,
+            bytes.start,
+            bytes.end - bytes.start,
+        )
+        .read_to_end(&mut slice)?;
+        Ok(Self { range, slice })
+    }
+
+    /// Verify the delta against `root`, and return the length of the content it belongs to. The
+    /// delta has to cover the final chunk, because that's the only thing that verifies the length
+    /// header. Otherwise this is an `InvalidData` error, like a delta that fails verification.
+    pub fn verified_len(&self, root: &Hash) -> io::Result<u64> {
+        let len = delta_len(self)?;
+        if self.range.end < ChunkRange::final_chunk(len).end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "delta doesn't cover the final chunk",
+            ));
+        }
+        let bytes = self.range.byte_range();
+        let mut decoder = crate::decode::SliceDecoder::new(
+            &*self.slice,
+            root,
+            bytes.start,
+            bytes.end - bytes.start,
+        );
+        let expected = len - cmp::min(bytes.start, len);
+        if io::copy(&mut decoder, &mut io::sink())? != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "delta doesn't cover its range",
+            ));
+        }
+        Ok(len)
+    }
+}
+
+/// Build the new content from the old content, `base`, and the `deltas` for the ranges that
+/// `tree_diff_trusted_lens` found, and write it to `output`. Returns the length of the new content.
+///
+/// Each delta is verified against `new_root` as it's decoded, and no chunk from a delta is written
+/// before it's verified. Everything outside the deltas is copied from `base`, without hashing it,
+/// on the strength of the diff. The new length comes from the deltas' length headers, which all
+/// have to agree, and the last delta always has to cover the final chunk of the new content,
+/// which verifies the length. That delta is verified before anything is written. The diff doesn't
+/// include the final chunk if it didn't change, so add `ChunkRange::final_chunk` to the ranges in
+/// that case.
+///
+/// The deltas have to be sorted and non-overlapping, like the output of `tree_diff_trusted_lens`,
+/// or this is an `InvalidInput` error. A delta that fails verification, or that doesn't cover its
+/// range, is an `InvalidData` error, and so is a missing final chunk. After an error, `output`
+/// holds a prefix of the new content, and every byte of it was either verified or copied from
+/// `base`.
+pub fn apply(
+    mut base: impl Read + Seek,
+    deltas: &[Delta],
+    new_root: &Hash,
+    mut output: impl Write,
+) -> io::Result<u64> {
+    for pair in deltas.windows(2) {
+        if pair[0].range.end > pair[1].range.start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "deltas out of order",
+            ));
+        }
+    }
+    let new_len = match deltas.last() {
+        Some(last) => last.verified_len(new_root)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no delta covers the final chunk",
+            ))
+        }
+    };
+    for delta in deltas {
+        if delta_len(delta)? != new_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "deltas disagree about the length",
+            ));
+        }
+    }
+
+    let mut position = 0;
+    for delta in deltas {
+        let bytes = delta.range.byte_range();
+        let start = cmp::min(bytes.start, new_len);
+        let end = cmp::min(bytes.end, new_len);
+        copy_base(&mut base, position, start, &mut output)?;
+        let mut decoder = crate::decode::SliceDecoder::new(
+            &*delta.slice,
+            new_root,
+            bytes.start,
+            bytes.end - bytes.start,
+        );
+        if io::copy(&mut decoder, &mut output)? != end - start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "delta doesn't cover its range",
+            ));
+        }
+        position = end;
+    }
+    copy_base(&mut base, position, new_len, &mut output)?;
+    Ok(new_len)
+}
+
+fn delta_len(delta: &Delta) -> io::Result<u64> {
+    let header = delta.slice.get(..HEADER_SIZE).ok_or(Error::Truncated)?;
+    Ok(crate::decode_len(array_ref!(header, 0, HEADER_SIZE)))
+}
+
+fn copy_base(
+    base: &mut dyn ReadSeek,
+    start: u64,
+    end: u64,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    base.seek(SeekFrom::Start(start))?;
+    if io::copy(&mut base.take(end - start), output)? != end - start {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use std::io::Cursor;
+
+    fn diff(a: &[u8], b: &[u8]) -> Vec<ChunkRange> {
+        let (outboard_a, hash_a) = encode::outboard(a);
+        let (outboard_b, hash_b) = encode::outboard(b);
+        tree_diff_trusted_lens(
+            &hash_a,
+            a.len() as u64,
+            Cursor::new(&outboard_a),
+            &hash_b,
+            b.len() as u64,
+            Cursor::new(&outboard_b),
+        )
+        .unwrap()
+    }
+
+    // Compare every chunk by hand. Chunk 0 of a single-chunk tree is the root, and it's hashed
+    // differently from chunk 0 of a bigger tree.
+    fn diff_by_hand(a: &[u8], b: &[u8]) -> Vec<ChunkRange> {
+        let a_chunks = encode::count_chunks(a.len() as u64);
+        let b_chunks = encode::count_chunks(b.len() as u64);
+        let mut ranges: Vec<ChunkRange> = Vec::new();
+        for index in 0..cmp::max(a_chunks, b_chunks) {
+            let chunk = |input: &[u8]| {
+                let start = cmp::min(index as usize * CHUNK_SIZE, input.len());
+                let end = cmp::min(start + CHUNK_SIZE, input.len());
+                input[start..end].to_vec()
+            };
+            let differs = index >= a_chunks
+                || index >= b_chunks
+                || chunk(a) != chunk(b)
+                || (index == 0 && (a_chunks == 1) != (b_chunks == 1));
+            if differs {
+                match ranges.last_mut() {
+                    Some(last) if last.end == index => last.end += 1,
+                    _ => ranges.push(ChunkRange {
+                        start: index,
+                        end: index + 1,
+                    }),
+                }
+            }
+        }
+        ranges
+    }
+
+    #[test]
+    fn test_tree_diff() {
+        let lens: Vec<usize> = crate::test_vectors::test_cases(4)
+            .chain([11 * CHUNK_SIZE])
+            .collect();
+        for &a_len in &lens {
+            for &b_len in &lens {
+                println!("a_len {} b_len {}", a_len, b_len);
+                let a = make_test_input(a_len);
+                let mut b = make_test_input(b_len);
+                assert_eq!(diff_by_hand(&a, &b), diff(&a, &b));
+                if b_len > 2 * CHUNK_SIZE {
+                    b[CHUNK_SIZE + 5] ^= 1;
+                    assert_eq!(diff_by_hand(&a, &b), diff(&a, &b));
+                    assert_eq!(diff_by_hand(&b, &a), diff(&b, &a));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tree_diff_ranges() {
+        let a = make_test_input(100 * CHUNK_SIZE);
+        assert!(diff(&a, &a).is_empty());
+        assert!(diff(b"", b"").is_empty());
+        assert_eq!(vec![ChunkRange { start: 0, end: 1 }], diff(b"", b"x"));
+
+        let mut b = a.clone();
+        for &index in &[3, 4, 50, 99] {
+            b[index * CHUNK_SIZE] ^= 1;
+        }
+        b.extend_from_slice(&[0; 2 * CHUNK_SIZE]);
+        let expected = vec![
+            ChunkRange { start: 3, end: 5 },
+            ChunkRange { start: 50, end: 51 },
+            ChunkRange {
+                start: 99,
+                end: 102,
+            },
+        ];
+        assert_eq!(expected, diff(&a, &b));
+        assert_eq!(expected, diff(&b, &a));
+        assert_eq!(
+            3 * CHUNK_SIZE as u64..5 * CHUNK_SIZE as u64,
+            expected[0].byte_range()
+        );
+    }
+
+    #[test]
+    fn test_tree_diff_corrupt() {
+        let a = make_test_input(10 * CHUNK_SIZE);
+        let mut b = a.clone();
+        b[0] ^= 1;
+        let len = a.len() as u64;
+        let (outboard_a, hash_a) = encode::outboard(&a);
+        let (mut outboard_b, hash_b) = encode::outboard(&b);
+        // The root node differs, and so does the path down to chunk 0.
+        outboard_b[HEADER_SIZE + PARENT_SIZE] ^= 1;
+        let err = tree_diff_trusted_lens(
+            &hash_a,
+            len,
+            Cursor::new(&outboard_a),
+            &hash_b,
+            len,
+            Cursor::new(&outboard_b),
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // The wrong root hash fails at the root node.
+        let (outboard_b, hash_b) = encode::outboard(&b);
+        let err = tree_diff_trusted_lens(
+            &blake3::hash(b"foo"),
+            len,
+            Cursor::new(&outboard_a),
+            &hash_b,
+            len,
+            Cursor::new(&outboard_b),
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // A truncated outboard encoding is an error too.
+        let err = tree_diff_trusted_lens(
+            &hash_a,
+            len,
+            Cursor::new(&outboard_a),
+            &hash_b,
+            len,
+            Cursor::new(&outboard_b[..HEADER_SIZE + PARENT_SIZE]),
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_tree_diff_forged_header() {
+        // With four chunks, the root's right child is the parent of chunks 2 and 3. A header that
+        // claims three chunks makes that parent look like chunk 2, and every parent the diff reads
+        // still verifies.
+        let a = make_test_input(4 * CHUNK_SIZE);
+        let mut b = a.clone();
+        b[3 * CHUNK_SIZE] ^= 1;
+        let (outboard_a, hash_a) = encode::outboard(&a);
+        let (mut outboard_b, hash_b) = encode::outboard(&b);
+        let forged_len = 3 * CHUNK_SIZE as u64;
+        outboard_b[..HEADER_SIZE].copy_from_slice(&crate::encode_len(forged_len));
+        let diff_b = |len_b| {
+            tree_diff_trusted_lens(
+                &hash_a,
+                a.len() as u64,
+                Cursor::new(&outboard_a),
+                &hash_b,
+                len_b,
+                Cursor::new(&outboard_b),
+            )
+        };
+
+        // Trusting the forged header gives the wrong diff, and nothing notices.
+        let wrong = diff_b(forged_len).unwrap();
+        assert_ne!(diff(&a, &b), wrong);
+
+        // With the real length, the forged header is an error.
+        let err = diff_b(b.len() as u64).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // The real length comes from a slice of the final chunk, which catches the forgery.
+        let (encoded_b, _) = encode::encode(&b);
+        let range = ChunkRange::final_chunk(b.len() as u64);
+        let final_chunk = Delta::extract(Cursor::new(&encoded_b), range).unwrap();
+        assert_eq!(b.len() as u64, final_chunk.verified_len(&hash_b).unwrap());
+        let mut forged = final_chunk.clone();
+        forged.slice[..HEADER_SIZE].copy_from_slice(&crate::encode_len(forged_len));
+        let err = forged.verified_len(&hash_b).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // A delta that stops short of the final chunk doesn't verify the length at all.
+        let range = ChunkRange { start: 0, end: 1 };
+        let first_chunk = Delta::extract(Cursor::new(&encoded_b), range).unwrap();
+        let err = first_chunk.verified_len(&hash_b).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Empty content has a final chunk too.
+        let (encoded_empty, hash_empty) = encode::encode(b"");
+        let range = ChunkRange::final_chunk(0);
+        assert_eq!(ChunkRange { start: 0, end: 1 }, range);
+        let delta = Delta::extract(Cursor::new(&encoded_empty), range).unwrap();
+        assert_eq!(0, delta.verified_len(&hash_empty).unwrap());
+    }
+
+    fn make_deltas(old: &[u8], new: &[u8]) -> (Vec<Delta>, Hash) {
+        let (new_encoded, new_hash) = encode::encode(new);
+        let (new_outboard, _) = encode::outboard(new);
+        let mut ranges = diff(old, new);
+        let final_chunk = ChunkRange::final_chunk(new.len() as u64);
+        if ranges.last().is_none_or(|last| last.end < final_chunk.end) {
+            ranges.push(final_chunk);
+        }
+        let mut deltas = Vec::new();
+        for range in ranges {
+            let delta = Delta::extract(Cursor::new(&new_encoded), range).unwrap();
+            let from_outboard =
+                Delta::extract_outboard(Cursor::new(new), Cursor::new(&new_outboard), range)
+                    .unwrap();
+            assert_eq!(delta, from_outboard);
+            deltas.push(delta);
+        }
+        (deltas, new_hash)
+    }
+
+    #[test]
+    fn test_apply() {
+        let lens: Vec<usize> = crate::test_vectors::test_cases(3)
+            .chain([11 * CHUNK_SIZE])
+            .collect();
+        for &old_len in &lens {
+            for &new_len in &lens {
+                println!("old_len {} new_len {}", old_len, new_len);
+                let old = make_test_input(old_len);
+                let mut new = make_test_input(new_len);
+                if new_len > CHUNK_SIZE {
+                    new[CHUNK_SIZE] ^= 1;
+                }
+                let (deltas, new_hash) = make_deltas(&old, &new);
+                let mut output = Vec::new();
+                let len = apply(Cursor::new(&old), &deltas, &new_hash, &mut output).unwrap();
+                assert_eq!(new.len() as u64, len);
+                assert_eq!(new, output);
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_bad_deltas() {
+        let old = make_test_input(20 * CHUNK_SIZE);
+        let mut new = old.clone();
+        new[2 * CHUNK_SIZE] ^= 1;
+        new[10 * CHUNK_SIZE] ^= 1;
+        new.extend_from_slice(&[1; 100]);
+        let (deltas, new_hash) = make_deltas(&old, &new);
+        assert_eq!(3, deltas.len());
+        let apply_err = |deltas: &[Delta], root: &Hash| {
+            let mut output = Vec::new();
+            let err = apply(Cursor::new(&old), deltas, root, &mut output).unwrap_err();
+            // Nothing unverified gets written, and nothing past the first bad delta.
+            assert!(new.starts_with(&output));
+            err.kind()
+        };
+
+        // A corrupt delta fails verification, after the content before it is written.
+        let mut bad = deltas.clone();
+        let last = bad[1].slice.len() - 1;
+        bad[1].slice[last] ^= 1;
+        assert_eq!(io::ErrorKind::InvalidData, apply_err(&bad, &new_hash));
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            apply_err(&deltas, &blake3::hash(b"foo"))
+        );
+
+        // A delta for the wrong range doesn't cover the range it claims.
+        let mut bad = deltas.clone();
+        bad[0].slice = bad[1].slice.clone();
+        assert_eq!(io::ErrorKind::InvalidData, apply_err(&bad, &new_hash));
+
+        // Leaving out the final chunk would leave the new length unverified.
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            apply_err(&deltas[..2], &new_hash)
+        );
+
+        // Deltas have to be in order.
+        let bad = vec![deltas[1].clone(), deltas[0].clone()];
+        assert_eq!(io::ErrorKind::InvalidInput, apply_err(&bad, &new_hash));
+
+        // A delta without a length header is truncated.
+        let mut bad = deltas.clone();
+        bad[0].slice.truncate(HEADER_SIZE - 1);
+        assert_eq!(io::ErrorKind::UnexpectedEof, apply_err(&bad, &new_hash));
+
+        // No deltas at all doesn't verify anything. If nothing changed, the final chunk is enough.
+        let (old_deltas, old_hash) = make_deltas(&old, &old);
+        assert_eq!(io::ErrorKind::InvalidData, apply_err(&[], &old_hash));
+        assert_eq!(1, old_deltas.len());
+        let mut output = Vec::new();
+        apply(Cursor::new(&old), &old_deltas, &old_hash, &mut output).unwrap();
+        assert_eq!(old, output);
+    }
+
+    #[test]
+    fn test_apply_forged_len() {
+        // The new content is longer than the old, in the same number of chunks, so the tree has the
+        // same shape either way. Forge the delta for chunk 2 to claim the old length, so that it
+        // still verifies and looks like the only change.
+        let old = make_test_input(20 * CHUNK_SIZE + 50);
+        let mut new = make_test_input(20 * CHUNK_SIZE + 100);
+        new[2 * CHUNK_SIZE] ^= 1;
+        let (deltas, new_hash) = make_deltas(&old, &new);
+        assert_eq!(2, deltas.len());
+        assert_eq!(ChunkRange { start: 2, end: 3 }, deltas[0].range);
+        let mut forged = deltas[0].clone();
+        forged.slice[..HEADER_SIZE].copy_from_slice(&crate::encode_len(old.len() as u64));
+        let mut output = Vec::new();
+        let err = apply(Cursor::new(&old), &[forged.clone()], &new_hash, &mut output).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(output.is_empty());
+
+        // Adding the real final chunk doesn't help, because the headers disagree.
+        let mut output = Vec::new();
+        let bad = vec![forged, deltas[1].clone()];
+        let err = apply(Cursor::new(&old), &bad, &new_hash, &mut output).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // A final chunk with the forged header fails verification.
+        let mut forged = deltas[1].clone();
+        forged.slice[..HEADER_SIZE].copy_from_slice(&crate::encode_len(old.len() as u64));
+        let mut output = Vec::new();
+        let bad = vec![deltas[0].clone(), forged];
+        let err = apply(Cursor::new(&old), &bad, &new_hash, &mut output).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(output.is_empty());
+    }
+}
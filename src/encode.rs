@@ -36,21 +36,35 @@
 //! # }
 //! ```
 
+use crate::backend::{self, BackendIo};
+use crate::config::Config;
+use crate::group;
+use crate::hash::ChunkHashCache;
+use crate::manifest::Manifest;
 use crate::Finalization::{self, NotRoot, Root};
-use crate::{Hash, ParentNode, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE};
-use arrayref::array_mut_ref;
+use crate::{
+    Hash, ParentNode, Progress, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE,
+};
+use arrayref::{array_mut_ref, array_ref};
 use arrayvec::ArrayVec;
 use std::cmp;
+use std::convert::TryFrom;
 use std::fmt;
 use std::io;
 use std::io::prelude::*;
-use std::io::SeekFrom;
+use std::io::{IoSlice, SeekFrom};
 
 /// Encode an entire slice into a bytes vector in the default combined mode.
 /// This is a convenience wrapper around `Encoder::write_all`.
 pub fn encode(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
     let bytes = input.as_ref();
-    let mut vec = Vec::with_capacity(encoded_size(bytes.len() as u64) as usize);
+    if bytes.len() <= CHUNK_SIZE {
+        let mut vec = vec![0; HEADER_SIZE + bytes.len()];
+        let (_, hash) = encode_small(bytes, &mut vec);
+        return (vec, hash);
+    }
+    let mut vec =
+        Vec::with_capacity(try_encoded_size(bytes.len() as u64).expect("encoding too large"));
     let mut encoder = Encoder::new(io::Cursor::new(&mut vec));
     encoder.write_all(bytes).unwrap();
     let hash = encoder.finalize().unwrap();
@@ -61,13 +75,534 @@ pub fn encode(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
 /// convenience wrapper around `Encoder::new_outboard` and `Encoder::write_all`.
 pub fn outboard(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
     let bytes = input.as_ref();
-    let mut vec = Vec::with_capacity(outboard_size(bytes.len() as u64) as usize);
+    if bytes.len() <= CHUNK_SIZE {
+        // The outboard encoding of a single chunk is just the length header.
+        return (
+            crate::encode_len(bytes.len() as u64).to_vec(),
+            hash_single_chunk(bytes),
+        );
+    }
+    let mut vec =
+        Vec::with_capacity(try_outboard_size(bytes.len() as u64).expect("outboard too large"));
     let mut encoder = Encoder::new_outboard(io::Cursor::new(&mut vec));
     encoder.write_all(bytes).unwrap();
     let hash = encoder.finalize().unwrap();
     (vec, hash)
 }
 
+/// The size of the largest encoding that `encode_small` produces, a length header and a full
+/// chunk.
+pub const MAX_SMALL_ENCODED_SIZE: usize = HEADER_SIZE + CHUNK_SIZE;
+
+/// Encode an input of at most one chunk into `output`, in the combined mode, and return the
+/// encoded length and the root hash. An encoding this small has no parent nodes, so it's just
+/// the length header followed by the input, and the root hash is a single call to
+/// `blake3::hash`. This doesn't allocate, so with a stack buffer of `MAX_SMALL_ENCODED_SIZE`
+/// bytes it's the fastest way to encode small records. `encode` and `outboard` take the same
+/// path for small inputs, apart from allocating the result.
+///
+/// # Panics
+///
+/// Panics if `input` is longer than `CHUNK_SIZE`, or if `output` is shorter than the encoding.
+///
+/// # Example
+///
+/// ```
+/// use bao::encode::{encode_small, MAX_SMALL_ENCODED_SIZE};
+///
+/// let mut buf = [0; MAX_SMALL_ENCODED_SIZE];
+/// let (len, hash) = encode_small(b"metadata", &mut buf);
+/// assert_eq!(bao::encode::encode(b"metadata"), (buf[..len].to_vec(), hash));
+/// ```
+pub fn encode_small(input: &[u8], output: &mut [u8]) -> (usize, Hash) {
+    assert!(input.len() <= CHUNK_SIZE, "input longer than one chunk");
+    let encoded_len = HEADER_SIZE + input.len();
+    assert!(output.len() >= encoded_len, "output too short");
+    output[..HEADER_SIZE].copy_from_slice(&crate::encode_len(input.len() as u64));
+    output[HEADER_SIZE..encoded_len].copy_from_slice(input);
+    (encoded_len, hash_single_chunk(input))
+}
+
+// The root hash of an input of at most one chunk, for the fast paths that skip the Encoder.
+fn hash_single_chunk(input: &[u8]) -> Hash {
+    trace_event!(trace, index = 0u64, len = input.len(), "chunk hashed");
+    blake3::hash(input)
+}
+
+/// Encode everything from `reader` in the default combined mode, writing the encoding to `writer`.
+/// Input is read one chunk at a time, so it never needs to be held in memory. This is a
+/// convenience wrapper around `Encoder::new` and `Encoder::finalize`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = b"some input";
+/// let mut encoded = Vec::new();
+/// let hash = bao::encode::encode_from_reader(&input[..], std::io::Cursor::new(&mut encoded))?;
+/// assert_eq!(bao::encode::encode(input), (encoded, hash));
+/// # Ok(())
+/// # }
+/// ```
+pub fn encode_from_reader(
+    mut reader: impl Read,
+    writer: impl Read + Write + Seek,
+) -> io::Result<Hash> {
+    let mut encoder = Encoder::new(writer);
+    let mut buf = [0; CHUNK_SIZE];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        encoder.write_all(&buf[..n])?;
+    }
+    encoder.finalize()
+}
+
+/// Make both the combined encoding and the outboard encoding of everything from `reader`, in a
+/// single pass. The input is read and hashed once, and each chunk and parent node goes to
+/// `combined`, with the parent nodes also going to `outboard`. This is cheaper than calling
+/// `encode_from_reader` and then making an `Encoder::new_outboard` encoding, which reads and
+/// hashes everything twice. Both writers should start out empty, like the writer of an `Encoder`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::Cursor;
+///
+/// let input = vec![0xab; 10_000];
+/// let mut combined = Vec::new();
+/// let mut outboard = Vec::new();
+/// let hash = bao::encode::encode_both(
+///     &input[..],
+///     Cursor::new(&mut combined),
+///     Cursor::new(&mut outboard),
+/// )?;
+/// assert_eq!(bao::encode::encode(&input), (combined, hash));
+/// assert_eq!(bao::encode::outboard(&input), (outboard, hash));
+/// # Ok(())
+/// # }
+/// ```
+pub fn encode_both(
+    mut reader: impl Read,
+    mut combined: impl Read + Write + Seek,
+    mut outboard: impl Read + Write + Seek,
+) -> io::Result<Hash> {
+    let mut state = EncoderState::new();
+    let mut buf = vec![0; DEFAULT_BUFFER_SIZE];
+    let mut combined_buf = Vec::with_capacity(2 * DEFAULT_BUFFER_SIZE);
+    let mut outboard_buf = Vec::new();
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let mut input = &buf[..n];
+        while !input.is_empty() {
+            let output = state.push(input);
+            combined_buf.extend_from_slice(output.tree_bytes());
+            combined_buf.extend_from_slice(output.content());
+            outboard_buf.extend_from_slice(output.tree_bytes());
+            input = &input[output.consumed()..];
+        }
+        combined.write_all(&combined_buf)?;
+        outboard.write_all(&outboard_buf)?;
+        combined_buf.clear();
+        outboard_buf.clear();
+    }
+    let (output, hash) = state.finalize();
+    combined.write_all(output.tree_bytes())?;
+    outboard.write_all(output.tree_bytes())?;
+    let config = Config::new();
+    flip_post_order(&mut combined, &config, false, 0, DEFAULT_FLIP_WINDOW_SIZE)?;
+    flip_post_order(&mut outboard, &config, true, 0, DEFAULT_FLIP_WINDOW_SIZE)?;
+    Ok(hash)
+}
+
+/// Concatenate several combined encodings into the encoding of their concatenated content, and
+/// return its root hash along with a reader for the new encoding. Each encoding comes with its
+/// hash, and nothing is returned unless every one of them verifies, so a corrupt input can't end
+/// up in the output under a new, valid hash. Every encoding but the last must have content that's
+/// a whole number of chunks, so that each one starts on a chunk boundary, and otherwise this
+/// returns an `InvalidInput` error.
+///
+/// This makes two passes over the encodings. The first one verifies them and computes the new
+/// tree, and only the new parent nodes and the chunks' new chaining values are kept in memory,
+/// about 3/32 of the content size. The second one is the returned reader, which streams the new
+/// encoding by interleaving those parent nodes with the content of each chunk, read from its
+/// encoding as it's needed. The reader verifies each chunk again against its chaining value from
+/// the first pass, so if an encoding changes in between, reading returns an `InvalidData` error
+/// instead of output that doesn't match the hash.
+///
+/// Each chunk's chaining value depends on the chunk's index in the tree, so moving a chunk to a
+/// new position changes its hash, and the parent nodes of the later encodings can't be reused.
+/// Their content is hashed twice in the first pass, once to verify it and once for its position
+/// in the new tree. The first encoding's chunks keep their indices, so its content is only hashed
+/// once, and the same chaining values both verify it and build the new tree.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+/// use std::io::Cursor;
+///
+/// let a = vec![1; 2 * bao::CHUNK_SIZE];
+/// let b = b"some more input";
+/// let (a_encoded, a_hash) = bao::encode::encode(&a);
+/// let (b_encoded, b_hash) = bao::encode::encode(b);
+/// let mut encodings = [(Cursor::new(a_encoded), a_hash), (Cursor::new(b_encoded), b_hash)];
+/// let (hash, mut reader) = bao::encode::concat(&mut encodings)?;
+/// let mut encoded = Vec::new();
+/// reader.read_to_end(&mut encoded)?;
+/// assert_eq!(bao::encode::encode([&a[..], &b[..]].concat()), (encoded, hash));
+/// # Ok(())
+/// # }
+/// ```
+pub fn concat<R: Read + Seek>(encodings: &mut [(R, Hash)]) -> io::Result<(Hash, impl Read + '_)> {
+    let mut parts = Vec::with_capacity(encodings.len());
+    let mut total_len: u64 = 0;
+    let mut chunk_cvs = Vec::new();
+    let mut last_chunk = Vec::with_capacity(CHUNK_SIZE);
+    for (encoded, hash) in encodings.iter_mut() {
+        if !total_len.is_multiple_of(CHUNK_SIZE as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encoding isn't a whole number of chunks",
+            ));
+        }
+        encoded.seek(SeekFrom::Start(0))?;
+        let chunk_start = chunk_cvs.len() as u64;
+        let len = concat_part(encoded, hash, &mut chunk_cvs, &mut last_chunk)?;
+        parts.push(ConcatPart { chunk_start, len });
+        total_len = total_len.checked_add(len).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "concatenation is too long")
+        })?;
+    }
+    let mut parents = Vec::new();
+    let hash = if count_chunks(total_len) == 1 {
+        crate::chunk_cv(0, &last_chunk, true)
+    } else {
+        parents_from_chunk_cvs(&chunk_cvs, total_len, Root, &mut parents)
+    };
+    let reader = ConcatReader {
+        encodings,
+        parts,
+        chunk_cvs,
+        total_len,
+        parents,
+        parents_position: 0,
+        chunk_index: 0,
+        part_index: 0,
+        buf: crate::encode_len(total_len).to_vec(),
+        buf_position: 0,
+    };
+    Ok((hash, reader))
+}
+
+// Read one encoding for `concat` and verify it against `hash`, pushing the chaining values that
+// its chunks have in the new tree onto `chunk_cvs`. Those chunks start at index
+// `chunk_cvs.len()`. The parent nodes are rebuilt from the chunks' own chaining values, and they
+// have to match the ones in the encoding, so this rejects the same encodings that a `Decoder`
+// would. The content of the last non-empty chunk so far is left in `last_chunk`, in case the new
+// tree is a single chunk, which is hashed as the root. Returns the content length.
+fn concat_part(
+    encoded: &mut impl Read,
+    hash: &Hash,
+    chunk_cvs: &mut Vec<Hash>,
+    last_chunk: &mut Vec<u8>,
+) -> io::Result<u64> {
+    let mut header = [0; HEADER_SIZE];
+    encoded.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    let layout = crate::layout::TreeLayout::new(content_len);
+    let chunk_start = chunk_cvs.len() as u64;
+    let mut own_cvs = Vec::new();
+    let mut position = HEADER_SIZE as u128;
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut parents = Vec::new();
+    for chunk_layout in layout.chunks() {
+        // Collect the parent nodes before this chunk, to check them at the end.
+        let parents_len = EncodedOffset::new(chunk_layout.encoded_offset - position).to_u64()?;
+        if encoded.take(parents_len).read_to_end(&mut parents)? < parents_len as usize {
+            return Err(crate::decode::Error::Truncated.into());
+        }
+        let bytes = &mut chunk[..chunk_layout.len];
+        encoded.read_exact(bytes)?;
+        position = chunk_layout.encoded_offset + chunk_layout.len as u128;
+        if content_len == 0 {
+            break;
+        }
+        let new_cv = crate::chunk_cv(chunk_start + chunk_layout.index, bytes, false);
+        chunk_cvs.push(new_cv);
+        // Chunks that keep their index have the same chaining value in both trees.
+        if chunk_start > 0 && layout.chunk_count() > 1 {
+            own_cvs.push(crate::chunk_cv(chunk_layout.index, bytes, false));
+        }
+        last_chunk.clear();
+        last_chunk.extend_from_slice(bytes);
+    }
+    let computed = if layout.chunk_count() == 1 {
+        let bytes = &chunk[..content_len as usize];
+        crate::chunk_cv(0, bytes, true)
+    } else {
+        let own_cvs = if chunk_start == 0 {
+            &chunk_cvs[..]
+        } else {
+            &own_cvs[..]
+        };
+        let mut expected_parents = Vec::with_capacity(parents.len());
+        let root = parents_from_chunk_cvs(own_cvs, content_len, Root, &mut expected_parents);
+        if parents != expected_parents {
+            return Err(crate::decode::Error::HashMismatch.into());
+        }
+        root
+    };
+    // Hash implements constant time equality.
+    if computed != *hash {
+        return Err(crate::decode::Error::HashMismatch.into());
+    }
+    Ok(content_len)
+}
+
+// Where one of the encodings for `concat` goes in the new tree.
+struct ConcatPart {
+    chunk_start: u64,
+    len: u64,
+}
+
+// The reader that `concat` returns. It emits the new encoding one chunk at a time, with the
+// parent nodes that come before the chunk in pre-order.
+struct ConcatReader<'a, R> {
+    encodings: &'a mut [(R, Hash)],
+    parts: Vec<ConcatPart>,
+    chunk_cvs: Vec<Hash>,
+    total_len: u64,
+    parents: Vec<u8>,
+    parents_position: usize,
+    chunk_index: u64,
+    part_index: usize,
+    buf: Vec<u8>,
+    buf_position: usize,
+}
+
+impl<R: Read + Seek> ConcatReader<'_, R> {
+    // Fill `buf` with the next chunk and the parent nodes before it. Returns false at the end.
+    fn fill_buf(&mut self) -> io::Result<bool> {
+        if self.chunk_index * CHUNK_SIZE as u64 >= self.total_len {
+            return Ok(false);
+        }
+        self.buf.clear();
+        self.buf_position = 0;
+        let parents_len =
+            pre_order_parent_nodes(self.chunk_index, self.total_len) as usize * PARENT_SIZE;
+        let parents = &self.parents[self.parents_position..][..parents_len];
+        self.buf.extend_from_slice(parents);
+        self.parents_position += parents_len;
+        // Empty parts have no chunks, so skip past them along with the finished ones.
+        let mut part = &self.parts[self.part_index];
+        while self.chunk_index >= part.chunk_start + part.len.div_ceil(CHUNK_SIZE as u64) {
+            self.part_index += 1;
+            part = &self.parts[self.part_index];
+        }
+        let index = self.chunk_index - part.chunk_start;
+        let offset = EncodedOffset::new(chunk_encoded_offset(index, part.len)).to_u64()?;
+        let len = cmp::min(CHUNK_SIZE as u64, part.len - index * CHUNK_SIZE as u64) as usize;
+        let encoded = &mut self.encodings[self.part_index].0;
+        encoded.seek(SeekFrom::Start(offset))?;
+        let start = self.buf.len();
+        self.buf.resize(start + len, 0);
+        encoded.read_exact(&mut self.buf[start..])?;
+        // The first pass verified this chunk, but the encoding could have changed since then.
+        // Every chunk has a non-root chaining value from that pass, even in a single-chunk tree.
+        let cv = crate::chunk_cv(self.chunk_index, &self.buf[start..], false);
+        // Hash implements constant time equality.
+        if cv != self.chunk_cvs[self.chunk_index as usize] {
+            return Err(crate::decode::Error::HashMismatch.into());
+        }
+        self.chunk_index += 1;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> Read for ConcatReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf_position == self.buf.len() && !self.fill_buf()? {
+            return Ok(0);
+        }
+        let available = &self.buf[self.buf_position..];
+        let n = cmp::min(buf.len(), available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.buf_position += n;
+        Ok(n)
+    }
+}
+
+// Append the parent nodes of a subtree in pre-order, given the chaining values of its chunks,
+// and return the subtree's chaining value. The root has to be a parent, not a single chunk.
+fn parents_from_chunk_cvs(
+    chunk_cvs: &[Hash],
+    len: u64,
+    finalization: Finalization,
+    output: &mut Vec<u8>,
+) -> Hash {
+    if chunk_cvs.len() == 1 {
+        debug_assert!(!finalization.is_root());
+        return chunk_cvs[0];
+    }
+    let left_len = left_subtree_len(len);
+    let (left, right) = chunk_cvs.split_at(count_chunks(left_len) as usize);
+    let parent_start = output.len();
+    output.extend_from_slice(&[0; PARENT_SIZE]);
+    let left_cv = parents_from_chunk_cvs(left, left_len, NotRoot, output);
+    let right_cv = parents_from_chunk_cvs(right, len - left_len, NotRoot, output);
+    output[parent_start..][..HASH_SIZE].copy_from_slice(left_cv.as_bytes());
+    output[parent_start + HASH_SIZE..][..HASH_SIZE].copy_from_slice(right_cv.as_bytes());
+    crate::parent_cv(&left_cv, &right_cv, finalization.is_root())
+}
+
+/// One piece of an encoding from `split`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Shard {
+    /// The offset of this shard's content in the original content.
+    pub start: u64,
+    /// The length of this shard's content. Only the last shard can be shorter than the shard size.
+    pub len: u64,
+    /// The shard's content along with the parent nodes that verify it against the original root
+    /// hash. This is a regular slice, so decode it with `SliceDecoder`, using `start` and `len`.
+    pub slice: Vec<u8>,
+}
+
+/// Split a combined encoding into shards of `shard_size` bytes of content each, the inverse of
+/// `concat`. Each shard can be verified against the original root hash on its own, so for
+/// example each storage provider holding a shard can prove its piece without the others. Empty
+/// content gives a single empty shard.
+///
+/// This reads the header right away and returns a `Split` iterator, which extracts one shard at a
+/// time as it's consumed, so only the current shard is held in memory. This doesn't verify the
+/// encoding. Splitting a corrupt encoding gives shards that fail to decode.
+///
+/// # Panics
+///
+/// Panics if `shard_size` isn't a nonzero multiple of `CHUNK_SIZE`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0xab; 5000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut shards = bao::encode::split(std::io::Cursor::new(&encoded), 2048)?;
+/// assert_eq!(3, shards.num_shards());
+/// let shard = shards.nth(1).unwrap()?;
+/// let mut content = Vec::new();
+/// bao::decode::SliceDecoder::new(&shard.slice[..], &hash, shard.start, shard.len)
+///     .read_to_end(&mut content)?;
+/// assert_eq!(&input[2048..4096], &content[..]);
+/// # Ok(())
+/// # }
+/// ```
+pub fn split<T: Read + Seek>(mut encoded: T, shard_size: u64) -> io::Result<Split<T>> {
+    assert!(
+        shard_size > 0 && shard_size.is_multiple_of(CHUNK_SIZE as u64),
+        "shard size must be a nonzero multiple of the chunk size"
+    );
+    let mut header = [0; HEADER_SIZE];
+    encoded.seek(SeekFrom::Start(0))?;
+    encoded.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    Ok(Split {
+        encoded,
+        shard_size,
+        content_len,
+        num_shards: cmp::max(1, content_len.div_ceil(shard_size)),
+        next_shard: 0,
+    })
+}
+
+/// An iterator over the shards of a combined encoding, from `split`.
+///
+/// Each item is extracted from the underlying reader when it's requested. After an IO error the
+/// iterator is done.
+#[derive(Debug)]
+pub struct Split<T: Read + Seek> {
+    encoded: T,
+    shard_size: u64,
+    content_len: u64,
+    num_shards: u64,
+    next_shard: u64,
+}
+
+impl<T: Read + Seek> Split<T> {
+    /// The length of the original content, from the header.
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// The total number of shards, including any that have already been yielded.
+    pub fn num_shards(&self) -> u64 {
+        self.num_shards
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> T {
+        self.encoded
+    }
+
+    fn extract(&mut self, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        // The extractor expects to start at the beginning of the encoding.
+        self.encoded.seek(SeekFrom::Start(0))?;
+        let mut slice = Vec::new();
+        SliceExtractor::new(&mut self.encoded, start, len).read_to_end(&mut slice)?;
+        Ok(slice)
+    }
+}
+
+impl<T: Read + Seek> Iterator for Split<T> {
+    type Item = io::Result<Shard>;
+
+    fn next(&mut self) -> Option<io::Result<Shard>> {
+        if self.next_shard >= self.num_shards {
+            return None;
+        }
+        let start = self.next_shard * self.shard_size;
+        let len = cmp::min(self.shard_size, self.content_len - start);
+        match self.extract(start, len) {
+            Ok(slice) => {
+                self.next_shard += 1;
+                Some(Ok(Shard { start, len, slice }))
+            }
+            Err(e) => {
+                self.next_shard = self.num_shards;
+                Some(Err(e))
+            }
+        }
+    }
+
+    fn nth(&mut self, n: usize) -> Option<io::Result<Shard>> {
+        // Skipping a shard doesn't need to read it.
+        self.next_shard = self.next_shard.saturating_add(n as u64);
+        self.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.num_shards.saturating_sub(self.next_shard);
+        match usize::try_from(remaining) {
+            Ok(n) => (n, Some(n)),
+            Err(_) => (usize::MAX, None),
+        }
+    }
+}
+
 /// Compute the size of a combined encoding, given the size of the input. Note that for input sizes
 /// close to `u64::MAX`, the result can overflow a `u64`.
 pub fn encoded_size(content_len: u64) -> u128 {
@@ -82,6 +617,92 @@ pub fn outboard_size(content_len: u64) -> u128 {
     outboard_subtree_size(content_len) + HEADER_SIZE as u128
 }
 
+/// Like `encoded_size`, but as a `usize` for sizing an in-memory buffer. This returns `None` when
+/// the encoding couldn't fit in memory on this target, which for a 32-bit or wasm32 target means
+/// any encoding of 4 GiB or more, rather than silently truncating the size.
+pub fn try_encoded_size(content_len: u64) -> Option<usize> {
+    EncodedOffset::new(encoded_size(content_len)).to_usize()
+}
+
+/// Like `outboard_size`, but as a `usize`. See `try_encoded_size`.
+pub fn try_outboard_size(content_len: u64) -> Option<usize> {
+    EncodedOffset::new(outboard_size(content_len)).to_usize()
+}
+
+/// The largest combined encoding of any content up to `max_content_len` bytes. Encoded sizes only
+/// grow with the content length, so this is `encoded_size(max_content_len)`, but it's the right
+/// name for a quota check that runs before the content length is known, for example to cap an
+/// upload. The overhead over the content is `max_encoded_size_for(n) - n as u128`, a bit more than
+/// 6% for large content.
+pub fn max_encoded_size_for(max_content_len: u64) -> u128 {
+    encoded_size(max_content_len)
+}
+
+/// The size of the slice that `SliceExtractor` produces for `slice_start` and `slice_len`, from an
+/// encoding of `content_len` bytes. This never underestimates, so it's safe for preallocating
+/// buffers and for quota checks before extracting or downloading anything, and for a well-formed
+/// encoding it's exact.
+///
+/// The slice holds the length header, every chunk that overlaps the requested range, and every
+/// parent node above those chunks. As with `SliceExtractor`, a `slice_len` of 0 counts as 1, and
+/// a `slice_start` at or past the end of the content means the final chunk. Working this out
+/// only takes a walk down the two edges of the range, not a pass over the chunks.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0; 1_000_000];
+/// let (encoded, _) = bao::encode::encode(&input);
+/// let bound = bao::encode::slice_size_upper_bound(65536, 8192, input.len() as u64);
+/// let mut slice = Vec::with_capacity(bound as usize);
+/// bao::encode::SliceExtractor::new(std::io::Cursor::new(&encoded), 65536, 8192)
+///     .read_to_end(&mut slice)?;
+/// assert_eq!(bound, slice.len() as u128);
+/// # Ok(())
+/// # }
+/// ```
+pub fn slice_size_upper_bound(slice_start: u64, slice_len: u64, content_len: u64) -> u128 {
+    let last_chunk = count_chunks(content_len) - 1;
+    let first_chunk = cmp::min(slice_start / CHUNK_SIZE as u64, last_chunk);
+    let range_start = first_chunk * CHUNK_SIZE as u64;
+    // Extend the end of the range out to a chunk boundary, or to the end of the content.
+    let slice_end = slice_start.saturating_add(cmp::max(slice_len, 1));
+    let range_end = cmp::min(
+        slice_end.div_ceil(CHUNK_SIZE as u64) as u128 * CHUNK_SIZE as u128,
+        content_len as u128,
+    ) as u64;
+    let range_end = cmp::max(
+        range_end,
+        cmp::min(range_start.saturating_add(CHUNK_SIZE as u64), content_len),
+    );
+    let parents = slice_parents(0, content_len, range_start, range_end);
+    HEADER_SIZE as u128 + parents as u128 * PARENT_SIZE as u128 + (range_end - range_start) as u128
+}
+
+// The number of parent nodes in the subtree at `subtree_start` that overlap the content range
+// from `start` to `end`, which has to overlap the subtree.
+fn slice_parents(subtree_start: u64, subtree_len: u64, start: u64, end: u64) -> u64 {
+    if subtree_len <= CHUNK_SIZE as u64 {
+        return 0;
+    }
+    if start <= subtree_start && subtree_start + subtree_len <= end {
+        return count_chunks(subtree_len) - 1;
+    }
+    let left_len = left_subtree_len(subtree_len);
+    let split = subtree_start + left_len;
+    let mut parents = 1;
+    if start < split {
+        parents += slice_parents(subtree_start, left_len, start, end);
+    }
+    if end > split {
+        parents += slice_parents(split, subtree_len - left_len, start, end);
+    }
+    parents
+}
+
 pub(crate) fn encoded_subtree_size(content_len: u64) -> u128 {
     content_len as u128 + outboard_subtree_size(content_len)
 }
@@ -107,6 +728,15 @@ pub(crate) fn chunk_size(chunk_index: u64, content_len: u64) -> usize {
     cmp::min(CHUNK_SIZE, (content_len - chunk_start) as usize)
 }
 
+// The content length of the left child of a subtree. This is the largest power of two number of
+// chunks that leaves at least one byte for the right child.
+pub(crate) fn left_subtree_len(content_len: u64) -> u64 {
+    debug_assert!(content_len > CHUNK_SIZE as u64);
+    let full_chunks = (content_len - 1) / CHUNK_SIZE as u64;
+    let left_chunks = 1 << (63 - full_chunks.leading_zeros());
+    left_chunks * CHUNK_SIZE as u64
+}
+
 // ----------------------------------------------------------------------------
 // When flipping the post-order tree to pre-order during encoding, and when
 // traversing the pre-order tree during decoding, we need to know how many
@@ -185,16 +815,353 @@ pub(crate) fn pre_order_parent_nodes(chunk_index: u64, content_len: u64) -> u8 {
     cmp::min(bit_length_rule, trailing_zeros_rule) as u8
 }
 
-// This type implements post-order-to-pre-order flipping for the encoder, in a way that could
-// support an incremental or asynchronous flip. (Though currently its only caller does the whole
-// flip all-at-once.)
-//
-// As discussed below and in bao.py, encoding first in post-order and then flipping to pre-order
-// makes it possible encode without knowing the input length in advance, and without requiring
-// buffer space for the entire input.
+// The offset of a chunk in a combined encoding, after all the parent nodes that precede it in
+// pre-order. Each of those parent nodes is either an ancestor of the chunk, or it's inside one of
+// the complete subtrees to the left of the chunk. Those subtrees add up to chunk_index chunks,
+// with one subtree for each 1 bit in chunk_index, and a complete subtree of N chunks has N-1
+// parent nodes. The ancestors we count by walking down from the root.
+pub(crate) fn chunk_encoded_offset(chunk_index: u64, content_len: u64) -> u128 {
+    debug_assert!(chunk_index < count_chunks(content_len));
+    let chunk_start = chunk_index * CHUNK_SIZE as u64;
+    let left_parents = chunk_index - chunk_index.count_ones() as u64;
+    let mut ancestors = 0;
+    let mut subtree_start = 0;
+    let mut subtree_len = content_len;
+    while subtree_len > CHUNK_SIZE as u64 {
+        ancestors += 1;
+        let left_len = left_subtree_len(subtree_len);
+        if chunk_start < subtree_start + left_len {
+            subtree_len = left_len;
+        } else {
+            subtree_start += left_len;
+            subtree_len -= left_len;
+        }
+    }
+    HEADER_SIZE as u128
+        + chunk_start as u128
+        + (left_parents + ancestors) as u128 * PARENT_SIZE as u128
+}
+
+/// Truncate a combined encoding in place, to the encoding of its first `new_len` content bytes,
+/// and return the new root hash.
+///
+/// The new encoding takes up the first `encoded_size(new_len)` bytes of `encoded`. This function
+/// can't shorten the underlying file, so callers need to do that themselves afterwards, for
+/// example with `File::set_len`. None of the remaining content gets rehashed, apart from the new
+/// final chunk. The chunks before it move forward to make room for a shorter tree, and only the
+/// parent nodes along the new right edge of the tree are recomputed.
+///
+/// Unlike `Appender::open`, this trusts the existing encoding and doesn't verify it. A `new_len`
+/// greater than the current content length is an `InvalidInput` error.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (mut encoded, _) = bao::encode::encode(b"hello world");
+/// let hash = bao::encode::truncate(std::io::Cursor::new(&mut encoded), 5)?;
+/// encoded.truncate(bao::encode::encoded_size(5) as usize);
+/// assert_eq!(bao::encode::encode(b"hello"), (encoded, hash));
+/// # Ok(())
+/// # }
+/// ```
+pub fn truncate(mut encoded: impl Read + Write + Seek, new_len: u64) -> io::Result<Hash> {
+    let mut header = [0; HEADER_SIZE];
+    encoded.seek(SeekFrom::Start(0))?;
+    encoded.read_exact(&mut header)?;
+    let old_len = crate::decode_len(&header);
+    if new_len > old_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "truncation longer than the content",
+        ));
+    }
+    let last_chunk = count_chunks(new_len) - 1;
+    let last_chunk_start = last_chunk * CHUNK_SIZE as u64;
+
+    // Walk down the old tree to the new final chunk. The left children we pass on the way are
+    // the complete subtrees to the left of that chunk, and they're the left children of the new
+    // right edge too.
+    let mut right_edge_lefts = ArrayVec::<(u64, Hash), MAX_DEPTH>::new();
+    let mut subtree_offset = HEADER_SIZE as u128;
+    let mut subtree_start = 0;
+    let mut subtree_len = old_len;
+    while subtree_len > CHUNK_SIZE as u64 {
+        let left_len = left_subtree_len(subtree_len);
+        if last_chunk_start < subtree_start + left_len {
+            subtree_offset += PARENT_SIZE as u128;
+            subtree_len = left_len;
+        } else {
+            let mut parent = [0; PARENT_SIZE];
+            encoded.seek(SeekFrom::Start(
+                EncodedOffset::new(subtree_offset).to_u64()?,
+            ))?;
+            encoded.read_exact(&mut parent)?;
+            right_edge_lefts.push((left_len, (*array_ref!(parent, 0, HASH_SIZE)).into()));
+            subtree_offset += PARENT_SIZE as u128 + encoded_subtree_size(left_len);
+            subtree_start += left_len;
+            subtree_len -= left_len;
+        }
+    }
+
+    // Rehash the new final chunk, and recompute the new right edge from the bottom up.
+    let mut last_chunk_bytes = [0; CHUNK_SIZE];
+    let last_chunk_size = (new_len - last_chunk_start) as usize;
+    encoded.seek(SeekFrom::Start(
+        EncodedOffset::new(subtree_offset).to_u64()?,
+    ))?;
+    encoded.read_exact(&mut last_chunk_bytes[..last_chunk_size])?;
+    let mut subtree_hash = crate::chunk_cv(
+        last_chunk,
+        &last_chunk_bytes[..last_chunk_size],
+        right_edge_lefts.is_empty(),
+    );
+    let mut right_edge = ArrayVec::<(u64, ParentNode), MAX_DEPTH>::new();
+    let mut right_edge_start = last_chunk_start;
+    for (i, (left_len, left_child)) in right_edge_lefts.iter().enumerate().rev() {
+        let mut parent = [0; PARENT_SIZE];
+        parent[..HASH_SIZE].copy_from_slice(left_child.as_bytes());
+        parent[HASH_SIZE..].copy_from_slice(subtree_hash.as_bytes());
+        right_edge_start -= left_len;
+        subtree_hash = crate::parent_cv(left_child, &subtree_hash, i == 0);
+        right_edge.push((right_edge_start, parent));
+    }
+    let root_hash = subtree_hash;
+
+    // Rewrite the tree in pre-order from front to back. Every node in the new tree is at or to
+    // the left of its old position, so we never clobber anything we haven't read yet. The new
+    // right edge nodes come first before their chunks. All the other parent nodes are complete
+    // subtrees that are unchanged from the old tree, and they're the innermost parents before
+    // each chunk in both trees.
+    encoded.seek(SeekFrom::Start(0))?;
+    encoded.write_all(&crate::encode_len(new_len))?;
+    let mut write_cursor = HEADER_SIZE as u64;
+    let mut buf = [0; CHUNK_SIZE];
+    for chunk_index in 0..=last_chunk {
+        let mut parents = pre_order_parent_nodes(chunk_index, new_len);
+        let chunk_start = chunk_index * CHUNK_SIZE as u64;
+        if let Some(&(_, parent)) = right_edge.iter().find(|&&(s, _)| s == chunk_start) {
+            encoded.seek(SeekFrom::Start(write_cursor))?;
+            encoded.write_all(&parent)?;
+            write_cursor += PARENT_SIZE as u64;
+            parents -= 1;
+        }
+        let old_chunk_offset = chunk_encoded_offset(chunk_index, old_len);
+        for height in (1..=parents as u128).rev() {
+            let old_offset = old_chunk_offset - height * PARENT_SIZE as u128;
+            encoded.seek(SeekFrom::Start(EncodedOffset::new(old_offset).to_u64()?))?;
+            encoded.read_exact(&mut buf[..PARENT_SIZE])?;
+            encoded.seek(SeekFrom::Start(write_cursor))?;
+            encoded.write_all(&buf[..PARENT_SIZE])?;
+            write_cursor += PARENT_SIZE as u64;
+        }
+        let size = chunk_size(chunk_index, new_len);
+        encoded.seek(SeekFrom::Start(
+            EncodedOffset::new(old_chunk_offset).to_u64()?,
+        ))?;
+        encoded.read_exact(&mut buf[..size])?;
+        encoded.seek(SeekFrom::Start(write_cursor))?;
+        encoded.write_all(&buf[..size])?;
+        write_cursor += size as u64;
+    }
+    debug_assert_eq!(write_cursor as u128, encoded_size(new_len));
+    Ok(root_hash)
+}
+
+/// Flip a combined encoding from post-order to pre-order in place.
+///
+/// A post-order encoding has the same nodes as a regular combined encoding, but each parent node
+/// comes after its children instead of before them, and the 8-byte length header comes last
+/// instead of first. That's the layout a streaming writer can produce without knowing the length
+/// in advance, and without seeking, since each parent node is ready as soon as its children are.
+/// It's what `Encoder` writes before `finalize`, and a peer can send it over the network as it
+/// goes. The receiver can write it to a file and then flip it with this function.
+///
+/// The flip reads and writes in windows of `DEFAULT_FLIP_WINDOW_SIZE`. This doesn't verify
+/// anything. Decode the result with the expected hash as usual.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// // A post-order encoding of three chunks: the first two chunks, their parent, the third
+/// // chunk, the root parent, and the length header.
+/// let input = vec![0xab; 2100];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let parents = &encoded[8..8 + 128];
+/// let chunks = &encoded[8 + 128..];
+/// let mut post_order = Vec::new();
+/// post_order.extend_from_slice(&chunks[..2048]);
+/// post_order.extend_from_slice(&parents[64..]);
+/// post_order.extend_from_slice(&chunks[2048..]);
+/// post_order.extend_from_slice(&parents[..64]);
+/// post_order.extend_from_slice(&encoded[..8]);
+///
+/// let mut file = std::io::Cursor::new(post_order);
+/// bao::encode::flip(&mut file)?;
+/// assert_eq!(encoded, file.into_inner());
+/// # Ok(())
+/// # }
+/// ```
+pub fn flip(encoding: impl Read + Write + Seek) -> io::Result<()> {
+    flip_with_config(encoding, &Config::new())
+}
+
+/// Flip an outboard encoding from post-order to pre-order in place. This is the same as `flip`,
+/// but for an encoding with only the parent nodes and the length header.
+pub fn flip_outboard(outboard: impl Read + Write + Seek) -> io::Result<()> {
+    flip_outboard_with_config(outboard, &Config::new())
+}
+
+/// Like `flip`, for the post-order output of an `EncoderState` with `set_config`. The profile
+/// sets the size of the parent nodes, and this adds the alignment padding, so the encoding grows
+/// to `config::aligned_encoded_size`. A `FlipperState` works with any profile too, as long as
+/// the caller reads and writes parent nodes of `2 * digest_len` bytes, at the front of the
+/// arrays that `feed_parent` and `take_parent` pass around. It doesn't know about alignment,
+/// though.
+pub fn flip_with_config(encoding: impl Read + Write + Seek, config: &Config) -> io::Result<()> {
+    flip_post_order(encoding, config, false, 0, DEFAULT_FLIP_WINDOW_SIZE)
+}
+
+/// Like `flip_outboard`, for the post-order output of an `EncoderState` with `set_config`.
+pub fn flip_outboard_with_config(
+    outboard: impl Read + Write + Seek,
+    config: &Config,
+) -> io::Result<()> {
+    flip_post_order(outboard, config, true, 0, DEFAULT_FLIP_WINDOW_SIZE)
+}
+
+/// Like `flip`, but for a combined encoding in a `Backend`, with positional reads and writes.
+pub fn flip_backend(backend: impl backend::Backend) -> io::Result<()> {
+    flip(BackendIo::new(backend))
+}
+
+/// Like `flip_outboard`, but for an outboard encoding in a `Backend`.
+pub fn flip_outboard_backend(backend: impl backend::Backend) -> io::Result<()> {
+    flip_outboard(BackendIo::new(backend))
+}
+
+// Flip a post-order encoding to pre-order in place. With a nonzero `chunk_group_log`, the leaves
+// of the tree are chunk groups, as in the `group` module, and the profile has to be the standard
+// one. A tree of groups has the same shape as a tree of chunks with one chunk per group, so the
+// FlipperState runs over that, and each leaf it moves is a whole group.
+pub(crate) fn flip_post_order(
+    mut inner: impl Read + Write + Seek,
+    config: &Config,
+    outboard: bool,
+    chunk_group_log: u8,
+    window_size: usize,
+) -> io::Result<()> {
+    trace_span!("encode.flip");
+    debug_assert!(chunk_group_log == 0 || config.is_standard());
+    let encoding_end = inner.seek(SeekFrom::End(0))?;
+    if encoding_end < HEADER_SIZE as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "missing length header",
+        ));
+    }
+    let mut header = [0; HEADER_SIZE];
+    inner.seek(SeekFrom::Start(encoding_end - HEADER_SIZE as u64))?;
+    inner.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    // The post-order encoding has no alignment padding. The flip adds it, so an aligned
+    // pre-order encoding ends past the post-order one.
+    let (expected_len, output_end) = if chunk_group_log > 0 {
+        let expected_len = if outboard {
+            group::outboard_size(content_len, chunk_group_log)
+        } else {
+            group::encoded_size(content_len, chunk_group_log)
+        };
+        (expected_len, encoding_end)
+    } else if outboard {
+        (config.outboard_size(content_len), encoding_end)
+    } else {
+        let output_end = EncodedOffset::new(config.encoded_size(content_len)).to_u64()?;
+        (
+            config.with_alignment(1).encoded_size(content_len),
+            output_end,
+        )
+    };
+    if encoding_end as u128 != expected_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "length header doesn't match the encoding size",
+        ));
+    }
+    let window_size = cmp::max(window_size, CHUNK_SIZE) as u64;
+    let window_size = cmp::min(window_size, encoding_end) as usize;
+    let mut reader = BackwardReader::new(encoding_end - HEADER_SIZE as u64, window_size);
+    let mut writer = BackwardWriter::new(output_end, window_size);
+    let group_size = group::group_size(chunk_group_log) as u64;
+    let mut flipper = if chunk_group_log > 0 {
+        let groups = group::count_groups(content_len, chunk_group_log);
+        FlipperState::new(groups * CHUNK_SIZE as u64)
+    } else {
+        FlipperState::new(content_len)
+    };
+    let parent_size = config.parent_size();
+    loop {
+        match flipper.next() {
+            FlipperNext::FeedParent => {
+                let mut parent = [0; PARENT_SIZE];
+                reader.read(&mut inner, &mut parent[..parent_size])?;
+                flipper.feed_parent(parent);
+            }
+            FlipperNext::TakeParent => {
+                let parent = flipper.take_parent();
+                writer.write(&mut inner, &parent[..parent_size])?;
+            }
+            FlipperNext::Chunk(size) => {
+                // In outboard moded, we skip over chunks.
+                if !outboard {
+                    let index = flipper.last_chunk_moved - 1;
+                    let mut remaining = if chunk_group_log > 0 {
+                        cmp::min(group_size, content_len - index * group_size) as usize
+                    } else {
+                        size
+                    };
+                    // A group can be much bigger than a chunk, so move it a chunk's worth at a
+                    // time, from the back.
+                    let mut chunk = [0; CHUNK_SIZE];
+                    while remaining > 0 {
+                        let piece = &mut chunk[..cmp::min(remaining, CHUNK_SIZE)];
+                        reader.read(&mut inner, piece)?;
+                        writer.write(&mut inner, piece)?;
+                        remaining -= piece.len();
+                    }
+                    let padding = config.chunk_padding_before(index, content_len);
+                    writer.write_zeros(&mut inner, padding as usize)?;
+                }
+                flipper.chunk_moved();
+            }
+            FlipperNext::Done => {
+                writer.flush(&mut inner)?;
+                debug_assert_eq!(HEADER_SIZE as u64, writer.position);
+                inner.seek(SeekFrom::Start(0))?;
+                inner.write_all(&header)?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The state machine behind `flip`, for callers who want to drive the flip themselves, for
+/// example incrementally or with async IO.
+///
+/// The flip works from the back of the post-order encoding to the front, reading nodes at a read
+/// cursor and writing them at a write cursor, both moving backwards. The write cursor starts at
+/// the end of the encoding, and the read cursor starts just before the length header. The write
+/// cursor never falls below the read cursor, so the flip can happen in place. Call `next` to find
+/// out what to do, do it, and repeat until `FlipperNext::Done`. Then write the length header at
+/// the front.
+///
+/// As discussed in bao.py, encoding first in post-order and then flipping to pre-order makes it
+/// possible to encode without knowing the input length in advance, and without requiring buffer
+/// space for the entire input.
 #[derive(Clone)]
-struct FlipperState {
-    parents: ArrayVec<crate::ParentNode, MAX_DEPTH>,
+pub struct FlipperState {
+    parents: ArrayVec<ParentNode, MAX_DEPTH>,
     content_len: u64,
     last_chunk_moved: u64,
     parents_needed: u8,
@@ -202,6 +1169,7 @@ struct FlipperState {
 }
 
 impl FlipperState {
+    /// Start a flip, given the content length from the length header.
     pub fn new(content_len: u64) -> Self {
         let total_chunks = count_chunks(content_len);
         Self {
@@ -213,6 +1181,7 @@ impl FlipperState {
         }
     }
 
+    /// What the caller needs to do next.
     pub fn next(&self) -> FlipperNext {
         // chunk_moved() adds both the parents_available for the chunk just moved and the
         // parents_needed for the chunk to its left, so we have to do TakeParent first.
@@ -227,6 +1196,7 @@ impl FlipperState {
         }
     }
 
+    /// Report that the caller moved the chunk from `FlipperNext::Chunk`.
     pub fn chunk_moved(&mut self) {
         // Add the pre-order parents available for the chunk that just moved and the post-order
         // parents needed for the chunk to its left.
@@ -240,7 +1210,8 @@ impl FlipperState {
         }
     }
 
-    pub fn feed_parent(&mut self, parent: crate::ParentNode) {
+    /// Supply the parent node that ends at the read cursor, for `FlipperNext::FeedParent`.
+    pub fn feed_parent(&mut self, parent: [u8; PARENT_SIZE]) {
         debug_assert!(self.last_chunk_moved > 0);
         debug_assert_eq!(self.parents_available, 0);
         debug_assert!(self.parents_needed > 0);
@@ -248,7 +1219,8 @@ impl FlipperState {
         self.parents.push(parent);
     }
 
-    pub fn take_parent(&mut self) -> crate::ParentNode {
+    /// Take the parent node to write before the write cursor, for `FlipperNext::TakeParent`.
+    pub fn take_parent(&mut self) -> [u8; PARENT_SIZE] {
         debug_assert!(self.parents_available > 0);
         self.parents_available -= 1;
         self.parents.pop().expect("took too many parents")
@@ -262,11 +1234,21 @@ impl fmt::Debug for FlipperState {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-enum FlipperNext {
+/// The next step of a flip, from `FlipperState::next`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlipperNext {
+    /// Read the parent node that ends at the read cursor, move the read cursor back over it, and
+    /// pass it to `feed_parent`.
     FeedParent,
+    /// Get a parent node from `take_parent`, write it so that it ends at the write cursor, and
+    /// move the write cursor back over it.
     TakeParent,
+    /// Move a chunk of this size: read it from before the read cursor and write it before the
+    /// write cursor, moving both cursors back over it. Then call `chunk_moved`. In an outboard
+    /// encoding, there are no chunks to move, so just call `chunk_moved`.
     Chunk(usize),
+    /// The flip is finished. The write cursor is at `HEADER_SIZE`, and the length header goes in
+    /// front of it.
     Done,
 }
 
@@ -293,15 +1275,13 @@ impl State {
         self.total_len
     }
 
-    fn merge_inner(&mut self, finalization: Finalization) -> ParentNode {
+    fn merge_inner(&mut self, config: &Config, finalization: Finalization) -> ParentNode {
         let right_child = self.subtrees.pop().unwrap();
         let left_child = self.subtrees.pop().unwrap();
-        let parent_cv = blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+        let parent_cv = config.parent_hash(&left_child, &right_child, finalization, self.total_len);
         self.subtrees.push(parent_cv);
-        let mut parent_node = [0; PARENT_SIZE];
-        parent_node[..HASH_SIZE].copy_from_slice(left_child.as_bytes());
-        parent_node[HASH_SIZE..].copy_from_slice(right_child.as_bytes());
-        parent_node
+        trace_event!(trace, content_end = self.total_len, "parent merged");
+        config.write_parent(&left_child, &right_child)
     }
 
     // We keep the subtree hashes in an array without storing their size, and we use this cute
@@ -356,22 +1336,22 @@ impl State {
     ///
     /// After the final call to `push_subtree`, you must call `merge_finalize` in a loop instead of
     /// this function.
-    pub fn merge_parent(&mut self) -> Option<ParentNode> {
+    pub fn merge_parent(&mut self, config: &Config) -> Option<ParentNode> {
         if !self.needs_merge() {
             return None;
         }
-        Some(self.merge_inner(NotRoot))
+        Some(self.merge_inner(config, NotRoot))
     }
 
     /// Returns a tuple of `ParentNode` bytes and (in the last call only) the root hash. Callers
     /// who need `ParentNode` bytes must call `merge_finalize` in a loop after pushing the final
     /// subtree, until the second return value is `Some`. Callers who don't need parent nodes
     /// should use the simpler `finalize` interface instead.
-    pub fn merge_finalize(&mut self) -> StateFinish {
+    pub fn merge_finalize(&mut self, config: &Config) -> StateFinish {
         if self.subtrees.len() > 2 {
-            StateFinish::Parent(self.merge_inner(NotRoot))
+            StateFinish::Parent(self.merge_inner(config, NotRoot))
         } else if self.subtrees.len() == 2 {
-            StateFinish::Parent(self.merge_inner(Root))
+            StateFinish::Parent(self.merge_inner(config, Root))
         } else {
             StateFinish::Root(self.subtrees[0])
         }
@@ -385,103 +1365,582 @@ impl fmt::Debug for State {
     }
 }
 
-/// An incremental encoder. Note that you must call `finalize` after you're
-/// done writing.
-///
-/// `Encoder` supports both combined and outboard encoding, depending on which
-/// constructor you use.
-///
-/// # Example
-///
-/// ```
-/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-/// use std::io::prelude::*;
+/// The output of `EncoderState::push` and `EncoderState::finalize`: some bytes of the post-order
+/// encoding, and the offset where they go.
 ///
-/// let mut encoded_incrementally = Vec::new();
-/// let encoded_cursor = std::io::Cursor::new(&mut encoded_incrementally);
-/// let mut encoder = bao::encode::Encoder::new(encoded_cursor);
-/// encoder.write_all(b"some input")?;
-/// encoder.finalize()?;
+/// The bytes are `tree_bytes` followed by `content`, contiguous in the encoding. Consecutive
+/// outputs are contiguous too, so a caller writing them to a stream in order can ignore
+/// `offset`. Callers whose writes might complete out of order can use it to put each one in its
+/// place.
+#[derive(Clone)]
+pub struct EncodeOutput<'a> {
+    offset: u64,
+    consumed: usize,
+    tree_bytes: ArrayVec<u8, { PARENT_SIZE * MAX_DEPTH + HEADER_SIZE }>,
+    content: &'a [u8],
+}
+
+impl<'a> EncodeOutput<'a> {
+    /// The offset of these bytes in the post-order encoding.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The number of input bytes that `push` took. The caller has to push the rest again. This
+    /// is always 0 from `finalize`.
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    /// Parent nodes, and from `finalize`, the length header at the end. These come first.
+    pub fn tree_bytes(&self) -> &[u8] {
+        &self.tree_bytes
+    }
+
+    /// Content bytes, which follow `tree_bytes`. These are a subslice of the input to `push`, and
+    /// they're always empty in outboard mode and from `finalize`.
+    pub fn content(&self) -> &'a [u8] {
+        self.content
+    }
+
+    /// The total number of bytes to write, `tree_bytes` and `content` together.
+    pub fn len(&self) -> usize {
+        self.tree_bytes.len() + self.content.len()
+    }
+
+    /// Returns `true` if there's nothing to write.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> fmt::Debug for EncodeOutput<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        f.debug_struct("EncodeOutput")
+            .field("offset", &self.offset)
+            .field("consumed", &self.consumed)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// The hashing and tree logic of an `Encoder`, without any IO.
+///
+/// `push` takes input and returns an `EncodeOutput` with the bytes to write and where to write
+/// them, and `finalize` returns the last of the bytes and the root hash. `EncoderState` never
+/// blocks and never fails, so any IO model can drive it, including async runtimes without
+/// `AsyncSeek` and completion-based IO that submits writes with explicit offsets. Every call to
+/// `push` with non-empty input makes progress.
+///
+/// The output is the post-order encoding, with the length header at the end, the same thing
+/// `Encoder` writes before `finalize`. To get the regular pre-order encoding, flip it
+/// afterwards, either with `flip` or `flip_outboard` if the storage supports `Read` and `Seek`,
+/// or with a `FlipperState` driven by whatever IO the caller has.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use bao::encode::EncoderState;
+///
+/// let input = vec![0xab; 5000];
+/// let mut state = EncoderState::new();
+/// let mut post_order = Vec::new();
+/// let mut remaining = &input[..];
+/// while !remaining.is_empty() {
+///     let output = state.push(remaining);
+///     assert_eq!(post_order.len() as u64, output.offset());
+///     post_order.extend_from_slice(output.tree_bytes());
+///     post_order.extend_from_slice(output.content());
+///     remaining = &remaining[output.consumed()..];
+/// }
+/// let (output, hash) = state.finalize();
+/// post_order.extend_from_slice(output.tree_bytes());
+///
+/// let mut encoding = std::io::Cursor::new(post_order);
+/// bao::encode::flip(&mut encoding)?;
+/// assert_eq!(bao::encode::encode(&input), (encoding.into_inner(), hash));
 /// # Ok(())
 /// # }
 /// ```
 #[derive(Clone, Debug)]
-pub struct Encoder<T: Read + Write + Seek> {
-    inner: T,
-    chunk_state: blake3::guts::ChunkState,
+pub struct EncoderState {
+    config: Config,
+    chunk_state: blake3::Hasher,
     tree_state: State,
     outboard: bool,
     finalized: bool,
+    position: u64,
+    cached_chunks: Option<CachedChunks>,
+    progress: Option<Progress>,
+    manifest: Option<Manifest>,
 }
 
-impl<T: Read + Write + Seek> Encoder<T> {
-    /// Create a new `Encoder` that will produce a combined encoding.The encoding will contain all
-    /// the input bytes, so that it can be decoded without the original input file. This is what
-    /// you get from `bao encode`.
-    pub fn new(inner: T) -> Self {
+impl EncoderState {
+    /// A state for the combined encoding.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let config = Config::new();
         Self {
-            inner,
-            chunk_state: blake3::guts::ChunkState::new(0),
+            config,
+            chunk_state: config.chunk_hasher(0),
             tree_state: State::new(),
             outboard: false,
             finalized: false,
+            position: 0,
+            cached_chunks: None,
+            progress: None,
+            manifest: None,
         }
     }
 
-    /// Create a new `Encoder` for making an outboard encoding. That means that the encoding won't
-    /// include any input bytes. Instead, the input will need to be supplied as a separate argument
-    /// when the outboard encoding is later decoded. This is what you get from `bao encode
-    /// --outboard`.
-    pub fn new_outboard(inner: T) -> Self {
-        let mut encoder = Self::new(inner);
-        encoder.outboard = true;
-        encoder
+    /// A state for the outboard encoding. Outputs never include content bytes.
+    pub fn new_outboard() -> Self {
+        let mut state = Self::new();
+        state.outboard = true;
+        state
     }
 
-    /// Finalize the encoding, after all the input has been written. You can't keep using this
-    /// `Encoder` again after calling `finalize`, and writing or finalizing again will panic.
+    /// Whether this state makes an outboard encoding.
+    pub fn is_outboard(&self) -> bool {
+        self.outboard
+    }
+
+    /// See `Encoder::set_config`. Flip the output with `flip_with_config` or
+    /// `flip_outboard_with_config`.
     ///
-    /// The underlying strategy of the `Encoder` is to first store the tree in a post-order layout,
-    /// and then to go back and flip the entire thing into pre-order. That makes it possible to
-    /// stream input without knowing its length in advance, which is a core requirement of the
-    /// `std::io::Write` interface. The downside is that `finalize` is a relatively expensive step.
-    pub fn finalize(&mut self) -> io::Result<Hash> {
+    /// # Panics
+    ///
+    /// This panics if any input has already been pushed.
+    pub fn set_config(&mut self, config: Config) {
+        assert_eq!(0, self.content_len(), "input already written");
+        assert!(
+            self.manifest.is_none() || config.is_standard(),
+            "manifests need the standard profile"
+        );
+        self.config = config;
+        self.chunk_state = config.chunk_hasher(0);
+    }
+
+    /// See `Encoder::set_chunk_cache`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if any input has already been pushed.
+    pub fn set_chunk_cache(&mut self, cache: ChunkHashCache) {
+        assert_eq!(0, self.content_len(), "input already written");
+        self.cached_chunks = Some(CachedChunks {
+            hit: cache.get(0),
+            cache,
+            buf: Vec::new(),
+        });
+    }
+
+    /// See `Encoder::set_progress_callback`.
+    pub fn set_progress_callback(&mut self, callback: impl Fn(u64) + Send + Sync + 'static) {
+        self.progress = Some(Progress::new(callback));
+    }
+
+    /// See `Encoder::enable_manifest`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if any input has already been pushed, or with a non-standard `Config`.
+    pub fn enable_manifest(&mut self) {
+        assert_eq!(0, self.content_len(), "input already written");
+        assert!(
+            self.config.is_standard(),
+            "manifests need the standard profile"
+        );
+        self.manifest = Some(Manifest::new());
+    }
+
+    /// See `Encoder::take_manifest`.
+    pub fn take_manifest(&mut self) -> Option<Manifest> {
+        self.manifest.take()
+    }
+
+    /// See `Encoder::take_chunk_cache`.
+    pub fn take_chunk_cache(&mut self) -> Option<ChunkHashCache> {
+        // If we're in the middle of a cached chunk, hash it for real, so that the encoding stays
+        // correct without the cache.
+        self.hash_cached_chunk();
+        self.cached_chunks.take().map(|cached| cached.cache)
+    }
+
+    /// The number of content bytes pushed so far.
+    pub fn content_len(&self) -> u64 {
+        self.tree_state.count() + self.chunk_len() as u64
+    }
+
+    /// The number of chunks hashed into the tree so far. See `Encoder::chunks_written`.
+    pub fn chunks_written(&self) -> u64 {
+        self.tree_state.count().div_ceil(CHUNK_SIZE as u64)
+    }
+
+    /// The number of bytes of output so far, which is also the offset of the next output.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Whether `finalize` has been called.
+    pub fn is_finalized(&self) -> bool {
+        self.finalized
+    }
+
+    /// Take as much of `input` as fits in the current chunk, and return the bytes to write. See
+    /// `EncodeOutput::consumed` for how much was taken. When the current chunk is full, this
+    /// hashes it into the tree first, which might complete some parent nodes.
+    ///
+    /// # Panics
+    ///
+    /// This panics after `finalize`.
+    pub fn push<'a>(&mut self, input: &'a [u8]) -> EncodeOutput<'a> {
+        assert!(!self.finalized, "already finalized");
+        let mut tree_bytes = ArrayVec::new();
+
+        // If the current chunk is full, we need to finalize it, add it to
+        // the tree state, and write out any completed parent nodes.
+        if self.chunk_len() == CHUNK_SIZE {
+            let chunk_index = self.tree_state.count() / CHUNK_SIZE as u64;
+            let chunk_hash = match &mut self.cached_chunks {
+                Some(cached) => {
+                    if let Some(hash) = cached.hit.take() {
+                        cached.buf.clear();
+                        hash
+                    } else {
+                        let hash = self.config.chunk_hash(&self.chunk_state, NotRoot, 0);
+                        cached.cache.insert(chunk_index, hash);
+                        hash
+                    }
+                }
+                None => self.config.chunk_hash(&self.chunk_state, NotRoot, 0),
+            };
+            if let Some(manifest) = &mut self.manifest {
+                manifest.push(chunk_hash, CHUNK_SIZE);
+            }
+            self.tree_state.push_subtree(&chunk_hash, CHUNK_SIZE);
+            trace_event!(trace, index = chunk_index, len = CHUNK_SIZE, "chunk hashed");
+            Progress::report(&self.progress, self.tree_state.count());
+            let chunk_counter = self.tree_state.count() / CHUNK_SIZE as u64;
+            self.chunk_state = self.config.chunk_hasher(chunk_counter);
+            if let Some(cached) = &mut self.cached_chunks {
+                cached.hit = cached.cache.get(chunk_counter);
+            }
+            while let Some(parent) = self.tree_state.merge_parent(&self.config) {
+                tree_bytes
+                    .try_extend_from_slice(&parent[..self.config.parent_size()])
+                    .unwrap();
+            }
+        }
+
+        // Add as many bytes as possible to the current chunk.
+        let want = CHUNK_SIZE - self.chunk_len();
+        let take = cmp::min(want, input.len());
+        match &mut self.cached_chunks {
+            Some(cached) if cached.hit.is_some() => cached.buf.extend_from_slice(&input[..take]),
+            _ => {
+                self.chunk_state.update(&input[..take]);
+            }
+        }
+        let content = if self.outboard { &[] } else { &input[..take] };
+        self.output(take, tree_bytes, content)
+    }
+
+    /// Hash the final chunk and return the rest of the encoding, the parent nodes along the
+    /// right edge of the tree and the length header, along with the root hash. Pushing or
+    /// finalizing again afterwards will panic.
+    pub fn finalize(&mut self) -> (EncodeOutput<'static>, Hash) {
         assert!(!self.finalized, "already finalized");
         self.finalized = true;
 
+        // A cached final chunk has to be hashed after all. It might be a partial chunk, or it
+        // might be the root.
+        self.hash_cached_chunk();
+
         // Compute the total len before we merge the final chunk into the
         // tree_state.
         let total_len = self
             .tree_state
             .count()
-            .checked_add(self.chunk_state.len() as u64)
+            .checked_add(self.chunk_state.count())
             .expect("addition overflowed");
+        let final_chunk_len = self.chunk_state.count() as usize;
 
         // If the chunk_state contains any chunk data, we have to finalize it
         // and incorporate it into the tree. Also, if there was never any data
         // at all, we have to hash the empty chunk. Note that any partial chunk
-        // bytes retained in the chunk_state have already been written to the
-        // underlying writer by .write().
-        if self.chunk_state.len() > 0 || self.tree_state.count() == 0 {
+        // bytes retained in the chunk_state have already been returned by
+        // .push().
+        if final_chunk_len > 0 || self.tree_state.count() == 0 {
             let is_root = self.tree_state.count() == 0;
-            let hash = self.chunk_state.finalize(is_root);
-            self.tree_state.push_subtree(&hash, self.chunk_state.len());
+            let finalization = if is_root { Root } else { NotRoot };
+            let hash = self
+                .config
+                .chunk_hash(&self.chunk_state, finalization, total_len);
+            if let Some(manifest) = &mut self.manifest {
+                let chunk_hash = if final_chunk_len == 0 {
+                    // Config can't hash an empty non-root chunk, but manifests are standard-only.
+                    crate::chunk_cv(0, &[], false)
+                } else if is_root {
+                    self.config.chunk_hash(&self.chunk_state, NotRoot, 0)
+                } else {
+                    hash
+                };
+                manifest.push(chunk_hash, final_chunk_len);
+            }
+            if let Some(cached) = &mut self.cached_chunks {
+                if !is_root && final_chunk_len == CHUNK_SIZE {
+                    let chunk_index = self.tree_state.count() / CHUNK_SIZE as u64;
+                    cached.cache.insert(chunk_index, hash);
+                }
+            }
+            self.tree_state.push_subtree(&hash, final_chunk_len);
+            trace_event!(
+                trace,
+                index = self.tree_state.count().saturating_sub(1) / CHUNK_SIZE as u64,
+                len = final_chunk_len,
+                "chunk hashed"
+            );
+            Progress::report(&self.progress, self.tree_state.count());
         }
 
-        // Merge and write all the parents along the right edge.
+        // Merge all the parents along the right edge, and put the length header at the end.
+        let mut tree_bytes = ArrayVec::new();
         let root_hash;
         loop {
-            match self.tree_state.merge_finalize() {
-                StateFinish::Parent(parent) => self.inner.write_all(&parent)?,
+            match self.tree_state.merge_finalize(&self.config) {
+                StateFinish::Parent(parent) => tree_bytes
+                    .try_extend_from_slice(&parent[..self.config.parent_size()])
+                    .unwrap(),
                 StateFinish::Root(root) => {
                     root_hash = root;
                     break;
                 }
             }
         }
+        tree_bytes
+            .try_extend_from_slice(&crate::encode_len(total_len))
+            .unwrap();
+        (self.output(0, tree_bytes, &[]), root_hash)
+    }
+
+    fn output<'a>(
+        &mut self,
+        consumed: usize,
+        tree_bytes: ArrayVec<u8, { PARENT_SIZE * MAX_DEPTH + HEADER_SIZE }>,
+        content: &'a [u8],
+    ) -> EncodeOutput<'a> {
+        let output = EncodeOutput {
+            offset: self.position,
+            consumed,
+            tree_bytes,
+            content,
+        };
+        self.position += output.len() as u64;
+        output
+    }
+
+    // The number of bytes written to the current chunk so far. While the chunk is a cache hit,
+    // the chunk_state doesn't see those bytes.
+    fn chunk_len(&self) -> usize {
+        match &self.cached_chunks {
+            Some(cached) if cached.hit.is_some() => cached.buf.len(),
+            _ => self.chunk_state.count() as usize,
+        }
+    }
+
+    fn hash_cached_chunk(&mut self) {
+        if let Some(cached) = &mut self.cached_chunks {
+            if cached.hit.take().is_some() {
+                self.chunk_state.update(&cached.buf);
+                cached.buf.clear();
+            }
+        }
+    }
+}
+
+/// The default size of the `Encoder`'s output buffer.
+pub const DEFAULT_BUFFER_SIZE: usize = 16 * CHUNK_SIZE;
+
+/// The default size of the windows that `Encoder::finalize` reads and writes in.
+pub const DEFAULT_FLIP_WINDOW_SIZE: usize = 8 * 1024 * 1024;
+
+/// An incremental encoder. Note that you must call `finalize` after you're
+/// done writing.
+///
+/// `Encoder` supports both combined and outboard encoding, depending on which
+/// constructor you use.
+///
+/// Output is buffered internally, so there's no need to wrap the underlying writer in a
+/// `BufWriter`. See `set_buffer_size`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let mut encoded_incrementally = Vec::new();
+/// let encoded_cursor = std::io::Cursor::new(&mut encoded_incrementally);
+/// let mut encoder = bao::encode::Encoder::new(encoded_cursor);
+/// encoder.write_all(b"some input")?;
+/// encoder.finalize()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Encoder<T: Read + Write + Seek> {
+    inner: T,
+    state: EncoderState,
+    output: OutputBuffer,
+    flip_window_size: usize,
+}
+
+impl<T: Read + Write + Seek> Encoder<T> {
+    /// Create a new `Encoder` that will produce a combined encoding.The encoding will contain all
+    /// the input bytes, so that it can be decoded without the original input file. This is what
+    /// you get from `bao encode`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            state: EncoderState::new(),
+            output: OutputBuffer::new(DEFAULT_BUFFER_SIZE),
+            flip_window_size: DEFAULT_FLIP_WINDOW_SIZE,
+        }
+    }
+
+    /// Create a new `Encoder` for making an outboard encoding. That means that the encoding won't
+    /// include any input bytes. Instead, the input will need to be supplied as a separate argument
+    /// when the outboard encoding is later decoded. This is what you get from `bao encode
+    /// --outboard`.
+    pub fn new_outboard(inner: T) -> Self {
+        let mut encoder = Self::new(inner);
+        encoder.state.outboard = true;
+        encoder
+    }
 
-        // Write the length header, at the end.
-        self.inner.write_all(&crate::encode_len(total_len))?;
+    /// Hash the tree with a profile from the `config` module, for example `Config::keyed`,
+    /// instead of the standard one. The layout of the encoding is the same, except that parent
+    /// nodes are smaller with `Config::with_digest_len`, and combined encodings get alignment
+    /// padding with `Config::with_alignment`, which `finalize` adds as it flips the encoding.
+    /// With a shorter digest the root hash from `finalize` has zeros past the digest length. A
+    /// `ChunkHashCache` attached to the same encoder has to hold hashes from the same profile.
+    ///
+    /// # Panics
+    ///
+    /// This panics if any input has already been written, or if a manifest is enabled and the
+    /// profile isn't standard.
+    pub fn set_config(&mut self, config: Config) {
+        self.state.set_config(config);
+    }
+
+    /// Attach a `ChunkHashCache`. The `Encoder` will skip hashing any chunk the cache already
+    /// has a hash for, and it will add the hash of every other complete chunk to the cache. Use
+    /// `take_chunk_cache` to get the cache back after finalizing.
+    ///
+    /// # Panics
+    ///
+    /// This panics if any input has already been written.
+    pub fn set_chunk_cache(&mut self, cache: ChunkHashCache) {
+        self.state.set_chunk_cache(cache);
+    }
+
+    /// Set the size of the output buffer, `DEFAULT_BUFFER_SIZE` by default. Chunk bytes and
+    /// parent nodes collect in the buffer until it's full, and then they go to the underlying
+    /// writer in a single vectored write. A size of 0 disables buffering, but the parent nodes
+    /// that follow each chunk still go out in a single write.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.output.capacity = size;
+    }
+
+    /// Set the size of the windows that `finalize` reads and writes in, `DEFAULT_FLIP_WINDOW_SIZE`
+    /// by default. `finalize` rearranges the encoding from back to front, and each window is one
+    /// large read or write. It holds a read window and a write window in memory at once, though
+    /// neither is larger than the encoding itself. Sizes smaller than `CHUNK_SIZE` are rounded
+    /// up.
+    pub fn set_flip_window_size(&mut self, size: usize) {
+        self.flip_window_size = size;
+    }
+
+    /// Call `callback` each time a chunk is hashed into the tree, with the number of content
+    /// bytes hashed so far. A chunk gets hashed when the first byte after it is written, or
+    /// during `finalize` for the final chunk, so the callback lags `content_len` by up to one
+    /// chunk, and its last call during `finalize` reports the whole length.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let hashed = Arc::new(AtomicU64::new(0));
+    /// let hashed_clone = hashed.clone();
+    /// let mut encoder = bao::encode::Encoder::new(std::io::Cursor::new(Vec::new()));
+    /// encoder.set_progress_callback(move |n| hashed_clone.store(n, Ordering::Relaxed));
+    /// encoder.write_all(&[0; 5000])?;
+    /// assert_eq!(4096, hashed.load(Ordering::Relaxed));
+    /// encoder.finalize()?;
+    /// assert_eq!(5000, hashed.load(Ordering::Relaxed));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_progress_callback(&mut self, callback: impl Fn(u64) + Send + Sync + 'static) {
+        self.state.set_progress_callback(callback);
+    }
+
+    /// The number of content bytes written so far. For an `Encoder` created by `Appender`, this
+    /// includes the existing content.
+    pub fn content_len(&self) -> u64 {
+        self.state.content_len()
+    }
+
+    /// The number of chunks hashed into the tree so far. See `set_progress_callback` for when
+    /// that happens.
+    pub fn chunks_written(&self) -> u64 {
+        self.state.chunks_written()
+    }
+
+    /// Record the hash of every chunk in a `Manifest`, to get with `take_manifest` after
+    /// finalizing. See the `manifest` module.
+    ///
+    /// # Panics
+    ///
+    /// This panics if any input has already been written, including for an `Encoder` created by
+    /// `Appender`, which doesn't have the hashes of the existing chunks. It also panics after
+    /// `set_config` with a non-standard profile, since the manifest's hashes and offsets are the
+    /// standard ones.
+    pub fn enable_manifest(&mut self) {
+        self.state.enable_manifest();
+    }
+
+    /// Detach the `Manifest` from `enable_manifest`, if any. Before `finalize`, it only has the
+    /// chunks hashed so far.
+    pub fn take_manifest(&mut self) -> Option<Manifest> {
+        self.state.take_manifest()
+    }
+
+    /// Detach the `ChunkHashCache` set with `set_chunk_cache`, if any.
+    pub fn take_chunk_cache(&mut self) -> Option<ChunkHashCache> {
+        self.state.take_chunk_cache()
+    }
+
+    /// Finalize the encoding, after all the input has been written. You can't keep using this
+    /// `Encoder` again after calling `finalize`, and writing or finalizing again will panic.
+    ///
+    /// The underlying strategy of the `Encoder` is to first store the tree in a post-order layout,
+    /// and then to go back and flip the entire thing into pre-order. That makes it possible to
+    /// stream input without knowing its length in advance, which is a core requirement of the
+    /// `std::io::Write` interface. The downside is that `finalize` is a relatively expensive step.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        // Write all the parents along the right edge, and the length header at the end.
+        let (output, root_hash) = self.state.finalize();
+        self.output
+            .write_all(&mut self.inner, output.tree_bytes())?;
+        self.output.flush(&mut self.inner)?;
 
         // Finally, flip the tree to be pre-order. This means rewriting the
         // entire output, so it's expensive.
@@ -490,90 +1949,376 @@ impl<T: Read + Write + Seek> Encoder<T> {
         Ok(root_hash)
     }
 
-    /// Return the underlying writer.
+    /// Return the underlying writer, after `finalize`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if any input has been written but `finalize` hasn't been called, since the
+    /// underlying writer doesn't hold a valid encoding yet. Use `abort` to give up on an
+    /// unfinished encoding.
     pub fn into_inner(self) -> T {
+        let untouched = self.state.content_len() == 0;
+        assert!(
+            self.state.finalized || untouched,
+            "not finalized, use abort() instead"
+        );
         self.inner
     }
 
+    /// Give up on an unfinished encoding and return the underlying writer. Whatever was written
+    /// to it so far is left in place, and it isn't a valid encoding. Buffered output is
+    /// discarded. This is also fine to call after `finalize`, for example after `finalize`
+    /// returned an error.
+    pub fn abort(self) -> T {
+        self.inner
+    }
+
+    /// Whether `finalize` has been called. Note that `finalize` might have returned an error, in
+    /// which case the encoding isn't valid.
+    pub fn is_finalized(&self) -> bool {
+        self.state.finalized
+    }
+
     fn flip_post_order_stream(&mut self) -> io::Result<()> {
-        let mut write_cursor = self.inner.seek(SeekFrom::End(0))?;
-        let mut read_cursor = write_cursor - HEADER_SIZE as u64;
-        let mut header = [0; HEADER_SIZE];
-        self.inner.seek(SeekFrom::Start(read_cursor))?;
-        self.inner.read_exact(&mut header)?;
-        let content_len = crate::decode_len(&header);
-        let mut flipper = FlipperState::new(content_len);
-        loop {
-            match flipper.next() {
-                FlipperNext::FeedParent => {
-                    let mut parent = [0; PARENT_SIZE];
-                    self.inner
-                        .seek(SeekFrom::Start(read_cursor - PARENT_SIZE as u64))?;
-                    self.inner.read_exact(&mut parent)?;
-                    read_cursor -= PARENT_SIZE as u64;
-                    flipper.feed_parent(parent);
-                }
-                FlipperNext::TakeParent => {
-                    let parent = flipper.take_parent();
-                    self.inner
-                        .seek(SeekFrom::Start(write_cursor - PARENT_SIZE as u64))?;
-                    self.inner.write_all(&parent)?;
-                    write_cursor -= PARENT_SIZE as u64;
-                }
-                FlipperNext::Chunk(size) => {
-                    // In outboard moded, we skip over chunks.
-                    if !self.outboard {
-                        let mut chunk = [0; CHUNK_SIZE];
-                        self.inner
-                            .seek(SeekFrom::Start(read_cursor - size as u64))?;
-                        self.inner.read_exact(&mut chunk[..size])?;
-                        read_cursor -= size as u64;
-                        self.inner
-                            .seek(SeekFrom::Start(write_cursor - size as u64))?;
-                        self.inner.write_all(&chunk[..size])?;
-                        write_cursor -= size as u64;
-                    }
-                    flipper.chunk_moved();
-                }
-                FlipperNext::Done => {
-                    debug_assert_eq!(HEADER_SIZE as u64, write_cursor);
-                    self.inner.seek(SeekFrom::Start(0))?;
-                    self.inner.write_all(&header)?;
-                    return Ok(());
-                }
+        flip_post_order(
+            &mut self.inner,
+            &self.state.config,
+            self.state.outboard,
+            0,
+            self.flip_window_size,
+        )
+    }
+}
+
+// The flip reads the post-order encoding from back to front, and it writes the pre-order encoding
+// from back to front. The write position never falls below the read position, so the flip can
+// happen in place. These two types do that IO a window at a time. A read window only holds bytes
+// below the read position, which the writer never touches, and the writer only holds bytes at or
+// above the write position.
+struct BackwardReader {
+    buf: Vec<u8>,
+    window_start: u64,
+    position: u64,
+}
+
+impl BackwardReader {
+    fn new(position: u64, window_size: usize) -> Self {
+        Self {
+            buf: vec![0; window_size],
+            window_start: position,
+            position,
+        }
+    }
+
+    // Read the bytes that end at the current position, and move the position back over them.
+    fn read(&mut self, inner: &mut (impl Read + Seek), out: &mut [u8]) -> io::Result<()> {
+        let start = self.position - out.len() as u64;
+        if start < self.window_start {
+            // Load the window that ends at the current position.
+            self.window_start = self.position.saturating_sub(self.buf.len() as u64);
+            let window_len = (self.position - self.window_start) as usize;
+            inner.seek(SeekFrom::Start(self.window_start))?;
+            inner.read_exact(&mut self.buf[..window_len])?;
+        }
+        let offset = (start - self.window_start) as usize;
+        out.copy_from_slice(&self.buf[offset..][..out.len()]);
+        self.position = start;
+        Ok(())
+    }
+}
+
+struct BackwardWriter {
+    buf: Vec<u8>,
+    // The pending bytes are buf[fill_start..], and they belong at the current position.
+    fill_start: usize,
+    position: u64,
+}
+
+impl BackwardWriter {
+    fn new(position: u64, window_size: usize) -> Self {
+        Self {
+            buf: vec![0; window_size],
+            fill_start: window_size,
+            position,
+        }
+    }
+
+    // Write bytes that end at the current position, and move the position back over them.
+    fn write(&mut self, inner: &mut (impl Write + Seek), data: &[u8]) -> io::Result<()> {
+        if data.len() > self.fill_start {
+            self.flush(inner)?;
+        }
+        self.fill_start -= data.len();
+        self.buf[self.fill_start..][..data.len()].copy_from_slice(data);
+        self.position -= data.len() as u64;
+        Ok(())
+    }
+
+    // Write zeros that end at the current position, for alignment padding.
+    fn write_zeros(&mut self, inner: &mut (impl Write + Seek), mut len: usize) -> io::Result<()> {
+        while len > 0 {
+            if self.fill_start == 0 {
+                self.flush(inner)?;
             }
+            let take = cmp::min(len, self.fill_start);
+            self.fill_start -= take;
+            self.buf[self.fill_start..][..take].fill(0);
+            self.position -= take as u64;
+            len -= take;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, inner: &mut (impl Write + Seek)) -> io::Result<()> {
+        if self.fill_start < self.buf.len() {
+            inner.seek(SeekFrom::Start(self.position))?;
+            inner.write_all(&self.buf[self.fill_start..])?;
+            self.fill_start = self.buf.len();
         }
+        Ok(())
+    }
+}
+
+impl<B: backend::Backend> Encoder<BackendIo<B>> {
+    /// Create a new `Encoder` that writes a combined encoding to a `Backend`. The output and the
+    /// flip pass in `finalize` become positional writes and reads, with no cursor underneath.
+    /// `into_inner` returns the `BackendIo`, and `BackendIo::into_inner` the backend.
+    pub fn from_backend(backend: B) -> Self {
+        Self::new(BackendIo::new(backend))
+    }
+
+    /// Like `from_backend`, but for an outboard encoding.
+    pub fn from_backend_outboard(backend: B) -> Self {
+        Self::new_outboard(BackendIo::new(backend))
     }
 }
 
 impl<T: Read + Write + Seek> Write for Encoder<T> {
     fn write(&mut self, input: &[u8]) -> io::Result<usize> {
-        assert!(!self.finalized, "already finalized");
+        let output = self.state.push(input);
+        // The completed parents go out in a single write.
+        if !output.tree_bytes().is_empty() {
+            self.output
+                .write_all(&mut self.inner, output.tree_bytes())?;
+        }
+        if !output.content().is_empty() {
+            self.output.write_all(&mut self.inner, output.content())?;
+        }
+        Ok(output.consumed())
+    }
 
-        // If the current chunk is full, we need to finalize it, add it to
-        // the tree state, and write out any completed parent nodes.
-        if self.chunk_state.len() == CHUNK_SIZE {
-            let chunk_hash = self.chunk_state.finalize(false);
-            self.tree_state.push_subtree(&chunk_hash, CHUNK_SIZE);
-            let chunk_counter = self.tree_state.count() / CHUNK_SIZE as u64;
-            self.chunk_state = blake3::guts::ChunkState::new(chunk_counter);
-            while let Some(parent) = self.tree_state.merge_parent() {
-                self.inner.write_all(&parent)?;
-            }
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush(&mut self.inner)?;
+        self.inner.flush()
+    }
+}
+
+// The Encoder's output buffer. Writes that fit go into the buffer. A write that doesn't fit goes
+// to the underlying writer together with the buffered bytes, in one vectored write.
+#[derive(Clone)]
+pub(crate) struct OutputBuffer {
+    buf: Vec<u8>,
+    pub(crate) capacity: usize,
+}
+
+impl OutputBuffer {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            capacity,
         }
+    }
 
-        // Add as many bytes as possible to the current chunk.
-        let want = CHUNK_SIZE - self.chunk_state.len();
-        let take = cmp::min(want, input.len());
-        if !self.outboard {
-            self.inner.write_all(&input[..take])?;
+    pub(crate) fn write_all(&mut self, inner: &mut impl Write, data: &[u8]) -> io::Result<()> {
+        if self.buf.len() + data.len() <= self.capacity {
+            self.buf.extend_from_slice(data);
+            return Ok(());
         }
-        self.chunk_state.update(&input[..take]);
-        Ok(take)
+        let mut slices = [IoSlice::new(&self.buf), IoSlice::new(data)];
+        write_all_vectored(inner, &mut slices)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self, inner: &mut impl Write) -> io::Result<()> {
+        inner.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl fmt::Debug for OutputBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Don't print the buffered bytes.
+        f.debug_struct("OutputBuffer")
+            .field("len", &self.buf.len())
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+// Like the unstable Write::write_all_vectored.
+fn write_all_vectored(inner: &mut impl Write, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    // Skip any empty slices up front.
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match inner.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// An incremental encoder that appends more input to an existing combined encoding. Note that
+/// you must call `finalize` after you're done writing.
+///
+/// Because the encoding is stored in pre-order, with the root node at the front, appending can't
+/// leave the existing bytes where they are. `open` reads the whole encoding and rearranges it in
+/// place back into the post-order layout that `Encoder` writes to as it goes, and `finalize`
+/// flips it to pre-order again. That's a pass over the existing encoding in each direction, but
+/// none of the existing input gets rehashed, apart from the final chunk.
+///
+/// `open` takes the root hash of the existing encoding and verifies the whole thing before it
+/// rearranges anything, so a wrong hash, a truncated encoding, a corrupt length header, or
+/// trailing bytes after the encoding are an error that leaves the underlying file as it was.
+/// Between a successful `open` and `finalize`, though, the underlying file isn't a valid encoding.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let (encoded, hash) = bao::encode::encode(b"hello");
+/// let mut appender = bao::encode::Appender::open(std::io::Cursor::new(encoded), &hash)?;
+/// appender.write_all(b" world")?;
+/// let hash = appender.finalize()?;
+///
+/// assert_eq!(blake3::hash(b"hello world"), hash);
+/// assert_eq!(bao::encode::encode(b"hello world").0, appender.into_inner().into_inner());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Appender<T: Read + Write + Seek> {
+    encoder: Encoder<T>,
+}
+
+impl<T: Read + Write + Seek> Appender<T> {
+    /// Open the combined encoding in `inner` for appending, after verifying it against `hash`.
+    pub fn open(mut inner: T, hash: &Hash) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(0))?;
+        // The decoders stop at the end of the final chunk, but anything after it would still be
+        // in the file after finalize, if the appended growth didn't cover it.
+        let content_len = crate::decode::validate_canonical(&mut inner, hash)?;
+        let mut encoder = Encoder::new(inner);
+        encoder.unflip_pre_order_stream(content_len)?;
+        Ok(Self { encoder })
+    }
+
+    /// Finalize the encoding, after all the new input has been written. As with
+    /// `Encoder::finalize`, writing or finalizing again afterwards will panic.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        self.encoder.finalize()
+    }
+
+    /// Return the underlying writer, after `finalize`. As with `Encoder::into_inner`, this panics
+    /// if `finalize` hasn't been called.
+    pub fn into_inner(self) -> T {
+        self.encoder.into_inner()
+    }
+
+    /// Give up on appending and return the underlying writer. See `Encoder::abort`. The writer
+    /// doesn't hold a valid encoding, not even the original one.
+    pub fn abort(self) -> T {
+        self.encoder.abort()
+    }
+}
+
+impl<T: Read + Write + Seek> Write for Appender<T> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        self.encoder.write(input)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+        self.encoder.flush()
+    }
+}
+
+impl<T: Read + Write + Seek> Encoder<T> {
+    // The inverse of flip_post_order_stream, used by the Appender. This walks the pre-order tree
+    // from front to back, moving each chunk and each completed parent node to its post-order
+    // position. Post-order positions are never to the right of pre-order ones, so we can do this
+    // in place. The parent nodes along the right edge of the tree are dropped, since finalize will
+    // recompute them, and the left children of those nodes are exactly the completed subtrees
+    // that the tree_state needs. The final chunk is rehashed into the chunk_state.
+    fn unflip_pre_order_stream(&mut self, content_len: u64) -> io::Result<()> {
+        let last_chunk = count_chunks(content_len) - 1;
+        let mut parents = ArrayVec::<ParentNode, MAX_DEPTH>::new();
+        let mut chunk = [0; CHUNK_SIZE];
+        let mut read_cursor = HEADER_SIZE as u64;
+        let mut write_cursor = 0;
+        for chunk_index in 0..=last_chunk {
+            for _ in 0..pre_order_parent_nodes(chunk_index, content_len) {
+                let mut parent = [0; PARENT_SIZE];
+                self.inner.seek(SeekFrom::Start(read_cursor))?;
+                self.inner.read_exact(&mut parent)?;
+                read_cursor += PARENT_SIZE as u64;
+                parents.push(parent);
+            }
+            let size = chunk_size(chunk_index, content_len);
+            self.inner.seek(SeekFrom::Start(read_cursor))?;
+            self.inner.read_exact(&mut chunk[..size])?;
+            read_cursor += size as u64;
+            self.inner.seek(SeekFrom::Start(write_cursor))?;
+            self.inner.write_all(&chunk[..size])?;
+            write_cursor += size as u64;
+            if chunk_index < last_chunk {
+                for _ in 0..post_order_parent_nodes_nonfinal(chunk_index) {
+                    let parent = parents.pop().expect("missing parent");
+                    self.inner.write_all(&parent)?;
+                    write_cursor += PARENT_SIZE as u64;
+                }
+            } else {
+                self.state.chunk_state = self.state.config.chunk_hasher(chunk_index);
+                self.state.chunk_state.update(&chunk[..size]);
+            }
+        }
+        debug_assert_eq!(
+            parents.len(),
+            post_order_parent_nodes_final(last_chunk) as usize
+        );
+        for parent in &parents {
+            self.state
+                .tree_state
+                .subtrees
+                .push((*array_ref!(parent, 0, HASH_SIZE)).into());
+        }
+        self.state.tree_state.total_len = last_chunk * CHUNK_SIZE as u64;
+        self.state.position = write_cursor;
+        // The Encoder picks up writing right after the final chunk.
+        self.inner.seek(SeekFrom::Start(write_cursor))?;
+        Ok(())
+    }
+}
+
+// The Encoder's bookkeeping for a ChunkHashCache. When the cache has a hash for the current chunk,
+// the chunk's bytes are buffered rather than hashed. If the chunk turns out to be the final one, we
+// hash the buffer after all, since the final chunk might be partial or might be the root.
+#[derive(Clone)]
+struct CachedChunks {
+    cache: ChunkHashCache,
+    hit: Option<Hash>,
+    buf: Vec<u8>,
+}
+
+impl fmt::Debug for CachedChunks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes or input bytes.
+        write!(f, "CachedChunks {{ cache: {:?}, ... }}", self.cache)
     }
 }
 
@@ -584,6 +2329,7 @@ impl<T: Read + Write + Seek> Write for Encoder<T> {
 // bytes that get read; all of that is left to the caller.
 #[derive(Clone, Debug)]
 pub(crate) struct ParseState {
+    config: Config,
     content_len: Option<u64>,
     content_position: u64, // can be in the middle of a chunk, after a seek
     encoding_position: u128,
@@ -598,6 +2344,7 @@ pub(crate) struct ParseState {
 impl ParseState {
     pub fn new() -> Self {
         Self {
+            config: Config::new(),
             content_len: None,
             content_position: 0,
             encoding_position: 0,
@@ -611,6 +2358,37 @@ impl ParseState {
         self.content_position
     }
 
+    pub fn content_len(&self) -> Option<u64> {
+        self.content_len
+    }
+
+    // The profile sets the hashing for callers that verify, and the size of parent nodes for
+    // everyone.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        debug_assert!(self.content_len.is_none(), "set_config after header");
+        self.config = config;
+    }
+
+    // The position of the next parent or chunk in the combined encoding. Outboard callers can
+    // still use this to identify nodes.
+    pub fn encoding_position(&self) -> u128 {
+        self.encoding_position
+    }
+
+    // The alignment padding before the next chunk in the combined encoding, which starts at
+    // encoding_position. Outboard callers parse with an alignment of 1, so this is always 0 for
+    // them. Only valid when the next read is a chunk.
+    pub fn chunk_padding(&self) -> usize {
+        let content_len = self.content_len.expect("chunk_padding before header");
+        let size = chunk_size(self.next_chunk_index(), content_len);
+        self.config
+            .chunk_padding(self.encoding_position, size as u64) as usize
+    }
+
     fn at_root(&self) -> bool {
         self.content_position < CHUNK_SIZE as u64 && self.stack_depth == 1
     }
@@ -647,6 +2425,22 @@ impl ParseState {
         self.content_position / CHUNK_SIZE as u64
     }
 
+    // The content start and length of the subtree under the next parent or chunk, if that subtree
+    // is complete. The subtree that contains the final chunk returns None, because verifying it is
+    // the only thing that verifies the length header. Not valid at EOF.
+    pub fn next_nonfinal_subtree(&self) -> Option<(u64, u64)> {
+        let content_len = self
+            .content_len
+            .expect("next_nonfinal_subtree before header");
+        let start = self.next_chunk_start();
+        let max_len = (CHUNK_SIZE as u128) << self.upcoming_parents;
+        if start as u128 + max_len >= content_len as u128 {
+            None
+        } else {
+            Some((start, max_len as u64))
+        }
+    }
+
     pub fn finalization(&self) -> Finalization {
         if self.at_root() {
             Root
@@ -811,7 +2605,9 @@ impl ParseState {
             // this case.
             let subtree_size = (CHUNK_SIZE as u64) << self.upcoming_parents;
             self.content_position = self.next_chunk_start() + subtree_size;
-            self.encoding_position += encoded_subtree_size(subtree_size);
+            self.encoding_position = self
+                .config
+                .subtree_end(self.encoding_position, subtree_size);
             self.stack_depth -= 1;
             // This depends on the update to content_position immediately above.
             self.upcoming_parents = pre_order_parent_nodes(self.next_chunk_index(), content_len);
@@ -855,7 +2651,7 @@ impl ParseState {
             self.upcoming_parents > 0,
             "too many calls to advance_parent"
         );
-        self.encoding_position += PARENT_SIZE as u128;
+        self.encoding_position += self.config.parent_size() as u128;
         self.stack_depth += 1;
         self.upcoming_parents -= 1;
     }
@@ -868,8 +2664,8 @@ impl ParseState {
         let content_len = self.content_len.expect("advance_chunk before header");
         let size = chunk_size(self.next_chunk_index(), content_len);
         let skip = self.content_position % CHUNK_SIZE as u64;
+        self.encoding_position += (self.chunk_padding() + size) as u128;
         self.content_position += size as u64 - skip;
-        self.encoding_position += size as u128;
         self.stack_depth -= 1;
         if self.content_position >= content_len {
             debug_assert_eq!(self.content_position, content_len, "position past EOF");
@@ -963,12 +2759,177 @@ impl SeekBookkeeping {
     }
 }
 
+// This only lives long enough to match on, so the size difference doesn't matter.
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub(crate) enum LenNext {
     Seek(SeekBookkeeping),
     Len(u64),
 }
 
+/// The context string for `epoch_chain`, for BLAKE3's key derivation mode.
+const EPOCH_CHAIN_CONTEXT: &str = "bao 0.12 epoch chain";
+
+/// Compute the chain value after an epoch, from the chain value after the previous epoch (`None`
+/// for the first epoch), the epoch's index, and its root hash. A subscriber who trusts the chain
+/// value after some epoch, for example because the publisher signed it, can check every epoch
+/// root up to that point against it. See `EpochWriter`.
+pub fn epoch_chain(previous: Option<&Hash>, index: u64, root: &Hash) -> Hash {
+    let mut hasher = blake3::Hasher::new_derive_key(EPOCH_CHAIN_CONTEXT);
+    match previous {
+        Some(previous) => hasher.update(&[1]).update(previous.as_bytes()),
+        None => hasher.update(&[0]),
+    };
+    hasher.update(&index.to_le_bytes());
+    hasher.update(root.as_bytes());
+    hasher.finalize()
+}
+
+/// One epoch from an `EpochWriter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Epoch {
+    /// The epoch's index, counting from 0.
+    pub index: u64,
+    /// The root hash of the epoch's content, which is what a subscriber verifies it against.
+    pub root: Hash,
+    /// The chain value after this epoch, from `epoch_chain`.
+    pub chain: Hash,
+    /// The length of the epoch's content.
+    pub content_len: u64,
+}
+
+/// A writer for live streams with no known final length, like pub/sub feeds.
+///
+/// A regular encoding can't be verified until its root hash is known, and the root hash isn't
+/// known until the input ends. `EpochWriter` splits the stream into epochs of
+/// `chunks_per_epoch` chunks instead, and writes each one as a separate combined encoding, with
+/// its own root hash, as soon as it fills up. The publisher sends out each `Epoch` from
+/// `take_epochs` over an authenticated channel, and subscribers verify the epochs as they
+/// arrive with `decode::EpochReader`, with the usual verified streaming within each one. The
+/// epoch roots also form a hash chain, so signing the chain value of the latest epoch
+/// authenticates every epoch before it, for subscribers who join late or audit the stream
+/// afterwards.
+///
+/// The output is just the encodings of the epochs, one after another. Each one begins with its
+/// length header, so a reader can find the boundaries. Epochs hold at most `chunks_per_epoch`
+/// chunks, and the writer keeps the current epoch in memory until it's complete. Call
+/// `finish_epoch` to close a partial epoch early, for example when the stream goes quiet.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use bao::encode::EpochWriter;
+/// use bao::decode::EpochReader;
+/// use std::io::prelude::*;
+///
+/// let mut writer = EpochWriter::new(Vec::new(), 4);
+/// writer.write_all(&[1; 5000])?;
+/// writer.finish_epoch()?;
+/// let epochs = writer.take_epochs();
+/// assert_eq!(2, epochs.len());
+/// let stream = writer.into_inner();
+///
+/// let mut reader = EpochReader::new(&stream[..], 4);
+/// let mut content = Vec::new();
+/// for epoch in &epochs {
+///     content.extend_from_slice(&reader.read_epoch(&epoch.root)?.unwrap());
+///     assert_eq!(Some(epoch.chain), reader.chain());
+/// }
+/// assert_eq!(vec![1; 5000], content);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct EpochWriter<W: Write> {
+    inner: W,
+    epoch_len: usize,
+    buf: Vec<u8>,
+    index: u64,
+    chain: Option<Hash>,
+    epochs: Vec<Epoch>,
+}
+
+impl<W: Write> EpochWriter<W> {
+    /// # Panics
+    ///
+    /// Panics if `chunks_per_epoch` is zero.
+    pub fn new(inner: W, chunks_per_epoch: usize) -> Self {
+        assert!(chunks_per_epoch > 0, "empty epochs");
+        Self {
+            inner,
+            epoch_len: chunks_per_epoch * CHUNK_SIZE,
+            buf: Vec::new(),
+            index: 0,
+            chain: None,
+            epochs: Vec::new(),
+        }
+    }
+
+    /// Encode and write out the current epoch, even if it isn't full, and return it. If the
+    /// current epoch is empty, this does nothing and returns `None`.
+    pub fn finish_epoch(&mut self) -> io::Result<Option<Epoch>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let (encoded, root) = encode(&self.buf);
+        self.inner.write_all(&encoded)?;
+        let chain = epoch_chain(self.chain.as_ref(), self.index, &root);
+        let epoch = Epoch {
+            index: self.index,
+            root,
+            chain,
+            content_len: self.buf.len() as u64,
+        };
+        self.epochs.push(epoch);
+        self.index += 1;
+        self.chain = Some(chain);
+        self.buf.clear();
+        Ok(Some(epoch))
+    }
+
+    /// Return the epochs written since the last call, for the publisher to send out.
+    pub fn take_epochs(&mut self) -> Vec<Epoch> {
+        std::mem::take(&mut self.epochs)
+    }
+
+    /// The chain value after the last epoch written, or `None` if there isn't one yet.
+    pub fn chain(&self) -> Option<Hash> {
+        self.chain
+    }
+
+    /// Return the underlying writer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current epoch has content that hasn't been written. Call `finish_epoch`
+    /// first.
+    pub fn into_inner(self) -> W {
+        assert!(
+            self.buf.is_empty(),
+            "unfinished epoch, call finish_epoch() first"
+        );
+        self.inner
+    }
+}
+
+impl<W: Write> Write for EpochWriter<W> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == self.epoch_len {
+            self.finish_epoch()?;
+        }
+        let take = cmp::min(input.len(), self.epoch_len - self.buf.len());
+        self.buf.extend_from_slice(&input[..take]);
+        Ok(take)
+    }
+
+    /// Flush the underlying writer. This doesn't finish the current epoch.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// An incremental slice extractor, which reads encoded bytes and produces a slice.
 ///
 /// `SliceExtractor` supports reading both the combined and outboard encoding, depending on which
@@ -983,6 +2944,11 @@ pub(crate) enum LenNext {
 /// decoding. You can quickly convert an outboard encoding to a combined encoding by "extracting" a
 /// slice with a `slice_start` of zero and a `slice_len` equal to the original input length.
 ///
+/// A `slice_len` of zero is allowed. The slice still includes the chunk at `slice_start`, or the
+/// final chunk if `slice_start` is at or past the end of the content, so decoding it proves that
+/// the content reaches that point, or doesn't. For empty content, every slice is just the length
+/// header, and decoding it verifies the root.
+///
 /// See the `decode` module for decoding slices.
 ///
 /// # Example
@@ -1028,6 +2994,30 @@ impl<T: Read + Seek> SliceExtractor<T, T> {
     }
 }
 
+impl<B: backend::Backend> SliceExtractor<BackendIo<B>, BackendIo<B>> {
+    /// Like `new`, but reading the combined encoding from a `Backend`.
+    pub fn from_backend(backend: B, slice_start: u64, slice_len: u64) -> Self {
+        Self::new(BackendIo::new(backend), slice_start, slice_len)
+    }
+}
+
+impl<C: backend::Backend, B: backend::Backend> SliceExtractor<BackendIo<C>, BackendIo<B>> {
+    /// Like `new_outboard`, but reading the content and the outboard encoding from `Backend`s.
+    pub fn from_backend_outboard(
+        content: C,
+        outboard: B,
+        slice_start: u64,
+        slice_len: u64,
+    ) -> Self {
+        Self::new_outboard(
+            BackendIo::new(content),
+            BackendIo::new(outboard),
+            slice_start,
+            slice_len,
+        )
+    }
+}
+
 impl<T: Read + Seek, O: Read + Seek> SliceExtractor<T, O> {
     /// Create a new `SliceExtractor` to read from an unmodified input file and an outboard
     /// encoding of that same file (see `Encoder::new_outboard`). As with `SliceExtractor::new`,
@@ -1038,6 +3028,19 @@ impl<T: Read + Seek, O: Read + Seek> SliceExtractor<T, O> {
         Self::new_inner(input, Some(outboard), slice_start, slice_len)
     }
 
+    /// Read an encoding from a profile in the `config` module. Only the layout matters here,
+    /// since extracting doesn't check hashes, so this is only needed with `with_digest_len` or
+    /// `with_alignment`. Slices never have alignment padding. The extractor skips it.
+    pub fn with_config(mut self, config: Config) -> Self {
+        // Outboard encodings have no chunks to align.
+        if self.outboard.is_some() {
+            self.parser.set_config(config.with_alignment(1));
+        } else {
+            self.parser.set_config(config);
+        }
+        self
+    }
+
     /// Return the underlying readers. The second reader is `Some` if and only if this
     /// `SliceExtractor` was created with `new_outboard`.
     pub fn into_inner(self) -> (T, Option<O>) {
@@ -1080,20 +3083,25 @@ impl<T: Read + Seek, O: Read + Seek> SliceExtractor<T, O> {
 
     // Note that unlike the regular Reader, the parent bytes go into the output buffer.
     fn read_parent(&mut self) -> io::Result<()> {
-        let parent = array_mut_ref!(self.buf, 0, PARENT_SIZE);
+        let parent_size = self.parser.config().parent_size();
+        let parent = &mut self.buf[..parent_size];
         if let Some(outboard) = &mut self.outboard {
             outboard.read_exact(parent)?;
         } else {
             self.input.read_exact(parent)?;
         }
         self.buf_start = 0;
-        self.buf_end = PARENT_SIZE;
+        self.buf_end = parent_size;
         self.parser.advance_parent();
         Ok(())
     }
 
     fn read_chunk(&mut self, size: usize, skip: usize) -> io::Result<()> {
         debug_assert_eq!(0, self.buf_len(), "read_chunk with nonempty buffer");
+        let padding = self.parser.chunk_padding();
+        if padding > 0 {
+            self.input.seek(SeekFrom::Current(padding as i64))?;
+        }
         let chunk = &mut self.buf[..size];
         self.input.read_exact(chunk)?;
         self.buf_start = 0;
@@ -1125,8 +3133,9 @@ impl<T: Read + Seek, O: Read + Seek> SliceExtractor<T, O> {
                 }
             } else {
                 if let Some(encoding_position) = bookkeeping.underlying_seek() {
-                    self.input
-                        .seek(SeekFrom::Start(cast_offset(encoding_position)?))?;
+                    self.input.seek(SeekFrom::Start(
+                        EncodedOffset::new(encoding_position).to_u64()?,
+                    ))?;
                 }
             }
             let next_read = self.parser.seek_bookkeeping_done(bookkeeping);
@@ -1179,14 +3188,43 @@ impl<T: Read + Seek, O: Read + Seek> Read for SliceExtractor<T, O> {
     }
 }
 
-pub(crate) fn cast_offset(offset: u128) -> io::Result<u64> {
-    if offset > u64::max_value() as u128 {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "seek offset overflowed u64",
-        ))
-    } else {
-        Ok(offset as u64)
+/// A byte offset or size within an encoding. Encodings are up to about 6% larger than their
+/// content, so for content near `u64::MAX` an encoded offset doesn't fit in a `u64`, and on
+/// 32-bit targets most encodings don't fit in a `usize`. Functions like `encoded_size` return a
+/// plain `u128`, and this wrapper gives the checked conversions down from there, so that a
+/// caller seeking or indexing with one gets an error instead of a silently truncated position.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EncodedOffset(u128);
+
+impl EncodedOffset {
+    pub fn new(offset: u128) -> Self {
+        EncodedOffset(offset)
+    }
+
+    pub fn get(self) -> u128 {
+        self.0
+    }
+
+    /// Convert to a `u64` for `SeekFrom::Start`, or fail with an `io::Error`.
+    pub fn to_u64(self) -> io::Result<u64> {
+        u64::try_from(self.0).map_err(|_| io::Error::other("seek offset overflowed u64"))
+    }
+
+    /// Convert to a `usize` for indexing memory, or `None` if that would truncate on this target.
+    pub fn to_usize(self) -> Option<usize> {
+        usize::try_from(self.0).ok()
+    }
+}
+
+impl From<u64> for EncodedOffset {
+    fn from(offset: u64) -> Self {
+        EncodedOffset(offset as u128)
+    }
+}
+
+impl From<EncodedOffset> for u128 {
+    fn from(offset: EncodedOffset) -> Self {
+        offset.0
     }
 }
 
@@ -1197,7 +3235,7 @@ mod test {
 
     #[test]
     fn test_encode() {
-        for &case in crate::test::TEST_CASES {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
             println!("case {}", case);
             let input = make_test_input(case);
             let expected_hash = blake3::hash(&input);
@@ -1214,7 +3252,7 @@ mod test {
 
     #[test]
     fn test_outboard_encode() {
-        for &case in crate::test::TEST_CASES {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
             println!("case {}", case);
             let input = make_test_input(case);
             let expected_hash = blake3::hash(&input);
@@ -1225,6 +3263,819 @@ mod test {
         }
     }
 
+    // Hand out input a few bytes at a time, and interrupt every other read, to make sure the
+    // reader loop doesn't depend on getting whole chunks.
+    struct TrickleReader<'a> {
+        input: &'a [u8],
+        interrupt: bool,
+    }
+
+    impl<'a> Read for TrickleReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let n = cmp::min(cmp::min(buf.len(), 7), self.input.len());
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input = &self.input[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_encode_small() {
+        let mut buf = [0xff; MAX_SMALL_ENCODED_SIZE + 1];
+        for &case in &[0, 1, 10, CHUNK_SIZE - 1, CHUNK_SIZE] {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (len, hash) = encode_small(&input, &mut buf);
+            // The slow path, through the Encoder.
+            let mut expected = Vec::new();
+            let mut encoder = Encoder::new(io::Cursor::new(&mut expected));
+            encoder.write_all(&input).unwrap();
+            let expected_hash = encoder.finalize().unwrap();
+            assert_eq!((&expected[..], expected_hash), (&buf[..len], hash));
+            assert_eq!((expected, hash), encode(&input));
+
+            let mut expected_outboard = Vec::new();
+            let mut encoder = Encoder::new_outboard(io::Cursor::new(&mut expected_outboard));
+            encoder.write_all(&input).unwrap();
+            encoder.finalize().unwrap();
+            assert_eq!((expected_outboard, hash), outboard(&input));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_encode_small_too_long_panics() {
+        encode_small(&[0; CHUNK_SIZE + 1], &mut [0; MAX_SMALL_ENCODED_SIZE + 1]);
+    }
+
+    #[test]
+    fn test_encode_from_reader() {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_encoded, expected_hash) = encode(&input);
+            let mut encoded = Vec::new();
+            let reader = TrickleReader {
+                input: &input,
+                interrupt: false,
+            };
+            let hash = encode_from_reader(reader, io::Cursor::new(&mut encoded)).unwrap();
+            assert_eq!(expected_hash, hash);
+            assert_eq!(expected_encoded, encoded);
+        }
+    }
+
+    #[test]
+    fn test_encode_both() {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_encoded, expected_hash) = encode(&input);
+            let (expected_outboard, _) = outboard(&input);
+            let mut combined = Vec::new();
+            let mut outboard_encoded = Vec::new();
+            let reader = TrickleReader {
+                input: &input,
+                interrupt: false,
+            };
+            let hash = encode_both(
+                reader,
+                io::Cursor::new(&mut combined),
+                io::Cursor::new(&mut outboard_encoded),
+            )
+            .unwrap();
+            assert_eq!(expected_hash, hash);
+            assert_eq!(expected_encoded, combined);
+            assert_eq!(expected_outboard, outboard_encoded);
+        }
+    }
+
+    #[test]
+    fn test_slice_size_upper_bound() {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            let input = make_test_input(case);
+            let (encoded, _) = encode(&input);
+            let (outboard_encoded, _) = outboard(&input);
+            assert_eq!(encoded_size(case as u64), max_encoded_size_for(case as u64));
+            for &start in &[
+                0,
+                1,
+                CHUNK_SIZE - 1,
+                CHUNK_SIZE,
+                case / 2,
+                case,
+                case + 5000,
+            ] {
+                for &len in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, 3 * CHUNK_SIZE + 5, case] {
+                    println!("case {} start {} len {}", case, start, len);
+                    let bound = slice_size_upper_bound(start as u64, len as u64, case as u64);
+                    let mut slice = Vec::new();
+                    SliceExtractor::new(io::Cursor::new(&encoded), start as u64, len as u64)
+                        .read_to_end(&mut slice)
+                        .unwrap();
+                    assert_eq!(bound, slice.len() as u128);
+                    let mut slice = Vec::new();
+                    SliceExtractor::new_outboard(
+                        io::Cursor::new(&input),
+                        io::Cursor::new(&outboard_encoded),
+                        start as u64,
+                        len as u64,
+                    )
+                    .read_to_end(&mut slice)
+                    .unwrap();
+                    assert_eq!(bound, slice.len() as u128);
+                }
+            }
+        }
+        // Huge values don't overflow.
+        let max = u64::MAX;
+        assert!(slice_size_upper_bound(max - 1, max, max) > CHUNK_SIZE as u128);
+        assert_eq!(encoded_size(max), slice_size_upper_bound(0, max, max));
+    }
+
+    fn encode_with_cache(input: &[u8], cache: ChunkHashCache) -> (Vec<u8>, Hash, ChunkHashCache) {
+        let mut encoded = Vec::new();
+        let mut encoder = Encoder::new(io::Cursor::new(&mut encoded));
+        encoder.set_chunk_cache(cache);
+        encoder.write_all(input).unwrap();
+        let hash = encoder.finalize().unwrap();
+        let cache = encoder.take_chunk_cache().unwrap();
+        (encoded, hash, cache)
+    }
+
+    #[test]
+    fn test_chunk_cache() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let mut input = make_test_input(case);
+            let (encoded, hash, mut cache) = encode_with_cache(&input, ChunkHashCache::new());
+            assert_eq!(encode(&input), (encoded, hash));
+            // Every full chunk gets cached, unless it's the root.
+            if case > CHUNK_SIZE {
+                assert_eq!(case / CHUNK_SIZE, cache.len());
+            } else {
+                assert!(cache.is_empty());
+            }
+
+            // Re-encoding the same input with a full cache gives the same result.
+            let (encoded, hash, cache2) = encode_with_cache(&input, cache.clone());
+            assert_eq!(encode(&input), (encoded, hash));
+            assert_eq!(cache.len(), cache2.len());
+
+            // Edit the first byte of each chunk, invalidate, and re-encode.
+            let mut offset = 0;
+            while offset < case {
+                input[offset] ^= 1;
+                cache.invalidate(offset as u64, 1);
+                offset += CHUNK_SIZE;
+            }
+            let (encoded, hash, _) = encode_with_cache(&input, cache);
+            assert_eq!(encode(&input), (encoded, hash));
+        }
+    }
+
+    #[test]
+    fn test_chunk_cache_is_trusted() {
+        // Poisoning the cache should change the result, which shows that the Encoder is using it
+        // rather than hashing.
+        let input = make_test_input(4 * CHUNK_SIZE);
+        let (_, hash, mut cache) = encode_with_cache(&input, ChunkHashCache::new());
+        cache.insert(2, [0; HASH_SIZE].into());
+        let (_, poisoned_hash, _) = encode_with_cache(&input, cache.clone());
+        assert!(hash != poisoned_hash);
+
+        // But the final chunk always gets rehashed, because it might be partial.
+        cache.invalidate(2 * CHUNK_SIZE as u64, 1);
+        cache.insert(3, [0; HASH_SIZE].into());
+        let (_, final_hash, _) = encode_with_cache(&input, cache);
+        assert_eq!(hash, final_hash);
+    }
+
+    #[test]
+    fn test_take_chunk_cache_mid_chunk() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (_, hash, cache) = encode_with_cache(&input, ChunkHashCache::new());
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.set_chunk_cache(cache);
+        encoder.write_all(&input[..CHUNK_SIZE + 10]).unwrap();
+        encoder.take_chunk_cache().unwrap();
+        encoder.write_all(&input[CHUNK_SIZE + 10..]).unwrap();
+        assert_eq!(hash, encoder.finalize().unwrap());
+    }
+
+    // Counts the writes that reach the underlying writer. Vectored writes count once.
+    struct CountingWriter {
+        inner: io::Cursor<Vec<u8>>,
+        writes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.inner.write(buf)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.writes += 1;
+            self.inner.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for CountingWriter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for CountingWriter {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    // Returns the encoding and the number of writes before finalize.
+    fn encode_buffered(input: &[u8], buffer_size: usize, outboard: bool) -> (Vec<u8>, usize) {
+        let writer = CountingWriter {
+            inner: io::Cursor::new(Vec::new()),
+            writes: 0,
+        };
+        let mut encoder = if outboard {
+            Encoder::new_outboard(writer)
+        } else {
+            Encoder::new(writer)
+        };
+        encoder.set_buffer_size(buffer_size);
+        for piece in input.chunks(100) {
+            encoder.write_all(piece).unwrap();
+        }
+        let writes = encoder.inner.writes;
+        encoder.finalize().unwrap();
+        (encoder.into_inner().inner.into_inner(), writes)
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_encoded, _) = encode(&input);
+            let (expected_outboard, _) = outboard(&input);
+            for &size in &[0, 1, 100, CHUNK_SIZE + 1, DEFAULT_BUFFER_SIZE] {
+                assert_eq!(expected_encoded, encode_buffered(&input, size, false).0);
+                assert_eq!(expected_outboard, encode_buffered(&input, size, true).0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_buffering_batches_writes() {
+        let input = make_test_input(100 * CHUNK_SIZE);
+        let (_, unbuffered) = encode_buffered(&input, 0, false);
+        let (_, buffered) = encode_buffered(&input, DEFAULT_BUFFER_SIZE, false);
+        // Unbuffered, every 100-byte write goes through.
+        assert!(unbuffered > input.len() / 100);
+        // Buffered, there's roughly one write per buffer.
+        assert!(buffered <= input.len() / DEFAULT_BUFFER_SIZE + 1);
+
+        // Outboard, the parents that follow each chunk go out in a single write, even without
+        // buffering. The final chunk's parents wait for finalize.
+        let (_, unbuffered) = encode_buffered(&input, 0, true);
+        assert!(unbuffered < 100);
+    }
+
+    #[test]
+    fn test_flip_window_sizes() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_encoded, _) = encode(&input);
+            let (expected_outboard, _) = outboard(&input);
+            for &size in &[0, CHUNK_SIZE, 3 * CHUNK_SIZE + 7, DEFAULT_FLIP_WINDOW_SIZE] {
+                let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+                encoder.set_flip_window_size(size);
+                encoder.write_all(&input).unwrap();
+                encoder.finalize().unwrap();
+                assert_eq!(expected_encoded, encoder.into_inner().into_inner());
+
+                let mut encoder = Encoder::new_outboard(io::Cursor::new(Vec::new()));
+                encoder.set_flip_window_size(size);
+                encoder.write_all(&input).unwrap();
+                encoder.finalize().unwrap();
+                assert_eq!(expected_outboard, encoder.into_inner().into_inner());
+            }
+        }
+    }
+
+    #[test]
+    fn test_flip_writes_in_windows() {
+        let input = make_test_input(100 * CHUNK_SIZE);
+        let writer = CountingWriter {
+            inner: io::Cursor::new(Vec::new()),
+            writes: 0,
+        };
+        let mut encoder = Encoder::new(writer);
+        encoder.set_flip_window_size(10 * CHUNK_SIZE);
+        encoder.write_all(&input).unwrap();
+        encoder.flush().unwrap();
+        let writes_before = encoder.inner.writes;
+        encoder.finalize().unwrap();
+        let flip_writes = encoder.inner.writes - writes_before;
+        // Roughly one write per window, rather than one per node. A write window can go out a
+        // little early when the next chunk doesn't fit, and finalize also writes the final
+        // parents and the header.
+        let encoded_len = encoded_size(input.len() as u64) as usize;
+        let windows = encoded_len.div_ceil(9 * CHUNK_SIZE);
+        assert!(flip_writes <= windows + 2, "{} writes", flip_writes);
+        assert_eq!(encode(&input).0, encoder.into_inner().inner.into_inner());
+    }
+
+    // Build a post-order encoding recursively, independent of the encoder, and return it along
+    // with the subtree's chaining value.
+    fn post_order_subtree(
+        input: &[u8],
+        chunk_index: u64,
+        finalization: Finalization,
+        outboard: bool,
+        out: &mut Vec<u8>,
+    ) -> Hash {
+        if input.len() <= CHUNK_SIZE {
+            if !outboard {
+                out.extend_from_slice(input);
+            }
+            return crate::chunk_cv(chunk_index, input, finalization.is_root());
+        }
+        let left_len = left_subtree_len(input.len() as u64) as usize;
+        let right_index = chunk_index + (left_len / CHUNK_SIZE) as u64;
+        let left = post_order_subtree(&input[..left_len], chunk_index, NotRoot, outboard, out);
+        let right = post_order_subtree(&input[left_len..], right_index, NotRoot, outboard, out);
+        out.extend_from_slice(left.as_bytes());
+        out.extend_from_slice(right.as_bytes());
+        crate::parent_cv(&left, &right, finalization.is_root())
+    }
+
+    fn post_order(input: &[u8], outboard: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        post_order_subtree(input, 0, Root, outboard, &mut out);
+        out.extend_from_slice(&crate::encode_len(input.len() as u64));
+        out
+    }
+
+    #[test]
+    fn test_flip() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+
+            let mut encoding = io::Cursor::new(post_order(&input, false));
+            flip(&mut encoding).unwrap();
+            assert_eq!(encode(&input).0, encoding.into_inner());
+
+            let mut encoding = io::Cursor::new(post_order(&input, true));
+            flip_outboard(&mut encoding).unwrap();
+            assert_eq!(outboard(&input).0, encoding.into_inner());
+        }
+    }
+
+    #[test]
+    fn test_encoder_state() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            for &outboard in &[false, true] {
+                let mut state = if outboard {
+                    EncoderState::new_outboard()
+                } else {
+                    EncoderState::new()
+                };
+                // Collect the writes, and then do them in reverse order at their offsets.
+                let mut writes = Vec::new();
+                let mut remaining = &input[..];
+                // Push in odd sizes, and push nothing now and then.
+                let mut push_size = 0;
+                while !remaining.is_empty() {
+                    let output = state.push(&remaining[..cmp::min(push_size, remaining.len())]);
+                    assert!(output.consumed() > 0 || push_size == 0);
+                    let mut bytes = output.tree_bytes().to_vec();
+                    bytes.extend_from_slice(output.content());
+                    assert_eq!(output.len(), bytes.len());
+                    writes.push((output.offset(), bytes));
+                    remaining = &remaining[output.consumed()..];
+                    push_size = (push_size + 500) % 1501;
+                }
+                assert_eq!(case as u64, state.content_len());
+                let (output, hash) = state.finalize();
+                assert_eq!(0, output.consumed());
+                assert!(output.content().is_empty());
+                writes.push((output.offset(), output.tree_bytes().to_vec()));
+                assert_eq!(post_order(&input, outboard).len() as u64, state.position());
+
+                let mut encoding = io::Cursor::new(Vec::new());
+                for (offset, bytes) in writes.iter().rev() {
+                    encoding.seek(SeekFrom::Start(*offset)).unwrap();
+                    encoding.write_all(bytes).unwrap();
+                }
+                assert_eq!(post_order(&input, outboard), *encoding.get_ref());
+                if outboard {
+                    flip_outboard(&mut encoding).unwrap();
+                    assert_eq!(super::outboard(&input), (encoding.into_inner(), hash));
+                } else {
+                    flip(&mut encoding).unwrap();
+                    assert_eq!(encode(&input), (encoding.into_inner(), hash));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_encoder_state_push_after_finalize_panics() {
+        let mut state = EncoderState::new();
+        state.finalize();
+        state.push(b"foo");
+    }
+
+    #[test]
+    fn test_flip_bad_length() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let mut encoding = post_order(&input, false);
+        encoding.remove(0);
+        let err = flip(io::Cursor::new(&mut encoding)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        // The combined post-order encoding isn't a valid outboard one.
+        let mut encoding = post_order(&input, false);
+        let err = flip_outboard(io::Cursor::new(&mut encoding)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let err = flip(io::Cursor::new(vec![0; 3])).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    fn concat_all(inputs: &[(&[u8], Hash)]) -> io::Result<(Vec<u8>, Hash)> {
+        let mut encodings: Vec<_> = inputs
+            .iter()
+            .map(|&(encoded, hash)| (io::Cursor::new(encoded), hash))
+            .collect();
+        let (hash, mut reader) = concat(&mut encodings)?;
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        Ok((output, hash))
+    }
+
+    #[test]
+    fn test_concat() {
+        let parts = [
+            make_test_input(0),
+            make_test_input(CHUNK_SIZE),
+            make_test_input(3 * CHUNK_SIZE),
+            make_test_input(0),
+            make_test_input(2 * CHUNK_SIZE + 1),
+        ];
+        let encoded: Vec<(Vec<u8>, Hash)> = parts.iter().map(encode).collect();
+        for start in 0..parts.len() {
+            for end in start..=parts.len() {
+                println!("parts {}..{}", start, end);
+                let inputs: Vec<_> = encoded[start..end]
+                    .iter()
+                    .map(|(e, h)| (&e[..], *h))
+                    .collect();
+                let expected = encode(parts[start..end].concat());
+                assert_eq!(expected, concat_all(&inputs).unwrap());
+            }
+        }
+
+        // A bigger tree, whose parts don't line up with its subtrees.
+        let parts = [
+            make_test_input(5 * CHUNK_SIZE),
+            make_test_input(13 * CHUNK_SIZE),
+            make_test_input(CHUNK_SIZE / 2),
+        ];
+        let encoded: Vec<(Vec<u8>, Hash)> = parts.iter().map(encode).collect();
+        let inputs: Vec<_> = encoded.iter().map(|(e, h)| (&e[..], *h)).collect();
+        let (output, hash) = concat_all(&inputs).unwrap();
+        assert_eq!(encode(parts.concat()), (output.clone(), hash));
+
+        // The reader streams, so small reads give the same output.
+        let mut encodings: Vec<_> = inputs
+            .iter()
+            .map(|&(e, h)| (io::Cursor::new(e), h))
+            .collect();
+        let (_, mut reader) = concat(&mut encodings).unwrap();
+        let mut streamed = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            streamed.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(output, streamed);
+
+        // Only the last part can be a partial chunk.
+        let inputs = [
+            (&encoded[2].0[..], encoded[2].1),
+            (&encoded[0].0[..], encoded[0].1),
+        ];
+        let err = concat_all(&inputs).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        // Every byte of every part is verified, including the header and the parent nodes, in
+        // the first position and after another part.
+        for part in &encoded {
+            for i in 0..part.0.len() {
+                let mut corrupt = part.0.clone();
+                corrupt[i] ^= 1;
+                let alone = [(&corrupt[..], part.1)];
+                assert!(concat_all(&alone).is_err(), "byte {}", i);
+                let first = [(&corrupt[..], part.1), (&encoded[1].0[..], encoded[1].1)];
+                assert!(concat_all(&first).is_err(), "byte {}", i);
+                let second = [(&encoded[0].0[..], encoded[0].1), (&corrupt[..], part.1)];
+                assert!(concat_all(&second).is_err(), "byte {}", i);
+            }
+            let truncated = [(&part.0[..part.0.len() - 1], part.1)];
+            let err = concat_all(&truncated).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_concat_rechecks_chunks() {
+        for &len in &[CHUNK_SIZE / 2, 5 * CHUNK_SIZE + 1] {
+            println!("len {}", len);
+            let (first, first_hash) = encode(make_test_input(4 * CHUNK_SIZE));
+            let (second, second_hash) = encode(make_test_input(len));
+            let file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(file.path(), &second).unwrap();
+            // The second encoding is a file, so that it can change between passes.
+            let mut encodings: Vec<(Box<dyn ReadSeek>, Hash)> = vec![
+                (Box::new(io::Cursor::new(first)), first_hash),
+                (
+                    Box::new(std::fs::File::open(file.path()).unwrap()),
+                    second_hash,
+                ),
+            ];
+            let (_, mut reader) = concat(&mut encodings).unwrap();
+            let mut changed = second.clone();
+            *changed.last_mut().unwrap() ^= 1;
+            std::fs::write(file.path(), &changed).unwrap();
+            let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+
+    trait ReadSeek: Read + Seek {}
+
+    impl<T: Read + Seek> ReadSeek for T {}
+
+    #[test]
+    fn test_split() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode(&input);
+            for &shard_size in &[CHUNK_SIZE as u64, 3 * CHUNK_SIZE as u64] {
+                let split = split(io::Cursor::new(&encoded), shard_size).unwrap();
+                let expected_shards = cmp::max(1, (case as u64).div_ceil(shard_size));
+                assert_eq!(expected_shards, split.num_shards());
+                assert_eq!(case as u64, split.content_len());
+                assert_eq!(Some(expected_shards as usize), split.size_hint().1);
+                let shards: Vec<Shard> = split.collect::<io::Result<_>>().unwrap();
+                assert_eq!(expected_shards, shards.len() as u64);
+                let mut joined = Vec::new();
+                for shard in &shards {
+                    let mut decoder = crate::decode::SliceDecoder::new(
+                        &shard.slice[..],
+                        &hash,
+                        shard.start,
+                        shard.len,
+                    );
+                    let mut content = Vec::new();
+                    decoder.read_to_end(&mut content).unwrap();
+                    assert_eq!(shard.len, content.len() as u64);
+                    joined.extend_from_slice(&content);
+                }
+                assert_eq!(input, joined);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_nth() {
+        let input = make_test_input(10 * CHUNK_SIZE + 1);
+        let (encoded, _) = encode(&input);
+        let shard_size = 2 * CHUNK_SIZE as u64;
+        let all: Vec<Shard> = split(io::Cursor::new(&encoded), shard_size)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let mut shards = split(io::Cursor::new(&encoded), shard_size).unwrap();
+        assert_eq!(all[2], shards.nth(2).unwrap().unwrap());
+        assert_eq!(all[3], shards.next().unwrap().unwrap());
+        assert_eq!(2, shards.size_hint().0);
+        assert!(shards.nth(5).is_none());
+        assert!(shards.next().is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_split_unaligned_panics() {
+        let (encoded, _) = encode(b"hello");
+        let _ = split(io::Cursor::new(&encoded), 1000);
+    }
+
+    #[test]
+    fn test_epoch_writer() {
+        let input = make_test_input(10 * CHUNK_SIZE + 1);
+        let mut writer = EpochWriter::new(Vec::new(), 3);
+        writer.write_all(&input[..5 * CHUNK_SIZE]).unwrap();
+        // The first epoch went out as soon as the next byte came in.
+        assert_eq!(1, writer.take_epochs().len());
+        assert_eq!(1, writer.finish_epoch().unwrap().unwrap().index);
+        writer.write_all(&input[5 * CHUNK_SIZE..]).unwrap();
+        writer.finish_epoch().unwrap();
+        assert_eq!(None, writer.finish_epoch().unwrap());
+        let epochs = writer.take_epochs();
+        let stream = writer.into_inner();
+
+        // The epochs were 3, 2 (finished early), 3, and 2 chunks plus a byte.
+        let lens: Vec<u64> = epochs.iter().map(|e| e.content_len).collect();
+        let k = CHUNK_SIZE as u64;
+        assert_eq!(vec![2 * k, 3 * k, 2 * k + 1], lens);
+        let mut expected_stream = encode(&input[..3 * CHUNK_SIZE]).0;
+        let mut chain = epoch_chain(None, 0, &blake3::hash(&input[..3 * CHUNK_SIZE]));
+        let mut start = 3 * CHUNK_SIZE;
+        for epoch in &epochs {
+            let end = start + epoch.content_len as usize;
+            let (encoded, root) = encode(&input[start..end]);
+            expected_stream.extend_from_slice(&encoded);
+            assert_eq!(root, epoch.root);
+            chain = epoch_chain(Some(&chain), epoch.index, &root);
+            assert_eq!(chain, epoch.chain);
+            start = end;
+        }
+        assert_eq!(expected_stream, stream);
+    }
+
+    #[test]
+    fn test_epoch_chain() {
+        let root = blake3::hash(b"foo");
+        let first = epoch_chain(None, 0, &root);
+        assert_ne!(first, epoch_chain(None, 1, &root));
+        assert_ne!(first, epoch_chain(Some(&first), 0, &root));
+        assert_ne!(first, epoch_chain(None, 0, &blake3::hash(b"bar")));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_into_inner_unfinalized_panics() {
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.write_all(b"hello").unwrap();
+        encoder.into_inner();
+    }
+
+    #[test]
+    fn test_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let input = make_test_input(3 * CHUNK_SIZE + 10);
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_clone = reports.clone();
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.set_progress_callback(move |n| reports_clone.lock().unwrap().push(n));
+        encoder.write_all(&input[..CHUNK_SIZE]).unwrap();
+        assert_eq!(CHUNK_SIZE as u64, encoder.content_len());
+        assert_eq!(0, encoder.chunks_written());
+        encoder.write_all(&input[CHUNK_SIZE..]).unwrap();
+        assert_eq!(input.len() as u64, encoder.content_len());
+        assert_eq!(3, encoder.chunks_written());
+        encoder.finalize().unwrap();
+        assert_eq!(4, encoder.chunks_written());
+        let expected = vec![1024, 2048, 3072, input.len() as u64];
+        assert_eq!(expected, *reports.lock().unwrap());
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.write_all(b"hello").unwrap();
+        assert!(!encoder.is_finalized());
+        // Buffered output is dropped.
+        assert_eq!(0, encoder.abort().into_inner().len());
+
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.write_all(b"hello").unwrap();
+        encoder.finalize().unwrap();
+        assert!(encoder.is_finalized());
+        assert_eq!(encode(b"hello").0, encoder.abort().into_inner());
+    }
+
+    #[test]
+    fn test_flush_writes_buffered_output() {
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.write_all(b"hello").unwrap();
+        assert_eq!(0, encoder.inner.get_ref().len());
+        encoder.flush().unwrap();
+        assert_eq!(b"hello", &encoder.inner.get_ref()[..]);
+    }
+
+    #[test]
+    fn test_appender() {
+        for &prefix_len in crate::test::TEST_CASES {
+            for &suffix_len in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, 3 * CHUNK_SIZE + 1] {
+                println!("prefix {} suffix {}", prefix_len, suffix_len);
+                let input = make_test_input(prefix_len + suffix_len);
+                let (encoded, prefix_hash) = encode(&input[..prefix_len]);
+                let mut appender = Appender::open(io::Cursor::new(encoded), &prefix_hash).unwrap();
+                appender.write_all(&input[prefix_len..]).unwrap();
+                let hash = appender.finalize().unwrap();
+                let appended = appender.into_inner().into_inner();
+                assert_eq!(encode(&input), (appended, hash));
+            }
+        }
+    }
+
+    #[test]
+    fn test_append_repeatedly() {
+        let input = make_test_input(20 * CHUNK_SIZE + 7);
+        let (mut encoded, _) = encode(b"");
+        let mut hash = blake3::hash(b"");
+        for piece in input.chunks(CHUNK_SIZE + 100) {
+            let mut appender = Appender::open(io::Cursor::new(encoded), &hash).unwrap();
+            appender.write_all(piece).unwrap();
+            hash = appender.finalize().unwrap();
+            encoded = appender.into_inner().into_inner();
+        }
+        assert_eq!(encode(&input), (encoded, hash));
+    }
+
+    #[test]
+    fn test_appender_rejects_bad_encodings() {
+        let input = make_test_input(5 * CHUNK_SIZE + 7);
+        let (encoded, hash) = encode(&input);
+        let mut bad_length = encoded.clone();
+        bad_length[..HEADER_SIZE].copy_from_slice(&crate::encode_len(4 * CHUNK_SIZE as u64));
+        let mut bad_chunk = encoded.clone();
+        *bad_chunk.last_mut().unwrap() ^= 1;
+        let cases = [
+            (encoded.clone(), blake3::hash(b"wrong")),
+            (encoded[..encoded.len() - 1].to_vec(), hash),
+            (bad_length, hash),
+            (bad_chunk, hash),
+            ([&encoded[..], &[0; 10]].concat(), hash),
+        ];
+        for (bad, bad_hash) in cases.iter() {
+            let mut cursor = io::Cursor::new(bad.clone());
+            Appender::open(&mut cursor, bad_hash).unwrap_err();
+            // Nothing got rearranged.
+            assert_eq!(bad, cursor.get_ref());
+        }
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut cases = crate::test::TEST_CASES.to_vec();
+        cases.extend_from_slice(&[37 * CHUNK_SIZE + 5, 64 * CHUNK_SIZE, 100 * CHUNK_SIZE - 1]);
+        for &old_len in &cases {
+            let input = make_test_input(old_len);
+            let (old_encoded, _) = encode(&input);
+            for &new_len in cases.iter().filter(|&&n| n <= old_len) {
+                println!("old {} new {}", old_len, new_len);
+                let mut encoded = old_encoded.clone();
+                let hash = truncate(io::Cursor::new(&mut encoded), new_len as u64).unwrap();
+                encoded.truncate(encoded_size(new_len as u64) as usize);
+                assert_eq!(encode(&input[..new_len]), (encoded, hash));
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncate_too_long() {
+        let (mut encoded, _) = encode(make_test_input(CHUNK_SIZE));
+        let original = encoded.clone();
+        let err = truncate(io::Cursor::new(&mut encoded), CHUNK_SIZE as u64 + 1).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(original, encoded);
+    }
+
+    #[test]
+    fn test_chunk_encoded_offset() {
+        for &case in crate::test::TEST_CASES {
+            let mut offset = HEADER_SIZE as u128;
+            for chunk in 0..count_chunks(case as u64) {
+                offset += pre_order_parent_nodes(chunk, case as u64) as u128 * PARENT_SIZE as u128;
+                assert_eq!(offset, chunk_encoded_offset(chunk, case as u64));
+                offset += chunk_size(chunk, case as u64) as u128;
+            }
+        }
+    }
+
     fn largest_power_of_two_leq(n: u64) -> u64 {
         ((n / 2) + 1).next_power_of_two()
     }
@@ -1293,22 +4144,18 @@ mod test {
         let mut state = State::new();
         let mut chunk_index = 0;
         while input.len() > CHUNK_SIZE {
-            let hash = blake3::guts::ChunkState::new(chunk_index)
-                .update(&input[..CHUNK_SIZE])
-                .finalize(false);
+            let hash = crate::chunk_cv(chunk_index, &input[..CHUNK_SIZE], false);
             chunk_index += 1;
             state.push_subtree(&hash, CHUNK_SIZE);
             input = &input[CHUNK_SIZE..];
             // Merge any parents, but throw away the result. We don't need
             // them, but we need to avoid tripping an assert.
-            while state.merge_parent().is_some() {}
+            while state.merge_parent(&Config::new()).is_some() {}
         }
-        let hash = blake3::guts::ChunkState::new(chunk_index)
-            .update(input)
-            .finalize(last_chunk_is_root);
+        let hash = crate::chunk_cv(chunk_index, input, last_chunk_is_root);
         state.push_subtree(&hash, input.len());
         loop {
-            match state.merge_finalize() {
+            match state.merge_finalize(&Config::new()) {
                 StateFinish::Parent(_) => {}
                 StateFinish::Root(hash) => return hash,
             }
@@ -1367,4 +4214,29 @@ mod test {
         assert_eq!(r3.into_inner(), v);
         assert_eq!(r4.unwrap().into_inner(), v);
     }
+
+    #[test]
+    fn test_encoded_offset() {
+        for &len in &[0, 1, CHUNK_SIZE as u64, 1 << 20] {
+            assert_eq!(
+                try_encoded_size(len),
+                Some(encoded_size(len) as usize),
+                "len {}",
+                len
+            );
+            assert_eq!(try_outboard_size(len), Some(outboard_size(len) as usize));
+        }
+        // The largest encodings overflow a u64, so they can't be in memory anywhere.
+        assert!(encoded_size(u64::MAX) > u64::MAX as u128);
+        assert_eq!(try_encoded_size(u64::MAX), None);
+        let err = EncodedOffset::new(encoded_size(u64::MAX))
+            .to_u64()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(EncodedOffset::from(u64::MAX).to_u64().unwrap(), u64::MAX);
+        // A usize holds any u64 on 64-bit targets, but only 32 bits on wasm32 and the like.
+        let four_gib = EncodedOffset::from(1 << 32);
+        assert_eq!(four_gib.to_usize().is_some(), usize::BITS >= 64);
+        assert_eq!(u128::from(four_gib), 1 << 32);
+    }
 }
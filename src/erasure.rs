@@ -0,0 +1,601 @@
+//! Reed-Solomon erasure coding aligned with the chunk group tree.
+//!
+//! Decentralized storage often spreads a file across several providers, with parity so that the
+//! file survives some of them going away. This module splits the content into `data_shards`
+//! shards of whole chunk groups, and computes `parity_shards` parity shards over them, so that
+//! any `data_shards` of the shards are enough to get the content back. Because each data shard
+//! is a run of whole chunk groups, it's also a whole set of subtrees of the verification tree.
+//! A provider holding a data shard can prove it on its own with a slice, like the ones from
+//! `encode::split` or `encode::SliceExtractor` with the shard's `content_range`, and
+//! `ShardLayout::encoded_ranges` says where the shard's content sits in a combined encoding with
+//! chunk groups.
+//!
+//! The code is systematic, so the data shards are just the content, zero-padded to the same
+//! length, and reading the content back doesn't need any decoding when all the data shards are
+//! present. `reconstruct` fills in missing shards from any `data_shards` of the others, and
+//! verifies the content against the root hash before returning it.
+//!
+//! The parity shards use a Cauchy matrix over GF(2^8), so there can be at most 256 shards in
+//! total. This is all in memory, so it's meant for files that fit in memory, or for callers that
+//! apply it to one piece of a larger file at a time.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use bao::erasure::{self, ShardLayout};
+//!
+//! let input = vec![0xab; 100_000];
+//! let hash = blake3::hash(&input);
+//! let layout = ShardLayout::new(input.len() as u64, 2, 4, 2);
+//! let shards = erasure::encode_shards(&input, &layout);
+//! assert_eq!(6, shards.len());
+//!
+//! // Lose any two shards.
+//! let mut shards: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+//! shards[0] = None;
+//! shards[4] = None;
+//! let content = erasure::reconstruct(&mut shards, &layout, &hash)?;
+//! assert_eq!(input, content);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::Error;
+use crate::group;
+use crate::{Hash, HEADER_SIZE, PARENT_SIZE};
+use std::cmp;
+use std::io;
+use std::ops::Range;
+
+/// The largest number of data and parity shards combined.
+pub const MAX_SHARDS: usize = 256;
+
+/// How a file maps onto data and parity shards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ShardLayoutParams"))]
+pub struct ShardLayout {
+    content_len: u64,
+    chunk_group_log: u8,
+    data_shards: usize,
+    parity_shards: usize,
+    groups_per_shard: u64,
+}
+
+// Deserialization goes through this, so that a deserialized ShardLayout is checked like a new
+// one, and groups_per_shard is recomputed rather than trusted. Otherwise a forged content_len
+// could disagree with the shard length that reconstruct checks, and size its output buffer.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct ShardLayoutParams {
+    content_len: u64,
+    chunk_group_log: u8,
+    data_shards: usize,
+    parity_shards: usize,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<ShardLayoutParams> for ShardLayout {
+    type Error = &'static str;
+
+    fn try_from(params: ShardLayoutParams) -> Result<Self, Self::Error> {
+        if params.chunk_group_log > group::MAX_CHUNK_GROUP_LOG {
+            return Err("chunk_group_log too large");
+        }
+        if !valid_shards(params.data_shards, params.parity_shards) {
+            return Err("invalid shard counts");
+        }
+        Ok(Self::new(
+            params.content_len,
+            params.chunk_group_log,
+            params.data_shards,
+            params.parity_shards,
+        ))
+    }
+}
+
+fn valid_shards(data_shards: usize, parity_shards: usize) -> bool {
+    data_shards > 0
+        && data_shards
+            .checked_add(parity_shards)
+            .is_some_and(|total| total <= MAX_SHARDS)
+}
+
+impl ShardLayout {
+    /// The layout of `content_len` bytes with chunk groups of `2^chunk_group_log` chunks, split
+    /// into `data_shards` data shards and `parity_shards` parity shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data_shards` is zero, if there are more than `MAX_SHARDS` shards in total, or
+    /// if `chunk_group_log` is greater than `group::MAX_CHUNK_GROUP_LOG`.
+    pub fn new(
+        content_len: u64,
+        chunk_group_log: u8,
+        data_shards: usize,
+        parity_shards: usize,
+    ) -> Self {
+        assert!(data_shards > 0, "at least one data shard is required");
+        assert!(valid_shards(data_shards, parity_shards), "too many shards");
+        let groups = group::count_groups(content_len, chunk_group_log);
+        Self {
+            content_len,
+            chunk_group_log,
+            data_shards,
+            parity_shards,
+            groups_per_shard: groups.div_ceil(data_shards as u64),
+        }
+    }
+
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    pub fn chunk_group_log(&self) -> u8 {
+        self.chunk_group_log
+    }
+
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    pub fn parity_shards(&self) -> usize {
+        self.parity_shards
+    }
+
+    /// The number of data and parity shards.
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    /// The length of every shard. Data shards with less content than this are zero-padded, and
+    /// data shards past the end of the content are all zeros.
+    pub fn shard_len(&self) -> u64 {
+        cmp::min(self.max_shard_content(), self.content_len)
+    }
+
+    fn max_shard_content(&self) -> u64 {
+        self.groups_per_shard * group::group_size(self.chunk_group_log) as u64
+    }
+
+    /// The chunk groups in a data shard, which might be empty at the end of the content.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shard` isn't a data shard.
+    pub fn groups(&self, shard: usize) -> Range<u64> {
+        assert!(shard < self.data_shards, "not a data shard");
+        let count = group::count_groups(self.content_len, self.chunk_group_log);
+        let start = cmp::min(shard as u64 * self.groups_per_shard, count);
+        let end = cmp::min(start + self.groups_per_shard, count);
+        start..end
+    }
+
+    /// The content in a data shard, which might be empty at the end of the content.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shard` isn't a data shard.
+    pub fn content_range(&self, shard: usize) -> Range<u64> {
+        assert!(shard < self.data_shards, "not a data shard");
+        let start = cmp::min(shard as u64 * self.max_shard_content(), self.content_len);
+        let end = cmp::min(start + self.max_shard_content(), self.content_len);
+        start..end
+    }
+
+    /// The byte ranges of a combined encoding with chunk groups, from `group::encode`, that hold
+    /// the content of a data shard. The parent nodes of the tree split the content up, so there's
+    /// a range for every group or so.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shard` isn't a data shard.
+    pub fn encoded_ranges(&self, shard: usize) -> Vec<Range<u128>> {
+        let target = self.content_range(shard);
+        let mut ranges = Vec::new();
+        if target.start < target.end {
+            self.push_encoded_ranges(
+                &target,
+                0,
+                self.content_len,
+                HEADER_SIZE as u128,
+                &mut ranges,
+            );
+        }
+        ranges
+    }
+
+    fn push_encoded_ranges(
+        &self,
+        target: &Range<u64>,
+        subtree_start: u64,
+        subtree_len: u64,
+        encoded_start: u128,
+        ranges: &mut Vec<Range<u128>>,
+    ) {
+        let subtree_end = subtree_start + subtree_len;
+        if subtree_end <= target.start || target.end <= subtree_start {
+            return;
+        }
+        if subtree_len <= group::group_size(self.chunk_group_log) as u64 {
+            let start =
+                encoded_start + (cmp::max(subtree_start, target.start) - subtree_start) as u128;
+            let end = encoded_start + (cmp::min(subtree_end, target.end) - subtree_start) as u128;
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+            return;
+        }
+        let left_len = crate::encode::left_subtree_len(subtree_len);
+        let left_start = encoded_start + PARENT_SIZE as u128;
+        let left_encoded_len =
+            group::encoded_size(left_len, self.chunk_group_log) - HEADER_SIZE as u128;
+        self.push_encoded_ranges(target, subtree_start, left_len, left_start, ranges);
+        self.push_encoded_ranges(
+            target,
+            subtree_start + left_len,
+            subtree_len - left_len,
+            left_start + left_encoded_len,
+            ranges,
+        );
+    }
+
+    fn data_shard(&self, content: &[u8], shard: usize) -> Vec<u8> {
+        let range = self.content_range(shard);
+        let mut data = content[range.start as usize..range.end as usize].to_vec();
+        data.resize(self.shard_len() as usize, 0);
+        data
+    }
+}
+
+/// Split the content into data shards and compute the parity shards, returning the data shards
+/// followed by the parity shards.
+///
+/// # Panics
+///
+/// Panics if the content length doesn't match the layout.
+pub fn encode_shards(content: &[u8], layout: &ShardLayout) -> Vec<Vec<u8>> {
+    assert_eq!(
+        layout.content_len(),
+        content.len() as u64,
+        "content length doesn't match the layout"
+    );
+    let mut shards: Vec<Vec<u8>> = (0..layout.data_shards())
+        .map(|i| layout.data_shard(content, i))
+        .collect();
+    for row in layout.data_shards()..layout.total_shards() {
+        let coefficients = matrix_row(layout, row);
+        let parity = combine(
+            &coefficients,
+            shards.iter().map(|s| &s[..]),
+            layout.shard_len(),
+        );
+        shards.push(parity);
+    }
+    shards
+}
+
+/// Fill in the missing shards from the ones that are present, then verify the content against
+/// the root hash and return it. `shards` has a slot for every data and parity shard, in order,
+/// with `None` for the missing ones. At least `data_shards` of them must be present.
+///
+/// A corrupt shard leads to the wrong content, and so to a hash mismatch, `InvalidData`. A
+/// provider's data shard can be checked on its own against the root hash, with a slice of its
+/// `content_range`, to find out which one it was. The wrong number of shards, shards of the
+/// wrong length, or too few shards are `InvalidInput` errors.
+pub fn reconstruct(
+    shards: &mut [Option<Vec<u8>>],
+    layout: &ShardLayout,
+    hash: &Hash,
+) -> io::Result<Vec<u8>> {
+    if shards.len() != layout.total_shards() {
+        return Err(invalid_input("wrong number of shards"));
+    }
+    let shard_len = layout.shard_len();
+    if shards.iter().flatten().any(|s| s.len() as u64 != shard_len) {
+        return Err(invalid_input("wrong shard length"));
+    }
+    let present: Vec<usize> = (0..shards.len())
+        .filter(|&i| shards[i].is_some())
+        .take(layout.data_shards())
+        .collect();
+    if present.len() < layout.data_shards() {
+        return Err(invalid_input("not enough shards"));
+    }
+
+    // Invert the rows of the encoding matrix for the shards we have. Then each data shard is a
+    // combination of those shards.
+    let mut decoding: Vec<Vec<u8>> = present.iter().map(|&i| matrix_row(layout, i)).collect();
+    invert(&mut decoding);
+    for i in 0..layout.data_shards() {
+        if shards[i].is_none() {
+            let inputs = present.iter().map(|&p| &shards[p].as_ref().unwrap()[..]);
+            shards[i] = Some(combine(&decoding[i], inputs, shard_len));
+        }
+    }
+    for row in layout.data_shards()..layout.total_shards() {
+        if shards[row].is_none() {
+            let coefficients = matrix_row(layout, row);
+            let inputs = shards[..layout.data_shards()]
+                .iter()
+                .map(|s| &s.as_ref().unwrap()[..]);
+            shards[row] = Some(combine(&coefficients, inputs, shard_len));
+        }
+    }
+
+    let mut content = Vec::with_capacity(layout.content_len() as usize);
+    for (i, shard) in shards[..layout.data_shards()].iter().enumerate() {
+        let range = layout.content_range(i);
+        content.extend_from_slice(&shard.as_ref().unwrap()[..(range.end - range.start) as usize]);
+    }
+    if blake3::hash(&content) != *hash {
+        return Err(Error::HashMismatch.into());
+    }
+    Ok(content)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// The row of the encoding matrix for a shard. The data shards are the identity, and the parity
+// shards are a Cauchy matrix, so that any set of data_shards rows is invertible.
+fn matrix_row(layout: &ShardLayout, row: usize) -> Vec<u8> {
+    let k = layout.data_shards();
+    if row < k {
+        let mut identity = vec![0; k];
+        identity[row] = 1;
+        identity
+    } else {
+        (0..k).map(|col| gf_inv(row as u8 ^ col as u8)).collect()
+    }
+}
+
+// Sum the inputs, each multiplied by its coefficient.
+fn combine<'a>(
+    coefficients: &[u8],
+    inputs: impl Iterator<Item = &'a [u8]>,
+    shard_len: u64,
+) -> Vec<u8> {
+    let mut output = vec![0; shard_len as usize];
+    for (&c, input) in coefficients.iter().zip(inputs) {
+        if c == 0 {
+            continue;
+        }
+        for (out, &x) in output.iter_mut().zip(input) {
+            *out ^= gf_mul(c, x);
+        }
+    }
+    output
+}
+
+// Gauss-Jordan elimination in place. The matrix is always invertible, since its rows are
+// distinct rows of the encoding matrix.
+fn invert(matrix: &mut [Vec<u8>]) {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n)
+        .map(|i| {
+            let mut row = vec![0; n];
+            row[i] = 1;
+            row
+        })
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n)
+            .find(|&r| matrix[r][col] != 0)
+            .expect("singular matrix");
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = gf_inv(matrix[col][col]);
+        for j in 0..n {
+            matrix[col][j] = gf_mul(matrix[col][j], scale);
+            inverse[col][j] = gf_mul(inverse[col][j], scale);
+        }
+        for r in 0..n {
+            let factor = matrix[r][col];
+            if r == col || factor == 0 {
+                continue;
+            }
+            for j in 0..n {
+                matrix[r][j] ^= gf_mul(factor, matrix[col][j]);
+                inverse[r][j] ^= gf_mul(factor, inverse[col][j]);
+            }
+        }
+    }
+    matrix.swap_with_slice(&mut inverse);
+}
+
+// Log and exp tables for GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1. The exp table is
+// doubled so that a sum of two logs doesn't need reducing.
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+const TABLES: Tables = make_tables();
+
+const fn make_tables() -> Tables {
+    let mut exp = [0; 512];
+    let mut log = [0; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    Tables { exp, log }
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    debug_assert_ne!(a, 0);
+    TABLES.exp[255 - TABLES.log[a as usize] as usize]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use crate::CHUNK_SIZE;
+
+    #[test]
+    fn test_field() {
+        for a in 1..=255u8 {
+            assert_eq!(1, gf_mul(a, gf_inv(a)));
+            assert_eq!(a, gf_mul(a, 1));
+            assert_eq!(0, gf_mul(a, 0));
+        }
+        // Multiplication distributes over addition, which is xor.
+        for &(a, b, c) in &[(3, 7, 200), (0x53, 0xca, 0x11), (255, 254, 2)] {
+            assert_eq!(gf_mul(a, b ^ c), gf_mul(a, b) ^ gf_mul(a, c));
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            for log in 0..3 {
+                let (encoded, _) = group::encode(&input, log);
+                let layout = ShardLayout::new(case as u64, log, 3, 2);
+                let group_size = group::group_size(log) as u64;
+                let mut next_group = 0;
+                let mut next_content = 0;
+                for shard in 0..layout.data_shards() {
+                    // The shards cover the groups and the content in order.
+                    let groups = layout.groups(shard);
+                    let range = layout.content_range(shard);
+                    assert_eq!(next_group, groups.start);
+                    assert_eq!(next_content, range.start);
+                    assert_eq!(
+                        range.start,
+                        cmp::min(groups.start * group_size, case as u64)
+                    );
+                    assert!(range.end - range.start <= layout.shard_len());
+                    next_group = groups.end;
+                    next_content = range.end;
+
+                    // The encoded ranges hold exactly the shard's content.
+                    let mut found = Vec::new();
+                    for encoded_range in layout.encoded_ranges(shard) {
+                        found.extend_from_slice(
+                            &encoded[encoded_range.start as usize..encoded_range.end as usize],
+                        );
+                    }
+                    assert_eq!(&input[range.start as usize..range.end as usize], &found[..]);
+                }
+                assert_eq!(group::count_groups(case as u64, log), next_group);
+                assert_eq!(case as u64, next_content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reconstruct() {
+        let input = make_test_input(10 * CHUNK_SIZE + 7);
+        let hash = blake3::hash(&input);
+        let layout = ShardLayout::new(input.len() as u64, 1, 4, 3);
+        let shards = encode_shards(&input, &layout);
+        assert_eq!(7, shards.len());
+        for (i, shard) in shards[..layout.data_shards()].iter().enumerate() {
+            let range = layout.content_range(i);
+            let content = &input[range.start as usize..range.end as usize];
+            assert_eq!(content, &shard[..content.len()]);
+        }
+
+        // Every way of losing three shards.
+        for a in 0..7 {
+            for b in a + 1..7 {
+                for c in b + 1..7 {
+                    let mut damaged: Vec<Option<Vec<u8>>> =
+                        shards.iter().cloned().map(Some).collect();
+                    damaged[a] = None;
+                    damaged[b] = None;
+                    damaged[c] = None;
+                    assert_eq!(input, reconstruct(&mut damaged, &layout, &hash).unwrap());
+                    let repaired: Vec<Vec<u8>> = damaged.into_iter().flatten().collect();
+                    assert_eq!(shards, repaired);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_errors() {
+        let input = make_test_input(5 * CHUNK_SIZE);
+        let hash = blake3::hash(&input);
+        let layout = ShardLayout::new(input.len() as u64, 0, 3, 1);
+        let shards: Vec<Option<Vec<u8>>> = encode_shards(&input, &layout)
+            .into_iter()
+            .map(Some)
+            .collect();
+
+        let mut too_few = shards.clone();
+        too_few[0] = None;
+        too_few[3] = None;
+        let err = reconstruct(&mut too_few, &layout, &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let err = reconstruct(&mut shards.clone()[..3], &layout, &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let mut corrupt = shards.clone();
+        corrupt[0] = None;
+        corrupt[3].as_mut().unwrap()[0] ^= 1;
+        let err = reconstruct(&mut corrupt, &layout, &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_checks_invariants() {
+        let layout = ShardLayout::new(100_000, 2, 4, 2);
+        let json = serde_json::to_string(&layout).unwrap();
+        assert_eq!(layout, serde_json::from_str::<ShardLayout>(&json).unwrap());
+        for bad in [
+            r#"{"content_len":5,"chunk_group_log":0,"data_shards":0,"parity_shards":1}"#,
+            r#"{"content_len":5,"chunk_group_log":0,"data_shards":200,"parity_shards":57}"#,
+            r#"{"content_len":5,"chunk_group_log":17,"data_shards":1,"parity_shards":1}"#,
+        ] {
+            assert!(serde_json::from_str::<ShardLayout>(bad).is_err(), "{}", bad);
+        }
+        // A forged content_len doesn't keep the serialized groups_per_shard. The shard length
+        // follows the content length, so reconstruct rejects the real shards before allocating.
+        let forged = json.replace("100000", &(1u64 << 50).to_string());
+        let forged: ShardLayout = serde_json::from_str(&forged).unwrap();
+        assert_eq!(forged, ShardLayout::new(1 << 50, 2, 4, 2));
+        let input = make_test_input(100_000);
+        let mut shards: Vec<Option<Vec<u8>>> = encode_shards(&input, &layout)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let err = reconstruct(&mut shards, &forged, &blake3::hash(&input)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_empty() {
+        let hash = blake3::hash(b"");
+        let layout = ShardLayout::new(0, 0, 2, 2);
+        let shards = encode_shards(b"", &layout);
+        assert!(shards.iter().all(|s| s.is_empty()));
+        let mut shards: Vec<Option<Vec<u8>>> = vec![None, None, Some(vec![]), Some(vec![])];
+        assert!(reconstruct(&mut shards, &layout, &hash).unwrap().is_empty());
+        assert!(layout.encoded_ranges(0).is_empty());
+    }
+}
@@ -0,0 +1,369 @@
+//! Random access to verified content, for serving encoded files through a filesystem.
+//!
+//! This module is behind the `fuse` feature. `VerifiedFile` provides pread-style reads at
+//! arbitrary offsets, the access pattern of a FUSE `read` handler, over either a combined
+//! encoding or content plus an outboard encoding. Unlike seeking a `Decoder` before every read,
+//! it remembers the parent nodes it has already verified, so reads only have to verify the
+//! chunks they touch.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let input = vec![0xab; 100_000];
+//! let (encoded, hash) = bao::encode::encode(&input);
+//! let mut file = bao::file::VerifiedFile::open(std::io::Cursor::new(&encoded), &hash)?;
+//! assert_eq!(100_000, file.len());
+//!
+//! let mut buf = [0; 10];
+//! assert_eq!(10, file.read_at(50_000, &mut buf)?);
+//! assert_eq!([0xab; 10], buf);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::{Error, Limits};
+use crate::encode;
+use crate::Finalization::{self, NotRoot, Root};
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+// Each cached parent takes about 100 bytes, so this is a few MB. When the cache fills up, we
+// clear it and start over, which is simple and good enough for sequential reads.
+const MAX_CACHED_PARENTS: usize = 1 << 16;
+
+/// Verified random access to the content of an encoding.
+///
+/// Opening a `VerifiedFile` reads the length header and verifies it, by verifying the final
+/// chunk, so `len` is always trustworthy. After that, `read_at` verifies everything it returns.
+/// Corruption shows up as an `InvalidData` error from the read that touched it, and reads
+/// elsewhere in the file continue to work.
+pub struct VerifiedFile<T: Read + Seek, O: Read + Seek> {
+    input: T,
+    outboard: Option<O>,
+    root_hash: Hash,
+    content_len: u64,
+    // Verified children of each parent node, keyed by the parent's offset in the encoding (or in
+    // the outboard encoding).
+    parents: HashMap<u128, (Hash, Hash)>,
+    chunk_buf: [u8; CHUNK_SIZE],
+}
+
+impl<T: Read + Seek> VerifiedFile<T, T> {
+    /// Open a combined encoding.
+    pub fn open(inner: T, hash: &Hash) -> io::Result<Self> {
+        Self::open_with_limits(inner, hash, &Limits::default())
+    }
+
+    /// Like `open`, but with `Limits` on the length header.
+    pub fn open_with_limits(inner: T, hash: &Hash, limits: &Limits) -> io::Result<Self> {
+        Self::open_inner(inner, None, hash, limits)
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> T {
+        self.input
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> VerifiedFile<T, O> {
+    /// Open content along with its outboard encoding.
+    pub fn open_outboard(content: T, outboard: O, hash: &Hash) -> io::Result<Self> {
+        Self::open_outboard_with_limits(content, outboard, hash, &Limits::default())
+    }
+
+    /// Like `open_outboard`, but with `Limits` on the length header.
+    pub fn open_outboard_with_limits(
+        content: T,
+        outboard: O,
+        hash: &Hash,
+        limits: &Limits,
+    ) -> io::Result<Self> {
+        Self::open_inner(content, Some(outboard), hash, limits)
+    }
+
+    fn open_inner(input: T, outboard: Option<O>, hash: &Hash, limits: &Limits) -> io::Result<Self> {
+        let mut file = Self {
+            input,
+            outboard,
+            root_hash: *hash,
+            content_len: 0,
+            parents: HashMap::new(),
+            chunk_buf: [0; CHUNK_SIZE],
+        };
+        let mut header = [0; HEADER_SIZE];
+        file.tree_reader().seek(SeekFrom::Start(0))?;
+        file.tree_reader().read_exact(&mut header)?;
+        limits.check_header(&header)?;
+        file.content_len = crate::decode_len(&header);
+        // Verify the final chunk, which verifies the length. This is the "final chunk
+        // requirement" from the spec.
+        file.verify_chunk(encode::count_chunks(file.content_len) - 1)?;
+        Ok(file)
+    }
+
+    /// The content length. This has been verified.
+    pub fn len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// Returns `true` if the content is empty.
+    pub fn is_empty(&self) -> bool {
+        self.content_len == 0
+    }
+
+    /// Read content bytes starting at `offset` into `buf`, and return how many were read. This
+    /// only reads fewer bytes than `buf.len()` at the end of the content, and it returns 0 for an
+    /// `offset` at or past the end.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.content_len {
+            return Ok(0);
+        }
+        let total = cmp::min(buf.len() as u64, self.content_len - offset) as usize;
+        let mut done = 0;
+        while done < total {
+            let position = offset + done as u64;
+            let chunk_index = position / CHUNK_SIZE as u64;
+            let chunk_len = self.verify_chunk(chunk_index)?;
+            let skip = (position % CHUNK_SIZE as u64) as usize;
+            let take = cmp::min(chunk_len - skip, total - done);
+            buf[done..][..take].copy_from_slice(&self.chunk_buf[skip..][..take]);
+            done += take;
+        }
+        Ok(total)
+    }
+
+    // The reader that holds the header and the parent nodes.
+    fn tree_reader(&mut self) -> &mut dyn ReadSeek {
+        match &mut self.outboard {
+            Some(outboard) => outboard,
+            None => &mut self.input,
+        }
+    }
+
+    fn subtree_size(&self, content_len: u64) -> u128 {
+        if self.outboard.is_some() {
+            encode::outboard_subtree_size(content_len)
+        } else {
+            encode::encoded_subtree_size(content_len)
+        }
+    }
+
+    // Return the verified children of the parent node at `offset`, reading and verifying it if
+    // it isn't cached.
+    fn parent_children(
+        &mut self,
+        offset: u128,
+        expected: &Hash,
+        finalization: Finalization,
+    ) -> io::Result<(Hash, Hash)> {
+        if let Some(children) = self.parents.get(&offset) {
+            return Ok(*children);
+        }
+        let mut parent = [0; PARENT_SIZE];
+        let offset_u64 = encode::EncodedOffset::new(offset).to_u64()?;
+        self.tree_reader().seek(SeekFrom::Start(offset_u64))?;
+        self.tree_reader().read_exact(&mut parent)?;
+        let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        let computed = crate::parent_cv(&left_child, &right_child, finalization.is_root());
+        // Hash implements constant time equality.
+        if &computed != expected {
+            return Err(Error::HashMismatch.into());
+        }
+        if self.parents.len() >= MAX_CACHED_PARENTS {
+            self.parents.clear();
+        }
+        self.parents.insert(offset, (left_child, right_child));
+        Ok((left_child, right_child))
+    }
+
+    // Read and verify a chunk into chunk_buf, and return its length.
+    fn verify_chunk(&mut self, chunk_index: u64) -> io::Result<usize> {
+        let chunk_start = chunk_index * CHUNK_SIZE as u64;
+        let mut subtree_start = 0;
+        let mut subtree_len = self.content_len;
+        let mut offset = HEADER_SIZE as u128;
+        let mut expected = self.root_hash;
+        let mut finalization = Root;
+        while subtree_len > CHUNK_SIZE as u64 {
+            let (left_child, right_child) =
+                self.parent_children(offset, &expected, finalization)?;
+            let left_len = encode::left_subtree_len(subtree_len);
+            offset += PARENT_SIZE as u128;
+            if chunk_start < subtree_start + left_len {
+                subtree_len = left_len;
+                expected = left_child;
+            } else {
+                offset += self.subtree_size(left_len);
+                subtree_start += left_len;
+                subtree_len -= left_len;
+                expected = right_child;
+            }
+            finalization = NotRoot;
+        }
+        let chunk_len = subtree_len as usize;
+        let chunk_offset = if self.outboard.is_some() {
+            chunk_start
+        } else {
+            encode::EncodedOffset::new(offset).to_u64()?
+        };
+        self.input.seek(SeekFrom::Start(chunk_offset))?;
+        self.input.read_exact(&mut self.chunk_buf[..chunk_len])?;
+        let computed = crate::chunk_cv(
+            chunk_index,
+            &self.chunk_buf[..chunk_len],
+            finalization.is_root(),
+        );
+        // Hash implements constant time equality.
+        if computed != expected {
+            return Err(Error::HashMismatch.into());
+        }
+        Ok(chunk_len)
+    }
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+impl<T: Read + Seek, O: Read + Seek> fmt::Debug for VerifiedFile<T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        write!(
+            f,
+            "VerifiedFile {{ is_outboard: {}, content_len: {}, cached_parents: {} }}",
+            self.outboard.is_some(),
+            self.content_len,
+            self.parents.len(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_at() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            let mut combined = VerifiedFile::open(Cursor::new(&encoded), &hash).unwrap();
+            let mut separate =
+                VerifiedFile::open_outboard(Cursor::new(&input), Cursor::new(&outboard), &hash)
+                    .unwrap();
+            for file in [&mut combined as &mut dyn ReadAt, &mut separate] {
+                assert_eq!(case as u64, file.file_len());
+                for &offset in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, case / 2, case] {
+                    for &len in &[0, 1, 10, CHUNK_SIZE, 3 * CHUNK_SIZE + 5] {
+                        let mut buf = vec![0xff; len];
+                        let n = file.read_at(offset as u64, &mut buf).unwrap();
+                        let start = cmp::min(offset, case);
+                        let end = cmp::min(offset + len, case);
+                        assert_eq!(end - start, n);
+                        assert_eq!(&input[start..end], &buf[..n]);
+                    }
+                }
+                let mut buf = [0];
+                assert_eq!(0, file.read_at(case as u64 + 100, &mut buf).unwrap());
+            }
+        }
+    }
+
+    // Lets the test above treat both kinds of VerifiedFile the same way.
+    trait ReadAt {
+        fn file_len(&self) -> u64;
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    }
+
+    impl<T: Read + Seek, O: Read + Seek> ReadAt for VerifiedFile<T, O> {
+        fn file_len(&self) -> u64 {
+            self.len()
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+            VerifiedFile::read_at(self, offset, buf)
+        }
+    }
+
+    #[test]
+    fn test_corruption() {
+        let case = 8 * CHUNK_SIZE + 1;
+        let input = make_test_input(case);
+        let (mut encoded, hash) = encode::encode(&input);
+
+        // A corrupt final chunk fails at open time.
+        let mut bad_encoded = encoded.clone();
+        *bad_encoded.last_mut().unwrap() ^= 1;
+        let err = VerifiedFile::open(Cursor::new(&bad_encoded), &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Otherwise only reads that touch the corruption fail.
+        let chunk_3 = encode::chunk_encoded_offset(3, case as u64) as usize;
+        encoded[chunk_3] ^= 1;
+        let mut file = VerifiedFile::open(Cursor::new(&encoded), &hash).unwrap();
+        let mut buf = [0; CHUNK_SIZE];
+        let err = file
+            .read_at(3 * CHUNK_SIZE as u64 + 5, &mut buf)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        file.read_at(2 * CHUNK_SIZE as u64, &mut buf).unwrap();
+        assert_eq!(&input[2 * CHUNK_SIZE..][..CHUNK_SIZE], &buf[..]);
+        file.read_at(4 * CHUNK_SIZE as u64, &mut buf).unwrap();
+        assert_eq!(&input[4 * CHUNK_SIZE..][..CHUNK_SIZE], &buf[..]);
+    }
+
+    #[test]
+    fn test_parents_are_cached() {
+        let case = 8 * CHUNK_SIZE;
+        let input = make_test_input(case);
+        let (mut encoded, hash) = encode::encode(&input);
+        let mut file = VerifiedFile::open(Cursor::new(&mut encoded), &hash).unwrap();
+        let mut buf = [0; 1];
+        file.read_at(0, &mut buf).unwrap();
+        // Every parent above chunk 0 has been verified now. Corrupting them doesn't matter,
+        // because they don't get read again.
+        for i in 0..3 {
+            file.input.get_mut()[HEADER_SIZE + i * PARENT_SIZE] ^= 1;
+        }
+        file.read_at(CHUNK_SIZE as u64, &mut buf).unwrap();
+        assert_eq!(input[CHUNK_SIZE], buf[0]);
+    }
+
+    #[test]
+    fn test_limits_reject_huge_header() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let (mut outboard, _) = encode::outboard(&input);
+        encoded[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        outboard[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        let limits = Limits {
+            max_content_len: 1 << 30,
+            ..Limits::default()
+        };
+        let err = VerifiedFile::open_with_limits(Cursor::new(&encoded), &hash, &limits)
+            .err()
+            .unwrap();
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+        let err = VerifiedFile::open_outboard_with_limits(
+            Cursor::new(&input),
+            Cursor::new(&outboard),
+            &hash,
+            &limits,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+    }
+}
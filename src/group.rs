@@ -0,0 +1,672 @@
+//! Encodings with chunk groups, for lower overhead on very large files.
+//!
+//! A regular encoding stores a parent node for every chunk, which is 64 bytes of overhead per
+//! 1024 bytes of content, about 6%, and decoding verifies every one of them. Callers who never
+//! need to verify less than, say, 16 KiB at a time can group chunks together instead. With
+//! `2^chunk_group_log` chunks per group, the encoding leaves out every parent node below the
+//! group level, and a decoder verifies a whole group at once by rehashing it. With 16 chunks per
+//! group, the overhead drops to 64 bytes per 16 KiB, about 0.4%.
+//!
+//! The groups are whole subtrees of the usual BLAKE3 tree, so **the root hash doesn't change**.
+//! It's still `blake3::hash` of the content, and the same hash can verify encodings with any
+//! group size. A `chunk_group_log` of 0 gives exactly the regular encoding. The layout is the
+//! same as a regular encoding too, with each group taking the place of a chunk: the 8-byte
+//! length header, then the parent nodes and groups in pre-order. The outboard layout leaves out
+//! the groups, as usual.
+//!
+//! `encode` and `outboard` take the whole input in memory. `Encoder` is the streaming version,
+//! which works like `encode::Encoder`, writing the tree in post-order and flipping it in
+//! `finalize`. Encodings with chunk groups only use the standard profile, so there's no
+//! `set_config`, and the `config` module's options don't apply here.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let input = vec![0xab; 1_000_000];
+//! let (encoded, hash) = bao::group::encode(&input, 4);
+//! assert_eq!(blake3::hash(&input), hash);
+//! assert!(encoded.len() < bao::encode::encode(&input).0.len());
+//!
+//! let mut output = Vec::new();
+//! bao::group::decode(&encoded[..], &mut output, &hash, 4)?;
+//! assert_eq!(input, output);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::config::Config;
+use crate::decode::{Error, Limits};
+use crate::encode::{self, OutputBuffer, State, StateFinish};
+use crate::Finalization::{self, NotRoot, Root};
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use blake3::hazmat::HasherExt;
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+/// The largest supported `chunk_group_log`, for groups of 64 MiB. Decoding holds a whole group
+/// in memory.
+pub const MAX_CHUNK_GROUP_LOG: u8 = 16;
+
+/// The number of content bytes in a full group.
+///
+/// # Panics
+///
+/// This panics if `chunk_group_log` is greater than `MAX_CHUNK_GROUP_LOG`. So do all the other
+/// functions in this module.
+pub fn group_size(chunk_group_log: u8) -> usize {
+    assert!(
+        chunk_group_log <= MAX_CHUNK_GROUP_LOG,
+        "chunk_group_log too large"
+    );
+    CHUNK_SIZE << chunk_group_log
+}
+
+/// The number of groups, which is never less than 1, even for empty content.
+pub fn count_groups(content_len: u64, chunk_group_log: u8) -> u64 {
+    let size = group_size(chunk_group_log) as u64;
+    std::cmp::max(1, content_len.div_ceil(size))
+}
+
+/// The size of a combined encoding with chunk groups.
+pub fn encoded_size(content_len: u64, chunk_group_log: u8) -> u128 {
+    content_len as u128 + outboard_size(content_len, chunk_group_log)
+}
+
+/// The size of an outboard encoding with chunk groups.
+pub fn outboard_size(content_len: u64, chunk_group_log: u8) -> u128 {
+    let parents = count_groups(content_len, chunk_group_log) - 1;
+    HEADER_SIZE as u128 + parents as u128 * PARENT_SIZE as u128
+}
+
+/// Encode the input into a combined encoding with chunk groups, and return it along with the
+/// root hash.
+pub fn encode(input: impl AsRef<[u8]>, chunk_group_log: u8) -> (Vec<u8>, Hash) {
+    encode_inner(input.as_ref(), chunk_group_log, false)
+}
+
+/// Encode the input into an outboard encoding with chunk groups, and return it along with the
+/// root hash.
+pub fn outboard(input: impl AsRef<[u8]>, chunk_group_log: u8) -> (Vec<u8>, Hash) {
+    encode_inner(input.as_ref(), chunk_group_log, true)
+}
+
+fn encode_inner(input: &[u8], chunk_group_log: u8, outboard: bool) -> (Vec<u8>, Hash) {
+    let content_len = input.len() as u64;
+    let size = if outboard {
+        outboard_size(content_len, chunk_group_log)
+    } else {
+        encoded_size(content_len, chunk_group_log)
+    };
+    let mut output = Vec::with_capacity(size as usize);
+    output.extend_from_slice(&crate::encode_len(content_len));
+    let group_size = group_size(chunk_group_log) as u64;
+    let hash = write_subtree(input, 0, group_size, Root, outboard, &mut output);
+    debug_assert_eq!(size, output.len() as u128);
+    (output, hash)
+}
+
+fn write_subtree(
+    input: &[u8],
+    first_chunk: u64,
+    group_size: u64,
+    finalization: Finalization,
+    outboard: bool,
+    output: &mut Vec<u8>,
+) -> Hash {
+    let subtree_len = input.len() as u64;
+    if subtree_len <= group_size {
+        if !outboard {
+            output.extend_from_slice(input);
+        }
+        return subtree_hash(input, first_chunk, finalization);
+    }
+    let left_len = encode::left_subtree_len(subtree_len);
+    let parent_start = output.len();
+    output.extend_from_slice(&[0; PARENT_SIZE]);
+    let (left, right) = input.split_at(left_len as usize);
+    let right_chunk = first_chunk + encode::count_chunks(left_len);
+    let left_hash = write_subtree(left, first_chunk, group_size, NotRoot, outboard, output);
+    let right_hash = write_subtree(right, right_chunk, group_size, NotRoot, outboard, output);
+    output[parent_start..][..HASH_SIZE].copy_from_slice(left_hash.as_bytes());
+    output[parent_start + HASH_SIZE..][..HASH_SIZE].copy_from_slice(right_hash.as_bytes());
+    crate::parent_cv(&left_hash, &right_hash, finalization.is_root())
+}
+
+/// An incremental encoder for encodings with chunk groups, like `encode::Encoder`. It gives the
+/// same output as `encode` and `outboard`, without holding the input in memory. Note that you
+/// must call `finalize` after you're done writing.
+///
+/// Each group is hashed as it's written, and its parent nodes go out once the first byte of the
+/// next group arrives. Output is buffered internally, and `finalize` flips the post-order
+/// encoding into pre-order, moving each group a chunk at a time, so neither step holds a whole
+/// group in memory.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0xab; 100_000];
+/// let mut encoder = bao::group::Encoder::new(std::io::Cursor::new(Vec::new()), 4);
+/// encoder.write_all(&input)?;
+/// let hash = encoder.finalize()?;
+/// let encoded = encoder.into_inner().into_inner();
+/// assert_eq!(bao::group::encode(&input, 4), (encoded, hash));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Encoder<T: Read + Write + Seek> {
+    inner: T,
+    chunk_group_log: u8,
+    outboard: bool,
+    state: State,
+    group: blake3::Hasher,
+    group_len: u64,
+    output: OutputBuffer,
+    flip_window_size: usize,
+    finalized: bool,
+}
+
+impl<T: Read + Write + Seek> Encoder<T> {
+    /// Create a new `Encoder` that will produce a combined encoding with `2^chunk_group_log`
+    /// chunks per group.
+    pub fn new(inner: T, chunk_group_log: u8) -> Self {
+        // Check chunk_group_log up front.
+        group_size(chunk_group_log);
+        Self {
+            inner,
+            chunk_group_log,
+            outboard: false,
+            state: State::new(),
+            group: blake3::Hasher::new(),
+            group_len: 0,
+            output: OutputBuffer::new(encode::DEFAULT_BUFFER_SIZE),
+            flip_window_size: encode::DEFAULT_FLIP_WINDOW_SIZE,
+            finalized: false,
+        }
+    }
+
+    /// Create a new `Encoder` for an outboard encoding with chunk groups.
+    pub fn new_outboard(inner: T, chunk_group_log: u8) -> Self {
+        let mut encoder = Self::new(inner, chunk_group_log);
+        encoder.outboard = true;
+        encoder
+    }
+
+    /// Set the size of the output buffer. See `encode::Encoder::set_buffer_size`.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.output.capacity = size;
+    }
+
+    /// Set the size of the windows that `finalize` reads and writes in. See
+    /// `encode::Encoder::set_flip_window_size`.
+    pub fn set_flip_window_size(&mut self, size: usize) {
+        self.flip_window_size = size;
+    }
+
+    /// The number of content bytes written so far.
+    pub fn content_len(&self) -> u64 {
+        self.state.count() + self.group_len
+    }
+
+    /// Finalize the encoding, after all the input has been written, and return the root hash.
+    /// Writing or finalizing again will panic.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        assert!(!self.finalized, "already finalized");
+        self.finalized = true;
+        let config = Config::new();
+        let content_len = self.content_len();
+        let hash = if self.state.count() == 0 {
+            // A single group is the root, and it's an ordinary BLAKE3 hash.
+            self.group.finalize()
+        } else {
+            let cv: Hash = self.group.finalize_non_root().into();
+            self.state.push_subtree(&cv, self.group_len as usize);
+            loop {
+                match self.state.merge_finalize(&config) {
+                    StateFinish::Parent(parent) => {
+                        self.output.write_all(&mut self.inner, &parent)?;
+                    }
+                    StateFinish::Root(hash) => break hash,
+                }
+            }
+        };
+        // The length header goes at the end, where the flip expects it.
+        self.output
+            .write_all(&mut self.inner, &crate::encode_len(content_len))?;
+        self.output.flush(&mut self.inner)?;
+        encode::flip_post_order(
+            &mut self.inner,
+            &config,
+            self.outboard,
+            self.chunk_group_log,
+            self.flip_window_size,
+        )?;
+        Ok(hash)
+    }
+
+    /// Return the underlying writer, after `finalize`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if any input has been written but `finalize` hasn't been called. Use `abort`
+    /// to give up on an unfinished encoding.
+    pub fn into_inner(self) -> T {
+        assert!(
+            self.finalized || self.content_len() == 0,
+            "not finalized, use abort() instead"
+        );
+        self.inner
+    }
+
+    /// Give up on an unfinished encoding and return the underlying writer, discarding buffered
+    /// output. See `encode::Encoder::abort`.
+    pub fn abort(self) -> T {
+        self.inner
+    }
+
+    // Hash the current full group into the tree, as a non-root subtree, and write the parent
+    // nodes that it completes. This is only right once we know more input is coming.
+    fn push_group(&mut self) -> io::Result<()> {
+        let config = Config::new();
+        let cv: Hash = self.group.finalize_non_root().into();
+        self.state.push_subtree(&cv, self.group_len as usize);
+        while let Some(parent) = self.state.merge_parent(&config) {
+            self.output.write_all(&mut self.inner, &parent)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read + Write + Seek> Write for Encoder<T> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        assert!(!self.finalized, "already finalized");
+        if input.is_empty() {
+            return Ok(0);
+        }
+        let group_size = group_size(self.chunk_group_log) as u64;
+        // More input means the full group isn't the last one.
+        if self.group_len == group_size {
+            self.push_group()?;
+            self.group = blake3::Hasher::new();
+            self.group.set_input_offset(self.state.count());
+            self.group_len = 0;
+        }
+        let want = cmp::min(input.len() as u64, group_size - self.group_len) as usize;
+        let input = &input[..want];
+        if !self.outboard {
+            self.output.write_all(&mut self.inner, input)?;
+        }
+        self.group.update(input);
+        self.group_len += want as u64;
+        Ok(want)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush(&mut self.inner)?;
+        self.inner.flush()
+    }
+}
+
+// The chaining value of a whole subtree, computed from its content.
+fn subtree_hash(content: &[u8], first_chunk: u64, finalization: Finalization) -> Hash {
+    let len = content.len() as u64;
+    if len <= CHUNK_SIZE as u64 {
+        return crate::chunk_cv(first_chunk, content, finalization.is_root());
+    }
+    let left_len = encode::left_subtree_len(len);
+    let (left, right) = content.split_at(left_len as usize);
+    let right_chunk = first_chunk + encode::count_chunks(left_len);
+    let left_hash = subtree_hash(left, first_chunk, NotRoot);
+    let right_hash = subtree_hash(right, right_chunk, NotRoot);
+    crate::parent_cv(&left_hash, &right_hash, finalization.is_root())
+}
+
+/// Decode a combined encoding with chunk groups, writing the verified content to `output` one
+/// group at a time, and return the content length. Nothing is written for a group until it's
+/// verified, but if there's an error, the groups before it have already been written.
+pub fn decode(
+    encoded: impl Read,
+    output: impl Write,
+    hash: &Hash,
+    chunk_group_log: u8,
+) -> io::Result<u64> {
+    decode_with_limits(encoded, output, hash, chunk_group_log, &Limits::default())
+}
+
+/// Like `decode`, but with `Limits` on the length header.
+pub fn decode_with_limits(
+    mut encoded: impl Read,
+    mut output: impl Write,
+    hash: &Hash,
+    chunk_group_log: u8,
+    limits: &Limits,
+) -> io::Result<u64> {
+    decode_inner(
+        &mut encoded,
+        None,
+        &mut output,
+        hash,
+        chunk_group_log,
+        limits,
+    )
+}
+
+/// Decode an outboard encoding with chunk groups. See `decode`.
+pub fn decode_outboard(
+    content: impl Read,
+    outboard: impl Read,
+    output: impl Write,
+    hash: &Hash,
+    chunk_group_log: u8,
+) -> io::Result<u64> {
+    decode_outboard_with_limits(
+        content,
+        outboard,
+        output,
+        hash,
+        chunk_group_log,
+        &Limits::default(),
+    )
+}
+
+/// Like `decode_outboard`, but with `Limits` on the length header.
+pub fn decode_outboard_with_limits(
+    mut content: impl Read,
+    mut outboard: impl Read,
+    mut output: impl Write,
+    hash: &Hash,
+    chunk_group_log: u8,
+    limits: &Limits,
+) -> io::Result<u64> {
+    decode_inner(
+        &mut content,
+        Some(&mut outboard),
+        &mut output,
+        hash,
+        chunk_group_log,
+        limits,
+    )
+}
+
+fn decode_inner<'a>(
+    content: &'a mut dyn Read,
+    mut outboard: Option<&'a mut dyn Read>,
+    output: &'a mut dyn Write,
+    hash: &Hash,
+    chunk_group_log: u8,
+    limits: &Limits,
+) -> io::Result<u64> {
+    let mut header = [0; HEADER_SIZE];
+    match &mut outboard {
+        Some(outboard) => outboard.read_exact(&mut header)?,
+        None => content.read_exact(&mut header)?,
+    }
+    limits.check_header(&header)?;
+    let content_len = crate::decode_len(&header);
+    let mut decoder = GroupDecoder {
+        content,
+        outboard,
+        output,
+        group_size: group_size(chunk_group_log) as u64,
+        buf: Vec::new(),
+    };
+    decoder.decode_subtree(*hash, 0, content_len, Root)?;
+    Ok(content_len)
+}
+
+struct GroupDecoder<'a> {
+    content: &'a mut dyn Read,
+    outboard: Option<&'a mut dyn Read>,
+    output: &'a mut dyn Write,
+    group_size: u64,
+    buf: Vec<u8>,
+}
+
+impl GroupDecoder<'_> {
+    fn decode_subtree(
+        &mut self,
+        expected: Hash,
+        first_chunk: u64,
+        subtree_len: u64,
+        finalization: Finalization,
+    ) -> io::Result<()> {
+        if subtree_len <= self.group_size {
+            self.buf.resize(subtree_len as usize, 0);
+            self.content.read_exact(&mut self.buf)?;
+            // Hash implements constant time equality.
+            if expected != subtree_hash(&self.buf, first_chunk, finalization) {
+                return Err(Error::HashMismatch.into());
+            }
+            return self.output.write_all(&self.buf);
+        }
+        let mut parent = [0; PARENT_SIZE];
+        match &mut self.outboard {
+            Some(outboard) => outboard.read_exact(&mut parent)?,
+            None => self.content.read_exact(&mut parent)?,
+        }
+        let left_hash: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let right_hash: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        let computed = crate::parent_cv(&left_hash, &right_hash, finalization.is_root());
+        if expected != computed {
+            return Err(Error::HashMismatch.into());
+        }
+        let left_len = encode::left_subtree_len(subtree_len);
+        let right_chunk = first_chunk + encode::count_chunks(left_len);
+        self.decode_subtree(left_hash, first_chunk, left_len, NotRoot)?;
+        self.decode_subtree(right_hash, right_chunk, subtree_len - left_len, NotRoot)
+    }
+}
+
+/// Read and verify a single group from a combined encoding with chunk groups, seeking past
+/// everything else. This reads the length header and the parent nodes on the path from the root
+/// to the group, so it's a single-group random access read.
+///
+/// A `group_index` past the end returns an empty `Vec`, after verifying the final group, which
+/// is what makes the length header trustworthy.
+pub fn read_group(
+    encoded: impl Read + Seek,
+    hash: &Hash,
+    chunk_group_log: u8,
+    group_index: u64,
+) -> io::Result<Vec<u8>> {
+    read_group_with_limits(
+        encoded,
+        hash,
+        chunk_group_log,
+        group_index,
+        &Limits::default(),
+    )
+}
+
+/// Like `read_group`, but with `Limits` on the length header.
+pub fn read_group_with_limits(
+    mut encoded: impl Read + Seek,
+    hash: &Hash,
+    chunk_group_log: u8,
+    group_index: u64,
+    limits: &Limits,
+) -> io::Result<Vec<u8>> {
+    let group_size = group_size(chunk_group_log) as u64;
+    let mut header = [0; HEADER_SIZE];
+    encoded.seek(SeekFrom::Start(0))?;
+    encoded.read_exact(&mut header)?;
+    limits.check_header(&header)?;
+    let content_len = crate::decode_len(&header);
+    let last_group = count_groups(content_len, chunk_group_log) - 1;
+    let target = std::cmp::min(group_index, last_group);
+    let target_start = target * group_size;
+
+    // Walk down from the root, checking each parent on the way, and seek past the left subtrees
+    // we don't need.
+    let mut expected = *hash;
+    let mut subtree_start = 0;
+    let mut subtree_len = content_len;
+    let mut finalization = Root;
+    let mut position = HEADER_SIZE as u64;
+    while subtree_len > group_size {
+        let mut parent = [0; PARENT_SIZE];
+        encoded.read_exact(&mut parent)?;
+        position += PARENT_SIZE as u64;
+        let left_hash: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let right_hash: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        let computed = crate::parent_cv(&left_hash, &right_hash, finalization.is_root());
+        if expected != computed {
+            return Err(Error::HashMismatch.into());
+        }
+        let left_len = encode::left_subtree_len(subtree_len);
+        if target_start < subtree_start + left_len {
+            expected = left_hash;
+            subtree_len = left_len;
+        } else {
+            let skip = encoded_size(left_len, chunk_group_log) - HEADER_SIZE as u128;
+            position = encode::EncodedOffset::new(position as u128 + skip).to_u64()?;
+            encoded.seek(SeekFrom::Start(position))?;
+            expected = right_hash;
+            subtree_start += left_len;
+            subtree_len -= left_len;
+        }
+        finalization = NotRoot;
+    }
+    let mut group = vec![0; subtree_len as usize];
+    encoded.read_exact(&mut group)?;
+    let first_chunk = subtree_start / CHUNK_SIZE as u64;
+    if expected != subtree_hash(&group, first_chunk, finalization) {
+        return Err(Error::HashMismatch.into());
+    }
+    if group_index > last_group {
+        group.clear();
+    }
+    Ok(group)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_log_zero_is_regular_encoding() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            assert_eq!(crate::encode::encode(&input), encode(&input, 0));
+            assert_eq!(crate::encode::outboard(&input), outboard(&input, 0));
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            for log in 0..4 {
+                println!("case {} log {}", case, log);
+                let (encoded, hash) = encode(&input, log);
+                assert_eq!(blake3::hash(&input), hash);
+                assert_eq!(encoded_size(case as u64, log), encoded.len() as u128);
+                let mut output = Vec::new();
+                let len = decode(&encoded[..], &mut output, &hash, log).unwrap();
+                assert_eq!(case as u64, len);
+                assert_eq!(input, output);
+
+                let (outboard, outboard_hash) = outboard(&input, log);
+                assert_eq!(hash, outboard_hash);
+                assert_eq!(outboard_size(case as u64, log), outboard.len() as u128);
+                let mut output = Vec::new();
+                decode_outboard(&input[..], &outboard[..], &mut output, &hash, log).unwrap();
+                assert_eq!(input, output);
+
+                let groups = count_groups(case as u64, log);
+                let size = group_size(log);
+                for index in 0..groups + 1 {
+                    let group = read_group(Cursor::new(&encoded), &hash, log, index).unwrap();
+                    let start = std::cmp::min(index as usize * size, case);
+                    let end = std::cmp::min(start + size, case);
+                    assert_eq!(&input[start..end], &group[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoder() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            for log in 0..4 {
+                println!("case {} log {}", case, log);
+                let mut encoder = Encoder::new(Cursor::new(Vec::new()), log);
+                // Small writes, so groups arrive in pieces.
+                for piece in input.chunks(700) {
+                    encoder.write_all(piece).unwrap();
+                }
+                let hash = encoder.finalize().unwrap();
+                let encoded = encoder.into_inner().into_inner();
+                assert_eq!(encode(&input, log), (encoded, hash));
+
+                let mut encoder = Encoder::new_outboard(Cursor::new(Vec::new()), log);
+                encoder.set_buffer_size(0);
+                encoder.set_flip_window_size(0);
+                encoder.write_all(&input).unwrap();
+                let hash = encoder.finalize().unwrap();
+                let encoded = encoder.into_inner().into_inner();
+                assert_eq!(outboard(&input, log), (encoded, hash));
+            }
+        }
+    }
+
+    #[test]
+    fn test_corruption() {
+        let log = 2;
+        let input = make_test_input(10 * group_size(log) + 1);
+        let (encoded, hash) = encode(&input, log);
+        // Corrupt a few bytes throughout the encoding, other than the header. Flipping a bit in
+        // the header might give a length that still fits, so skip it here.
+        for &point in &[
+            HEADER_SIZE,
+            HEADER_SIZE + 100,
+            encoded.len() / 2,
+            encoded.len() - 1,
+        ] {
+            let mut corrupt = encoded.clone();
+            corrupt[point] ^= 1;
+            let err = decode(&corrupt[..], io::sink(), &hash, log).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+        // A group that isn't corrupt still reads fine, as long as the corruption isn't on its
+        // path.
+        let mut corrupt = encoded.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        read_group(Cursor::new(&corrupt), &hash, log, 0).unwrap();
+        read_group(Cursor::new(&corrupt), &hash, log, 10).unwrap_err();
+        // A different group size doesn't verify.
+        assert!(decode(&encoded[..], io::sink(), &hash, log + 1).is_err());
+    }
+
+    #[test]
+    fn test_limits_reject_huge_header() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode(&input, 1);
+        let (mut outboard, _) = outboard(&input, 1);
+        encoded[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        outboard[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        let limits = Limits {
+            max_content_len: 1 << 30,
+            ..Limits::default()
+        };
+        let err = decode_with_limits(&encoded[..], io::sink(), &hash, 1, &limits).unwrap_err();
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+        let err =
+            decode_outboard_with_limits(&input[..], &outboard[..], io::sink(), &hash, 1, &limits)
+                .unwrap_err();
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+        let err = read_group_with_limits(Cursor::new(&encoded), &hash, 1, 0, &limits).unwrap_err();
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+    }
+}
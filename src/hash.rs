@@ -0,0 +1,245 @@
+//! Hashing helpers that sit underneath the encoder.
+//!
+//! The `encode` and `decode` modules do all of their hashing internally. This module holds the
+//! pieces that callers can keep around between encodings, like the `ChunkHashCache`, and bulk
+//! hashing functions for callers who build trees themselves.
+
+use crate::{Hash, CHUNK_SIZE};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::thread;
+
+// Below this many chunks, hashing them all on one thread is faster than spawning more.
+const MIN_CHUNKS_PER_THREAD: usize = 64;
+
+/// Compute the chaining values of many chunks at once, with each chunk hashed at its position in
+/// the tree: `chunks[0]` is chunk number `first_chunk_index`, `chunks[1]` the one after it, and
+/// so on. These are the non-root hashes that go into parent nodes. A tree of just one chunk has a
+/// root hash instead, which this doesn't compute.
+///
+/// Large batches are split across as many threads as `std::thread::available_parallelism`
+/// reports. Batching up chunks like this is much faster than hashing them one at a time, for
+/// example as they arrive off the network, and callers that receive chunks in bulk should prefer
+/// it.
+///
+/// # Panics
+///
+/// Panics if any chunk is longer than 1024 bytes, or if the chunk indexes overflow `u64`.
+///
+/// # Example
+///
+/// ```
+/// let input = vec![0xab; 3000];
+/// let chunks: Vec<&[u8]> = input.chunks(1024).collect();
+/// let hashes = bao::hash::hash_many_chunks(&chunks, 0);
+/// assert_eq!(3, hashes.len());
+/// // Chunks with identical bytes get different hashes at different positions.
+/// assert_ne!(hashes[0], hashes[1]);
+/// ```
+pub fn hash_many_chunks(chunks: &[&[u8]], first_chunk_index: u64) -> Vec<Hash> {
+    assert!(
+        chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE),
+        "chunk too long"
+    );
+    first_chunk_index
+        .checked_add(chunks.len() as u64)
+        .expect("chunk index overflow");
+    let mut hashes = vec![Hash::from([0; crate::HASH_SIZE]); chunks.len()];
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunks_per_thread = cmp::max(chunks.len().div_ceil(threads), MIN_CHUNKS_PER_THREAD);
+    if chunks.len() <= chunks_per_thread {
+        hash_chunks_into(chunks, first_chunk_index, &mut hashes);
+        return hashes;
+    }
+    thread::scope(|scope| {
+        let groups = chunks
+            .chunks(chunks_per_thread)
+            .zip(hashes.chunks_mut(chunks_per_thread));
+        for (i, (chunk_group, hash_group)) in groups.enumerate() {
+            let group_index = first_chunk_index + (i * chunks_per_thread) as u64;
+            scope.spawn(move || hash_chunks_into(chunk_group, group_index, hash_group));
+        }
+    });
+    hashes
+}
+
+fn hash_chunks_into(chunks: &[&[u8]], first_chunk_index: u64, hashes: &mut [Hash]) {
+    for (i, (chunk, hash)) in chunks.iter().zip(hashes).enumerate() {
+        *hash = crate::chunk_cv(first_chunk_index + i as u64, chunk, false);
+    }
+}
+
+/// A cache of chunk hashes, keyed by chunk index, which an
+/// [`Encoder`](../encode/struct.Encoder.html) can consult to avoid rehashing chunks that haven't
+/// changed since a previous encoding of the same file.
+///
+/// Attach a cache with `Encoder::set_chunk_cache`, and the encoder will fill it with the hash of
+/// every complete chunk it writes. After editing the file, call `invalidate` with the edited
+/// range and attach the same cache to a new encoder. Chunks the cache still has an entry for
+/// aren't hashed again. Note that a chunk hash depends on the chunk's position, so any edit that
+/// shifts bytes around (an insertion or a deletion) invalidates everything after it, and callers
+/// should use `invalidate_from` in that case.
+///
+/// The cache trusts its entries. If a chunk changes without being invalidated, the resulting
+/// encoding will have the wrong hash.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let mut input = vec![0; 1_000_000];
+/// let mut cache = bao::hash::ChunkHashCache::new();
+///
+/// let mut encoder = bao::encode::Encoder::new(std::io::Cursor::new(Vec::new()));
+/// encoder.set_chunk_cache(cache);
+/// encoder.write_all(&input)?;
+/// encoder.finalize()?;
+/// cache = encoder.take_chunk_cache().unwrap();
+///
+/// // Edit a few bytes, and re-encode. Only the edited chunk gets hashed again.
+/// input[500_000..500_010].copy_from_slice(b"0123456789");
+/// cache.invalidate(500_000, 10);
+/// let mut encoder = bao::encode::Encoder::new(std::io::Cursor::new(Vec::new()));
+/// encoder.set_chunk_cache(cache);
+/// encoder.write_all(&input)?;
+/// let hash = encoder.finalize()?;
+/// assert_eq!(blake3::hash(&input), hash);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct ChunkHashCache {
+    hashes: BTreeMap<u64, Hash>,
+}
+
+impl ChunkHashCache {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of chunk hashes in the cache.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Returns `true` if the cache holds no chunk hashes.
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Drop the cached hashes of every chunk that overlaps the `len` content bytes starting at
+    /// `start`. Call this after editing those bytes in place.
+    pub fn invalidate(&mut self, start: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let first_chunk = start / CHUNK_SIZE as u64;
+        let last_chunk = start.saturating_add(len - 1) / CHUNK_SIZE as u64;
+        let mut invalid = self.hashes.split_off(&first_chunk);
+        let mut after = invalid.split_off(&(last_chunk + 1));
+        self.hashes.append(&mut after);
+    }
+
+    /// Drop the cached hashes of every chunk that overlaps the content bytes starting at `start`,
+    /// up to the end of the file. Call this after inserting or deleting bytes at `start`.
+    pub fn invalidate_from(&mut self, start: u64) {
+        self.hashes.split_off(&(start / CHUNK_SIZE as u64));
+    }
+
+    /// Drop all the cached hashes.
+    pub fn clear(&mut self) {
+        self.hashes.clear();
+    }
+
+    pub(crate) fn get(&self, chunk_index: u64) -> Option<Hash> {
+        self.hashes.get(&chunk_index).copied()
+    }
+
+    pub(crate) fn insert(&mut self, chunk_index: u64, hash: Hash) {
+        self.hashes.insert(chunk_index, hash);
+    }
+}
+
+impl fmt::Debug for ChunkHashCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        write!(f, "ChunkHashCache {{ len: {} }}", self.hashes.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cache_with_chunks(count: u64) -> ChunkHashCache {
+        let mut cache = ChunkHashCache::new();
+        for i in 0..count {
+            cache.insert(i, blake3::hash(&i.to_le_bytes()));
+        }
+        cache
+    }
+
+    fn cached_indexes(cache: &ChunkHashCache) -> Vec<u64> {
+        cache.hashes.keys().copied().collect()
+    }
+
+    #[test]
+    fn test_hash_many_chunks() {
+        use blake3::hazmat::HasherExt;
+        let input = crate::decode::make_test_input(1000 * CHUNK_SIZE + 1);
+        let chunks: Vec<&[u8]> = input.chunks(CHUNK_SIZE).collect();
+        for &(start, end) in &[(0, 0), (0, 1), (5, 9), (0, chunks.len()), (300, 1000)] {
+            let hashes = hash_many_chunks(&chunks[start..end], start as u64);
+            assert_eq!(end - start, hashes.len());
+            for (i, hash) in hashes.iter().enumerate() {
+                let index = (start + i) as u64;
+                let mut hasher = blake3::Hasher::new();
+                hasher.set_input_offset(index * CHUNK_SIZE as u64);
+                hasher.update(chunks[start + i]);
+                let expected: Hash = hasher.finalize_non_root().into();
+                assert_eq!(expected, *hash);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hash_many_chunks_too_long() {
+        hash_many_chunks(&[&[0; CHUNK_SIZE + 1]], 0);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let chunk = CHUNK_SIZE as u64;
+
+        let mut cache = cache_with_chunks(6);
+        cache.invalidate(chunk, chunk);
+        assert_eq!(vec![0, 2, 3, 4, 5], cached_indexes(&cache));
+
+        // A range that straddles a chunk boundary invalidates both sides.
+        let mut cache = cache_with_chunks(6);
+        cache.invalidate(2 * chunk - 1, 2);
+        assert_eq!(vec![0, 3, 4, 5], cached_indexes(&cache));
+
+        // Empty ranges are a no-op.
+        let mut cache = cache_with_chunks(6);
+        cache.invalidate(3 * chunk, 0);
+        assert_eq!(6, cache.len());
+
+        // Ranges that run off the end don't overflow.
+        let mut cache = cache_with_chunks(6);
+        cache.invalidate(5 * chunk, u64::MAX);
+        assert_eq!(vec![0, 1, 2, 3, 4], cached_indexes(&cache));
+
+        let mut cache = cache_with_chunks(6);
+        cache.invalidate_from(3 * chunk + 1);
+        assert_eq!(vec![0, 1, 2], cached_indexes(&cache));
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
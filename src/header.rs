@@ -0,0 +1,51 @@
+//! The length header at the start of every encoding and every slice.
+//!
+//! The header is the content length as an 8-byte little-endian integer. In an outboard encoding
+//! it's at the start of the outboard file, and the content has no header. Note that the header
+//! isn't authenticated by itself. A decoder only trusts it once it has verified the final chunk,
+//! since the length is part of the root hash.
+//!
+//! # Example
+//!
+//! ```
+//! use bao::HEADER_SIZE;
+//! use std::convert::TryInto;
+//!
+//! let (encoded, _) = bao::encode::encode(b"hello");
+//! let header: [u8; HEADER_SIZE] = encoded[..HEADER_SIZE].try_into().unwrap();
+//! assert_eq!(5, bao::header::parse(&header));
+//! assert_eq!(header, bao::header::encode(5));
+//! ```
+
+use crate::HEADER_SIZE;
+
+/// Parse a length header into the content length.
+pub fn parse(header: &[u8; HEADER_SIZE]) -> u64 {
+    u64::from_le_bytes(*header)
+}
+
+/// Encode a content length as a length header.
+pub fn encode(content_len: u64) -> [u8; HEADER_SIZE] {
+    content_len.to_le_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for &len in &[0, 1, 1024, 0x0102_0304_0506_0708, u64::MAX] {
+            assert_eq!(len, parse(&encode(len)));
+        }
+        assert_eq!([8, 7, 6, 5, 4, 3, 2, 1], encode(0x0102_0304_0506_0708));
+    }
+
+    #[test]
+    fn test_matches_encoding() {
+        for &case in crate::test::TEST_CASES {
+            let (encoded, _) = crate::encode::encode(crate::decode::make_test_input(case));
+            assert_eq!(encode(case as u64), encoded[..HEADER_SIZE]);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Export the tree of an encoding as IPLD blocks in a CARv1 stream.
+//!
+//! This module is behind the `ipld` feature, which also enables `multihash`. Chunks become raw
+//! blocks (multicodec `0x55`), and parent nodes become DAG-CBOR blocks (multicodec `0x71`) holding
+//! a list of two links, to the left child and the right child. Every block is named by a CIDv1
+//! whose digest is the node's existing hash, so nothing is hashed twice, and the root block's CID
+//! carries the Bao root hash.
+//!
+//! In a tree of more than one chunk, those hashes are BLAKE3 chaining values, which are **not**
+//! hashes of the block bytes. So these CIDs don't claim BLAKE3 (multihash code `0x1e`). They use
+//! `CHAINING_VALUE_CODE` instead, a code from the private-use range of the multicodec table.
+//! Tooling that moves blocks around by CID works as usual, but tooling that verifies blocks by
+//! rehashing them will reject the unknown code, unless it knows about Bao. A Bao-aware verifier
+//! recomputes a raw block's digest as a chunk hash (with its index and root flag) and a DAG-CBOR
+//! block's digest as a parent hash of the digests of its two links. The one exception is content
+//! of a single chunk, where the root hash is an ordinary BLAKE3 hash of the only block, and the
+//! root CID is an ordinary BLAKE3 CID.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let input = vec![0; 10_000];
+//! let (encoded, hash) = bao::encode::encode(&input);
+//! let mut car = Vec::new();
+//! bao::ipld::write_car(&*encoded, &hash, &mut car)?;
+//!
+//! let root = bao::ipld::root_cid(&hash, input.len() as u64);
+//! assert!(root.ends_with(hash.as_bytes()));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::Error;
+use crate::encode;
+use crate::multihash::BLAKE3_CODE;
+use crate::Finalization::{self, NotRoot, Root};
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::io;
+use std::io::prelude::*;
+
+/// The multicodec for raw blocks, used for chunks.
+pub const RAW_CODEC: u64 = 0x55;
+/// The multicodec for DAG-CBOR blocks, used for parent nodes.
+pub const DAG_CBOR_CODEC: u64 = 0x71;
+
+/// The multihash code for BLAKE3 chaining values, which name every block except the root of a
+/// single-chunk tree. It's in the private-use range of the multicodec table, so it isn't
+/// registered, and other private users could mean something else by it. See the module docs.
+pub const CHAINING_VALUE_CODE: u64 = 0x3b_a000;
+
+// The size of a CID for a chaining value: single-byte varints for the version and the codec, a
+// 4-byte varint for the multihash code, a single-byte varint for the digest length, and the
+// digest. Every CID in a parent block has this size, since children are never roots.
+const CID_SIZE: usize = 2 + 4 + 1 + HASH_SIZE;
+
+fn cid(codec: u64, hash: &Hash, finalization: Finalization) -> Vec<u8> {
+    // CIDv1
+    let mut cid = vec![1, codec as u8];
+    // Only the root of a single-chunk tree is the BLAKE3 hash of its block.
+    let code = if codec == RAW_CODEC && finalization.is_root() {
+        BLAKE3_CODE as u64
+    } else {
+        CHAINING_VALUE_CODE
+    };
+    write_varint(code, &mut cid).unwrap();
+    cid.push(HASH_SIZE as u8);
+    cid.extend_from_slice(hash.as_bytes());
+    cid
+}
+
+/// The binary CIDv1 of the root block, for content of length `content_len`. When the content is
+/// a single chunk, that's a raw block named by an ordinary BLAKE3 multihash. Otherwise it's a
+/// DAG-CBOR block named by `CHAINING_VALUE_CODE`, since the root hash is a parent hash of its
+/// children and not a hash of the block bytes.
+pub fn root_cid(hash: &Hash, content_len: u64) -> Vec<u8> {
+    let codec = if content_len <= CHUNK_SIZE as u64 {
+        RAW_CODEC
+    } else {
+        DAG_CBOR_CODEC
+    };
+    cid(codec, hash, Root)
+}
+
+/// Read a combined encoding, verifying it, and write its tree to `output` as a CARv1 stream.
+///
+/// Blocks are written in pre-order, the same order as the encoding, so the root block comes
+/// first, and the CAR header lists it as the only root. If verification fails, the error comes
+/// after some blocks have already been written, and the caller should discard the output.
+pub fn write_car(mut encoded: impl Read, hash: &Hash, mut output: impl Write) -> io::Result<()> {
+    let mut header = [0; HEADER_SIZE];
+    encoded.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    write_car_header(&root_cid(hash, content_len), &mut output)?;
+    let mut exporter = Exporter {
+        encoded,
+        output,
+        chunk_buf: [0; CHUNK_SIZE],
+    };
+    exporter.export_subtree(hash, 0, content_len, Root)
+}
+
+// The header is the DAG-CBOR map {"roots": [root], "version": 1}, with a varint length prefix.
+fn write_car_header(root: &[u8], output: &mut impl Write) -> io::Result<()> {
+    let mut header = Vec::new();
+    header.push(0xa2); // a map of 2 entries
+    header.push(0x65); // a 5-byte string
+    header.extend_from_slice(b"roots");
+    header.push(0x81); // an array of 1 element
+    write_cbor_link(root, &mut header);
+    header.push(0x67); // a 7-byte string
+    header.extend_from_slice(b"version");
+    header.push(0x01); // the integer 1
+    write_varint(header.len() as u64, output)?;
+    output.write_all(&header)
+}
+
+// A link is CBOR tag 42 on a byte string with a leading zero byte and the binary CID.
+fn write_cbor_link(cid: &[u8], output: &mut Vec<u8>) {
+    output.extend_from_slice(&[0xd8, 42]);
+    output.push(0x58); // a byte string with a 1-byte length
+    output.push(cid.len() as u8 + 1);
+    output.push(0x00);
+    output.extend_from_slice(cid);
+}
+
+fn write_varint(mut n: u64, output: &mut impl Write) -> io::Result<()> {
+    let mut buf = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    output.write_all(&buf[..len])
+}
+
+struct Exporter<R, W> {
+    encoded: R,
+    output: W,
+    chunk_buf: [u8; CHUNK_SIZE],
+}
+
+impl<R: Read, W: Write> Exporter<R, W> {
+    fn write_block(&mut self, cid: &[u8], data: &[u8]) -> io::Result<()> {
+        write_varint((cid.len() + data.len()) as u64, &mut self.output)?;
+        self.output.write_all(cid)?;
+        self.output.write_all(data)
+    }
+
+    fn export_subtree(
+        &mut self,
+        expected: &Hash,
+        first_chunk: u64,
+        subtree_len: u64,
+        finalization: Finalization,
+    ) -> io::Result<()> {
+        if subtree_len <= CHUNK_SIZE as u64 {
+            let chunk_len = subtree_len as usize;
+            self.encoded.read_exact(&mut self.chunk_buf[..chunk_len])?;
+            let computed = crate::chunk_cv(
+                first_chunk,
+                &self.chunk_buf[..chunk_len],
+                finalization.is_root(),
+            );
+            // Hash implements constant time equality.
+            if &computed != expected {
+                return Err(Error::HashMismatch.into());
+            }
+            let chunk = self.chunk_buf;
+            let cid = cid(RAW_CODEC, expected, finalization);
+            return self.write_block(&cid, &chunk[..chunk_len]);
+        }
+        let mut parent = [0; PARENT_SIZE];
+        self.encoded.read_exact(&mut parent)?;
+        let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        let computed = crate::parent_cv(&left_child, &right_child, finalization.is_root());
+        // Hash implements constant time equality.
+        if &computed != expected {
+            return Err(Error::HashMismatch.into());
+        }
+        let left_len = encode::left_subtree_len(subtree_len);
+        let right_len = subtree_len - left_len;
+        let mut block = Vec::with_capacity(1 + 2 * (5 + CID_SIZE));
+        block.push(0x82); // an array of 2 elements
+        write_cbor_link(
+            &cid(child_codec(left_len), &left_child, NotRoot),
+            &mut block,
+        );
+        write_cbor_link(
+            &cid(child_codec(right_len), &right_child, NotRoot),
+            &mut block,
+        );
+        self.write_block(&cid(DAG_CBOR_CODEC, expected, finalization), &block)?;
+        let left_chunks = encode::count_chunks(left_len);
+        self.export_subtree(&left_child, first_chunk, left_len, NotRoot)?;
+        self.export_subtree(&right_child, first_chunk + left_chunks, right_len, NotRoot)
+    }
+}
+
+fn child_codec(subtree_len: u64) -> u64 {
+    if subtree_len <= CHUNK_SIZE as u64 {
+        RAW_CODEC
+    } else {
+        DAG_CBOR_CODEC
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use std::collections::HashMap;
+
+    fn read_varint(input: &mut &[u8]) -> u64 {
+        let mut n = 0;
+        let mut shift = 0;
+        loop {
+            let byte = input[0];
+            *input = &input[1..];
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return n;
+            }
+            shift += 7;
+        }
+    }
+
+    type Block = (Vec<u8>, Vec<u8>);
+
+    // Returns the header and the (CID, data) blocks.
+    fn parse_car(mut car: &[u8]) -> (Vec<u8>, Vec<Block>) {
+        let header_len = read_varint(&mut car) as usize;
+        let header = car[..header_len].to_vec();
+        car = &car[header_len..];
+        let mut blocks = Vec::new();
+        while !car.is_empty() {
+            let len = read_varint(&mut car) as usize;
+            let (cid, data) = car[..len].split_at(cid_len(&car[..len]));
+            blocks.push((cid.to_vec(), data.to_vec()));
+            car = &car[len..];
+        }
+        (header, blocks)
+    }
+
+    // Parse the CID at the front of a block, checking its multihash code against the codec.
+    fn cid_len(block: &[u8]) -> usize {
+        let mut rest = block;
+        assert_eq!(1, read_varint(&mut rest));
+        let codec = read_varint(&mut rest);
+        let code = read_varint(&mut rest);
+        assert_eq!(HASH_SIZE as u64, read_varint(&mut rest));
+        if code == BLAKE3_CODE as u64 {
+            assert_eq!(RAW_CODEC, codec);
+        } else {
+            assert_eq!(CHAINING_VALUE_CODE, code);
+        }
+        block.len() - rest.len() + HASH_SIZE
+    }
+
+    // Rebuild the content from the blocks, verifying the tree the way a Bao-aware consumer would.
+    fn rebuild(
+        blocks: &HashMap<Vec<u8>, Vec<u8>>,
+        cid: &[u8],
+        first_chunk: u64,
+        finalization: Finalization,
+        output: &mut Vec<u8>,
+    ) -> u64 {
+        let data = &blocks[cid];
+        let digest: Hash = (*array_ref!(cid, cid.len() - HASH_SIZE, HASH_SIZE)).into();
+        if cid[1] == RAW_CODEC as u8 {
+            let computed = crate::chunk_cv(first_chunk, data, finalization.is_root());
+            assert_eq!(digest, computed);
+            output.extend_from_slice(data);
+            return 1;
+        }
+        assert_eq!(DAG_CBOR_CODEC as u8, cid[1]);
+        assert_eq!(1 + 2 * (5 + CID_SIZE), data.len());
+        assert_eq!(0x82, data[0]);
+        let left = &data[1 + 5..][..CID_SIZE];
+        let right = &data[1 + 2 * 5 + CID_SIZE..][..CID_SIZE];
+        let left_hash: Hash = (*array_ref!(left, CID_SIZE - HASH_SIZE, HASH_SIZE)).into();
+        let right_hash: Hash = (*array_ref!(right, CID_SIZE - HASH_SIZE, HASH_SIZE)).into();
+        let computed = crate::parent_cv(&left_hash, &right_hash, finalization.is_root());
+        assert_eq!(digest, computed);
+        let left_chunks = rebuild(blocks, left, first_chunk, NotRoot, output);
+        let right_chunks = rebuild(blocks, right, first_chunk + left_chunks, NotRoot, output);
+        left_chunks + right_chunks
+    }
+
+    #[test]
+    fn test_write_car() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut car = Vec::new();
+            write_car(&*encoded, &hash, &mut car).unwrap();
+            let (header, blocks) = parse_car(&car);
+
+            let root = root_cid(&hash, case as u64);
+            let mut expected_header = vec![0xa2, 0x65];
+            expected_header.extend_from_slice(b"roots");
+            expected_header.extend_from_slice(&[0x81, 0xd8, 42, 0x58, root.len() as u8 + 1, 0x00]);
+            expected_header.extend_from_slice(&root);
+            expected_header.push(0x67);
+            expected_header.extend_from_slice(b"version");
+            expected_header.push(0x01);
+            assert_eq!(expected_header, header);
+
+            // One block per chunk and one per parent, with the root first.
+            let chunks = encode::count_chunks(case as u64);
+            assert_eq!(2 * chunks - 1, blocks.len() as u64);
+            assert_eq!(root, blocks[0].0);
+
+            let blocks: HashMap<Vec<u8>, Vec<u8>> = blocks.into_iter().collect();
+            let mut output = Vec::new();
+            rebuild(&blocks, &root, 0, Root, &mut output);
+            assert_eq!(input, output);
+        }
+    }
+
+    #[test]
+    fn test_single_chunk_cid_is_a_plain_blake3_cid() {
+        let input = b"hello world";
+        let (encoded, hash) = encode::encode(input);
+        let mut car = Vec::new();
+        write_car(&*encoded, &hash, &mut car).unwrap();
+        let (_, blocks) = parse_car(&car);
+        assert_eq!(1, blocks.len());
+        assert_eq!(&input[..], &blocks[0].1[..]);
+        let mut expected_cid = vec![1, 0x55, 0x1e, 32];
+        expected_cid.extend_from_slice(blake3::hash(input).as_bytes());
+        assert_eq!(expected_cid, blocks[0].0);
+    }
+
+    #[test]
+    fn test_chaining_values_dont_claim_blake3() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (encoded, hash) = encode::encode(&input);
+        let mut car = Vec::new();
+        write_car(&*encoded, &hash, &mut car).unwrap();
+        let (_, blocks) = parse_car(&car);
+        assert_eq!(5, blocks.len());
+        for (cid, data) in &blocks {
+            assert_eq!(CID_SIZE, cid.len());
+            assert_eq!([0x80, 0xc0, 0xee, 0x01], cid[2..6]);
+            // None of these digests is the BLAKE3 hash of the block.
+            assert_ne!(blake3::hash(data).as_bytes(), &cid[CID_SIZE - HASH_SIZE..]);
+        }
+    }
+
+    #[test]
+    fn test_corruption() {
+        let input = make_test_input(5 * CHUNK_SIZE);
+        let (encoded, hash) = encode::encode(&input);
+        for &i in &[HEADER_SIZE, HEADER_SIZE + PARENT_SIZE, encoded.len() - 1] {
+            let mut bad = encoded.clone();
+            bad[i] ^= 1;
+            let err = write_car(&*bad, &hash, io::sink()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+        let err = write_car(&encoded[..encoded.len() - 1], &hash, io::sink()).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_varint() {
+        for &(n, expected) in &[
+            (0, &[0x00][..]),
+            (1, &[0x01][..]),
+            (127, &[0x7f][..]),
+            (128, &[0x80, 0x01][..]),
+            (300, &[0xac, 0x02][..]),
+        ] {
+            let mut out = Vec::new();
+            write_varint(n, &mut out).unwrap();
+            assert_eq!(expected, &out[..]);
+            assert_eq!(n, read_varint(&mut &out[..]));
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Map between content offsets and offsets in the combined encoding.
+//!
+//! A combined encoding interleaves parent nodes with the content chunks, in pre-order. Callers
+//! that present an encoded file as its plain content, like a FUSE filesystem, need to know where
+//! each content byte lives in the encoding. `TreeLayout` answers those questions without reading
+//! the encoding itself.
+//!
+//! # Example
+//!
+//! ```
+//! use bao::layout::TreeLayout;
+//!
+//! let input = vec![0xab; 3000];
+//! let (encoded, _) = bao::encode::encode(&input);
+//! let layout = TreeLayout::new(input.len() as u64);
+//! assert_eq!(encoded.len() as u128, layout.encoded_len());
+//!
+//! // Chunks are interleaved with parent nodes, so the second chunk starts after the header, two
+//! // parent nodes, and the first chunk.
+//! let offset = layout.content_to_encoded_offset(1024);
+//! assert_eq!(8 + 2 * 64 + 1024, offset);
+//! assert_eq!(1024, layout.encoded_to_content_offset(offset));
+//!
+//! for chunk in layout.chunks() {
+//!     let start = chunk.encoded_offset as usize;
+//!     let content_start = chunk.content_offset as usize;
+//!     assert_eq!(
+//!         &input[content_start..][..chunk.len],
+//!         &encoded[start..][..chunk.len],
+//!     );
+//! }
+//! ```
+
+use crate::encode;
+use crate::{CHUNK_SIZE, PARENT_SIZE};
+use std::cmp;
+
+/// The layout of the combined encoding of `content_len` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TreeLayout {
+    content_len: u64,
+}
+
+/// The position of one chunk in a `TreeLayout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChunkLayout {
+    /// The chunk's index, counting from 0.
+    pub index: u64,
+    /// The offset of the chunk's first byte in the content.
+    pub content_offset: u64,
+    /// The offset of the chunk's first byte in the combined encoding, after the parent nodes that
+    /// precede it.
+    pub encoded_offset: u128,
+    /// The length of the chunk. Every chunk is 1024 bytes, except that the final chunk may be
+    /// shorter. Empty content has a single empty chunk.
+    pub len: usize,
+}
+
+impl TreeLayout {
+    pub fn new(content_len: u64) -> Self {
+        Self { content_len }
+    }
+
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// The size of the whole combined encoding, the same as `encode::encoded_size`.
+    pub fn encoded_len(&self) -> u128 {
+        encode::encoded_size(self.content_len)
+    }
+
+    /// The number of chunks. This is at least 1, even for empty content.
+    pub fn chunk_count(&self) -> u64 {
+        encode::count_chunks(self.content_len)
+    }
+
+    /// The layout of the chunk at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` isn't less than `chunk_count()`.
+    pub fn chunk(&self, index: u64) -> ChunkLayout {
+        assert!(index < self.chunk_count(), "chunk index out of range");
+        ChunkLayout {
+            index,
+            content_offset: index * CHUNK_SIZE as u64,
+            encoded_offset: encode::chunk_encoded_offset(index, self.content_len),
+            len: encode::chunk_size(index, self.content_len),
+        }
+    }
+
+    /// Iterate over all the chunks in order.
+    pub fn chunks(&self) -> Chunks {
+        Chunks {
+            content_len: self.content_len,
+            next_index: 0,
+            end_index: self.chunk_count(),
+            next_encoded_offset: encode::chunk_encoded_offset(0, self.content_len),
+        }
+    }
+
+    /// The position in the encoding of the content byte at `content_offset`. The end of the
+    /// content maps to the end of the encoding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `content_offset` is greater than `content_len()`.
+    pub fn content_to_encoded_offset(&self, content_offset: u64) -> u128 {
+        assert!(content_offset <= self.content_len, "offset past the end");
+        if content_offset == self.content_len {
+            return self.encoded_len();
+        }
+        let index = content_offset / CHUNK_SIZE as u64;
+        let within_chunk = content_offset % CHUNK_SIZE as u64;
+        encode::chunk_encoded_offset(index, self.content_len) + within_chunk as u128
+    }
+
+    /// The content offset of the first content byte at or after `encoded_offset`. Offsets inside
+    /// a chunk map to the corresponding content byte, and offsets inside the header or a parent
+    /// node map to the start of the chunk that follows. Offsets at or past the end of the
+    /// encoding map to `content_len()`.
+    pub fn encoded_to_content_offset(&self, encoded_offset: u128) -> u64 {
+        if encoded_offset >= self.encoded_len() {
+            return self.content_len;
+        }
+        // Find the last chunk that starts at or before the target, or chunk 0 if the target is in
+        // the header or the parent nodes before it.
+        let mut low = 0;
+        let mut high = self.chunk_count();
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if encode::chunk_encoded_offset(mid, self.content_len) <= encoded_offset {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        let chunk = self.chunk(low);
+        if chunk.encoded_offset > encoded_offset {
+            return 0;
+        }
+        let distance = encoded_offset - chunk.encoded_offset;
+        if distance < chunk.len as u128 {
+            chunk.content_offset + distance as u64
+        } else {
+            cmp::min(chunk.content_offset + CHUNK_SIZE as u64, self.content_len)
+        }
+    }
+}
+
+/// An iterator over the chunks in a `TreeLayout`, returned by `TreeLayout::chunks`.
+#[derive(Clone, Debug)]
+pub struct Chunks {
+    content_len: u64,
+    next_index: u64,
+    end_index: u64,
+    next_encoded_offset: u128,
+}
+
+impl Iterator for Chunks {
+    type Item = ChunkLayout;
+
+    fn next(&mut self) -> Option<ChunkLayout> {
+        if self.next_index == self.end_index {
+            return None;
+        }
+        let chunk = ChunkLayout {
+            index: self.next_index,
+            content_offset: self.next_index * CHUNK_SIZE as u64,
+            encoded_offset: self.next_encoded_offset,
+            len: encode::chunk_size(self.next_index, self.content_len),
+        };
+        self.next_index += 1;
+        if self.next_index < self.end_index {
+            let parents = encode::pre_order_parent_nodes(self.next_index, self.content_len);
+            self.next_encoded_offset += chunk.len as u128 + parents as u128 * PARENT_SIZE as u128;
+        }
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.end_index - self.next_index;
+        if remaining > usize::MAX as u64 {
+            (usize::MAX, None)
+        } else {
+            (remaining as usize, Some(remaining as usize))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chunks() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let layout = TreeLayout::new(case as u64);
+            let chunks: Vec<ChunkLayout> = layout.chunks().collect();
+            assert_eq!(layout.chunk_count(), chunks.len() as u64);
+            for chunk in &chunks {
+                assert_eq!(layout.chunk(chunk.index), *chunk);
+            }
+            let last = chunks.last().unwrap();
+            assert_eq!(layout.encoded_len(), last.encoded_offset + last.len as u128);
+            assert_eq!(layout.content_len(), last.content_offset + last.len as u64);
+        }
+    }
+
+    #[test]
+    fn test_offset_translation() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let layout = TreeLayout::new(case as u64);
+            let mut encoded_offset = 0;
+            for chunk in layout.chunks() {
+                // The header and parent nodes before a chunk map to the start of the chunk.
+                while encoded_offset < chunk.encoded_offset {
+                    let content_offset = layout.encoded_to_content_offset(encoded_offset);
+                    assert_eq!(chunk.content_offset, content_offset);
+                    encoded_offset += 1;
+                }
+                // Chunk bytes map one-to-one in both directions.
+                for i in 0..chunk.len as u64 {
+                    let content_offset = chunk.content_offset + i;
+                    assert_eq!(
+                        encoded_offset,
+                        layout.content_to_encoded_offset(content_offset)
+                    );
+                    assert_eq!(
+                        content_offset,
+                        layout.encoded_to_content_offset(encoded_offset)
+                    );
+                    encoded_offset += 1;
+                }
+            }
+            let end = layout.encoded_len();
+            assert_eq!(end, encoded_offset);
+            assert_eq!(case as u64, layout.encoded_to_content_offset(end));
+            assert_eq!(case as u64, layout.encoded_to_content_offset(end + 1000));
+            assert_eq!(end, layout.content_to_encoded_offset(case as u64));
+        }
+    }
+
+    #[test]
+    fn test_content_bytes_match_encoding() {
+        let input = crate::decode::make_test_input(10 * CHUNK_SIZE + 7);
+        let (encoded, _) = encode::encode(&input);
+        let layout = TreeLayout::new(input.len() as u64);
+        for (offset, &byte) in input.iter().enumerate() {
+            let encoded_offset = layout.content_to_encoded_offset(offset as u64);
+            assert_eq!(byte, encoded[encoded_offset as usize]);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let layout = TreeLayout::new(5000);
+        let json = serde_json::to_string(&layout).unwrap();
+        assert_eq!(layout, serde_json::from_str::<TreeLayout>(&json).unwrap());
+        let chunks: Vec<ChunkLayout> = layout.chunks().collect();
+        let json = serde_json::to_string(&chunks).unwrap();
+        assert_eq!(
+            chunks,
+            serde_json::from_str::<Vec<ChunkLayout>>(&json).unwrap()
+        );
+    }
+}
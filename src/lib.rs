@@ -37,33 +37,134 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! # Tracing
+//!
+//! With the `tracing` feature, the `Encoder` and the `Decoder`, along with the functions and
+//! types built on them, report what they're doing through the
+//! [`tracing`](https://docs.rs/tracing) crate, for profiling in production. Install any
+//! subscriber to collect it. Without the feature, none of this is compiled in.
+//!
+//! - `chunk hashed`, at `TRACE`, with `index` and `len`, for every chunk the encoder adds to the
+//!   tree, including chunks whose hashes came from a `hash::ChunkHashCache`.
+//! - `parent merged`, at `TRACE`, with `content_end`, the end of the parent's subtree in the
+//!   content, for every parent node the encoder merges.
+//! - `chunk verified`, at `TRACE`, with `index`, for every chunk a decoder verifies.
+//! - `seek performed`, at `DEBUG`, with `from` and `to` content positions, for every `Decoder`
+//!   seek.
+//! - `verification failed`, at `WARN`, for every parent node or chunk that doesn't match its
+//!   hash. `encoded_offset` is the offset of the node in the combined encoding, and
+//!   `content_offset` is the content position it covers. For an outboard encoding, the offset of
+//!   the node is `encoded_offset - content_offset`.
+//! - The `encode.flip` span, at `DEBUG`, covers the flip at the end of `Encoder::finalize`, and
+//!   the `decode.seek` span covers each `Decoder` seek.
 
 #![forbid(unsafe_code)]
 
+// Emit a tracing event, at the level given first, with the fields and message that follow. This
+// compiles to nothing without the tracing feature.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($arg)+);
+    };
+}
+
+// Enter a tracing span, until the end of the enclosing block.
+macro_rules! trace_span {
+    ($name:expr) => {
+        #[cfg(feature = "tracing")]
+        let _span = ::tracing::debug_span!($name).entered();
+    };
+}
+
+pub mod backend;
+pub mod batch;
+pub mod cdc;
+pub mod challenge;
+pub mod compress;
+pub mod config;
+pub mod container;
+#[cfg(feature = "crypto")]
+pub mod crypto;
 pub mod decode;
+pub mod diff;
 pub mod encode;
+#[cfg(feature = "erasure")]
+pub mod erasure;
+#[cfg(feature = "fuse")]
+pub mod file;
+pub mod group;
+pub mod hash;
+pub mod header;
+#[cfg(feature = "ipld")]
+pub mod ipld;
+pub mod layout;
+pub mod manifest;
+#[cfg(feature = "multihash")]
+pub mod multihash;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(feature = "sparse")]
+pub mod sparse;
+pub mod supertree;
+pub mod test_vectors;
+pub mod transform;
+pub mod update;
+pub mod verified;
 
+/// The root hash of an encoding, re-exported from the `blake3` crate.
+///
+/// `Hash` implements constant-time equality, both with other hashes and with raw `[u8; 32]`
+/// arrays, so comparing a hash supplied by an attacker against an expected value doesn't leak
+/// timing information. Prefer comparing `Hash` values directly over comparing the output of
+/// `as_bytes`. Use `to_hex` and `from_hex` (or `FromStr`) to convert to and from the hex strings
+/// printed by `bao hash`. With the `serde` feature enabled, `Hash` also implements `Serialize`
+/// and `Deserialize`.
+///
+/// Because `Hash` is defined in `blake3`, this crate can't implement `AsRef<[u8; 32]>` for it.
+/// Use `as_bytes` to borrow the bytes as a `&[u8; 32]` instead, and `From` to convert between
+/// `Hash` and an owned `[u8; 32]` in either direction.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (_, hash) = bao::encode::encode(b"foo");
+/// let hex = hash.to_hex();
+/// let parsed: bao::Hash = hex.parse()?;
+/// assert_eq!(hash, parsed);
+/// assert_eq!(hash, bao::Hash::from_hex(hex.as_bytes())?);
+/// # Ok(())
+/// # }
+/// ```
 pub use blake3::Hash;
 
-use std::mem;
+use std::fmt;
+use std::sync::Arc;
 
 /// The size of a `Hash`, 32 bytes.
 pub const HASH_SIZE: usize = 32;
-pub(crate) const PARENT_SIZE: usize = 2 * HASH_SIZE;
-pub(crate) const HEADER_SIZE: usize = 8;
-pub(crate) const CHUNK_SIZE: usize = 1024;
-pub(crate) const MAX_DEPTH: usize = 54; // 2^54 * CHUNK_SIZE = 2^64
+/// The size of a parent node, two `Hash`es, 64 bytes.
+pub const PARENT_SIZE: usize = 2 * HASH_SIZE;
+/// The size of the length header at the start of an encoding, 8 bytes. See the `header` module.
+pub const HEADER_SIZE: usize = 8;
+/// The size of a chunk, 1024 bytes. Every chunk but the last is exactly this size.
+pub const CHUNK_SIZE: usize = 1024;
+/// The maximum depth of the tree, and so the maximum number of parent nodes on the path from the
+/// root to any chunk. 2<sup>54</sup> chunks of 1024 bytes make up the maximum content length of
+/// 2<sup>64</sup> bytes.
+pub const MAX_DEPTH: usize = 54;
 
 /// An array of `HASH_SIZE` bytes. This will be a wrapper type in a future version.
 pub(crate) type ParentNode = [u8; 2 * HASH_SIZE];
 
 pub(crate) fn encode_len(len: u64) -> [u8; HEADER_SIZE] {
-    debug_assert_eq!(mem::size_of_val(&len), HEADER_SIZE);
-    len.to_le_bytes()
+    header::encode(len)
 }
 
 pub(crate) fn decode_len(bytes: &[u8; HEADER_SIZE]) -> u64 {
-    u64::from_le_bytes(*bytes)
+    header::parse(bytes)
 }
 
 // The root node is hashed differently from interior nodes. It gets suffixed
@@ -85,6 +186,66 @@ impl Finalization {
     }
 }
 
+// The non-root chaining value of the empty chunk. The hazmat module refuses to hash an empty
+// subtree, but the empty chunk still has a chaining value, which the manifest of empty content
+// records.
+const EMPTY_CHUNK_CV: [u8; HASH_SIZE] = [
+    0xb4, 0x68, 0x6b, 0x3c, 0x8d, 0x95, 0x3f, 0x4d, 0x18, 0x5d, 0x51, 0xbc, 0x9c, 0xd7, 0xbc, 0xe6,
+    0xd9, 0x78, 0x2d, 0x76, 0x59, 0xf8, 0xc0, 0x60, 0x68, 0xd4, 0xc3, 0xff, 0xa6, 0xe5, 0x68, 0x41,
+];
+
+// The standard chaining value of the chunk at `chunk_index`, through `blake3::hazmat`. The root
+// chunk is always chunk 0, and its hash is an ordinary BLAKE3 hash. Other profiles go through
+// `config::Config` instead.
+pub(crate) fn chunk_cv(chunk_index: u64, chunk: &[u8], is_root: bool) -> Hash {
+    use blake3::hazmat::HasherExt;
+    if is_root {
+        debug_assert_eq!(0, chunk_index);
+        return blake3::hash(chunk);
+    }
+    if chunk.is_empty() {
+        return EMPTY_CHUNK_CV.into();
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher.set_input_offset(chunk_index * CHUNK_SIZE as u64);
+    hasher.update(chunk);
+    hasher.finalize_non_root().into()
+}
+
+// The standard chaining value of a parent node, through `blake3::hazmat`.
+pub(crate) fn parent_cv(left: &Hash, right: &Hash, is_root: bool) -> Hash {
+    use blake3::hazmat::{self, Mode};
+    let (left, right) = (left.as_bytes(), right.as_bytes());
+    if is_root {
+        hazmat::merge_subtrees_root(left, right, Mode::Hash)
+    } else {
+        hazmat::merge_subtrees_non_root(left, right, Mode::Hash).into()
+    }
+}
+
+// A progress callback shared by the Encoder and the Decoder. It gets called with a content
+// position after each chunk is hashed or verified.
+#[derive(Clone)]
+pub(crate) struct Progress(Arc<dyn Fn(u64) + Send + Sync>);
+
+impl Progress {
+    fn new(callback: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    fn report(this: &Option<Self>, position: u64) {
+        if let Some(progress) = this {
+            (progress.0)(position);
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Progress")
+    }
+}
+
 #[doc(hidden)]
 pub mod benchmarks {
     pub const CHUNK_SIZE: usize = super::CHUNK_SIZE;
@@ -118,4 +279,169 @@ pub(crate) mod test {
         16 * CHUNK_SIZE,
         16 * CHUNK_SIZE + 1,
     ];
+
+    // The core encoding tests run on every length near a chunk boundary, up to this many chunks,
+    // from `test_vectors::test_cases`.
+    pub const MAX_TEST_CHUNKS: usize = 16;
+
+    #[test]
+    fn test_hash_hex_round_trip() {
+        let hash = blake3::hash(b"foo");
+        let hex = hash.to_hex();
+        assert_eq!(2 * HASH_SIZE, hex.len());
+        assert_eq!(hash, Hash::from_hex(hex.as_str()).unwrap());
+        assert_eq!(hash, hex.parse::<Hash>().unwrap());
+        assert_eq!(hash, *hash.as_bytes());
+        assert!(Hash::from_hex(&hex[1..]).is_err());
+        assert!("not hex".parse::<Hash>().is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_empty_chunk_cv() {
+        let expected = blake3::guts::ChunkState::new(0).finalize(false);
+        assert_eq!(expected, chunk_cv(0, &[], false));
+        assert_eq!(blake3::hash(b""), chunk_cv(0, &[], true));
+    }
+
+    #[test]
+    fn test_hash_bytes_conversions() {
+        let hash = blake3::hash(b"foo");
+        let borrowed: &[u8; HASH_SIZE] = hash.as_bytes();
+        let owned: [u8; HASH_SIZE] = hash.into();
+        assert_eq!(borrowed, &owned);
+        assert_eq!(hash, Hash::from(owned));
+        assert_eq!(hash, owned[..]);
+    }
+
+    // Records the events and the names of the spans from a thread, as strings like
+    // "chunk hashed index=0 len=1024".
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Recorder {
+        events: std::sync::Mutex<Vec<String>>,
+        spans: std::sync::Mutex<Vec<&'static str>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span.metadata().name());
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut message = String::new();
+            let mut fields = String::new();
+            event.record(
+                &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                    if field.name() == "message" {
+                        message = format!("{:?}", value);
+                    } else {
+                        fields += &format!(" {}={:?}", field.name(), value);
+                    }
+                },
+            );
+            self.events.lock().unwrap().push(message + &fields);
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use crate::decode::make_test_input;
+        use std::io::prelude::*;
+        use std::io::{Cursor, SeekFrom};
+        use std::sync::Arc;
+
+        // A thread-local subscriber doesn't see the other tests running in parallel.
+        let recorder = Arc::new(Recorder::default());
+        let _guard = tracing::subscriber::set_default(Arc::clone(&recorder));
+        let take = || std::mem::take(&mut *recorder.events.lock().unwrap());
+
+        let input = make_test_input(4 * CHUNK_SIZE + 1);
+        let mut encoded = Vec::new();
+        let mut encoder = encode::Encoder::new(Cursor::new(&mut encoded));
+        encoder.write_all(&input).unwrap();
+        let hash = encoder.finalize().unwrap();
+        let events = take();
+        let chunks: Vec<&String> = events
+            .iter()
+            .filter(|e| e.starts_with("chunk hashed"))
+            .collect();
+        assert_eq!(
+            vec![
+                "chunk hashed index=0 len=1024",
+                "chunk hashed index=1 len=1024",
+                "chunk hashed index=2 len=1024",
+                "chunk hashed index=3 len=1024",
+                "chunk hashed index=4 len=1",
+            ],
+            chunks
+        );
+        let parents = events
+            .iter()
+            .filter(|e| e.starts_with("parent merged"))
+            .count();
+        assert_eq!(4, parents);
+        assert!(events.contains(&"parent merged content_end=2048".to_string()));
+        assert!(recorder.spans.lock().unwrap().contains(&"encode.flip"));
+
+        let mut decoder = decode::Decoder::new(Cursor::new(&encoded), &hash);
+        decoder
+            .seek(SeekFrom::Start(2 * CHUNK_SIZE as u64))
+            .unwrap();
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        let events = take();
+        assert!(events.contains(&"seek performed from=0 to=2048".to_string()));
+        assert!(recorder.spans.lock().unwrap().contains(&"decode.seek"));
+        let verified: Vec<&String> = events
+            .iter()
+            .filter(|e| e.starts_with("chunk verified"))
+            .collect();
+        assert_eq!(
+            vec![
+                "chunk verified index=2",
+                "chunk verified index=3",
+                "chunk verified index=4",
+            ],
+            verified
+        );
+
+        // Corrupt the first chunk, which comes after the header and two parent nodes.
+        let chunk_0 = HEADER_SIZE + 2 * PARENT_SIZE;
+        encoded[chunk_0] ^= 1;
+        assert!(decode::decode(&encoded, &hash).is_err());
+        let failure = format!(
+            "verification failed encoded_offset={} content_offset=0",
+            chunk_0
+        );
+        assert!(take().contains(&failure));
+
+        // Empty content has a single empty chunk.
+        encode::encode(b"");
+        assert!(take().contains(&"chunk hashed index=0 len=0".to_string()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_hash_serde() {
+        let hash = blake3::hash(b"foo");
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(hash, serde_json::from_str::<Hash>(&json).unwrap());
+    }
 }
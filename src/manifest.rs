@@ -0,0 +1,257 @@
+//! A catalog of the chunks in an encoding, built while encoding.
+//!
+//! Deduplication and sync tools want to know the hash of every chunk, and where each chunk sits
+//! in the content and in the encoding. The `Encoder` computes those hashes anyway, and with
+//! `Encoder::enable_manifest` it keeps them, so callers don't have to make a second pass over
+//! the content. The chunk hashes are the non-root chaining values, the same ones that
+//! `hash::hash_many_chunks` and `ChunkHashCache` use, so they depend on the position of the
+//! chunk as well as its bytes.
+//!
+//! A manifest keeps 32 bytes per chunk in memory, about 3% of the content length. The compact
+//! serialized form from `to_bytes` is the same thing: the 8-byte length header, followed by the
+//! chunk hashes in order. The offsets aren't stored, because they follow from the content length.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use bao::manifest::Manifest;
+//! use std::io::prelude::*;
+//!
+//! let input = vec![0xab; 3000];
+//! let mut encoder = bao::encode::Encoder::new(std::io::Cursor::new(Vec::new()));
+//! encoder.enable_manifest();
+//! encoder.write_all(&input)?;
+//! encoder.finalize()?;
+//! let manifest = encoder.take_manifest().unwrap();
+//! let encoded = encoder.into_inner().into_inner();
+//!
+//! assert_eq!(3, manifest.len());
+//! for entry in manifest.entries() {
+//!     let content = &input[entry.content_offset as usize..][..entry.len];
+//!     let start = entry.encoded_offset as usize;
+//!     assert_eq!(content, &encoded[start..][..entry.len]);
+//! }
+//!
+//! let bytes = manifest.to_bytes();
+//! assert_eq!(8 + 3 * 32, bytes.len());
+//! assert_eq!(manifest, Manifest::from_bytes(&bytes)?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::encode;
+use crate::layout::TreeLayout;
+use crate::{Hash, HASH_SIZE, HEADER_SIZE};
+use arrayref::array_ref;
+use std::fmt;
+use std::io;
+
+/// The chunk hashes of an encoding. See the module docs.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Manifest {
+    content_len: u64,
+    hashes: Vec<Hash>,
+}
+
+/// One chunk in a `Manifest`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ManifestEntry {
+    /// The chunk's index, counting from 0.
+    pub index: u64,
+    /// The chunk's non-root chaining value.
+    pub hash: Hash,
+    /// The offset of the chunk in the content.
+    pub content_offset: u64,
+    /// The offset of the chunk in the combined encoding. For an outboard encoding, the chunk is
+    /// at `content_offset` in the content instead.
+    pub encoded_offset: u128,
+    /// The length of the chunk, 1024 bytes except for the final chunk.
+    pub len: usize,
+}
+
+impl Manifest {
+    pub(crate) fn new() -> Self {
+        Self {
+            content_len: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, hash: Hash, len: usize) {
+        self.hashes.push(hash);
+        self.content_len += len as u64;
+    }
+
+    /// The content length.
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// The number of chunks. Empty content has a single empty chunk.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Returns `true` if there are no chunks, which only happens for a manifest taken from an
+    /// `Encoder` before any chunk was hashed.
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// The chunk at `index`, or `None` if it's past the end.
+    pub fn get(&self, index: u64) -> Option<ManifestEntry> {
+        let hash = *self.hashes.get(index as usize)?;
+        let chunk = TreeLayout::new(self.content_len).chunk(index);
+        Some(ManifestEntry {
+            index,
+            hash,
+            content_offset: chunk.content_offset,
+            encoded_offset: chunk.encoded_offset,
+            len: chunk.len,
+        })
+    }
+
+    /// All the chunks, in order.
+    pub fn entries(&self) -> impl Iterator<Item = ManifestEntry> + '_ {
+        (0..self.len() as u64).map(move |index| self.get(index).unwrap())
+    }
+
+    /// Serialize the manifest: the length header, then the chunk hashes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + HASH_SIZE * self.hashes.len());
+        bytes.extend_from_slice(&crate::encode_len(self.content_len));
+        for hash in &self.hashes {
+            bytes.extend_from_slice(hash.as_bytes());
+        }
+        bytes
+    }
+
+    /// Parse a manifest from `to_bytes`. The number of hashes has to match the content length,
+    /// or else this is an `InvalidData` error. Nothing here verifies the hashes themselves.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid manifest");
+        if bytes.len() < HEADER_SIZE {
+            return Err(invalid());
+        }
+        let content_len = crate::decode_len(array_ref!(bytes, 0, HEADER_SIZE));
+        let hash_bytes = &bytes[HEADER_SIZE..];
+        let chunks = encode::count_chunks(content_len) as u128;
+        if hash_bytes.len() as u128 != chunks * HASH_SIZE as u128 {
+            return Err(invalid());
+        }
+        let hashes = hash_bytes
+            .chunks_exact(HASH_SIZE)
+            .map(|hash| Hash::from(*array_ref!(hash, 0, HASH_SIZE)))
+            .collect();
+        Ok(Self {
+            content_len,
+            hashes,
+        })
+    }
+}
+
+impl fmt::Debug for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        write!(
+            f,
+            "Manifest {{ content_len: {}, len: {} }}",
+            self.content_len,
+            self.hashes.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use crate::CHUNK_SIZE;
+    use std::io::prelude::*;
+
+    fn encode_with_manifest(input: &[u8], outboard: bool) -> (Vec<u8>, Manifest) {
+        let output = io::Cursor::new(Vec::new());
+        let mut encoder = if outboard {
+            encode::Encoder::new_outboard(output)
+        } else {
+            encode::Encoder::new(output)
+        };
+        encoder.enable_manifest();
+        encoder.write_all(input).unwrap();
+        encoder.finalize().unwrap();
+        let manifest = encoder.take_manifest().unwrap();
+        (encoder.into_inner().into_inner(), manifest)
+    }
+
+    #[test]
+    fn test_manifest() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, manifest) = encode_with_manifest(&input, false);
+            assert_eq!(case as u64, manifest.content_len());
+            assert_eq!(encode::count_chunks(case as u64), manifest.len() as u64);
+            let chunks: Vec<&[u8]> = if input.is_empty() {
+                vec![&[]]
+            } else {
+                input.chunks(CHUNK_SIZE).collect()
+            };
+            let expected_hashes = crate::hash::hash_many_chunks(&chunks, 0);
+            for (entry, expected_hash) in manifest.entries().zip(expected_hashes) {
+                assert_eq!(expected_hash, entry.hash);
+                let content = &input[entry.content_offset as usize..][..entry.len];
+                assert_eq!(
+                    content,
+                    &encoded[entry.encoded_offset as usize..][..entry.len]
+                );
+            }
+            assert_eq!(None, manifest.get(manifest.len() as u64));
+
+            // Outboard encoding gives the same manifest.
+            let (_, outboard_manifest) = encode_with_manifest(&input, true);
+            assert_eq!(manifest, outboard_manifest);
+
+            let bytes = manifest.to_bytes();
+            assert_eq!(manifest, Manifest::from_bytes(&bytes).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_from_bytes_errors() {
+        let (_, manifest) = encode_with_manifest(&make_test_input(3 * CHUNK_SIZE), false);
+        let bytes = manifest.to_bytes();
+        for bad in &[
+            &bytes[..4],
+            &bytes[..bytes.len() - 1],
+            &bytes[..bytes.len() - 32],
+        ] {
+            let err = Manifest::from_bytes(bad).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+        let mut extra = bytes.clone();
+        extra.extend_from_slice(&[0; 32]);
+        assert!(Manifest::from_bytes(&extra).is_err());
+    }
+
+    #[test]
+    fn test_manifest_with_chunk_cache() {
+        let input = make_test_input(5 * CHUNK_SIZE + 1);
+        let (_, expected) = encode_with_manifest(&input, false);
+        let mut encoder = encode::Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.set_chunk_cache(crate::hash::ChunkHashCache::new());
+        encoder.write_all(&input).unwrap();
+        encoder.finalize().unwrap();
+        let cache = encoder.take_chunk_cache().unwrap();
+
+        // Cache hits still show up in the manifest.
+        let mut encoder = encode::Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.set_chunk_cache(cache);
+        encoder.enable_manifest();
+        encoder.write_all(&input).unwrap();
+        encoder.finalize().unwrap();
+        assert_eq!(expected, encoder.take_manifest().unwrap());
+    }
+}
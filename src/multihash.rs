@@ -0,0 +1,339 @@
+//! Multihash and multibase representations of root hashes.
+//!
+//! This module is behind the `multihash` feature. A [multihash](https://multiformats.io/multihash/)
+//! is a self-describing hash: a varint code for the algorithm (`0x1e` for BLAKE3), a varint
+//! digest length, and the digest. A [multibase](https://github.com/multiformats/multibase)
+//! string is a one-character prefix naming an encoding, followed by bytes in that encoding. Put
+//! together, they embed root hashes in CIDs and other self-describing identifiers. Since `Hash`
+//! comes from the `blake3` crate, the conversions are free functions rather than methods.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use bao::multihash::{self, Base};
+//!
+//! let (_, hash) = bao::encode::encode(b"foo");
+//! let bytes = multihash::to_multihash(&hash);
+//! assert_eq!([0x1e, 0x20], bytes[..2]);
+//! assert_eq!(hash, multihash::from_multihash(&bytes)?);
+//!
+//! let s = multihash::to_multibase(&hash, Base::Base58Btc);
+//! assert!(s.starts_with('z'));
+//! assert_eq!(hash, multihash::from_multibase(&s)?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{Hash, HASH_SIZE};
+use std::error;
+use std::fmt;
+
+/// The multihash code for BLAKE3.
+pub const BLAKE3_CODE: u8 = 0x1e;
+
+/// The size of a BLAKE3 multihash. The code and the length both fit in a single varint byte.
+pub const MULTIHASH_SIZE: usize = 2 + HASH_SIZE;
+
+/// Errors from parsing multihashes and multibase strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The multihash isn't BLAKE3.
+    UnsupportedCode,
+    /// The digest isn't 32 bytes, or the input is the wrong length for one.
+    WrongLength,
+    /// The multibase prefix isn't one of the supported bases.
+    UnsupportedBase,
+    /// The string contains a character that isn't valid in its base.
+    InvalidCharacter,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnsupportedCode => write!(f, "multihash code is not BLAKE3"),
+            Error::WrongLength => write!(f, "wrong multihash length"),
+            Error::UnsupportedBase => write!(f, "unsupported multibase prefix"),
+            Error::InvalidCharacter => write!(f, "invalid multibase character"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// The multibase encodings supported here. These are the ones CIDs commonly use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Base {
+    /// Lowercase hex, prefix `f`.
+    Base16,
+    /// Lowercase RFC 4648 base32 without padding, prefix `b`. This is the default for CIDv1.
+    Base32,
+    /// Bitcoin's base58 alphabet, prefix `z`.
+    Base58Btc,
+    /// URL-safe RFC 4648 base64 without padding, prefix `u`.
+    Base64Url,
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BASE64URL_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+impl Base {
+    /// The multibase prefix character.
+    pub fn prefix(&self) -> char {
+        match self {
+            Base::Base16 => 'f',
+            Base::Base32 => 'b',
+            Base::Base58Btc => 'z',
+            Base::Base64Url => 'u',
+        }
+    }
+
+    fn from_prefix(prefix: char) -> Result<Self, Error> {
+        match prefix {
+            'f' => Ok(Base::Base16),
+            'b' => Ok(Base::Base32),
+            'z' => Ok(Base::Base58Btc),
+            'u' => Ok(Base::Base64Url),
+            _ => Err(Error::UnsupportedBase),
+        }
+    }
+
+    fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            Base::Base16 => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            Base::Base32 => encode_bits(bytes, 5, BASE32_ALPHABET),
+            Base::Base58Btc => encode_base58(bytes),
+            Base::Base64Url => encode_bits(bytes, 6, BASE64URL_ALPHABET),
+        }
+    }
+
+    fn decode(&self, s: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Base::Base16 => {
+                if s.len() % 2 == 1 {
+                    return Err(Error::WrongLength);
+                }
+                s.as_bytes()
+                    .chunks(2)
+                    .map(|pair| {
+                        let hi = hex_value(pair[0])?;
+                        let lo = hex_value(pair[1])?;
+                        Ok(hi << 4 | lo)
+                    })
+                    .collect()
+            }
+            Base::Base32 => decode_bits(s, 5, BASE32_ALPHABET),
+            Base::Base58Btc => decode_base58(s),
+            Base::Base64Url => decode_bits(s, 6, BASE64URL_ALPHABET),
+        }
+    }
+}
+
+/// The multihash of a root hash.
+pub fn to_multihash(hash: &Hash) -> [u8; MULTIHASH_SIZE] {
+    let mut multihash = [0; MULTIHASH_SIZE];
+    multihash[0] = BLAKE3_CODE;
+    multihash[1] = HASH_SIZE as u8;
+    multihash[2..].copy_from_slice(hash.as_bytes());
+    multihash
+}
+
+/// Parse a BLAKE3 multihash with a 32-byte digest. Other algorithms and digest lengths are
+/// errors, as are trailing bytes.
+pub fn from_multihash(bytes: &[u8]) -> Result<Hash, Error> {
+    if bytes.is_empty() {
+        return Err(Error::WrongLength);
+    }
+    if bytes[0] != BLAKE3_CODE {
+        return Err(Error::UnsupportedCode);
+    }
+    if bytes.len() != MULTIHASH_SIZE || bytes[1] as usize != HASH_SIZE {
+        return Err(Error::WrongLength);
+    }
+    let mut digest = [0; HASH_SIZE];
+    digest.copy_from_slice(&bytes[2..]);
+    Ok(digest.into())
+}
+
+/// The multihash of a root hash, as a multibase string in `base`.
+pub fn to_multibase(hash: &Hash, base: Base) -> String {
+    let mut s = String::new();
+    s.push(base.prefix());
+    s.push_str(&base.encode(&to_multihash(hash)));
+    s
+}
+
+/// Parse a multibase string holding a BLAKE3 multihash, in any of the supported bases.
+pub fn from_multibase(s: &str) -> Result<Hash, Error> {
+    let mut chars = s.chars();
+    let prefix = chars.next().ok_or(Error::UnsupportedBase)?;
+    let base = Base::from_prefix(prefix)?;
+    from_multihash(&base.decode(chars.as_str())?)
+}
+
+fn hex_value(c: u8) -> Result<u8, Error> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(Error::InvalidCharacter),
+    }
+}
+
+// Base32 and base64 both read the input as a big-endian bit string, bits_per_char at a time.
+fn encode_bits(bytes: &[u8], bits_per_char: u32, alphabet: &[u8]) -> String {
+    let mut s = String::new();
+    let mut acc: u32 = 0;
+    let mut acc_bits = 0;
+    for &b in bytes {
+        acc = acc << 8 | b as u32;
+        acc_bits += 8;
+        while acc_bits >= bits_per_char {
+            acc_bits -= bits_per_char;
+            s.push(alphabet[(acc >> acc_bits) as usize & ((1 << bits_per_char) - 1)] as char);
+        }
+    }
+    if acc_bits > 0 {
+        let index = (acc << (bits_per_char - acc_bits)) as usize & ((1 << bits_per_char) - 1);
+        s.push(alphabet[index] as char);
+    }
+    s
+}
+
+fn decode_bits(s: &str, bits_per_char: u32, alphabet: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    let mut acc: u32 = 0;
+    let mut acc_bits = 0;
+    for c in s.bytes() {
+        let value = alphabet
+            .iter()
+            .position(|&a| a == c)
+            .ok_or(Error::InvalidCharacter)?;
+        acc = acc << bits_per_char | value as u32;
+        acc_bits += bits_per_char;
+        if acc_bits >= 8 {
+            acc_bits -= 8;
+            bytes.push((acc >> acc_bits) as u8);
+        }
+    }
+    // Leftover bits are padding, and they have to be zero.
+    if acc_bits >= bits_per_char || acc & ((1 << acc_bits) - 1) != 0 {
+        return Err(Error::WrongLength);
+    }
+    Ok(bytes)
+}
+
+// Base58 treats the input as one big-endian number, with a leading '1' for each leading zero
+// byte. Our inputs are small, so the quadratic long division is fine.
+fn encode_base58(bytes: &[u8]) -> String {
+    let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    // Little-endian base58 digits.
+    let mut digits: Vec<u8> = Vec::new();
+    for &b in &bytes[zeros..] {
+        let mut carry = b as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let mut s = "1".repeat(zeros);
+    s.extend(
+        digits
+            .iter()
+            .rev()
+            .map(|&d| BASE58_ALPHABET[d as usize] as char),
+    );
+    s
+}
+
+fn decode_base58(s: &str) -> Result<Vec<u8>, Error> {
+    let zeros = s.bytes().take_while(|&c| c == b'1').count();
+    // Little-endian bytes.
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.bytes().skip(zeros) {
+        let value = BASE58_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or(Error::InvalidCharacter)?;
+        let mut carry = value as u32;
+        for byte in bytes.iter_mut() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let mut output = vec![0; zeros];
+    output.extend(bytes.iter().rev());
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ALL_BASES: &[Base] = &[Base::Base16, Base::Base32, Base::Base58Btc, Base::Base64Url];
+
+    #[test]
+    fn test_round_trip() {
+        for &len in &[0, 1, 1000] {
+            let (_, hash) = crate::encode::encode(vec![0xab; len]);
+            assert_eq!(hash, from_multihash(&to_multihash(&hash)).unwrap());
+            for &base in ALL_BASES {
+                let s = to_multibase(&hash, base);
+                assert_eq!(Some(base.prefix()), s.chars().next());
+                assert_eq!(hash, from_multibase(&s).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_known_encodings() {
+        // Checked against the RFC 4648 test vectors and the base58 reference implementation.
+        assert_eq!("my", Base::Base32.encode(b"f"));
+        assert_eq!("mzxw6ytboi", Base::Base32.encode(b"foobar"));
+        assert_eq!("Zm9vYmE", Base::Base64Url.encode(b"fooba"));
+        assert_eq!("_-8", Base::Base64Url.encode(&[0xff, 0xef]));
+        assert_eq!("StV1DL6CwTryKyV", Base::Base58Btc.encode(b"hello world"));
+        assert_eq!("11", Base::Base58Btc.encode(&[0, 0]));
+        assert_eq!("1ZiCa", Base::Base58Btc.encode(&[0, 0x61, 0x62, 0x63]));
+        for &base in ALL_BASES {
+            for input in &[&b""[..], b"f", b"fo", b"foo", b"\0\0foobar", b"\xff\xff"] {
+                let encoded = base.encode(input);
+                assert_eq!(&input[..], &base.decode(&encoded).unwrap()[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let (_, hash) = crate::encode::encode(b"foo");
+        let multihash = to_multihash(&hash);
+        assert_eq!(Err(Error::WrongLength), from_multihash(&[]));
+        assert_eq!(Err(Error::WrongLength), from_multihash(&multihash[..33]));
+        let mut sha256 = multihash;
+        sha256[0] = 0x12;
+        assert_eq!(Err(Error::UnsupportedCode), from_multihash(&sha256));
+        let mut short_digest = multihash;
+        short_digest[1] = 16;
+        assert_eq!(Err(Error::WrongLength), from_multihash(&short_digest));
+
+        assert_eq!(Err(Error::UnsupportedBase), from_multibase(""));
+        assert_eq!(Err(Error::UnsupportedBase), from_multibase("Qmfoo"));
+        assert_eq!(Err(Error::InvalidCharacter), from_multibase("z0OIl"));
+        assert_eq!(Err(Error::InvalidCharacter), from_multibase("b1"));
+        assert_eq!(Err(Error::WrongLength), from_multibase("fabc"));
+        // Nonzero padding bits.
+        assert_eq!(Err(Error::WrongLength), Base::Base32.decode("mz"));
+    }
+}
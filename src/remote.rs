@@ -0,0 +1,618 @@
+//! Slice extraction and verified partial reads over an object store, like S3 or GCS.
+//!
+//! This module is behind the `remote` feature. Its functions fetch bytes through a callback that
+//! takes a byte range and returns a future, which usually wraps a ranged GET request, and which
+//! can come from any async runtime or HTTP client. A slice needs the length header, the parent
+//! nodes along the path down to the slice, and the chunks in it. `slice_ranges` works out where
+//! those are in the encoding and coalesces the ones that are next to each other, and
+//! `extract_slice` makes one request for the header, and one for each of those ranges. The
+//! requests go one at a time. Callers who want to send them concurrently can call
+//! `slice_ranges` themselves.
+//!
+//! `read_slice` and `read_slice_outboard` also verify the slice against a root hash, and return
+//! just the content. Extracting a slice doesn't verify anything, so a slice from
+//! `extract_slice` should be decoded with `decode::SliceDecoder` on the receiving end.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! use std::future::Future;
+//! use std::io;
+//! use std::ops::Range;
+//! use std::task::{Context, Poll, Waker};
+//!
+//! // A real fetcher would make a ranged GET request here, for example to S3. Note that HTTP
+//! // ranges are inclusive at the end. This one slices an encoding in memory instead.
+//! async fn get_range(object: &[u8], range: Range<u64>) -> io::Result<Vec<u8>> {
+//!     Ok(object[range.start as usize..range.end as usize].to_vec())
+//! }
+//!
+//! let input = vec![0xab; 100_000];
+//! let (encoded, hash) = bao::encode::encode(&input);
+//! let read = bao::remote::read_slice(|range| get_range(&encoded, range), &hash, 50_000, 10_000);
+//!
+//! // In an async runtime, this would be `read.await?`. Fetching from memory never waits, so a
+//! // single poll finishes it.
+//! let mut read = Box::pin(read);
+//! let content = match read.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+//!     Poll::Ready(content) => content?,
+//!     Poll::Pending => unreachable!("fetching from memory never waits"),
+//! };
+//! assert_eq!(&input[50_000..60_000], &content[..]);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::{Limits, SliceDecoder};
+use crate::encode;
+use crate::{Hash, CHUNK_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::io::prelude::*;
+use std::ops::Range;
+
+/// The ranges of a combined encoding that the slice at `slice_start` with length `slice_len`
+/// needs, after the length header, in order. Ranges that are next to each other in the encoding
+/// are merged, so this is the smallest set of requests that can fetch the slice. The slice itself
+/// is the header followed by these ranges.
+///
+/// Like `SliceExtractor`, a `slice_len` of zero counts as one, and a slice that starts at or past
+/// the end of the content includes the final chunk.
+pub fn slice_ranges(
+    content_len: u64,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<Vec<Range<u64>>> {
+    Ok(Walk::run(content_len, slice_start, slice_len, false)?.ranges(false))
+}
+
+/// The ranges that an outboard slice needs, from `outboard_slice_ranges`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboardRanges {
+    /// Ranges of the outboard encoding, after its length header.
+    pub outboard: Vec<Range<u64>>,
+    /// Ranges of the content.
+    pub content: Vec<Range<u64>>,
+}
+
+/// Like `slice_ranges`, but for an outboard encoding. The parent nodes come from the outboard
+/// encoding, and the chunks come from the content.
+pub fn outboard_slice_ranges(
+    content_len: u64,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<OutboardRanges> {
+    let walk = Walk::run(content_len, slice_start, slice_len, true)?;
+    Ok(OutboardRanges {
+        outboard: walk.ranges(false),
+        content: walk.ranges(true),
+    })
+}
+
+/// Extract a slice from a combined encoding, fetching bytes with `get_range`. The result is the
+/// same as what `SliceExtractor::new` would return.
+pub async fn extract_slice<F, Fut, B>(
+    get_range: F,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<Vec<u8>>
+where
+    F: FnMut(Range<u64>) -> Fut,
+    Fut: Future<Output = io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    extract_slice_inner(get_range, slice_start, slice_len, &Limits::default()).await
+}
+
+async fn extract_slice_inner<F, Fut, B>(
+    mut get_range: F,
+    slice_start: u64,
+    slice_len: u64,
+    limits: &Limits,
+) -> io::Result<Vec<u8>>
+where
+    F: FnMut(Range<u64>) -> Fut,
+    Fut: Future<Output = io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    let header = fetch(&mut get_range, 0..HEADER_SIZE as u64).await?;
+    let content_len = crate::decode_len(array_ref!(header, 0, HEADER_SIZE));
+    limits.check_len(content_len)?;
+    let mut slice = header;
+    for range in slice_ranges(content_len, slice_start, slice_len)? {
+        slice.extend_from_slice(&fetch(&mut get_range, range).await?);
+    }
+    Ok(slice)
+}
+
+/// Extract a slice from content and its outboard encoding, fetching bytes with `get_content` and
+/// `get_outboard`. The result is the same as what `SliceExtractor::new_outboard` would return.
+pub async fn extract_slice_outboard<C, CFut, CBytes, O, OFut, OBytes>(
+    get_content: C,
+    get_outboard: O,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<Vec<u8>>
+where
+    C: FnMut(Range<u64>) -> CFut,
+    CFut: Future<Output = io::Result<CBytes>>,
+    CBytes: AsRef<[u8]>,
+    O: FnMut(Range<u64>) -> OFut,
+    OFut: Future<Output = io::Result<OBytes>>,
+    OBytes: AsRef<[u8]>,
+{
+    extract_slice_outboard_inner(
+        get_content,
+        get_outboard,
+        slice_start,
+        slice_len,
+        &Limits::default(),
+    )
+    .await
+}
+
+async fn extract_slice_outboard_inner<C, CFut, CBytes, O, OFut, OBytes>(
+    mut get_content: C,
+    mut get_outboard: O,
+    slice_start: u64,
+    slice_len: u64,
+    limits: &Limits,
+) -> io::Result<Vec<u8>>
+where
+    C: FnMut(Range<u64>) -> CFut,
+    CFut: Future<Output = io::Result<CBytes>>,
+    CBytes: AsRef<[u8]>,
+    O: FnMut(Range<u64>) -> OFut,
+    OFut: Future<Output = io::Result<OBytes>>,
+    OBytes: AsRef<[u8]>,
+{
+    let header = fetch(&mut get_outboard, 0..HEADER_SIZE as u64).await?;
+    let content_len = crate::decode_len(array_ref!(header, 0, HEADER_SIZE));
+    limits.check_len(content_len)?;
+    let walk = Walk::run(content_len, slice_start, slice_len, true)?;
+    let mut outboard = Fetched::default();
+    for range in walk.ranges(false) {
+        let bytes = fetch(&mut get_outboard, range.clone()).await?;
+        outboard.buffers.push((range, bytes));
+    }
+    let mut content = Fetched::default();
+    for range in walk.ranges(true) {
+        let bytes = fetch(&mut get_content, range.clone()).await?;
+        content.buffers.push((range, bytes));
+    }
+    // Interleave the parent nodes and the chunks in the order of the slice.
+    let mut slice = header;
+    for piece in &walk.pieces {
+        let source = if piece.is_content {
+            &content
+        } else {
+            &outboard
+        };
+        slice.extend_from_slice(source.get(&piece.range));
+    }
+    Ok(slice)
+}
+
+/// Fetch the slice at `slice_start` with length `slice_len` from a combined encoding, like
+/// `extract_slice`, verify it against `hash`, and return its content.
+pub async fn read_slice<F, Fut, B>(
+    get_range: F,
+    hash: &Hash,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<Vec<u8>>
+where
+    F: FnMut(Range<u64>) -> Fut,
+    Fut: Future<Output = io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    read_slice_with_limits(get_range, hash, slice_start, slice_len, &Limits::default()).await
+}
+
+/// Like `read_slice`, but with `Limits` on the length header. The header is checked before any
+/// of the slice's ranges are fetched.
+pub async fn read_slice_with_limits<F, Fut, B>(
+    get_range: F,
+    hash: &Hash,
+    slice_start: u64,
+    slice_len: u64,
+    limits: &Limits,
+) -> io::Result<Vec<u8>>
+where
+    F: FnMut(Range<u64>) -> Fut,
+    Fut: Future<Output = io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    let slice = extract_slice_inner(get_range, slice_start, slice_len, limits).await?;
+    decode_slice(&slice, hash, slice_start, slice_len, limits)
+}
+
+/// Fetch the slice at `slice_start` with length `slice_len` from content and its outboard
+/// encoding, like `extract_slice_outboard`, verify it against `hash`, and return its content.
+pub async fn read_slice_outboard<C, CFut, CBytes, O, OFut, OBytes>(
+    get_content: C,
+    get_outboard: O,
+    hash: &Hash,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<Vec<u8>>
+where
+    C: FnMut(Range<u64>) -> CFut,
+    CFut: Future<Output = io::Result<CBytes>>,
+    CBytes: AsRef<[u8]>,
+    O: FnMut(Range<u64>) -> OFut,
+    OFut: Future<Output = io::Result<OBytes>>,
+    OBytes: AsRef<[u8]>,
+{
+    read_slice_outboard_with_limits(
+        get_content,
+        get_outboard,
+        hash,
+        slice_start,
+        slice_len,
+        &Limits::default(),
+    )
+    .await
+}
+
+/// Like `read_slice_outboard`, but with `Limits` on the length header. The header is checked
+/// before any of the slice's ranges are fetched.
+pub async fn read_slice_outboard_with_limits<C, CFut, CBytes, O, OFut, OBytes>(
+    get_content: C,
+    get_outboard: O,
+    hash: &Hash,
+    slice_start: u64,
+    slice_len: u64,
+    limits: &Limits,
+) -> io::Result<Vec<u8>>
+where
+    C: FnMut(Range<u64>) -> CFut,
+    CFut: Future<Output = io::Result<CBytes>>,
+    CBytes: AsRef<[u8]>,
+    O: FnMut(Range<u64>) -> OFut,
+    OFut: Future<Output = io::Result<OBytes>>,
+    OBytes: AsRef<[u8]>,
+{
+    let slice =
+        extract_slice_outboard_inner(get_content, get_outboard, slice_start, slice_len, limits)
+            .await?;
+    decode_slice(&slice, hash, slice_start, slice_len, limits)
+}
+
+fn decode_slice(
+    slice: &[u8],
+    hash: &Hash,
+    slice_start: u64,
+    slice_len: u64,
+    limits: &Limits,
+) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    SliceDecoder::new(slice, hash, slice_start, slice_len)
+        .with_limits(*limits)
+        .read_to_end(&mut content)?;
+    Ok(content)
+}
+
+// Make one request, and check that it returned the whole range.
+async fn fetch<F, Fut, B>(get_range: &mut F, range: Range<u64>) -> io::Result<Vec<u8>>
+where
+    F: FnMut(Range<u64>) -> Fut,
+    Fut: Future<Output = io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    let expected = range.end - range.start;
+    let bytes = get_range(range).await?;
+    if bytes.as_ref().len() as u64 != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "ranged read returned the wrong length",
+        ));
+    }
+    Ok(bytes.as_ref().to_vec())
+}
+
+// The coalesced ranges fetched from one source.
+#[derive(Default)]
+struct Fetched {
+    buffers: Vec<(Range<u64>, Vec<u8>)>,
+}
+
+impl Fetched {
+    fn get(&self, range: &Range<u64>) -> &[u8] {
+        let index = self
+            .buffers
+            .partition_point(|(fetched, _)| fetched.end <= range.start);
+        let (fetched, bytes) = &self.buffers[index];
+        let start = (range.start - fetched.start) as usize;
+        &bytes[start..][..(range.end - range.start) as usize]
+    }
+}
+
+// One parent node or chunk of the slice, and where it is in the encoding, or in the content if
+// it's a chunk of an outboard slice.
+struct Piece {
+    range: Range<u64>,
+    is_content: bool,
+}
+
+// A walk over the tree in pre-order, collecting the pieces that a slice needs, the same ones that
+// SliceExtractor reads.
+struct Walk {
+    outboard: bool,
+    // The content range that the slice covers, which always includes at least one chunk.
+    target: Range<u64>,
+    pieces: Vec<Piece>,
+}
+
+impl Walk {
+    fn run(content_len: u64, slice_start: u64, slice_len: u64, outboard: bool) -> io::Result<Self> {
+        let mut walk = Self::new(content_len, slice_start, slice_len, outboard);
+        walk.walk(0, content_len, HEADER_SIZE as u128, HEADER_SIZE as u128)?;
+        Ok(walk)
+    }
+
+    // The ranges of the content, or of the encoding, that the pieces came from, with the ones
+    // that are next to each other merged.
+    fn ranges(&self, is_content: bool) -> Vec<Range<u64>> {
+        let mut merged: Vec<Range<u64>> = Vec::new();
+        for piece in self.pieces.iter().filter(|p| p.is_content == is_content) {
+            match merged.last_mut() {
+                Some(last) if last.end == piece.range.start => last.end = piece.range.end,
+                _ => merged.push(piece.range.clone()),
+            }
+        }
+        merged
+    }
+
+    fn new(content_len: u64, slice_start: u64, slice_len: u64, outboard: bool) -> Self {
+        let slice_len = cmp::max(slice_len, 1);
+        let last_chunk_start =
+            encode::count_chunks(content_len).saturating_sub(1) * CHUNK_SIZE as u64;
+        let start = cmp::min(slice_start, last_chunk_start);
+        let end = cmp::max(slice_start.saturating_add(slice_len), start + 1);
+        Self {
+            outboard,
+            target: start..end,
+            pieces: Vec::new(),
+        }
+    }
+
+    // `offset` is the subtree's position in the encoding, and `outboard_offset` is its position
+    // in the outboard encoding.
+    fn walk(
+        &mut self,
+        start: u64,
+        len: u64,
+        offset: u128,
+        outboard_offset: u128,
+    ) -> io::Result<()> {
+        let end = start + len;
+        // An empty chunk is the only subtree that's empty, and it's only ever the whole tree.
+        let overlaps = start < self.target.end && (self.target.start < end || len == 0);
+        if !overlaps {
+            return Ok(());
+        }
+        if len <= CHUNK_SIZE as u64 {
+            let range = if self.outboard {
+                start..end
+            } else {
+                let offset = encode::EncodedOffset::new(offset).to_u64()?;
+                offset..offset + len
+            };
+            if !range.is_empty() {
+                self.pieces.push(Piece {
+                    range,
+                    is_content: self.outboard,
+                });
+            }
+            return Ok(());
+        }
+        let parent_offset = encode::EncodedOffset::new(if self.outboard {
+            outboard_offset
+        } else {
+            offset
+        })
+        .to_u64()?;
+        self.pieces.push(Piece {
+            range: parent_offset..parent_offset + PARENT_SIZE as u64,
+            is_content: false,
+        });
+        let left_len = encode::left_subtree_len(len);
+        let left_offset = offset + PARENT_SIZE as u128;
+        let left_outboard_offset = outboard_offset + PARENT_SIZE as u128;
+        self.walk(start, left_len, left_offset, left_outboard_offset)?;
+        self.walk(
+            start + left_len,
+            len - left_len,
+            left_offset + encode::encoded_subtree_size(left_len),
+            left_outboard_offset + encode::outboard_subtree_size(left_len),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    // The futures in these tests are always ready, but poll them properly anyway.
+    use crate::backend::block_on;
+    use crate::decode::make_test_input;
+    use std::cell::Cell;
+    use std::io::Cursor;
+
+    // A fake object store that counts requests.
+    fn store<'a>(
+        object: &'a [u8],
+        requests: &'a Cell<usize>,
+    ) -> impl FnMut(Range<u64>) -> std::future::Ready<io::Result<&'a [u8]>> {
+        move |range| {
+            requests.set(requests.get() + 1);
+            let start = cmp::min(range.start as usize, object.len());
+            let end = cmp::min(range.end as usize, object.len());
+            std::future::ready(Ok(&object[start..end]))
+        }
+    }
+
+    #[test]
+    fn test_extract_slice() {
+        for case in crate::test_vectors::test_cases(8) {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            for &start in &[
+                0,
+                1,
+                CHUNK_SIZE,
+                3 * CHUNK_SIZE - 1,
+                case / 2,
+                case,
+                case + 1,
+            ] {
+                for &len in &[0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 1, case] {
+                    println!("case {} start {} len {}", case, start, len);
+                    let (start, len) = (start as u64, len as u64);
+                    let mut expected = Vec::new();
+                    encode::SliceExtractor::new(Cursor::new(&encoded), start, len)
+                        .read_to_end(&mut expected)
+                        .unwrap();
+
+                    let requests = Cell::new(0);
+                    let slice = block_on(extract_slice(store(&encoded, &requests), start, len));
+                    assert_eq!(expected, slice.unwrap());
+                    let ranges = slice_ranges(case as u64, start, len).unwrap();
+                    assert_eq!(1 + ranges.len(), requests.get());
+                    assert!(ranges.windows(2).all(|pair| pair[0].end < pair[1].start));
+
+                    let content_requests = Cell::new(0);
+                    let outboard_requests = Cell::new(0);
+                    let slice = block_on(extract_slice_outboard(
+                        store(&input, &content_requests),
+                        store(&outboard, &outboard_requests),
+                        start,
+                        len,
+                    ));
+                    assert_eq!(expected, slice.unwrap());
+                    let ranges = outboard_slice_ranges(case as u64, start, len).unwrap();
+                    assert_eq!(1 + ranges.outboard.len(), outboard_requests.get());
+                    assert_eq!(ranges.content.len(), content_requests.get());
+
+                    let content = block_on(read_slice(
+                        store(&encoded, &Cell::new(0)),
+                        &hash,
+                        start,
+                        len,
+                    ));
+                    let content_start = cmp::min(start, case as u64) as usize;
+                    let content_end = cmp::min(start + len, case as u64) as usize;
+                    assert_eq!(&input[content_start..content_end], &content.unwrap()[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_slice_ranges_coalesced() {
+        // A slice of whole subtrees at the start is one contiguous range, right after the header,
+        // and so is the whole encoding.
+        let len = 1 << 20;
+        let ranges = slice_ranges(len, 0, 64 * CHUNK_SIZE as u64).unwrap();
+        assert_eq!(1, ranges.len());
+        assert_eq!(HEADER_SIZE as u64, ranges[0].start);
+        let size = encode::encoded_size(len) as u64;
+        assert_eq!(
+            vec![HEADER_SIZE as u64..size],
+            slice_ranges(len, 0, len).unwrap()
+        );
+        // Further in, every step down into a right child skips a left subtree.
+        let ranges = slice_ranges(len, len - 1, 1).unwrap();
+        assert_eq!(encode::encoded_size(len) as u64, ranges.last().unwrap().end);
+        let ranges = outboard_slice_ranges(len, 0, len).unwrap();
+        let outboard_size = encode::outboard_size(len) as u64;
+        assert_eq!(vec![HEADER_SIZE as u64..outboard_size], ranges.outboard);
+        assert_eq!(vec![0..len], ranges.content);
+        // An empty encoding is only the header.
+        assert!(slice_ranges(0, 0, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_slice_errors() {
+        let input = make_test_input(10 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+        // A short read is an error.
+        let result = block_on(read_slice(
+            store(&encoded[..encoded.len() - 1], &Cell::new(0)),
+            &hash,
+            0,
+            input.len() as u64,
+        ));
+        assert_eq!(io::ErrorKind::UnexpectedEof, result.unwrap_err().kind());
+        // So is a failed request.
+        let result = block_on(read_slice(
+            |_| std::future::ready(Err::<Vec<u8>, _>(io::Error::other("503"))),
+            &hash,
+            0,
+            1,
+        ));
+        assert_eq!(io::ErrorKind::Other, result.unwrap_err().kind());
+        // Corruption fails verification.
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        let result = block_on(read_slice(
+            store(&encoded, &Cell::new(0)),
+            &hash,
+            9 * CHUNK_SIZE as u64,
+            CHUNK_SIZE as u64,
+        ));
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+        let mut bad_input = input.clone();
+        bad_input[0] ^= 1;
+        let result = block_on(read_slice_outboard(
+            store(&bad_input, &Cell::new(0)),
+            store(&outboard, &Cell::new(0)),
+            &hash,
+            0,
+            CHUNK_SIZE as u64,
+        ));
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_limits_reject_huge_header() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let (mut outboard, _) = encode::outboard(&input);
+        encoded[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        outboard[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        let limits = Limits {
+            max_content_len: 1 << 30,
+            ..Limits::default()
+        };
+        // Only the header is fetched.
+        let requests = Cell::new(0);
+        let err = block_on(read_slice_with_limits(
+            store(&encoded, &requests),
+            &hash,
+            0,
+            CHUNK_SIZE as u64,
+            &limits,
+        ))
+        .unwrap_err();
+        assert_eq!(crate::decode::Error::TooLong.to_string(), err.to_string());
+        assert_eq!(1, requests.get());
+        let (content_requests, outboard_requests) = (Cell::new(0), Cell::new(0));
+        let err = block_on(read_slice_outboard_with_limits(
+            store(&input, &content_requests),
+            store(&outboard, &outboard_requests),
+            &hash,
+            0,
+            CHUNK_SIZE as u64,
+            &limits,
+        ))
+        .unwrap_err();
+        assert_eq!(crate::decode::Error::TooLong.to_string(), err.to_string());
+        assert_eq!((0, 1), (content_requests.get(), outboard_requests.get()));
+    }
+}
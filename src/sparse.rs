@@ -0,0 +1,367 @@
+//! Encoding and decoding sparse files, like VM images, without touching their holes.
+//!
+//! This module is behind the `sparse` feature. Where the OS supports `SEEK_DATA` and `SEEK_HOLE`
+//! (Linux, Android, the Apple platforms, FreeBSD, DragonFly, illumos, and Solaris),
+//! `data_ranges` asks the filesystem where a file's data is, and `SparseReader` reads only those
+//! ranges, filling the holes with zeros from memory instead of reading zero pages. `encode_file`
+//! and `outboard_file` encode through a `SparseReader`. Going the other way, `decode_to_file`
+//! seeks over all-zero chunks instead of writing them, so the restored file has holes again.
+//! Elsewhere, a file is all data, and everything still works, just without the savings.
+//!
+//! The holes still have to be hashed. Each chunk's chaining value depends on the chunk's index in
+//! the tree, so there's no table of precomputed hashes for zero chunks or zero subtrees to look
+//! up, but hashing zeros from memory is much cheaper than reading them from storage.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::io::prelude::*;
+//! use std::io::SeekFrom;
+//!
+//! // A 64 MiB file with a few bytes of data in the middle.
+//! let mut file = tempfile::tempfile()?;
+//! file.set_len(1 << 26)?;
+//! file.seek(SeekFrom::Start(1 << 25))?;
+//! file.write_all(b"some data")?;
+//!
+//! let mut outboard = Vec::new();
+//! let hash = bao::sparse::outboard_file(&file, std::io::Cursor::new(&mut outboard))?;
+//!
+//! let restored = tempfile::tempfile()?;
+//! let content = bao::backend::ReadAtCursor::new(&file);
+//! let decoder = bao::decode::Decoder::new_outboard(content, &*outboard, &hash);
+//! assert_eq!(1 << 26, bao::sparse::decode_to_file(decoder, &restored)?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::backend::ReadAt;
+use crate::encode;
+use crate::{Hash, CHUNK_SIZE};
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::ops::Range;
+
+// How much decode_to_file reads at a time.
+const DECODE_BUF_SIZE: usize = 16 * CHUNK_SIZE;
+
+/// Return the ranges of `file` that hold data, in order, according to `SEEK_DATA` and
+/// `SEEK_HOLE`. Everything outside these ranges is a hole that reads as zeros. On platforms or
+/// filesystems without hole support, this is the whole file. This moves the file's cursor, but it
+/// puts it back before returning.
+pub fn data_ranges(file: &File) -> io::Result<Vec<Range<u64>>> {
+    let len = file.metadata()?.len();
+    seek_data_ranges(file, len)
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "illumos",
+    target_os = "solaris",
+))]
+fn seek_data_ranges(file: &File, len: u64) -> io::Result<Vec<Range<u64>>> {
+    use rustix::fs::{seek, tell, SeekFrom::Data, SeekFrom::Hole, SeekFrom::Start};
+    use rustix::io::Errno;
+
+    let cursor = tell(file)?;
+    let mut ranges = Vec::new();
+    let mut position = 0;
+    let result = loop {
+        if position >= len {
+            break Ok(());
+        }
+        let start = match seek(file, Data(position)) {
+            Ok(start) => start,
+            // No data between here and the end of the file.
+            Err(Errno::NXIO) => break Ok(()),
+            // The filesystem doesn't support holes.
+            Err(Errno::INVAL) if position == 0 => {
+                ranges.push(0..len);
+                break Ok(());
+            }
+            Err(e) => break Err(e),
+        };
+        let end = match seek(file, Hole(start)) {
+            Ok(end) => cmp::min(end, len),
+            Err(e) => break Err(e),
+        };
+        if start < end {
+            ranges.push(start..end);
+        }
+        position = end;
+    };
+    seek(file, Start(cursor))?;
+    result?;
+    Ok(ranges)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "illumos",
+    target_os = "solaris",
+)))]
+fn seek_data_ranges(_file: &File, len: u64) -> io::Result<Vec<Range<u64>>> {
+    Ok(if len > 0 { vec![0..len] } else { Vec::new() })
+}
+
+/// A reader over a file that only reads the file's data ranges, and produces zeros for its holes
+/// without reading them.
+///
+/// The ranges come from `data_ranges` when the reader is created. Reads use positional IO, so
+/// they don't move the file's cursor. If the file shrinks while it's being read, the reader stops
+/// early at the new end.
+#[derive(Debug)]
+pub struct SparseReader<'a> {
+    file: &'a File,
+    ranges: Vec<Range<u64>>,
+    next_range: usize,
+    position: u64,
+    len: u64,
+}
+
+impl<'a> SparseReader<'a> {
+    pub fn new(file: &'a File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file,
+            ranges: seek_data_ranges(file, len)?,
+            next_range: 0,
+            position: 0,
+            len,
+        })
+    }
+
+    /// The total length of the file's data ranges, which is how many bytes this reader will
+    /// actually read from storage.
+    pub fn data_len(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    /// The length of the file, including holes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'a> Read for SparseReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.next_range < self.ranges.len()
+            && self.ranges[self.next_range].end <= self.position
+        {
+            self.next_range += 1;
+        }
+        let (in_data, until) = match self.ranges.get(self.next_range) {
+            Some(range) if range.start <= self.position => (true, range.end),
+            Some(range) => (false, range.start),
+            None => (false, self.len),
+        };
+        let want = cmp::min(buf.len() as u64, until.saturating_sub(self.position)) as usize;
+        let n = if in_data {
+            self.file.read_at(self.position, &mut buf[..want])?
+        } else {
+            buf[..want].iter_mut().for_each(|b| *b = 0);
+            want
+        };
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Encode `input` in the combined mode, writing the encoding to `output`, without reading the
+/// holes of `input`. The encoding itself isn't sparse, because it holds the content.
+pub fn encode_file(input: &File, output: impl Read + Write + Seek) -> io::Result<Hash> {
+    encode::encode_from_reader(SparseReader::new(input)?, output)
+}
+
+/// Make the outboard encoding of `input`, writing it to `outboard`, without reading the holes
+/// of `input`.
+pub fn outboard_file(input: &File, outboard: impl Read + Write + Seek) -> io::Result<Hash> {
+    let mut reader = SparseReader::new(input)?;
+    let mut encoder = encode::Encoder::new_outboard(outboard);
+    let mut buf = [0; CHUNK_SIZE];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        encoder.write_all(&buf[..n])?;
+    }
+    encoder.finalize()
+}
+
+/// Read all the content from `decoder`, usually a `decode::Decoder`, and write it to `output`,
+/// leaving holes where whole chunks are zero. `output` is truncated first, so the holes read as
+/// zeros, and then extended to the full length at the end. Return the content length.
+///
+/// If `decoder` returns an error partway through, `output` is left with some of the content.
+pub fn decode_to_file(mut decoder: impl Read, output: &File) -> io::Result<u64> {
+    output.set_len(0)?;
+    let mut writer = output;
+    let mut buf = vec![0; DECODE_BUF_SIZE];
+    let mut position = 0u64;
+    loop {
+        let n = read_up_to(&mut decoder, &mut buf)?;
+        // Write each run of chunks that aren't all zero with a single write.
+        let mut run_start = None;
+        for (i, chunk) in buf[..n].chunks(CHUNK_SIZE).enumerate() {
+            let is_zero = chunk.iter().all(|&b| b == 0);
+            match (run_start, is_zero) {
+                (None, false) => run_start = Some(i * CHUNK_SIZE),
+                (Some(start), true) => {
+                    writer.seek(SeekFrom::Start(position + start as u64))?;
+                    writer.write_all(&buf[start..i * CHUNK_SIZE])?;
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            writer.seek(SeekFrom::Start(position + start as u64))?;
+            writer.write_all(&buf[start..n])?;
+        }
+        position += n as u64;
+        if n < buf.len() {
+            break;
+        }
+    }
+    output.set_len(position)?;
+    Ok(position)
+}
+
+// Fill as much of buf as the reader will, stopping only at EOF.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode;
+    use crate::decode::make_test_input;
+
+    // A file of `len` bytes with some data at each of `offsets`, and holes everywhere else (if
+    // the filesystem supports them).
+    fn make_sparse_file(len: u64, offsets: &[u64]) -> (File, Vec<u8>) {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(len).unwrap();
+        let mut contents = vec![0; len as usize];
+        let data = make_test_input(3 * CHUNK_SIZE + 5);
+        for &offset in offsets {
+            let end = cmp::min(offset as usize + data.len(), len as usize);
+            let data = &data[..end - offset as usize];
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(data).unwrap();
+            contents[offset as usize..end].copy_from_slice(data);
+        }
+        (file, contents)
+    }
+
+    #[test]
+    fn test_encode_file() {
+        let len = 1 << 22;
+        let cases: &[&[u64]] = &[&[], &[0], &[12345], &[1 << 20, (1 << 21) + 1], &[len - 100]];
+        for &offsets in cases {
+            println!("offsets {:?}", offsets);
+            let (file, contents) = make_sparse_file(len, offsets);
+            let ranges = data_ranges(&file).unwrap();
+            for range in &ranges {
+                assert!(range.start < range.end && range.end <= len);
+            }
+            // Everything outside the data ranges really is zero.
+            let mut position = 0;
+            for range in ranges.iter().chain(Some(&(len..len))) {
+                assert!(contents[position as usize..range.start as usize]
+                    .iter()
+                    .all(|&b| b == 0));
+                position = range.end;
+            }
+
+            let reader = SparseReader::new(&file).unwrap();
+            assert_eq!(len, reader.len());
+            assert!(reader.data_len() <= len);
+            let mut read = Vec::new();
+            { reader }.read_to_end(&mut read).unwrap();
+            assert_eq!(contents, read);
+
+            let (expected_encoded, expected_hash) = encode::encode(&contents);
+            let mut encoded = Vec::new();
+            let hash = encode_file(&file, io::Cursor::new(&mut encoded)).unwrap();
+            assert_eq!(expected_hash, hash);
+            assert_eq!(expected_encoded, encoded);
+
+            let (expected_outboard, _) = encode::outboard(&contents);
+            let mut outboard = Vec::new();
+            let hash = outboard_file(&file, io::Cursor::new(&mut outboard)).unwrap();
+            assert_eq!(expected_hash, hash);
+            assert_eq!(expected_outboard, outboard);
+
+            let (mut restored, _) = make_sparse_file(len + 100, &[0, len]);
+            let decoder = decode::Decoder::new(&*encoded, &hash);
+            assert_eq!(len, decode_to_file(decoder, &restored).unwrap());
+            let mut restored_contents = Vec::new();
+            restored.seek(SeekFrom::Start(0)).unwrap();
+            restored.read_to_end(&mut restored_contents).unwrap();
+            assert_eq!(contents, restored_contents);
+            // If the filesystem supports holes, the restored file has them too.
+            let data_len = SparseReader::new(&file).unwrap().data_len();
+            if data_len < len {
+                assert!(SparseReader::new(&restored).unwrap().data_len() <= data_len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let file = tempfile::tempfile().unwrap();
+        assert!(data_ranges(&file).unwrap().is_empty());
+        let mut encoded = Vec::new();
+        let hash = encode_file(&file, io::Cursor::new(&mut encoded)).unwrap();
+        assert_eq!(encode::encode(b""), (encoded.clone(), hash));
+        let restored = tempfile::tempfile().unwrap();
+        let decoder = decode::Decoder::new(&*encoded, &hash);
+        assert_eq!(0, decode_to_file(decoder, &restored).unwrap());
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let (mut encoded, hash) = encode::encode(make_test_input(100 * CHUNK_SIZE));
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        let restored = tempfile::tempfile().unwrap();
+        let err = decode_to_file(decode::Decoder::new(&*encoded, &hash), &restored).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -0,0 +1,452 @@
+//! Compose many encodings into one logical volume, with lengths beyond `u64`.
+//!
+//! A single Bao tree holds at most `u64::MAX` bytes. A super-tree strings together any number of
+//! member trees, each an ordinary encoding with its own root hash, under an index with a root
+//! hash of its own. The index is itself an ordinary Bao encoding, of one fixed-size record per
+//! member:
+//!
+//! - the member's root hash (32 bytes),
+//! - the member's starting offset in the volume (16 bytes, little-endian), and
+//! - the member's length (8 bytes, little-endian).
+//!
+//! The index root hash covers every member's hash, position, and length, so it identifies the
+//! whole volume. `SuperTreeReader` reads the volume as one stream of up to `u128::MAX` bytes,
+//! verifying the index and the members as it goes, and seeks across member boundaries
+//! transparently. Seeking looks up records by binary search, so it only reads a logarithmic
+//! number of them, and the index never has to fit in memory. For slicing, `locate` maps a volume
+//! offset to a member and an offset within it, and `Member::hash` is what the member's slices
+//! verify against.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::io::prelude::*;
+//! use std::io::Cursor;
+//! use bao::supertree::{self, Member, SuperTreeReader};
+//!
+//! let objects = vec![vec![1; 5000], vec![2; 3000]];
+//! let encodings: Vec<Vec<u8>> = objects.iter().map(|o| bao::encode::encode(o).0).collect();
+//! let members: Vec<Member> = objects
+//!     .iter()
+//!     .map(|o| Member::new(bao::encode::encode(o).1, o.len() as u64))
+//!     .collect();
+//! let (index, volume_hash) = supertree::encode_index(&members);
+//!
+//! let mut reader = SuperTreeReader::open(Cursor::new(&index), &volume_hash, |i| {
+//!     Ok(Cursor::new(encodings[i as usize].clone()))
+//! })?;
+//! assert_eq!(8000, reader.len());
+//!
+//! // Read across the boundary between the two members.
+//! reader.seek_to(4990)?;
+//! let mut buf = [0; 20];
+//! reader.read_exact(&mut buf)?;
+//! assert_eq!([1; 10], buf[..10]);
+//! assert_eq!([2; 10], buf[10..]);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode;
+use crate::encode;
+use crate::{Hash, HASH_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+/// The size of one member record in the index.
+pub const RECORD_SIZE: usize = HASH_SIZE + 16 + 8;
+
+/// One member tree of a super-tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Member {
+    /// The member's root hash.
+    pub hash: Hash,
+    /// The member's offset in the volume. `encode_index` fills this in.
+    pub start: u128,
+    /// The member's content length.
+    pub len: u64,
+}
+
+impl Member {
+    /// A member with the given hash and length. Its start is filled in by `encode_index`.
+    pub fn new(hash: Hash, len: u64) -> Self {
+        Self {
+            hash,
+            start: 0,
+            len,
+        }
+    }
+
+    fn to_record(self) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        record[..HASH_SIZE].copy_from_slice(self.hash.as_bytes());
+        record[HASH_SIZE..][..16].copy_from_slice(&self.start.to_le_bytes());
+        record[HASH_SIZE + 16..].copy_from_slice(&self.len.to_le_bytes());
+        record
+    }
+
+    fn from_record(record: &[u8; RECORD_SIZE]) -> Self {
+        Self {
+            hash: (*array_ref!(record, 0, HASH_SIZE)).into(),
+            start: u128::from_le_bytes(*array_ref!(record, HASH_SIZE, 16)),
+            len: u64::from_le_bytes(*array_ref!(record, HASH_SIZE + 16, 8)),
+        }
+    }
+
+    fn end(&self) -> u128 {
+        // Records come from the index, so don't panic on a bogus one.
+        self.start.saturating_add(self.len as u128)
+    }
+}
+
+/// Build the index for `members`, in order, and return its combined encoding and the volume's
+/// root hash. The `start` fields of `members` are ignored, and the records get the members'
+/// actual offsets in the volume.
+///
+/// # Panics
+///
+/// Panics if the total length overflows `u128`.
+pub fn encode_index(members: &[Member]) -> (Vec<u8>, Hash) {
+    let mut records = Vec::with_capacity(members.len() * RECORD_SIZE);
+    let mut start: u128 = 0;
+    for member in members {
+        let record = Member { start, ..*member }.to_record();
+        records.extend_from_slice(&record);
+        start = start
+            .checked_add(member.len as u128)
+            .expect("volume length overflowed");
+    }
+    encode::encode(&records)
+}
+
+/// Verified reads from a super-tree volume.
+///
+/// `open_member` opens the combined encoding of the member at a given index. The reader keeps
+/// one member open at a time, and reopens members as seeks require.
+pub struct SuperTreeReader<T: Read + Seek, M: Read + Seek, F: FnMut(u64) -> io::Result<M>> {
+    index: decode::Decoder<T, T>,
+    open_member: F,
+    member_count: u64,
+    len: u128,
+    position: u128,
+    // The open member, its index, and the position of its decoder.
+    current: Option<(u64, Member, decode::Decoder<M, M>, u64)>,
+}
+
+impl<T: Read + Seek, M: Read + Seek, F: FnMut(u64) -> io::Result<M>> SuperTreeReader<T, M, F> {
+    /// Open a volume from its index encoding and root hash. This verifies the index length and
+    /// the last record.
+    pub fn open(index: T, hash: &Hash, open_member: F) -> io::Result<Self> {
+        let mut index = decode::Decoder::new(index, hash);
+        let index_len = index.seek(SeekFrom::End(0))?;
+        if index_len % RECORD_SIZE as u64 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "index length isn't a whole number of records",
+            ));
+        }
+        let mut reader = Self {
+            index,
+            open_member,
+            member_count: index_len / RECORD_SIZE as u64,
+            len: 0,
+            position: 0,
+            current: None,
+        };
+        if reader.member_count > 0 {
+            reader.len = reader.member(reader.member_count - 1)?.end();
+        }
+        Ok(reader)
+    }
+
+    /// The total length of the volume.
+    pub fn len(&self) -> u128 {
+        self.len
+    }
+
+    /// Returns `true` if the volume is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of members.
+    pub fn member_count(&self) -> u64 {
+        self.member_count
+    }
+
+    /// Read and verify the index record for the member at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` isn't less than `member_count()`.
+    pub fn member(&mut self, index: u64) -> io::Result<Member> {
+        assert!(index < self.member_count, "member index out of range");
+        let mut record = [0; RECORD_SIZE];
+        self.index
+            .seek(SeekFrom::Start(index * RECORD_SIZE as u64))?;
+        self.index.read_exact(&mut record)?;
+        Ok(Member::from_record(&record))
+    }
+
+    /// Find the member holding the volume byte at `position`, and return its index and the
+    /// offset within it. Empty members never hold anything. Returns `None` at or past the end of
+    /// the volume.
+    pub fn locate(&mut self, position: u128) -> io::Result<Option<(u64, u64)>> {
+        if position >= self.len {
+            return Ok(None);
+        }
+        // Find the last member that starts at or before the position. Any empty members that
+        // start there come before it.
+        let mut low = 0;
+        let mut high = self.member_count;
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if self.member(mid)?.start <= position {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        let member = self.member(low)?;
+        if position < member.start || position >= member.end() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "index records aren't contiguous",
+            ));
+        }
+        Ok(Some((low, (position - member.start) as u64)))
+    }
+
+    /// The current position in the volume.
+    pub fn position(&self) -> u128 {
+        self.position
+    }
+
+    /// Seek to `position` in the volume. Like `std::io::Seek`, but with a `u128` position.
+    /// Seeking past the end is allowed, and reads there return EOF.
+    pub fn seek_to(&mut self, position: u128) -> io::Result<()> {
+        self.position = position;
+        Ok(())
+    }
+
+    // Make sure the current member holds the current position, and position its decoder.
+    fn prepare_member(&mut self) -> io::Result<bool> {
+        if let Some((_, member, _, _)) = &self.current {
+            if member.start <= self.position && self.position < member.end() {
+                return Ok(true);
+            }
+        }
+        let (index, offset) = match self.locate(self.position)? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        let member = self.member(index)?;
+        let mut decoder = decode::Decoder::new((self.open_member)(index)?, &member.hash);
+        // Verify that the member's length matches its record.
+        if decoder.seek(SeekFrom::End(0))? != member.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "member length doesn't match the index",
+            ));
+        }
+        decoder.seek(SeekFrom::Start(offset))?;
+        self.current = Some((index, member, decoder, offset));
+        Ok(true)
+    }
+}
+
+impl<T: Read + Seek, M: Read + Seek, F: FnMut(u64) -> io::Result<M>> Read
+    for SuperTreeReader<T, M, F>
+{
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if output.is_empty() || !self.prepare_member()? {
+            return Ok(0);
+        }
+        let (_, member, decoder, decoder_position) = self.current.as_mut().expect("prepared");
+        let offset = (self.position - member.start) as u64;
+        // A seek within the member since the last read leaves the decoder somewhere else.
+        if *decoder_position != offset {
+            decoder.seek(SeekFrom::Start(offset))?;
+            *decoder_position = offset;
+        }
+        let remaining = member.len - offset;
+        let take = cmp::min(output.len() as u64, remaining) as usize;
+        let n = decoder.read(&mut output[..take])?;
+        if n == 0 {
+            return Err(decode::Error::Truncated.into());
+        }
+        *decoder_position += n as u64;
+        self.position += n as u128;
+        Ok(n)
+    }
+}
+
+impl<T: Read + Seek, M: Read + Seek, F: FnMut(u64) -> io::Result<M>> fmt::Debug
+    for SuperTreeReader<T, M, F>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SuperTreeReader {{ member_count: {}, len: {}, position: {}, current_member: {:?} }}",
+            self.member_count,
+            self.len,
+            self.position,
+            self.current.as_ref().map(|(index, _, _, _)| *index),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use crate::CHUNK_SIZE;
+    use std::io::Cursor;
+
+    struct Volume {
+        content: Vec<u8>,
+        encodings: Vec<Vec<u8>>,
+        index: Vec<u8>,
+        hash: Hash,
+    }
+
+    fn make_volume(lens: &[usize]) -> Volume {
+        let mut content = Vec::new();
+        let mut encodings = Vec::new();
+        let mut members = Vec::new();
+        for (i, &len) in lens.iter().enumerate() {
+            let mut input = make_test_input(len);
+            for b in &mut input {
+                *b = b.wrapping_add(i as u8);
+            }
+            let (encoded, hash) = encode::encode(&input);
+            content.extend_from_slice(&input);
+            encodings.push(encoded);
+            members.push(Member::new(hash, len as u64));
+        }
+        let (index, hash) = encode_index(&members);
+        Volume {
+            content,
+            encodings,
+            index,
+            hash,
+        }
+    }
+
+    #[test]
+    fn test_read_and_seek() {
+        let lens = [0, 1, CHUNK_SIZE, 0, 0, 3 * CHUNK_SIZE + 7, 100, 0];
+        let volume = make_volume(&lens);
+        let mut reader = SuperTreeReader::open(Cursor::new(&volume.index), &volume.hash, |i| {
+            Ok(Cursor::new(&volume.encodings[i as usize]))
+        })
+        .unwrap();
+        assert_eq!(volume.content.len() as u128, reader.len());
+        assert_eq!(lens.len() as u64, reader.member_count());
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(volume.content, output);
+
+        let len = volume.content.len();
+        for &offset in &[
+            0,
+            1,
+            2,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            len - 50,
+            len - 1,
+            len,
+            len + 5,
+        ] {
+            reader.seek_to(offset as u128).unwrap();
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(&volume.content[cmp::min(offset, len)..], &output[..]);
+        }
+
+        // Seeking backwards within a member.
+        reader.seek_to(CHUNK_SIZE as u128 + 100).unwrap();
+        let mut buf = [0; 10];
+        reader.read_exact(&mut buf).unwrap();
+        reader.seek_to(CHUNK_SIZE as u128 + 50).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&volume.content[CHUNK_SIZE + 50..][..10], &buf);
+    }
+
+    #[test]
+    fn test_locate() {
+        let volume = make_volume(&[0, 10, 0, 20]);
+        let mut reader = SuperTreeReader::open(Cursor::new(&volume.index), &volume.hash, |i| {
+            Ok(Cursor::new(&volume.encodings[i as usize]))
+        })
+        .unwrap();
+        assert_eq!(Some((1, 0)), reader.locate(0).unwrap());
+        assert_eq!(Some((1, 9)), reader.locate(9).unwrap());
+        assert_eq!(Some((3, 0)), reader.locate(10).unwrap());
+        assert_eq!(Some((3, 19)), reader.locate(29).unwrap());
+        assert_eq!(None, reader.locate(30).unwrap());
+    }
+
+    #[test]
+    fn test_beyond_u64() {
+        // The members don't have to exist to look at the index.
+        let hash = blake3::hash(b"foo");
+        let members = vec![Member::new(hash, u64::MAX); 4];
+        let (index, volume_hash) = encode_index(&members);
+        let mut reader = SuperTreeReader::open(Cursor::new(&index), &volume_hash, |_| {
+            Err::<Cursor<Vec<u8>>, _>(io::Error::new(io::ErrorKind::NotFound, "no members"))
+        })
+        .unwrap();
+        assert_eq!(4 * u64::MAX as u128, reader.len());
+        let position = 2 * u64::MAX as u128 + 12345;
+        assert_eq!(Some((2, 12345)), reader.locate(position).unwrap());
+        assert_eq!(2 * u64::MAX as u128, reader.member(2).unwrap().start);
+    }
+
+    #[test]
+    fn test_corruption() {
+        let volume = make_volume(&[100, 2 * CHUNK_SIZE, 100]);
+
+        // A corrupt index fails to open.
+        let mut bad_index = volume.index.clone();
+        let last = bad_index.len() - 1;
+        bad_index[last] ^= 1;
+        let open = |index: Vec<u8>| {
+            SuperTreeReader::open(Cursor::new(index), &volume.hash, |i| {
+                Ok(Cursor::new(volume.encodings[i as usize].clone()))
+            })
+        };
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            open(bad_index).unwrap_err().kind()
+        );
+
+        // A corrupt member fails when it's read.
+        let mut reader = SuperTreeReader::open(Cursor::new(&volume.index), &volume.hash, |i| {
+            let mut encoding = volume.encodings[i as usize].clone();
+            if i == 1 {
+                encoding[100] ^= 1;
+            }
+            Ok(Cursor::new(encoding))
+        })
+        .unwrap();
+        let mut output = Vec::new();
+        let err = reader.read_to_end(&mut output).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // A member that doesn't match its record's length fails too.
+        let mut reader = SuperTreeReader::open(Cursor::new(&volume.index), &volume.hash, |i| {
+            Ok(Cursor::new(volume.encodings[(i as usize + 1) % 3].clone()))
+        })
+        .unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -0,0 +1,243 @@
+//! Golden encodings for checking other implementations against this one.
+//!
+//! The JSON test vectors in the repo (`tests/test_vectors.json`) only record a hash of each
+//! encoding, which tells another implementation that it disagrees with this one but not where.
+//! This module generates the complete encodings, outboard encodings, and slices for a standard
+//! set of inputs, so that a port can diff its output byte for byte. The same vectors are checked
+//! in as `tests/golden_vectors.json`, which you can regenerate with:
+//!
+//! ```text
+//! cargo run --example generate_golden_vectors > tests/golden_vectors.json
+//! ```
+//!
+//! Inputs are the same as in the other test vectors: the bytes of a 4-byte little-endian
+//! counter, starting at 1. For example, an input of length 10 is the bytes
+//! `[1, 0, 0, 0, 2, 0, 0, 0, 3, 0]`.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! for vector in bao::test_vectors::generate() {
+//!     let input = bao::test_vectors::input(vector.input_len);
+//!     assert_eq!(input, bao::decode::decode(&vector.encoded, &vector.hash)?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::encode;
+use crate::{Hash, CHUNK_SIZE};
+use std::cmp;
+use std::fmt::Write;
+use std::io::prelude::*;
+use std::io::Cursor;
+
+/// The input lengths that vectors are generated for. These match `tests/test_vectors.json`.
+pub const INPUT_LENGTHS: &[usize] = &[
+    0,
+    1,
+    CHUNK_SIZE - 1,
+    CHUNK_SIZE,
+    CHUNK_SIZE + 1,
+    2 * CHUNK_SIZE - 1,
+    2 * CHUNK_SIZE,
+    2 * CHUNK_SIZE + 1,
+    3 * CHUNK_SIZE - 1,
+    3 * CHUNK_SIZE,
+    3 * CHUNK_SIZE + 1,
+    // The first case that has chunks at three different depths.
+    11 * CHUNK_SIZE,
+    // The first case that has a depth jump greater than one.
+    13 * CHUNK_SIZE,
+];
+
+/// Generate every input length within two bytes of a chunk boundary, from 0 up to and including
+/// `max_chunks` full chunks, in increasing order. Subtree boundaries all fall on chunk boundaries,
+/// so this includes the lengths on either side of every place where the tree gets deeper or
+/// gains a subtree. These are the lengths the encoding tests in this crate run on, and
+/// implementations built on top of it can use them to get the same coverage.
+///
+/// # Panics
+///
+/// Panics if `max_chunks * CHUNK_SIZE + 2` overflows `usize`.
+///
+/// # Example
+///
+/// ```
+/// let cases: Vec<usize> = bao::test_vectors::test_cases(1).collect();
+/// assert_eq!(vec![0, 1, 2, 1022, 1023, 1024, 1025, 1026], cases);
+/// ```
+pub fn test_cases(max_chunks: usize) -> impl Iterator<Item = usize> + Clone {
+    max_chunks
+        .checked_mul(CHUNK_SIZE)
+        .and_then(|len| len.checked_add(2))
+        .expect("test case length overflow");
+    (0..=max_chunks).flat_map(|chunks| {
+        let boundary = chunks * CHUNK_SIZE;
+        boundary.saturating_sub(2)..=boundary + 2
+    })
+}
+
+/// The encodings of one input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Vector {
+    pub input_len: usize,
+    pub hash: Hash,
+    pub encoded: Vec<u8>,
+    pub outboard: Vec<u8>,
+    pub slices: Vec<SliceVector>,
+}
+
+/// One slice of a `Vector`'s encoding, as produced by
+/// [`SliceExtractor`](../encode/struct.SliceExtractor.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SliceVector {
+    pub start: u64,
+    pub len: u64,
+    pub encoded: Vec<u8>,
+}
+
+/// Generate the input bytes for a vector of length `len`.
+pub fn input(len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(len);
+    let mut counter: u32 = 1;
+    while output.len() < len {
+        let take = cmp::min(4, len - output.len());
+        output.extend_from_slice(&counter.to_le_bytes()[..take]);
+        counter += 1;
+    }
+    output
+}
+
+/// Generate the vectors for every length in `INPUT_LENGTHS`.
+pub fn generate() -> Vec<Vector> {
+    INPUT_LENGTHS.iter().map(|&len| generate_one(len)).collect()
+}
+
+fn generate_one(input_len: usize) -> Vector {
+    let input = input(input_len);
+    let (encoded, hash) = encode::encode(&input);
+    let (outboard, _) = encode::outboard(&input);
+    let mut slices = Vec::new();
+    for &start in &slice_starts(input_len as u64) {
+        for &len in &[0, CHUNK_SIZE as u64] {
+            let mut extractor = encode::SliceExtractor::new(Cursor::new(&encoded), start, len);
+            let mut slice = Vec::new();
+            extractor
+                .read_to_end(&mut slice)
+                .expect("slicing a Vec can't fail");
+            slices.push(SliceVector {
+                start,
+                len,
+                encoded: slice,
+            });
+        }
+    }
+    Vector {
+        input_len,
+        hash,
+        encoded,
+        outboard,
+        slices,
+    }
+}
+
+// The start, the middle, the last byte, and just past the end.
+fn slice_starts(input_len: u64) -> Vec<u64> {
+    let mut starts = vec![0, input_len / 2, input_len.saturating_sub(1), input_len];
+    starts.dedup();
+    starts
+}
+
+/// Serialize vectors as JSON, with all the hashes and encodings in lowercase hex. This is the
+/// format of `tests/golden_vectors.json`.
+pub fn to_json(vectors: &[Vector]) -> String {
+    let mut json = String::new();
+    json.push_str("{\n");
+    json.push_str("  \"_comment\": \"Generated by bao::test_vectors. Input bytes are generated by incrementing a 4-byte little-endian integer, starting with 1.\",\n");
+    json.push_str("  \"vectors\": [");
+    for (i, vector) in vectors.iter().enumerate() {
+        json.push_str(if i == 0 { "\n" } else { ",\n" });
+        json.push_str("    {\n");
+        writeln!(json, "      \"input_len\": {},", vector.input_len).unwrap();
+        writeln!(json, "      \"hash\": \"{}\",", vector.hash.to_hex()).unwrap();
+        writeln!(json, "      \"encoded\": \"{}\",", hex(&vector.encoded)).unwrap();
+        writeln!(json, "      \"outboard\": \"{}\",", hex(&vector.outboard)).unwrap();
+        json.push_str("      \"slices\": [");
+        for (j, slice) in vector.slices.iter().enumerate() {
+            json.push_str(if j == 0 { "\n" } else { ",\n" });
+            write!(
+                json,
+                "        {{ \"start\": {}, \"len\": {}, \"encoded\": \"{}\" }}",
+                slice.start,
+                slice.len,
+                hex(&slice.encoded),
+            )
+            .unwrap();
+        }
+        json.push_str("\n      ]\n    }");
+    }
+    json.push_str("\n  ]\n}\n");
+    json
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        write!(s, "{:02x}", byte).unwrap();
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode;
+
+    #[test]
+    fn test_vectors_decode() {
+        for vector in generate() {
+            println!("input_len {}", vector.input_len);
+            let input = input(vector.input_len);
+            assert_eq!(
+                input,
+                decode::decode(&vector.encoded, &vector.hash).unwrap()
+            );
+            assert_eq!(
+                encode::outboard_size(input.len() as u64),
+                vector.outboard.len() as u128
+            );
+            for slice in &vector.slices {
+                let mut decoder = decode::SliceDecoder::new(
+                    &*slice.encoded,
+                    &vector.hash,
+                    slice.start,
+                    slice.len,
+                );
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                let start = cmp::min(slice.start as usize, input.len());
+                let end = cmp::min((slice.start + slice.len) as usize, input.len());
+                assert_eq!(&input[start..end], &*output);
+            }
+        }
+    }
+
+    #[test]
+    fn test_test_cases() {
+        assert_eq!(vec![0, 1, 2], test_cases(0).collect::<Vec<_>>());
+        let cases: Vec<usize> = test_cases(16).collect();
+        assert_eq!(3 + 16 * 5, cases.len());
+        assert!(cases.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(Some(&(16 * CHUNK_SIZE + 2)), cases.last());
+        // The fixed lists of interesting lengths are all near chunk boundaries.
+        for &len in crate::test::TEST_CASES.iter().chain(INPUT_LENGTHS) {
+            if len <= 16 * CHUNK_SIZE && len != 10 {
+                assert!(cases.contains(&len), "missing {}", len);
+            }
+        }
+    }
+}
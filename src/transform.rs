@@ -0,0 +1,447 @@
+//! Transform each chunk of content between the input and the encoder.
+//!
+//! A `ChunkTransform` rewrites chunks in place, after the input is read and before it's hashed,
+//! and reverses that after the decoder has verified them. The encoding and the root hash cover
+//! the transformed bytes. Convergent encryption, per-chunk block ciphers, and keyed
+//! obfuscation all fit this shape, without forking the encoder loop.
+//!
+//! Transforms have to preserve length. The Bao format has fixed-size chunks, and seeking depends
+//! on content offsets mapping directly to chunks, so there's no room for a transform like
+//! compression that changes the size of a chunk. Compress the input as a whole before encoding
+//! it instead.
+//!
+//! # Example
+//!
+//! A toy convergent encryption scheme, where the key comes from the plaintext itself. Anyone
+//! with the same plaintext derives the same key, so identical files deduplicate.
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::io::prelude::*;
+//! use bao::transform::{ChunkTransform, TransformDecoder, TransformEncoder};
+//!
+//! struct Xor([u8; 32]);
+//!
+//! impl ChunkTransform for Xor {
+//!     fn encode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]) {
+//!         let mut stream = blake3::Hasher::new_keyed(&self.0)
+//!             .update(&chunk_index.to_le_bytes())
+//!             .finalize_xof();
+//!         let mut keystream = vec![0; chunk.len()];
+//!         stream.fill(&mut keystream);
+//!         for (b, k) in chunk.iter_mut().zip(keystream) {
+//!             *b ^= k;
+//!         }
+//!     }
+//!
+//!     fn decode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]) {
+//!         self.encode_chunk(chunk_index, chunk);
+//!     }
+//! }
+//!
+//! let input = vec![0xab; 5000];
+//! let key = blake3::derive_key("bao convergent example", &input);
+//! let mut encoder = TransformEncoder::new(std::io::Cursor::new(Vec::new()), Xor(key));
+//! encoder.write_all(&input)?;
+//! let hash = encoder.finalize()?;
+//! let encoded = encoder.into_inner().into_inner();
+//!
+//! let mut decoder = TransformDecoder::new(&*encoded, &hash, Xor(key));
+//! let mut output = Vec::new();
+//! decoder.read_to_end(&mut output)?;
+//! assert_eq!(input, output);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode;
+use crate::encode;
+use crate::{Hash, CHUNK_SIZE};
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+/// A length-preserving transformation of content chunks.
+///
+/// Chunks are always complete, so `chunk` is `CHUNK_SIZE` bytes long, except that the final
+/// chunk may be shorter. The final chunk of empty content is empty, and the transform sees it
+/// anyway. The chunk index counts from 0. `decode_chunk` must exactly undo `encode_chunk` for
+/// the same index, and both must be deterministic, because decoders seek by transforming the
+/// same chunk again.
+pub trait ChunkTransform {
+    /// Transform a chunk of input in place, before it's hashed.
+    fn encode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]);
+
+    /// Reverse `encode_chunk`, after the chunk is verified.
+    fn decode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]);
+}
+
+impl<F: ChunkTransform + ?Sized> ChunkTransform for &mut F {
+    fn encode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]) {
+        (**self).encode_chunk(chunk_index, chunk)
+    }
+
+    fn decode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]) {
+        (**self).decode_chunk(chunk_index, chunk)
+    }
+}
+
+impl<F: ChunkTransform + ?Sized> ChunkTransform for Box<F> {
+    fn encode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]) {
+        (**self).encode_chunk(chunk_index, chunk)
+    }
+
+    fn decode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]) {
+        (**self).decode_chunk(chunk_index, chunk)
+    }
+}
+
+/// An `encode::Encoder` that applies a `ChunkTransform` to its input. It buffers input until it
+/// has a complete chunk, and the final chunk goes through the transform in `finalize`.
+pub struct TransformEncoder<T: Read + Write + Seek, F: ChunkTransform> {
+    inner: encode::Encoder<T>,
+    transform: F,
+    chunk_index: u64,
+    buf: Vec<u8>,
+}
+
+impl<T: Read + Write + Seek, F: ChunkTransform> TransformEncoder<T, F> {
+    pub fn new(inner: T, transform: F) -> Self {
+        Self::from_encoder(encode::Encoder::new(inner), transform)
+    }
+
+    /// Write an outboard encoding of the transformed content. The caller has to store the
+    /// transformed content separately, and it's only available to the transform, so this is
+    /// mostly useful when the transform also writes its output somewhere.
+    pub fn new_outboard(inner: T, transform: F) -> Self {
+        Self::from_encoder(encode::Encoder::new_outboard(inner), transform)
+    }
+
+    fn from_encoder(inner: encode::Encoder<T>, transform: F) -> Self {
+        Self {
+            inner,
+            transform,
+            chunk_index: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Transform the final chunk, finish the encoding, and return the root hash. See
+    /// `encode::Encoder::finalize`.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        self.transform.encode_chunk(self.chunk_index, &mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        self.inner.finalize()
+    }
+
+    /// Return the underlying writer, after `finalize`. See `encode::Encoder::into_inner`.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
+    /// Give up on an unfinished encoding and return the underlying writer. See
+    /// `encode::Encoder::abort`.
+    pub fn abort(self) -> T {
+        self.inner.abort()
+    }
+}
+
+impl<T: Read + Write + Seek, F: ChunkTransform> Write for TransformEncoder<T, F> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        // Hold back a full chunk until more input arrives, because until then we don't know
+        // whether it's the final chunk. Transforms don't care, but it keeps finalize simple.
+        if self.buf.len() == CHUNK_SIZE && !input.is_empty() {
+            self.transform.encode_chunk(self.chunk_index, &mut self.buf);
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+            self.chunk_index += 1;
+        }
+        let take = cmp::min(input.len(), CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&input[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Read + Write + Seek, F: ChunkTransform> fmt::Debug for TransformEncoder<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing the buffered input or the transform, they might be secret.
+        write!(
+            f,
+            "TransformEncoder {{ chunk_index: {}, buf_len: {} }}",
+            self.chunk_index,
+            self.buf.len(),
+        )
+    }
+}
+
+/// A `decode::Decoder` that reverses a `ChunkTransform` on its output. It decodes a complete
+/// chunk at a time, and seeking is supported when the underlying reader supports it.
+pub struct TransformDecoder<T: Read, O: Read, F: ChunkTransform> {
+    inner: decode::Decoder<T, O>,
+    transform: F,
+    // The content offset of the chunk in buf. Before the first read, and after reaching EOF, buf
+    // is empty.
+    buf_start: u64,
+    buf: Vec<u8>,
+    buf_pos: usize,
+}
+
+impl<T: Read, F: ChunkTransform> TransformDecoder<T, T, F> {
+    pub fn new(inner: T, hash: &Hash, transform: F) -> Self {
+        Self::from_decoder(decode::Decoder::new(inner, hash), transform)
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Read, O: Read, F: ChunkTransform> TransformDecoder<T, O, F> {
+    /// Decode the transformed content in `inner` with the outboard encoding in `outboard`.
+    pub fn new_outboard(inner: T, outboard: O, hash: &Hash, transform: F) -> Self {
+        Self::from_decoder(
+            decode::Decoder::new_outboard(inner, outboard, hash),
+            transform,
+        )
+    }
+
+    /// Wrap a `decode::Decoder` that has already been configured, with `with_max_len` for
+    /// example. The decoder must not have read anything yet.
+    pub fn from_decoder(inner: decode::Decoder<T, O>, transform: F) -> Self {
+        Self {
+            inner,
+            transform,
+            buf_start: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            buf_pos: 0,
+        }
+    }
+
+    // Read and reverse the chunk at the inner decoder's position, chunk_start. That's a chunk
+    // boundary, unless it's past the end of the content.
+    fn fill_buf(&mut self, chunk_start: u64) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.resize(CHUNK_SIZE, 0);
+        let mut filled = 0;
+        while filled < CHUNK_SIZE {
+            let n = self.inner.read(&mut self.buf[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        self.buf.truncate(filled);
+        self.buf_start = chunk_start;
+        self.buf_pos = 0;
+        // Skip the transform for the empty read at EOF, unless it's the empty chunk of empty
+        // content.
+        if filled > 0 || chunk_start == 0 {
+            let chunk_index = chunk_start / CHUNK_SIZE as u64;
+            self.transform.decode_chunk(chunk_index, &mut self.buf);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read, O: Read, F: ChunkTransform> Read for TransformDecoder<T, O, F> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if output.is_empty() {
+            return Ok(0);
+        }
+        if self.buf_pos == self.buf.len() {
+            let next_chunk = self.buf_start + self.buf.len() as u64;
+            if !self.buf.is_empty() && self.buf.len() < CHUNK_SIZE {
+                // The final chunk was short, so this is EOF.
+                return Ok(0);
+            }
+            self.fill_buf(next_chunk)?;
+        }
+        let take = cmp::min(output.len(), self.buf.len() - self.buf_pos);
+        output[..take].copy_from_slice(&self.buf[self.buf_pos..][..take]);
+        self.buf_pos += take;
+        Ok(take)
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek, F: ChunkTransform> Seek for TransformDecoder<T, O, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = self.buf_start + self.buf_pos as u64;
+        let target = match pos {
+            SeekFrom::Start(n) => n,
+            SeekFrom::Current(n) => crate::decode::add_offset(position, n)?,
+            // The inner decoder knows the length, and it verifies it.
+            SeekFrom::End(_) => self.inner.seek(pos)?,
+        };
+        let chunk_start = target - target % CHUNK_SIZE as u64;
+        let offset_in_chunk = (target - chunk_start) as usize;
+        if chunk_start != self.buf_start || self.buf.is_empty() {
+            self.inner.seek(SeekFrom::Start(chunk_start))?;
+            self.fill_buf(chunk_start)?;
+        }
+        self.buf_pos = offset_in_chunk;
+        if self.buf_pos > self.buf.len() {
+            // Like the inner decoder, we can seek past the end, and reads there return EOF.
+            self.buf.clear();
+            self.buf_start = target;
+            self.buf_pos = 0;
+        }
+        Ok(self.buf_start + self.buf_pos as u64)
+    }
+}
+
+impl<T: Read, O: Read, F: ChunkTransform> fmt::Debug for TransformDecoder<T, O, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing the buffered output or the transform, they might be secret.
+        write!(
+            f,
+            "TransformDecoder {{ buf_start: {}, buf_len: {}, buf_pos: {} }}",
+            self.buf_start,
+            self.buf.len(),
+            self.buf_pos,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use std::io::Cursor;
+
+    // Adds the chunk index to every byte, and records the chunks it sees.
+    #[derive(Default)]
+    struct AddIndex {
+        encoded: Vec<(u64, usize)>,
+        decoded: Vec<(u64, usize)>,
+    }
+
+    impl ChunkTransform for AddIndex {
+        fn encode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]) {
+            self.encoded.push((chunk_index, chunk.len()));
+            for b in chunk {
+                *b = b.wrapping_add(chunk_index as u8 + 1);
+            }
+        }
+
+        fn decode_chunk(&mut self, chunk_index: u64, chunk: &mut [u8]) {
+            self.decoded.push((chunk_index, chunk.len()));
+            for b in chunk {
+                *b = b.wrapping_sub(chunk_index as u8 + 1);
+            }
+        }
+    }
+
+    fn expected_chunks(len: usize) -> Vec<(u64, usize)> {
+        let count = encode::count_chunks(len as u64);
+        (0..count)
+            .map(|i| (i, encode::chunk_size(i, len as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut transform = AddIndex::default();
+            let mut encoder = TransformEncoder::new(Cursor::new(Vec::new()), &mut transform);
+            // Small writes, to exercise the buffering.
+            for piece in input.chunks(100) {
+                encoder.write_all(piece).unwrap();
+            }
+            let hash = encoder.finalize().unwrap();
+            let encoded = encoder.into_inner().into_inner();
+            assert_eq!(expected_chunks(case), transform.encoded);
+
+            // The encoding is an ordinary encoding of the transformed bytes.
+            let transformed = decode::decode(&encoded, &hash).unwrap();
+            for (i, (&a, &b)) in input.iter().zip(&transformed).enumerate() {
+                let chunk_index = (i / CHUNK_SIZE) as u8;
+                assert_eq!(a.wrapping_add(chunk_index + 1), b);
+            }
+
+            let mut decoder = TransformDecoder::new(&*encoded, &hash, &mut transform);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+            assert_eq!(expected_chunks(case), transform.decoded);
+        }
+    }
+
+    #[test]
+    fn test_seek() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut encoder = TransformEncoder::new(Cursor::new(Vec::new()), AddIndex::default());
+            encoder.write_all(&input).unwrap();
+            let hash = encoder.finalize().unwrap();
+            let encoded = encoder.into_inner().into_inner();
+            let mut decoder =
+                TransformDecoder::new(Cursor::new(&encoded), &hash, AddIndex::default());
+            for &offset in &[case / 2, 0, case.saturating_sub(1), case, case + 1] {
+                let position = decoder.seek(SeekFrom::Start(offset as u64)).unwrap();
+                assert_eq!(offset as u64, position);
+                let mut output = Vec::new();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(&input[cmp::min(offset, case)..], &output[..]);
+            }
+            let position = decoder.seek(SeekFrom::End(0)).unwrap();
+            assert_eq!(case as u64, position);
+            let position = decoder.seek(SeekFrom::Current(10)).unwrap();
+            assert_eq!(case as u64 + 10, position);
+            assert_eq!(0, decoder.read(&mut [0; 10]).unwrap());
+            if case > 0 {
+                decoder.seek(SeekFrom::Start(0)).unwrap();
+                decoder.seek(SeekFrom::Current(case as i64 - 1)).unwrap();
+                let mut byte = [0];
+                decoder.read_exact(&mut byte).unwrap();
+                assert_eq!(input[case - 1], byte[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seek_overflow() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let mut encoder = TransformEncoder::new(Cursor::new(Vec::new()), AddIndex::default());
+        encoder.write_all(&input).unwrap();
+        let hash = encoder.finalize().unwrap();
+        let encoded = encoder.into_inner().into_inner();
+        let mut decoder = TransformDecoder::new(Cursor::new(&encoded), &hash, AddIndex::default());
+        assert_eq!(u64::MAX, decoder.seek(SeekFrom::Start(u64::MAX)).unwrap());
+        let err = decoder.seek(SeekFrom::Current(1)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = decoder.seek(SeekFrom::Current(i64::MAX)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        decoder.seek(SeekFrom::Start(0)).unwrap();
+        let err = decoder.seek(SeekFrom::Current(-1)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_corruption() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let mut encoder = TransformEncoder::new(Cursor::new(Vec::new()), AddIndex::default());
+        encoder.write_all(&input).unwrap();
+        let hash = encoder.finalize().unwrap();
+        let mut encoded = encoder.into_inner().into_inner();
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        let mut transform = AddIndex::default();
+        let mut decoder = TransformDecoder::new(&*encoded, &hash, &mut transform);
+        let mut output = Vec::new();
+        let err = decoder.read_to_end(&mut output).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        // Only verified chunks reach the transform.
+        assert_eq!(expected_chunks(2 * CHUNK_SIZE), transform.decoded);
+    }
+}
@@ -0,0 +1,240 @@
+//! Update an existing encoding in place, after editing some of its content.
+//!
+//! Re-encoding a large file after changing a few bytes means rehashing all of it. Patching only
+//! touches the chunks that changed and the parent nodes above them, so the cost is proportional
+//! to the size of the edit and the height of the tree.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut input = vec![0; 100_000];
+//! let (mut encoded, mut hash) = bao::encode::encode(&input);
+//!
+//! input[50_000..50_005].copy_from_slice(b"hello");
+//! bao::update::patch_encoding(std::io::Cursor::new(&mut encoded), &mut hash, 50_000, b"hello")?;
+//! assert_eq!(bao::encode::encode(&input), (encoded, hash));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::Error;
+use crate::encode;
+use crate::Finalization::{self, NotRoot, Root};
+use crate::{Hash, ParentNode, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+/// Overwrite `new_bytes.len()` content bytes starting at `edit_offset` in a combined encoding,
+/// and update `hash` to be the new root hash.
+///
+/// Only the chunks that overlap the edit and the parent nodes along their paths to the root are
+/// read and rewritten. Every node that gets read is verified against the old value of `hash`
+/// first, and if anything doesn't match, this returns an `InvalidData` error without writing
+/// anything. The edit can't change the length of the content; edits that extend past the end of
+/// the content are an `InvalidInput` error.
+pub fn patch_encoding(
+    encoded: impl Read + Write + Seek,
+    hash: &mut Hash,
+    edit_offset: u64,
+    new_bytes: &[u8],
+) -> io::Result<()> {
+    let mut patcher = Patcher {
+        encoded,
+        edit_start: edit_offset,
+        edit_end: 0,
+        new_bytes,
+        writes: Vec::new(),
+    };
+    let mut header = [0; HEADER_SIZE];
+    patcher.read_at(0, &mut header)?;
+    let content_len = crate::decode_len(&header);
+    patcher.edit_end = match edit_offset.checked_add(new_bytes.len() as u64) {
+        Some(end) if end <= content_len => end,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "edit extends past the end of the content",
+            ))
+        }
+    };
+    if new_bytes.is_empty() {
+        return Ok(());
+    }
+
+    // Verify and recompute everything first, and only then write, so that an encoding that
+    // doesn't match the old hash is left untouched.
+    let new_hash = patcher.patch_subtree(HEADER_SIZE as u128, 0, content_len, hash, Root)?;
+    for (offset, bytes) in &patcher.writes {
+        patcher.encoded.seek(SeekFrom::Start(
+            encode::EncodedOffset::new(*offset).to_u64()?,
+        ))?;
+        patcher.encoded.write_all(bytes)?;
+    }
+    *hash = new_hash;
+    Ok(())
+}
+
+struct Patcher<'a, T> {
+    encoded: T,
+    edit_start: u64,
+    edit_end: u64,
+    new_bytes: &'a [u8],
+    writes: Vec<(u128, Vec<u8>)>,
+}
+
+impl<'a, T: Read + Write + Seek> Patcher<'a, T> {
+    fn read_at(&mut self, offset: u128, buf: &mut [u8]) -> io::Result<()> {
+        self.encoded.seek(SeekFrom::Start(
+            encode::EncodedOffset::new(offset).to_u64()?,
+        ))?;
+        self.encoded.read_exact(buf)
+    }
+
+    fn overlaps_edit(&self, start: u64, len: u64) -> bool {
+        start < self.edit_end && self.edit_start < start + len
+    }
+
+    // Returns the new chaining value of the subtree of `len` content bytes starting at content
+    // offset `start` and encoded offset `encoded_offset`. The caller only calls this for
+    // subtrees that overlap the edit.
+    fn patch_subtree(
+        &mut self,
+        encoded_offset: u128,
+        start: u64,
+        len: u64,
+        expected: &Hash,
+        finalization: Finalization,
+    ) -> io::Result<Hash> {
+        if len <= CHUNK_SIZE as u64 {
+            let mut chunk = vec![0; len as usize];
+            self.read_at(encoded_offset, &mut chunk)?;
+            let chunk_index = start / CHUNK_SIZE as u64;
+            let old_hash = crate::chunk_cv(chunk_index, &chunk, finalization.is_root());
+            // Hash implements constant time equality.
+            if &old_hash != expected {
+                return Err(Error::HashMismatch.into());
+            }
+            let patch_start = cmp::max(start, self.edit_start);
+            let patch_end = cmp::min(start + len, self.edit_end);
+            chunk[(patch_start - start) as usize..(patch_end - start) as usize].copy_from_slice(
+                &self.new_bytes[(patch_start - self.edit_start) as usize..]
+                    [..(patch_end - patch_start) as usize],
+            );
+            let new_hash = crate::chunk_cv(chunk_index, &chunk, finalization.is_root());
+            self.writes.push((encoded_offset, chunk));
+            return Ok(new_hash);
+        }
+
+        let mut parent: ParentNode = [0; PARENT_SIZE];
+        self.read_at(encoded_offset, &mut parent)?;
+        let mut left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let mut right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        let old_hash = crate::parent_cv(&left_child, &right_child, finalization.is_root());
+        // Hash implements constant time equality.
+        if &old_hash != expected {
+            return Err(Error::HashMismatch.into());
+        }
+        let left_len = encode::left_subtree_len(len);
+        let left_offset = encoded_offset + PARENT_SIZE as u128;
+        if self.overlaps_edit(start, left_len) {
+            left_child = self.patch_subtree(left_offset, start, left_len, &left_child, NotRoot)?;
+        }
+        let right_start = start + left_len;
+        let right_len = len - left_len;
+        if self.overlaps_edit(right_start, right_len) {
+            let right_offset = left_offset + encode::encoded_subtree_size(left_len);
+            right_child =
+                self.patch_subtree(right_offset, right_start, right_len, &right_child, NotRoot)?;
+        }
+        let mut new_parent = vec![0; PARENT_SIZE];
+        new_parent[..HASH_SIZE].copy_from_slice(left_child.as_bytes());
+        new_parent[HASH_SIZE..].copy_from_slice(right_child.as_bytes());
+        self.writes.push((encoded_offset, new_parent));
+        Ok(crate::parent_cv(
+            &left_child,
+            &right_child,
+            finalization.is_root(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_patch_encoding() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let edits = [
+                (0, 1),
+                (0, case),
+                (case / 2, 1),
+                (case / 3, case / 3),
+                (case.saturating_sub(CHUNK_SIZE + 1), CHUNK_SIZE + 1),
+                (case.saturating_sub(1), 1),
+                (case, 0),
+            ];
+            for &(offset, len) in edits.iter() {
+                let len = cmp::min(len, case - cmp::min(offset, case));
+                println!("edit {} {}", offset, len);
+                let mut input = make_test_input(case);
+                let (mut encoded, mut hash) = encode::encode(&input);
+                let new_bytes: Vec<u8> = input[offset..][..len].iter().map(|b| !b).collect();
+                input[offset..][..len].copy_from_slice(&new_bytes);
+                patch_encoding(
+                    Cursor::new(&mut encoded),
+                    &mut hash,
+                    offset as u64,
+                    &new_bytes,
+                )
+                .unwrap();
+                assert_eq!(encode::encode(&input), (encoded, hash));
+            }
+        }
+    }
+
+    #[test]
+    fn test_patch_past_the_end() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (mut encoded, mut hash) = encode::encode(&input);
+        let err = patch_encoding(
+            Cursor::new(&mut encoded),
+            &mut hash,
+            3 * CHUNK_SIZE as u64 - 1,
+            b"ab",
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = patch_encoding(Cursor::new(&mut encoded), &mut hash, u64::MAX, b"a").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_patch_corrupt_encoding() {
+        let input = make_test_input(5 * CHUNK_SIZE);
+        let (mut encoded, mut hash) = encode::encode(&input);
+        let original_hash = hash;
+        // Corrupt the last byte of the final chunk. Patching the start of the content doesn't
+        // read that chunk and succeeds, but patching the end fails and writes nothing.
+        *encoded.last_mut().unwrap() ^= 1;
+        let corrupt = encoded.clone();
+        let err = patch_encoding(
+            Cursor::new(&mut encoded),
+            &mut hash,
+            5 * CHUNK_SIZE as u64 - 2,
+            b"ab",
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(corrupt, encoded);
+        assert_eq!(original_hash, hash);
+        patch_encoding(Cursor::new(&mut encoded), &mut hash, 0, b"ab").unwrap();
+    }
+}
@@ -0,0 +1,431 @@
+//! Zero-copy verified random access to a combined encoding in memory.
+//!
+//! Read-heavy servers, like a static asset server, often memory-map their encoded files and serve
+//! many small reads from them. Decoding into a buffer for every read is wasted copying, and
+//! verifying the whole file up front is wasted hashing when most of it is never read.
+//! `VerifiedBytes` wraps a combined encoding held in anything that derefs to `[u8]`, like a
+//! `Vec<u8>` or a memory map, and verifies each chunk the first time it's touched. It remembers
+//! which chunks it has verified in a bitset, and after that it hands out `&[u8]` pointing straight
+//! into the encoding.
+//!
+//! The bitset is atomic, so a `VerifiedBytes` can be shared between threads, and concurrent first
+//! touches of the same chunk just verify it twice. Verifying a chunk also verifies the parent
+//! nodes above it, which costs one parent hash per level of the tree and isn't cached, so the
+//! first touch of each chunk is a little more expensive than in a sequential `Decoder`.
+//!
+//! The encoding must not change after it's been verified. That's automatic for a `Vec<u8>`, but a
+//! memory map of a file that another process modifies can change underneath any reader, and
+//! `VerifiedBytes` can't detect that.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let input = vec![0xab; 100_000];
+//! let (encoded, hash) = bao::encode::encode(&input);
+//! let bytes = bao::verified::VerifiedBytes::new(encoded, &hash)?;
+//! assert_eq!(100_000, bytes.len());
+//!
+//! // A range inside one chunk is a single borrowed slice.
+//! let slice = bytes.slice(50_000, 10)?;
+//! assert_eq!(Some(&[0xab; 10][..]), slice.as_contiguous());
+//!
+//! // A longer range comes in pieces, one per chunk, because a combined encoding interleaves
+//! // parent nodes with the content.
+//! let slice = bytes.slice(0, 5000)?;
+//! assert_eq!(5, slice.chunks().count());
+//! assert_eq!(vec![0xab; 5000], slice.to_vec());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::{Error, Limits};
+use crate::encode;
+use crate::Finalization::{NotRoot, Root};
+use crate::{Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A combined encoding that verifies its chunks on first touch. See the module docs.
+///
+/// Like `VerifiedFile`, construction verifies the final chunk, so `len` is trustworthy. Any
+/// corruption shows up as a `HashMismatch` error from the call that touched it, and the rest of
+/// the encoding stays readable.
+pub struct VerifiedBytes<T: AsRef<[u8]>> {
+    encoded: T,
+    root_hash: Hash,
+    content_len: u64,
+    // One bit per chunk, set once the chunk has been verified. Bits are never cleared.
+    verified: Vec<AtomicU64>,
+}
+
+impl<T: AsRef<[u8]>> VerifiedBytes<T> {
+    /// Wrap a combined encoding. This reads the length header and verifies the final chunk, and
+    /// returns a `Truncated` error if `encoded` is shorter than the header says it should be.
+    /// Bytes past the end of the encoding are ignored, as they are by `decode`.
+    pub fn new(encoded: T, hash: &Hash) -> io::Result<Self> {
+        Self::new_with_limits(encoded, hash, &Limits::default())
+    }
+
+    /// Like `new`, but with `Limits` on the length header.
+    pub fn new_with_limits(encoded: T, hash: &Hash, limits: &Limits) -> io::Result<Self> {
+        let bytes = encoded.as_ref();
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::Truncated.into());
+        }
+        let header = array_ref!(bytes, 0, HEADER_SIZE);
+        limits.check_header(header)?;
+        let content_len = crate::decode_len(header);
+        // Checking the size up front means every offset in the tree fits in a usize, so the
+        // casts below are lossless, and chunk lookups can't run off the end.
+        match encode::try_encoded_size(content_len) {
+            Some(size) if size <= bytes.len() => {}
+            _ => return Err(Error::Truncated.into()),
+        }
+        let chunks = encode::count_chunks(content_len);
+        let verified = (0..chunks.div_ceil(64))
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        let verified_bytes = Self {
+            encoded,
+            root_hash: *hash,
+            content_len,
+            verified,
+        };
+        // This is the "final chunk requirement" from the spec, which verifies the length.
+        verified_bytes.chunk(chunks - 1)?;
+        Ok(verified_bytes)
+    }
+
+    /// The content length. This has been verified.
+    pub fn len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// Returns `true` if the content is empty.
+    pub fn is_empty(&self) -> bool {
+        self.content_len == 0
+    }
+
+    /// The number of chunks in the content. Empty content still has one (empty) chunk.
+    pub fn chunk_count(&self) -> u64 {
+        encode::count_chunks(self.content_len)
+    }
+
+    /// Returns `true` if chunk `index` has already been verified, so touching it again won't do
+    /// any hashing.
+    pub fn is_verified(&self, index: u64) -> bool {
+        let word = self.verified[(index / 64) as usize].load(Ordering::Relaxed);
+        word & (1 << (index % 64)) != 0
+    }
+
+    /// The content of chunk `index`, verifying it if this is the first touch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than `chunk_count`.
+    pub fn chunk(&self, index: u64) -> io::Result<&[u8]> {
+        assert!(index < self.chunk_count(), "chunk index out of range");
+        if self.is_verified(index) {
+            let offset = encode::chunk_encoded_offset(index, self.content_len) as usize;
+            let len = encode::chunk_size(index, self.content_len);
+            return Ok(&self.encoded.as_ref()[offset..][..len]);
+        }
+        let chunk = self.verify_chunk(index)?;
+        self.verified[(index / 64) as usize].fetch_or(1 << (index % 64), Ordering::Relaxed);
+        Ok(chunk)
+    }
+
+    /// Verify every chunk that overlaps `len` bytes starting at `start`, and return them as a
+    /// `VerifiedSlice`. As with `read_at` on a `VerifiedFile`, the range is clipped at the end of
+    /// the content, so it can come back shorter than `len`, or empty.
+    pub fn slice(&self, start: u64, len: u64) -> io::Result<VerifiedSlice<'_>> {
+        let start = cmp::min(start, self.content_len);
+        let end = start.saturating_add(len).min(self.content_len);
+        if start < end {
+            for index in start / CHUNK_SIZE as u64..=(end - 1) / CHUNK_SIZE as u64 {
+                self.chunk(index)?;
+            }
+        }
+        Ok(VerifiedSlice {
+            encoded: self.encoded.as_ref(),
+            content_len: self.content_len,
+            start,
+            end,
+        })
+    }
+
+    /// Copy verified content starting at `offset` into `buf`, and return how many bytes were
+    /// copied. This only copies fewer than `buf.len()` bytes at the end of the content.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut copied = 0;
+        for piece in self.slice(offset, buf.len() as u64)?.chunks() {
+            buf[copied..][..piece.len()].copy_from_slice(piece);
+            copied += piece.len();
+        }
+        Ok(copied)
+    }
+
+    /// Return the underlying encoding.
+    pub fn into_inner(self) -> T {
+        self.encoded
+    }
+
+    // Walk down from the root to chunk `index`, verifying each parent along the way, and then
+    // verify the chunk itself.
+    fn verify_chunk(&self, index: u64) -> io::Result<&[u8]> {
+        let encoded = self.encoded.as_ref();
+        let chunk_start = index * CHUNK_SIZE as u64;
+        let mut subtree_start = 0;
+        let mut subtree_len = self.content_len;
+        let mut offset = HEADER_SIZE;
+        let mut expected = self.root_hash;
+        let mut finalization = Root;
+        while subtree_len > CHUNK_SIZE as u64 {
+            let parent = array_ref!(encoded, offset, PARENT_SIZE);
+            let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+            let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+            let computed = crate::parent_cv(&left_child, &right_child, finalization.is_root());
+            // Hash implements constant time equality.
+            if computed != expected {
+                return Err(Error::HashMismatch.into());
+            }
+            let left_len = encode::left_subtree_len(subtree_len);
+            offset += PARENT_SIZE;
+            if chunk_start < subtree_start + left_len {
+                subtree_len = left_len;
+                expected = left_child;
+            } else {
+                offset += encode::encoded_subtree_size(left_len) as usize;
+                subtree_start += left_len;
+                subtree_len -= left_len;
+                expected = right_child;
+            }
+            finalization = NotRoot;
+        }
+        let chunk = &encoded[offset..][..subtree_len as usize];
+        let computed = crate::chunk_cv(index, chunk, finalization.is_root());
+        // Hash implements constant time equality.
+        if computed != expected {
+            return Err(Error::HashMismatch.into());
+        }
+        Ok(chunk)
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Debug for VerifiedBytes<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        let verified_chunks: u32 = self
+            .verified
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_ones())
+            .sum();
+        write!(
+            f,
+            "VerifiedBytes {{ content_len: {}, verified_chunks: {} }}",
+            self.content_len, verified_chunks,
+        )
+    }
+}
+
+/// A verified range of content, borrowed from a `VerifiedBytes`. Every chunk it overlaps was
+/// verified when it was created, so reading it can't fail.
+#[derive(Clone, Copy)]
+pub struct VerifiedSlice<'a> {
+    encoded: &'a [u8],
+    content_len: u64,
+    start: u64,
+    end: u64,
+}
+
+impl<'a> VerifiedSlice<'a> {
+    /// The content offset where the slice starts.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The length of the slice in bytes.
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Returns `true` if the slice is empty.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// The content of the slice, as one borrowed piece per chunk that it overlaps. The pieces are
+    /// in order, and the first and last are trimmed to the slice. An empty slice has no pieces.
+    pub fn chunks(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let Self {
+            encoded,
+            content_len,
+            start,
+            end,
+        } = *self;
+        let first = start / CHUNK_SIZE as u64;
+        let chunks = if start < end {
+            (end - 1) / CHUNK_SIZE as u64 + 1 - first
+        } else {
+            0
+        };
+        (first..first + chunks).map(move |index| {
+            let chunk_start = index * CHUNK_SIZE as u64;
+            let skip = start.saturating_sub(chunk_start) as usize;
+            let take = cmp::min(end - chunk_start, CHUNK_SIZE as u64) as usize - skip;
+            // VerifiedBytes::new checked that every offset fits in a usize.
+            let offset = encode::chunk_encoded_offset(index, content_len) as usize;
+            &encoded[offset + skip..][..take]
+        })
+    }
+
+    /// The whole slice as a single borrowed `&[u8]`, if it fits within one chunk. Reads that are
+    /// small and don't straddle a chunk boundary never need to copy.
+    pub fn as_contiguous(&self) -> Option<&'a [u8]> {
+        let mut chunks = self.chunks();
+        match (chunks.next(), chunks.next()) {
+            (None, _) => Some(&[]),
+            (Some(piece), None) => Some(piece),
+            _ => None,
+        }
+    }
+
+    /// Copy the slice into a new `Vec`.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(self.len() as usize);
+        for piece in self.chunks() {
+            vec.extend_from_slice(piece);
+        }
+        vec
+    }
+}
+
+impl<'a> fmt::Debug for VerifiedSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "VerifiedSlice {{ start: {}, len: {} }}",
+            self.start,
+            self.len(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+
+    #[test]
+    fn test_verified_bytes() {
+        for case in crate::test_vectors::test_cases(crate::test::MAX_TEST_CHUNKS) {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let bytes = VerifiedBytes::new(&encoded, &hash).unwrap();
+            assert_eq!(case as u64, bytes.len());
+            // Only the final chunk has been verified so far.
+            let last = bytes.chunk_count() - 1;
+            for index in 0..bytes.chunk_count() {
+                assert_eq!(index == last, bytes.is_verified(index));
+            }
+            for &start in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, case / 2, case, case + 1] {
+                for &len in &[0, 1, 10, CHUNK_SIZE, 3 * CHUNK_SIZE + 5] {
+                    let slice = bytes.slice(start as u64, len as u64).unwrap();
+                    let expected_start = cmp::min(start, case);
+                    let expected_end = cmp::min(start + len, case);
+                    let expected = &input[expected_start..expected_end];
+                    assert_eq!(expected.len() as u64, slice.len());
+                    assert_eq!(expected, &slice.to_vec()[..]);
+                    if let Some(contiguous) = slice.as_contiguous() {
+                        assert_eq!(expected, contiguous);
+                    } else {
+                        assert!(expected_start / CHUNK_SIZE < (expected_end - 1) / CHUNK_SIZE);
+                    }
+                    let mut buf = vec![0xff; len];
+                    let n = bytes.read_at(start as u64, &mut buf).unwrap();
+                    assert_eq!(expected, &buf[..n]);
+                }
+            }
+            for index in 0..bytes.chunk_count() {
+                let chunk_start = index as usize * CHUNK_SIZE;
+                let chunk = bytes.chunk(index).unwrap();
+                assert_eq!(&input[chunk_start..][..chunk.len()], chunk);
+                assert!(bytes.is_verified(index));
+            }
+        }
+    }
+
+    #[test]
+    fn test_verified_bytes_corrupt() {
+        let input = make_test_input(10 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let chunk_3 = encode::chunk_encoded_offset(3, input.len() as u64) as usize;
+        encoded[chunk_3] ^= 1;
+        let bytes = VerifiedBytes::new(&encoded, &hash).unwrap();
+        // Corruption only affects the chunk it's in, and it's never marked verified.
+        for _ in 0..2 {
+            let err = bytes.slice(0, input.len() as u64).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(!bytes.is_verified(3));
+        }
+        let err = bytes.chunk(3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            &input[..3 * CHUNK_SIZE],
+            &bytes.slice(0, 3 * CHUNK_SIZE as u64).unwrap().to_vec()[..]
+        );
+        assert_eq!(
+            &input[4 * CHUNK_SIZE..][..CHUNK_SIZE],
+            bytes.chunk(4).unwrap()
+        );
+
+        // A bad root hash fails on construction, as does a truncated encoding.
+        let (encoded, _) = encode::encode(&input);
+        let err = VerifiedBytes::new(&encoded, &blake3::hash(b"foo")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = VerifiedBytes::new(&encoded[..encoded.len() - 1], &hash).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = VerifiedBytes::new(&encoded[..HEADER_SIZE - 1], &hash).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_verified_bytes_threads() {
+        let input = make_test_input(100 * CHUNK_SIZE + 1);
+        let (encoded, hash) = encode::encode(&input);
+        let bytes = VerifiedBytes::new(encoded, &hash).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let (bytes, input) = (&bytes, &input);
+                scope.spawn(move || {
+                    for start in (thread * 100..input.len()).step_by(1000) {
+                        let slice = bytes.slice(start as u64, 3000).unwrap();
+                        let end = cmp::min(start + 3000, input.len());
+                        assert_eq!(&input[start..end], &slice.to_vec()[..]);
+                    }
+                });
+            }
+        });
+        assert!((0..bytes.chunk_count()).all(|index| bytes.is_verified(index)));
+    }
+
+    #[test]
+    fn test_limits_reject_huge_header() {
+        let (mut encoded, hash) = encode::encode(make_test_input(3 * CHUNK_SIZE));
+        encoded[..HEADER_SIZE].copy_from_slice(&crate::encode_len(u64::MAX));
+        let limits = Limits {
+            max_content_len: 1 << 30,
+            ..Limits::default()
+        };
+        let err = VerifiedBytes::new_with_limits(&encoded, &hash, &limits)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(Error::TooLong.to_string(), err.to_string());
+    }
+}
@@ -0,0 +1,226 @@
+//! The tests in this file check the Rust implementation against the Python reference
+//! implementation in `bao.py`, by running it as a subprocess. They need a `python3` on the PATH,
+//! or the interpreter named by `BAO_PYTHON`, so they only run with `--features interop`.
+//!
+//! Each test runs both implementations on pseudo-random inputs and checks that each one accepts
+//! what the other one produces. `bao.py` is slow, so the inputs are kept small, but they cover
+//! all the interesting tree shapes up to a few levels deep.
+
+#![cfg(feature = "interop")]
+
+use bao::{Hash, CHUNK_SIZE};
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const INPUT_LENGTHS: &[usize] = &[
+    0,
+    1,
+    CHUNK_SIZE - 1,
+    CHUNK_SIZE,
+    CHUNK_SIZE + 1,
+    2 * CHUNK_SIZE,
+    3 * CHUNK_SIZE + 1,
+    5 * CHUNK_SIZE - 1,
+    8 * CHUNK_SIZE + 7,
+];
+
+// Deterministic pseudo-random input, seeded by its length, so that a failure reproduces. Unlike
+// the counter inputs in the test vectors, this doesn't repeat any patterns between chunks.
+fn make_input(len: usize) -> Vec<u8> {
+    let mut rng = ChaCha8Rng::seed_from_u64(len as u64);
+    let mut input = vec![0; len];
+    rng.fill_bytes(&mut input);
+    input
+}
+
+// Returns (start, len) pairs covering slices at the edges and in the middle of the input,
+// including empty slices and slices that run past the end.
+fn slices(input_len: usize) -> Vec<(u64, u64)> {
+    let len = input_len as u64;
+    let chunk = CHUNK_SIZE as u64;
+    vec![
+        (0, 0),
+        (0, 1),
+        (0, len),
+        (len / 2, chunk),
+        (len.saturating_sub(1), 1),
+        (len, 0),
+        (len + 1, chunk),
+        (chunk - 1, 2),
+        (chunk, 3 * chunk),
+    ]
+}
+
+fn bao_py() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/bao.py")
+}
+
+// Run bao.py with the given arguments and stdin. Returns stdout, or None if it failed.
+fn try_python(args: &[&str], stdin: &[u8]) -> Option<Vec<u8>> {
+    let python = std::env::var("BAO_PYTHON").unwrap_or_else(|_| "python3".into());
+    let mut child = Command::new(python)
+        .arg(bao_py())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run bao.py");
+    // Large inputs could fill the stdout pipe before we finish writing, so write from another
+    // thread.
+    let mut child_stdin = child.stdin.take().unwrap();
+    let stdin = stdin.to_vec();
+    let writer = std::thread::spawn(move || {
+        // bao.py might exit early on bad input, so ignore broken pipes.
+        let _ = child_stdin.write_all(&stdin);
+    });
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+    if output.status.success() {
+        Some(output.stdout)
+    } else {
+        None
+    }
+}
+
+fn python(args: &[&str], stdin: &[u8]) -> Vec<u8> {
+    try_python(args, stdin).unwrap_or_else(|| panic!("bao.py {:?} failed", args))
+}
+
+// Write a temporary file, for the --outboard flag, or for input that bao.py needs to seek.
+fn temp_file(contents: &[u8]) -> tempfile::NamedTempFile {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(contents).unwrap();
+    file.flush().unwrap();
+    file
+}
+
+fn outboard_flag(file: &tempfile::NamedTempFile) -> String {
+    format!("--outboard={}", file.path().to_str().unwrap())
+}
+
+fn python_hash(input: &[u8]) -> Hash {
+    let hex = String::from_utf8(python(&["hash"], input)).unwrap();
+    hex.trim().parse().unwrap()
+}
+
+#[test]
+fn test_hash() {
+    for &len in INPUT_LENGTHS {
+        println!("input_len {}", len);
+        let input = make_input(len);
+        assert_eq!(blake3::hash(&input), python_hash(&input));
+    }
+}
+
+#[test]
+fn test_encode_and_decode() {
+    for &len in INPUT_LENGTHS {
+        println!("input_len {}", len);
+        let input = make_input(len);
+        let (encoded, hash) = bao::encode::encode(&input);
+        let hex = hash.to_hex();
+
+        // The encodings match byte for byte.
+        assert_eq!(encoded, python(&["encode", "-", "-"], &input));
+
+        // Each implementation decodes the other's encoding. Since the encodings are identical,
+        // this is mostly a check that bao.py decodes what we expect it to.
+        assert_eq!(input, python(&["decode", &hex], &encoded));
+        assert_eq!(input, bao::decode::decode(&encoded, &hash).unwrap());
+
+        // Both reject a corrupt encoding.
+        let mut corrupt = encoded.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert!(try_python(&["decode", &hex], &corrupt).is_none());
+        assert!(bao::decode::decode(&corrupt, &hash).is_err());
+    }
+}
+
+#[test]
+fn test_outboard() {
+    for &len in INPUT_LENGTHS {
+        println!("input_len {}", len);
+        let input = make_input(len);
+        let (outboard, hash) = bao::encode::outboard(&input);
+        let hex = hash.to_hex();
+
+        let python_outboard = tempfile::NamedTempFile::new().unwrap();
+        let flag = outboard_flag(&python_outboard);
+        python(&["encode", "-", &flag], &input);
+        assert_eq!(outboard, std::fs::read(python_outboard.path()).unwrap());
+
+        let outboard_file = temp_file(&outboard);
+        let flag = outboard_flag(&outboard_file);
+        assert_eq!(input, python(&["decode", &hex, "-", "-", &flag], &input));
+        let mut decoder = bao::decode::Decoder::new_outboard(&input[..], &outboard[..], &hash);
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(input, output);
+    }
+}
+
+#[test]
+fn test_slices() {
+    for &len in INPUT_LENGTHS {
+        let input = make_input(len);
+        let (encoded, hash) = bao::encode::encode(&input);
+        let (outboard, _) = bao::encode::outboard(&input);
+        // Slicing seeks, so bao.py needs its input in files rather than on stdin.
+        let input_file = temp_file(&input);
+        let input_path = input_file.path().to_str().unwrap();
+        let encoded_file = temp_file(&encoded);
+        let encoded_path = encoded_file.path().to_str().unwrap();
+        let outboard_file = temp_file(&outboard);
+        let outboard_flag = outboard_flag(&outboard_file);
+        let hex = hash.to_hex();
+        for (start, slice_len) in slices(len) {
+            println!("input_len {} start {} len {}", len, start, slice_len);
+            let start_arg = start.to_string();
+            let len_arg = slice_len.to_string();
+
+            // Extract the slice both ways, from both combined and outboard encodings.
+            let mut slice = Vec::new();
+            bao::encode::SliceExtractor::new(Cursor::new(&encoded), start, slice_len)
+                .read_to_end(&mut slice)
+                .unwrap();
+            let args = ["slice", &start_arg, &len_arg, encoded_path];
+            assert_eq!(slice, python(&args, &[]));
+            let mut outboard_slice = Vec::new();
+            bao::encode::SliceExtractor::new_outboard(
+                Cursor::new(&input),
+                Cursor::new(&outboard),
+                start,
+                slice_len,
+            )
+            .read_to_end(&mut outboard_slice)
+            .unwrap();
+            assert_eq!(slice, outboard_slice);
+            let args = [
+                "slice",
+                &start_arg,
+                &len_arg,
+                input_path,
+                "-",
+                &outboard_flag,
+            ];
+            assert_eq!(slice, python(&args, &[]));
+
+            // Decode the slice both ways.
+            let mut expected = Vec::new();
+            bao::decode::SliceDecoder::new(&slice[..], &hash, start, slice_len)
+                .read_to_end(&mut expected)
+                .unwrap();
+            let clamped_start = std::cmp::min(start as usize, len);
+            let clamped_end = std::cmp::min(start.saturating_add(slice_len) as usize, len);
+            assert_eq!(&input[clamped_start..clamped_end], &expected[..]);
+            let args = ["decode-slice", &hex, &start_arg, &len_arg];
+            assert_eq!(expected, python(&args, &slice));
+        }
+    }
+}
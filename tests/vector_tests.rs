@@ -334,3 +334,36 @@ fn test_slice_vectors() {
         }
     }
 }
+
+#[test]
+fn test_golden_vectors_up_to_date() {
+    // If this fails after an intentional format change, regenerate the file with:
+    // cargo run --example generate_golden_vectors > tests/golden_vectors.json
+    let golden = include_str!("golden_vectors.json");
+    let generated = bao::test_vectors::to_json(&bao::test_vectors::generate());
+    assert!(golden == generated, "golden_vectors.json is out of date");
+}
+
+#[test]
+fn test_golden_vectors_match_test_vectors() {
+    let golden = bao::test_vectors::generate();
+    assert_eq!(TEST_VECTORS.encode.len(), golden.len());
+    for (case, vector) in TEST_VECTORS.encode.iter().zip(&golden) {
+        assert_eq!(case.input_len, vector.input_len);
+        assert_eq!(
+            make_input(case.input_len),
+            bao::test_vectors::input(vector.input_len)
+        );
+        assert_eq!(&*case.bao_hash, &*vector.hash.to_hex());
+        assert_eq!(
+            case.encoded_blake3,
+            blake3::hash(&vector.encoded).to_hex().as_str()
+        );
+    }
+    for (case, vector) in TEST_VECTORS.outboard.iter().zip(&golden) {
+        assert_eq!(
+            case.encoded_blake3,
+            blake3::hash(&vector.outboard).to_hex().as_str()
+        );
+    }
+}